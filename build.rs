@@ -0,0 +1,54 @@
+//! Sets a `have_<feature>` cfg for each component feature that is enabled
+//! and supported on the target architecture, so that code is gated on e.g.
+//! `have_efi` instead of repeating which architectures EFI is built for.
+//! The aliases in [`ALIASES`], e.g. `have_component`, group these.
+
+use std::env;
+
+/// The component features, and the architectures each is built on.
+const COMPONENTS: &[(&str, &[&str])] = &[
+    ("bios", &["x86_64", "powerpc64"]),
+    ("coreboot", &["x86_64"]),
+    ("efi", &["x86_64", "aarch64"]),
+    ("extlinux", &["arm"]),
+    ("rpi", &["aarch64"]),
+    ("uboot", &["aarch64"]),
+    // Direct ESP access, which implies `efi`
+    ("fat", &["x86_64", "aarch64"]),
+];
+
+/// Aliases set if any of the listed component features is.
+const ALIASES: &[(&str, &[&str])] = &[
+    (
+        "component",
+        &["bios", "coreboot", "efi", "extlinux", "rpi", "uboot"],
+    ),
+    // Reading the block device topology; see src/blockdev.rs
+    ("blockdev", &["bios", "efi", "uboot"]),
+    // Reading back what's written; see src/readback.rs
+    ("readback", &["bios", "efi", "extlinux", "rpi", "uboot"]),
+    // Copying files into place; see `util::copy_file_contents`
+    ("filecopy", &["bios", "efi", "extlinux", "rpi"]),
+    // Installing a tree of files; see src/filetree.rs
+    ("filetree", &["efi", "extlinux", "rpi"]),
+];
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").expect("CARGO_CFG_TARGET_ARCH");
+    let mut have = Vec::new();
+    for &(feature, arches) in COMPONENTS {
+        println!("cargo:rustc-check-cfg=cfg(have_{feature})");
+        let enabled = env::var_os(format!("CARGO_FEATURE_{}", feature.to_uppercase())).is_some();
+        if enabled && arches.contains(&arch.as_str()) {
+            println!("cargo:rustc-cfg=have_{feature}");
+            have.push(feature);
+        }
+    }
+    for &(alias, features) in ALIASES {
+        println!("cargo:rustc-check-cfg=cfg(have_{alias})");
+        if features.iter().any(|f| have.contains(f)) {
+            println!("cargo:rustc-cfg=have_{alias}");
+        }
+    }
+}
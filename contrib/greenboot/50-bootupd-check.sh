@@ -0,0 +1,7 @@
+#!/bin/bash
+# greenboot health check; install into /etc/greenboot/check/required.d/
+#
+# Fails the boot if the installed bootloader doesn't match what bootupd
+# recorded, or if a bootloader update was interrupted.
+set -euo pipefail
+exec /usr/libexec/bootupd health-check
@@ -0,0 +1,7 @@
+#!/bin/bash
+# greenboot red-boot handler; install into /etc/greenboot/red.d/
+#
+# Reapplies the bootloader payloads of the ostree rollback deployment, so
+# that the boot chain matches the OS content booted after the rollback.
+set -euo pipefail
+exec /usr/libexec/bootupd health-check --rollback
@@ -36,6 +36,9 @@ BuildRequires: systemd-rpm-macros
 %{_libexecdir}/bootupd
 %{_prefix}/lib/bootupd/grub2-static/
 %{_unitdir}/bootloader-update.service
+%{_unitdir}/bootupd-firstboot.service
+%{_unitdir}/bootupd-stage.path
+%{_unitdir}/bootupd-stage.service
 
 %prep
 %autosetup -n %{crate}-%{version} -p1 -Sgit
@@ -57,6 +60,10 @@ cargo build --release
 make install-grub-static DESTDIR=%{?buildroot} INSTALL="%{__install} -p"
 make install-systemd-unit DESTDIR=%{?buildroot} INSTALL="%{__install} -p"
 
+# Refresh the cached update metadata when the bootloader packages change
+%filetriggerin -- /boot/efi/EFI /usr/lib/grub /usr/sbin/grub2-install
+%{_libexecdir}/bootupd trigger-payload-changed || :
+
 %changelog
 * Tue Oct 18 2022 Colin Walters <walters@verbum.org> - 0.2.8-3
 - Dummy changelog
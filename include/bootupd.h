@@ -0,0 +1,43 @@
+/*
+ * C bindings of the bootupd library; build with `make ffi`, which
+ * produces libbootupd.so.
+ *
+ * Each function returns 0 with its JSON result in `*out`, or -1 with a
+ * JSON error report in `*out` (as printed by `bootupctl
+ * --error-format=json`).  The strings returned in `*out` must be released
+ * with bootupd_free().  Options are JSON objects; NULL selects the
+ * defaults.  The functions operate on the running system and must be
+ * called with root privileges.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+#ifndef BOOTUPD_H
+#define BOOTUPD_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The status of the installed and adoptable components, as printed by
+ * `bootupctl status --json`. */
+int bootupd_status(char **out);
+
+/* Update the installed components with an available update; options
+ * are e.g. {"components": ["EFI"], "jobs": 1}.  The result maps the
+ * updated components to their previous and new versions. */
+int bootupd_update(const char *options, char **out);
+
+/* Validate the installed files of the installed components; options are
+ * e.g. {"components": ["EFI"]}.  The result maps each component to
+ * "valid", "skip" or {"errors": [...]}. */
+int bootupd_validate(const char *options, char **out);
+
+/* Release a string returned in `*out`. */
+void bootupd_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* BOOTUPD_H */
@@ -0,0 +1,59 @@
+# Type stubs of the bootupd Python module, built with `make python`.
+
+from datetime import datetime
+from typing import Optional
+
+class Error(Exception):
+    # The class of the error, as in `bootupctl --error-format=json`
+    error_class: str
+    hint: Optional[str]
+
+class ContentMetadata:
+    timestamp: datetime
+    version: str
+
+class ComponentStatus:
+    installed: ContentMetadata
+    interrupted: Optional[ContentMetadata]
+    update: Optional[ContentMetadata]
+    # "no-update-available", "at-latest-version", "upgradable" or "would-downgrade"
+    updatable: str
+    adopted_from: Optional[ContentMetadata]
+    devices: list[str]
+
+class Adoptable:
+    version: ContentMetadata
+    confident: bool
+
+class Firmware:
+    # "uefi", "bios" or "other"
+    boot_mode: str
+    secure_boot: Optional[bool]
+    vendor: Optional[str]
+    version: Optional[str]
+    date: Optional[str]
+
+class Status:
+    components: dict[str, ComponentStatus]
+    adoptable: dict[str, Adoptable]
+    firmware: Optional[Firmware]
+
+class UpdateResult:
+    # Both unset if the component was already at the latest version
+    previous: Optional[ContentMetadata]
+    interrupted: Optional[ContentMetadata]
+    new: Optional[ContentMetadata]
+
+class Validation:
+    # "valid", "skip" or "errors"
+    state: str
+    errors: list[str]
+
+def status() -> Status: ...
+def update(
+    components: Optional[list[str]] = None, jobs: Optional[int] = None
+) -> dict[str, UpdateResult]: ...
+def adopt_and_update(
+    component: str, create_bios_boot_partition: bool = False
+) -> ContentMetadata: ...
+def validate(components: Optional[list[str]] = None) -> dict[str, Validation]: ...
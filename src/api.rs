@@ -0,0 +1,549 @@
+//! Library interface, for tools like bootc and osbuild that would otherwise
+//! invoke `bootupctl` and parse its output.
+//!
+//! These functions operate directly on the running system (or the given
+//! target root for [`install`]) and must be called with root privileges.
+//! Unlike `bootupctl`, they do not re-execute via `systemd-run`; callers
+//! are responsible for any sandboxing.
+//!
+//! Everything exported here follows semantic versioning: the option and
+//! result types are `#[non_exhaustive]` so that fields and variants can be
+//! added in minor releases, and [`Component`] is sealed, so that methods
+//! can be added to it too.  The rest of the crate is internal.
+//!
+//! Failures are returned as an [`Error`], whose [`ErrorKind`] is the kind
+//! of failure to match on, e.g. [`ErrorKind::EspFull`]; its [`Details`] display
+//! the message (with `{:#}`, including its causes), chain the causes as
+//! [`std::error::Error::source`], and tell the component and device
+//! operated on, if known.
+//!
+//! With the `tracing` cargo feature, the operations are instrumented with
+//! `tracing` spans named `bootupd`, which flow to the consumer's
+//! subscriber; other messages go through `log`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::component::Options;
+use crate::errors::ComponentContext;
+#[cfg(any(feature = "ffi", feature = "python"))]
+use crate::errors::ErrorReport;
+
+pub use crate::bootupd::{ComponentUpdateResult, ConfigMode};
+pub use crate::component::ValidationResult;
+pub use crate::errors::ErrorKind;
+pub use crate::events::{Observer, Operation, Registration};
+pub use crate::model::{
+    Adoptable, AdoptionAction, AdoptionReport, AdoptionState, BootMode, ComponentAdoption,
+    ComponentStatus, ComponentUpdatable, ContentMetadata, DetectedBootloader, Firmware, Status,
+};
+
+/// The result of the operations of the library interface.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A failed operation: its [`ErrorKind`], as classified in the JSON error
+/// reports of `bootupctl --error-format=json`, and its [`Details`].
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    details: Details,
+}
+
+impl Error {
+    /// The kind of failure.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// The details of the failure, whatever its kind.
+    pub fn details(&self) -> &Details {
+        &self.details
+    }
+
+    /// The JSON error report of the failure.
+    #[cfg(any(feature = "ffi", feature = "python"))]
+    pub(crate) fn report(&self) -> ErrorReport {
+        ErrorReport::new(&self.details.0)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.details, f)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        std::error::Error::source(&self.details)
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        Self {
+            kind: crate::errors::classify(&e),
+            details: Details(e),
+        }
+    }
+}
+
+/// The message and causes of an [`Error`], and what it was operating on.
+pub struct Details(anyhow::Error);
+
+impl Details {
+    /// The component operated on, e.g. `EFI`, if known.
+    pub fn component(&self) -> Option<&str> {
+        self.context().map(|c| c.component.as_str())
+    }
+
+    /// The device operated on, e.g. `/dev/vda`, if known.
+    pub fn device(&self) -> Option<&str> {
+        self.context().and_then(|c| c.device.as_deref())
+    }
+
+    /// The OS error which caused the failure, if any.
+    pub fn errno(&self) -> Option<i32> {
+        crate::errors::errno(&self.0)
+    }
+
+    /// The error output of the failed external command, if any.
+    pub fn stderr(&self) -> Option<&str> {
+        crate::errors::stderr(&self.0)
+    }
+
+    /// A short remediation hint for users, if there's one for the kind of
+    /// failure.
+    pub fn hint(&self) -> Option<&'static str> {
+        crate::errors::classify(&self.0).hint()
+    }
+
+    /// The messages of the failure and of its causes, outermost first.
+    pub fn chain(&self) -> impl Iterator<Item = &(dyn std::error::Error + 'static)> {
+        self.0.chain()
+    }
+
+    fn context(&self) -> Option<&ComponentContext> {
+        self.0.downcast_ref::<ComponentContext>()
+    }
+}
+
+impl std::fmt::Debug for Details {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl std::fmt::Display for Details {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl std::error::Error for Details {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.chain().nth(1)
+    }
+}
+
+/// Manifests of directory trees and their differences, as bootupd uses to
+/// update the files it manages: e.g. for diffing images with the same
+/// semantics.
+///
+/// A [`FileTree`](filetree::FileTree) records the size and SHA-512 digest
+/// of each regular file below a directory, as stored in the bootupd state;
+/// a file has changed if either differs.  [`FileTree::diff`](filetree::FileTree::diff)
+/// lists the files added, removed and changed from one tree to another,
+/// which serializes to JSON with each list sorted.
+pub mod filetree {
+    pub use crate::filetree::{FileMetadata, FileTree, FileTreeDiff};
+    pub use crate::sha512string::SHA512String;
+}
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// A bootloader component managed by bootupd, such as `EFI` or `BIOS`.
+///
+/// This trait is sealed; the components are those built into this crate,
+/// as listed by [`components`].
+pub trait Component: private::Sealed {
+    /// The name of the component, as used in the state and the status.
+    fn name(&self) -> &'static str;
+
+    /// Return the update available in the OS root `sysroot`, if any.
+    fn query_update(&self, sysroot: &Path) -> Result<Option<ContentMetadata>>;
+
+    /// Detect an installation of the component on the running system which
+    /// wasn't made by bootupd, and could be adopted.
+    fn query_adopt(&self) -> Result<Option<Adoptable>>;
+
+    /// Names of components which must finish updating before this one
+    /// starts.
+    fn update_after(&self) -> &'static [&'static str];
+}
+
+struct BuiltinComponent(Box<dyn crate::component::Component>);
+
+impl private::Sealed for BuiltinComponent {}
+
+impl Component for BuiltinComponent {
+    fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    fn query_update(&self, sysroot: &Path) -> Result<Option<ContentMetadata>> {
+        let sysroot = openat::Dir::open(sysroot).map_err(anyhow::Error::from)?;
+        Ok(self.0.query_update(&sysroot)?)
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        Ok(self.0.query_adopt()?)
+    }
+
+    fn update_after(&self) -> &'static [&'static str] {
+        self.0.update_after()
+    }
+}
+
+/// The components supported on this architecture, by name.
+pub fn components() -> BTreeMap<&'static str, Box<dyn Component>> {
+    crate::bootupd::get_components()
+        .into_iter()
+        .map(|(name, c)| (name, Box::new(BuiltinComponent(c)) as Box<dyn Component>))
+        .collect()
+}
+
+/// Look up a component by name, e.g. `EFI`.
+pub fn component(name: &str) -> Result<Box<dyn Component>> {
+    Ok(Box::new(BuiltinComponent(crate::component::new_from_name(
+        name,
+    )?)))
+}
+
+/// Options for [`install`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct InstallOptions {
+    /// Root containing the update payloads; defaults to `/`
+    pub src_root: String,
+    /// Target root
+    pub dest_root: String,
+    /// Target device, used by bios bootloader installation
+    pub device: Option<PathBuf>,
+    /// Static bootloader configs to install
+    pub configs: ConfigMode,
+    /// On EFI systems, invoke `efibootmgr` to update the firmware
+    pub update_firmware: bool,
+    /// Only install these components
+    pub components: Option<Vec<String>>,
+    /// Automatically choose components based on booted host state
+    pub auto: bool,
+    /// Produce identical output for identical inputs; see
+    /// `bootupctl backend install --deterministic`
+    pub deterministic: bool,
+    /// Install components even if they don't match the firmware the system
+    /// is booted with; see `bootupctl backend install --force`
+    pub force: bool,
+    /// Install the BIOS GRUB modules to /boot even if it's a FAT filesystem,
+    /// such as the ESP mounted at /boot
+    pub allow_fat_boot: bool,
+    /// On x86_64, create a BIOS boot partition in the free space of the
+    /// target GPT disk if it lacks one
+    pub create_bios_boot_partition: bool,
+    /// Read back everything written once it's synced, and fail unless it
+    /// matches what was written
+    pub verify_writes: bool,
+    /// Install U-Boot for this board rather than the only one the target
+    /// root has a profile for
+    pub uboot_board: Option<String>,
+}
+
+impl Default for InstallOptions {
+    fn default() -> Self {
+        Self {
+            src_root: "/".into(),
+            dest_root: "/".into(),
+            device: None,
+            configs: ConfigMode::None,
+            update_firmware: false,
+            components: None,
+            auto: false,
+            deterministic: false,
+            force: false,
+            allow_fat_boot: false,
+            create_bios_boot_partition: false,
+            verify_writes: false,
+            uboot_board: None,
+        }
+    }
+}
+
+impl InstallOptions {
+    /// How the components write when installing.
+    // Which fields are left depends on the architecture
+    #[allow(clippy::needless_update)]
+    pub(crate) fn component_options(&self) -> Options {
+        Options {
+            #[cfg(have_readback)]
+            verify_writes: self.verify_writes,
+            #[cfg(have_bios)]
+            allow_fat_boot: self.allow_fat_boot,
+            #[cfg(all(have_bios, target_arch = "x86_64"))]
+            create_bios_boot_partition: self.create_bios_boot_partition,
+            #[cfg(have_uboot)]
+            uboot_board: self.uboot_board.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Options for [`update`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct UpdateOptions {
+    /// Only update these components (matched case-insensitively)
+    pub components: Option<Vec<String>>,
+    /// Maximum number of independent components to update concurrently
+    pub jobs: usize,
+    /// Update even if Secure Boot is enabled and the new EFI loader isn't
+    /// signed by a certificate in the firmware db
+    pub force: bool,
+    /// Read back everything written once it's synced, and fail unless it
+    /// matches what was written
+    pub verify_writes: bool,
+    /// Limit writing files to this many bytes per second
+    pub write_rate_limit: Option<u64>,
+    /// Update the ESP by writing its FAT filesystem through the partition
+    /// device, without mounting it; see `bootupctl update --direct-esp`
+    pub direct_esp: bool,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        Self {
+            components: None,
+            jobs: crate::bootupd::DEFAULT_UPDATE_JOBS,
+            force: false,
+            verify_writes: false,
+            write_rate_limit: None,
+            direct_esp: false,
+        }
+    }
+}
+
+impl UpdateOptions {
+    /// How the components write when updating.
+    // Which fields are left depends on the architecture
+    #[allow(clippy::needless_update)]
+    pub(crate) fn component_options(&self) -> Options {
+        Options {
+            #[cfg(have_readback)]
+            verify_writes: self.verify_writes,
+            #[cfg(have_filetree)]
+            write_rate_limit: self.write_rate_limit,
+            #[cfg(have_efi)]
+            force: self.force,
+            #[cfg(have_fat)]
+            direct_esp: self.direct_esp,
+            ..Default::default()
+        }
+    }
+}
+
+/// Options for [`probe_adoption`] and [`adopt_and_update`].
+#[derive(Debug, Default)]
+#[non_exhaustive]
+pub struct AdoptOptions {
+    /// On x86_64, allow creating a BIOS boot partition in the free space of
+    /// GPT disks lacking one, so that systems booted via EFI can adopt BIOS
+    /// too; see `bootupctl adopt-and-update --create-bios-boot-partition`
+    pub create_bios_boot_partition: bool,
+    /// Adopt even if Secure Boot is enabled and the new EFI loader isn't
+    /// signed by a certificate in the firmware db
+    pub force: bool,
+    /// Read back everything written once it's synced, and fail unless it
+    /// matches what was written
+    pub verify_writes: bool,
+}
+
+impl AdoptOptions {
+    /// How the components write when adopting.
+    // Which fields are left depends on the architecture
+    #[allow(clippy::needless_update)]
+    pub(crate) fn component_options(&self) -> Options {
+        Options {
+            #[cfg(have_readback)]
+            verify_writes: self.verify_writes,
+            #[cfg(have_efi)]
+            force: self.force,
+            #[cfg(all(have_bios, target_arch = "x86_64"))]
+            create_bios_boot_partition: self.create_bios_boot_partition,
+            ..Default::default()
+        }
+    }
+}
+
+/// Generate the update metadata of all components in the OS root
+/// `sysroot`, as done by `bootupctl backend generate-update-metadata` when
+/// building an OS image; with `compress`, it's stored zstd-compressed.
+pub fn generate_update_metadata(sysroot: &str, compress: bool) -> Result<()> {
+    Ok(crate::bootupd::generate_update_metadata(sysroot, compress)?)
+}
+
+/// Install the bootloader components into a target root, as done by
+/// `bootupctl backend install`.
+pub fn install(opts: &InstallOptions) -> Result<()> {
+    Ok(crate::bootupd::install(
+        &opts.src_root,
+        &opts.dest_root,
+        opts.device.as_deref(),
+        opts.configs,
+        opts.update_firmware,
+        opts.components.as_deref(),
+        opts.auto,
+        false,
+        opts.deterministic,
+        opts.force,
+        &opts.component_options(),
+    )?)
+}
+
+/// Return the status of installed and adoptable components, as output by
+/// `bootupctl status --json`.
+pub fn status() -> Result<Status> {
+    let mut status = crate::bootupd::status()?;
+    crate::bootupd::check_consistency(&mut status)?;
+    Ok(status)
+}
+
+/// Update installed components which have an available update.  Components
+/// which are already current are omitted from the result.
+pub fn update(opts: &UpdateOptions) -> Result<BTreeMap<String, ComponentUpdateResult>> {
+    let status = crate::bootupd::status()?;
+    let names = match opts.components.as_deref() {
+        Some(selected) => crate::bootupd::resolve_component_names(
+            status.components.keys().map(|k| k.as_str()),
+            selected,
+        )?,
+        None => status.components.keys().cloned().collect(),
+    };
+    let upgradable = names
+        .into_iter()
+        .filter(|name| {
+            matches!(
+                status.components[name].updatable,
+                ComponentUpdatable::Upgradable
+            )
+        })
+        .collect::<Vec<_>>();
+    let source = openat::Dir::open("/").map_err(anyhow::Error::from)?;
+    Ok(crate::bootupd::update_many(
+        &upgradable,
+        opts.jobs,
+        &source,
+        &opts.component_options(),
+    )?)
+}
+
+/// Validate the installed files of the components `components` (matched
+/// case-insensitively), or of all installed components, as done by
+/// `bootupctl validate`.
+pub fn validate(components: Option<&[String]>) -> Result<BTreeMap<String, ValidationResult>> {
+    let status = crate::bootupd::status_with(false, &Options::default())?;
+    let installed = status.components.keys().map(|k| k.as_str());
+    let names = match components {
+        Some(selected) => crate::bootupd::resolve_component_names(installed, selected)?,
+        None => installed.map(ToOwned::to_owned).collect(),
+    };
+    names
+        .into_iter()
+        .map(|name| {
+            let r = crate::bootupd::validate(&name, &Options::default())?;
+            Ok((name, r))
+        })
+        .collect()
+}
+
+/// Probe whether each component could be adopted, with the bootloader
+/// found and what adopting would take, e.g. for an installer to decide
+/// whether to take over the bootloader of an existing system before
+/// calling [`adopt_and_update`].  Nothing is written.
+pub fn probe_adoption(opts: &AdoptOptions) -> Result<AdoptionReport> {
+    Ok(crate::bootupd::probe_adoption(&opts.component_options())?)
+}
+
+/// Adopt a component which was not installed via bootupd and update it,
+/// returning the new version.
+pub fn adopt_and_update(component: &str, opts: &AdoptOptions) -> Result<ContentMetadata> {
+    Ok(crate::bootupd::adopt_and_update(
+        component,
+        &opts.component_options(),
+    )?)
+}
+
+/// Notify `observer` of the progress of [`install`], [`update`] and
+/// [`adopt_and_update`] (in any thread) until the returned registration is
+/// dropped, e.g. to show progress.
+pub fn add_observer(observer: Arc<dyn Observer>) -> Registration {
+    crate::events::register(observer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors() {
+        use std::error::Error as _;
+
+        let e = anyhow::Error::from(std::io::Error::from_raw_os_error(libc::ENOSPC))
+            .context("copying shimx64.efi")
+            .context(crate::errors::Error::new(
+                ErrorKind::EspFull,
+                "No space left on the ESP",
+            ))
+            .context(ComponentContext::new("EFI", "update").device("/dev/vda"));
+        let e = Error::from(e);
+        assert_eq!(e.kind(), ErrorKind::EspFull, "{e:?}");
+        let details = e.details();
+        assert_eq!(details.component(), Some("EFI"));
+        assert_eq!(details.device(), Some("/dev/vda"));
+        assert_eq!(details.errno(), Some(libc::ENOSPC));
+        assert_eq!(details.hint(), ErrorKind::EspFull.hint());
+        // The context chain is preserved, as sources
+        assert_eq!(e.to_string(), "Failed to update EFI on /dev/vda");
+        assert_eq!(
+            e.source().map(|s| s.to_string()).as_deref(),
+            Some("No space left on the ESP")
+        );
+        assert_eq!(details.chain().count(), 4);
+        assert!(
+            format!("{details:#}").ends_with("(os error 28)"),
+            "{details:#}"
+        );
+
+        let e = Error::from(anyhow::anyhow!("oops"));
+        assert_eq!(e.kind(), ErrorKind::Other);
+        assert!(e.source().is_none());
+        assert_eq!(e.details().component(), None);
+    }
+
+    #[test]
+    fn test_components() -> anyhow::Result<()> {
+        let components = components();
+        for (name, c) in components.iter() {
+            assert_eq!(*name, c.name());
+            assert_eq!(component(name)?.name(), c.name());
+        }
+        assert!(component("NONEXISTENT").is_err());
+        // Without an update payload in the root
+        let td = tempfile::tempdir()?;
+        if let Some(c) = components.values().next() {
+            assert_eq!(c.query_update(td.path())?, None);
+        }
+        Ok(())
+    }
+}
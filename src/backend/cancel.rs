@@ -0,0 +1,60 @@
+//! Cooperative cancellation of mutating operations.
+//!
+//! While a state write lock is held, SIGTERM and SIGINT don't terminate
+//! the process; instead they are recorded here, and long-running operations
+//! check for them at points where stopping leaves the system in a
+//! consistent state.
+
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use anyhow::Result;
+
+/// The signal which requested cancellation, or zero.
+static CANCEL_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// Record a cancellation request; this is async-signal-safe.
+pub(crate) fn request(signal: i32) {
+    CANCEL_SIGNAL.store(signal, Ordering::SeqCst);
+}
+
+/// Return the signal that requested cancellation, if any.
+pub(crate) fn requested() -> Option<i32> {
+    match CANCEL_SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        n => Some(n),
+    }
+}
+
+/// Return an error if cancellation was requested.
+pub(crate) fn check() -> Result<()> {
+    if let Some(signal) = requested() {
+        return Err(crate::errors::Error::new(
+            crate::errors::ErrorKind::Cancelled,
+            format!("Operation cancelled by signal {signal}"),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Record in the journal (falling back to our log) that a transaction
+/// was aborted before the given components could be updated.
+pub(crate) fn record_aborted(operation: &str, components: &[&str]) {
+    let components = components.join(" ");
+    let msg = format!("bootupd: {operation} aborted; not processed: {components}");
+    let fields = [
+        ("BOOTUPD_OPERATION", operation),
+        ("BOOTUPD_ABORTED_COMPONENTS", components.as_str()),
+    ];
+    if libsystemd::logging::connected_to_journal() {
+        let r = libsystemd::logging::journal_send(
+            libsystemd::logging::Priority::Warning,
+            &msg,
+            fields.into_iter(),
+        );
+        if let Err(e) = r {
+            log::debug!("Failed to write to journal: {e}");
+        }
+    }
+    log::warn!("{msg}");
+}
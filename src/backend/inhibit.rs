@@ -0,0 +1,75 @@
+//! Blocking shutdown and sleep during mutating operations.
+//!
+//! A reboot or suspend in the middle of e.g. swapping the files on the ESP
+//! could leave the system unbootable.  While a state write lock is held,
+//! we hold a logind inhibitor lock too, through a `systemd-inhibit` helper
+//! process which waits for its stdin to be closed.  That happens when the
+//! [`Inhibitor`] is dropped, or when we exit in any other way, so the lock
+//! can't outlive us.
+
+use std::io::{BufRead, BufReader};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Stdio};
+
+use anyhow::{Context, Result};
+
+/// What is inhibited.
+const WHAT: &str = "shutdown:sleep";
+
+/// Holds the inhibitor lock until dropped.
+#[derive(Debug)]
+pub(crate) struct Inhibitor(Child);
+
+impl Inhibitor {
+    /// Take the inhibitor lock, explaining that we're doing `why`.  There's
+    /// nothing to inhibit without systemd, e.g. in a container, and failure
+    /// to take it isn't fatal either.
+    pub(crate) fn new(why: &str) -> Option<Self> {
+        if !Path::new("/run/systemd/system").exists() {
+            return None;
+        }
+        match Self::take(why) {
+            Ok(i) => Some(i),
+            Err(e) => {
+                log::warn!("Failed to block shutdown during the update: {e:#}");
+                None
+            }
+        }
+    }
+
+    fn take(why: &str) -> Result<Self> {
+        let mut child = crate::util::command("systemd-inhibit")
+            .arg(format!("--what={WHAT}"))
+            .args(["--who=bootupd", "--mode=block"])
+            .arg(format!("--why={why}"))
+            // The helper prints a line once it holds the lock, then waits for EOF
+            .args(["sh", "-c", "echo; read _"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            // Not interrupted along with us by ^C, while we defer signals
+            .process_group(0)
+            .spawn()
+            .context("Spawning systemd-inhibit")?;
+        // Unwrap safety: piped above
+        let stdout = child.stdout.take().unwrap();
+        let mut line = String::new();
+        BufReader::new(stdout).read_line(&mut line)?;
+        if line.is_empty() {
+            let status = child.wait()?;
+            anyhow::bail!("systemd-inhibit failed: {status}");
+        }
+        log::debug!("Inhibiting {WHAT}");
+        Ok(Self(child))
+    }
+}
+
+impl Drop for Inhibitor {
+    fn drop(&mut self) {
+        // Closing stdin ends the helper, which releases the lock
+        drop(self.0.stdin.take());
+        if let Err(e) = self.0.wait() {
+            log::warn!("Failed to release the inhibitor lock: {e}");
+        }
+    }
+}
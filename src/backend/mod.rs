@@ -1,3 +1,5 @@
 //! Internal logic for bootloader and system state manipulation.
 
+pub(crate) mod cancel;
+mod inhibit;
 mod statefile;
@@ -1,6 +1,6 @@
 //! On-disk saved state.
 
-use crate::model::SavedState;
+use crate::model::{SavedState, StateSummary};
 use anyhow::{bail, Context, Result};
 use fn_error_context::context;
 use fs2::FileExt;
@@ -9,22 +9,30 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
 
-/// Suppress SIGTERM while active
-// TODO: In theory we could record if we got SIGTERM and exit
-// on drop, but in practice we don't care since we're going to exit anyways.
+/// Defer SIGTERM and SIGINT while active; received signals are recorded as
+/// a cancellation request (see [`super::cancel`]) which in-progress operations
+/// honor once they reach a consistent point.
 #[derive(Debug)]
-struct SignalTerminationGuard(signal_hook_registry::SigId);
+struct SignalTerminationGuard(Vec<signal_hook_registry::SigId>);
 
 impl SignalTerminationGuard {
     pub(crate) fn new() -> Result<Self> {
-        let signal = unsafe { signal_hook_registry::register(libc::SIGTERM, || {})? };
-        Ok(Self(signal))
+        let mut ids = Vec::new();
+        for signal in [libc::SIGTERM, libc::SIGINT] {
+            let id = unsafe {
+                signal_hook_registry::register(signal, move || super::cancel::request(signal))?
+            };
+            ids.push(id);
+        }
+        Ok(Self(ids))
     }
 }
 
 impl Drop for SignalTerminationGuard {
     fn drop(&mut self) {
-        signal_hook_registry::unregister(self.0);
+        for &id in self.0.iter() {
+            signal_hook_registry::unregister(id);
+        }
     }
 }
 
@@ -35,6 +43,8 @@ impl SavedState {
     pub(crate) const STATEFILE_DIR: &'static str = "boot";
     /// On-disk bootloader statefile, akin to a tiny rpm/dpkg database, stored in `/boot`.
     pub(crate) const STATEFILE_NAME: &'static str = "bootupd-state.json";
+    /// The previous generation of the statefile, used if the current one is corrupt.
+    pub(crate) const STATEFILE_BACKUP_NAME: &'static str = "bootupd-state.json.prev";
 
     /// Try to acquire a system-wide lock to ensure non-conflicting state updates.
     ///
@@ -47,6 +57,7 @@ impl SavedState {
         let guard = StateLockGuard {
             sysroot,
             termguard: Some(SignalTerminationGuard::new()?),
+            inhibitor: super::inhibit::Inhibitor::new("Updating the bootloader"),
             lockfile: Some(lockfile),
         };
         Ok(guard)
@@ -58,41 +69,97 @@ impl SavedState {
         Ok(StateLockGuard {
             sysroot,
             termguard: None,
+            inhibitor: None,
             lockfile: None,
         })
     }
 
-    /// Load the JSON file containing on-disk state.
+    /// Load the JSON file containing on-disk state.  If it's corrupt (or
+    /// missing after an interrupted write), the previous generation is used.
     #[context("Loading saved state")]
     pub(crate) fn load_from_disk(root_path: impl AsRef<Path>) -> Result<Option<SavedState>> {
         let root_path = root_path.as_ref();
-        let sysroot = openat::Dir::open(root_path)
-            .with_context(|| format!("opening sysroot '{}'", root_path.display()))?;
+        let err = match Self::open_statefile(root_path, Self::STATEFILE_NAME)? {
+            Some(f) => match Self::parse(f) {
+                Ok(s) => return Ok(Some(s)),
+                Err(e) => Some(e),
+            },
+            None => None,
+        };
+        let backup_name = Self::STATEFILE_BACKUP_NAME;
+        let Some(backup) = Self::open_statefile(root_path, backup_name)? else {
+            return match err {
+                Some(e) => Err(Self::corrupt(Self::STATEFILE_NAME, e)),
+                None => Ok(None),
+            };
+        };
+        match Self::parse(backup) {
+            Ok(s) => {
+                match err {
+                    Some(e) => log::warn!("State file is corrupt ({e}); using {backup_name}"),
+                    None => log::warn!("State file is missing; using {backup_name}"),
+                }
+                Ok(Some(s))
+            }
+            Err(backup_err) => Err(match err {
+                Some(e) => Self::corrupt(Self::STATEFILE_NAME, e),
+                None => Self::corrupt(backup_name, backup_err),
+            }),
+        }
+    }
+
+    fn corrupt(name: &str, e: serde_json::Error) -> anyhow::Error {
+        let path = Path::new("/").join(Self::STATEFILE_DIR).join(name);
+        anyhow::Error::from(e).context(crate::errors::Error::new(
+            crate::errors::ErrorKind::StateCorrupt,
+            format!("Corrupt state file {}", path.display()),
+        ))
+    }
 
-        let statefile_path = Path::new(Self::STATEFILE_DIR).join(Self::STATEFILE_NAME);
-        let saved_state = if let Some(statusf) = sysroot.open_file_optional(&statefile_path)? {
-            let mut bufr = std::io::BufReader::new(statusf);
-            let mut s = String::new();
-            bufr.read_to_string(&mut s)?;
-            let state: serde_json::Result<SavedState> = serde_json::from_str(s.as_str());
-            let r = match state {
-                Ok(s) => s,
-                Err(orig_err) => {
-                    let state: serde_json::Result<crate::model_legacy::SavedState01> =
-                        serde_json::from_str(s.as_str());
-                    match state {
-                        Ok(s) => s.upconvert(),
-                        Err(_) => {
-                            return Err(orig_err.into());
-                        }
-                    }
+    /// Parse a statefile in the current or the legacy format.
+    pub(crate) fn parse(mut statusf: impl Read + Seek) -> serde_json::Result<SavedState> {
+        // Parse straight from the file, rather than reading it into a
+        // string first; the filetrees can make it large.
+        let state: serde_json::Result<SavedState> =
+            serde_json::from_reader(std::io::BufReader::new(&mut statusf));
+        match state {
+            Ok(s) => Ok(s),
+            Err(orig_err) => {
+                if statusf.rewind().is_err() {
+                    return Err(orig_err);
                 }
-            };
-            Some(r)
-        } else {
-            None
+                let state: serde_json::Result<crate::model_legacy::SavedState01> =
+                    serde_json::from_reader(std::io::BufReader::new(&mut statusf));
+                match state {
+                    Ok(s) => Ok(s.upconvert()),
+                    Err(_) => Err(orig_err),
+                }
+            }
+        }
+    }
+
+    /// Load the parts of the on-disk state needed for status, without
+    /// building the filetrees.
+    #[context("Loading saved state")]
+    pub(crate) fn load_summary_from_disk(
+        root_path: impl AsRef<Path>,
+    ) -> Result<Option<StateSummary>> {
+        let root_path = root_path.as_ref();
+        let Some(statusf) = Self::open_statefile(root_path, Self::STATEFILE_NAME)? else {
+            return Ok(Self::load_from_disk(root_path)?.map(Into::into));
         };
-        Ok(saved_state)
+        match serde_json::from_reader(std::io::BufReader::new(statusf)) {
+            Ok(s) => Ok(Some(s)),
+            // Older formats and corruption are handled by the full load
+            Err(_) => Ok(Self::load_from_disk(root_path)?.map(Into::into)),
+        }
+    }
+
+    fn open_statefile(root_path: &Path, name: &str) -> Result<Option<File>> {
+        let sysroot = openat::Dir::open(root_path)
+            .with_context(|| format!("opening sysroot '{}'", root_path.display()))?;
+        let statefile_path = Path::new(Self::STATEFILE_DIR).join(name);
+        Ok(sysroot.open_file_optional(&statefile_path)?)
     }
 
     /// Check whether statefile exists.
@@ -113,18 +180,96 @@ pub(crate) struct StateLockGuard {
     pub(crate) sysroot: openat::Dir,
     #[allow(dead_code)]
     termguard: Option<SignalTerminationGuard>,
+    /// Blocks shutdown until the transaction is complete
+    #[allow(dead_code)]
+    inhibitor: Option<super::inhibit::Inhibitor>,
     #[allow(dead_code)]
     lockfile: Option<File>,
 }
 
 impl StateLockGuard {
-    /// Atomically replace the on-disk state with a new version.
+    /// Atomically replace the on-disk state with a new version, keeping the
+    /// current one as the previous generation.  Writers of the state,
+    /// including those which don't hold the write lock, are serialized by
+    /// a lock on its directory.
     pub(crate) fn update_state(&mut self, state: &SavedState) -> Result<()> {
         let subdir = self.sysroot.sub_dir(SavedState::STATEFILE_DIR)?;
+        let _lock = crate::util::lock_dir(&subdir)?;
+        Self::keep_previous(&subdir).context("Keeping the previous state")?;
         subdir.write_file_with_sync(SavedState::STATEFILE_NAME, 0o644, |w| -> Result<()> {
             serde_json::to_writer(w, state)?;
             Ok(())
         })?;
+        // Persist the renames too
+        crate::util::fsync_dir(&subdir, ".")?;
+        Ok(())
+    }
+
+    /// Make the current state the previous generation, leaving it in place
+    /// so that readers always find a complete state.  It's moved instead
+    /// where hard links aren't supported, e.g. on FAT.
+    fn keep_previous(subdir: &openat::Dir) -> Result<()> {
+        use rustix::fs::AtFlags;
+        use std::os::unix::io::AsRawFd;
+        let tmp = format!(".{}.tmp", SavedState::STATEFILE_BACKUP_NAME);
+        subdir.remove_file_optional(&tmp)?;
+        let fd = unsafe { rustix::fd::BorrowedFd::borrow_raw(subdir.as_raw_fd()) };
+        match rustix::fs::linkat(fd, SavedState::STATEFILE_NAME, fd, &tmp, AtFlags::empty()) {
+            Ok(()) => subdir.local_rename(&tmp, SavedState::STATEFILE_BACKUP_NAME)?,
+            Err(rustix::io::Errno::NOENT) => {}
+            Err(rustix::io::Errno::PERM | rustix::io::Errno::OPNOTSUPP) => {
+                subdir.local_rename_optional(
+                    SavedState::STATEFILE_NAME,
+                    SavedState::STATEFILE_BACKUP_NAME,
+                )?;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_corrupt_statefile() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path();
+        std::fs::create_dir(root.join(SavedState::STATEFILE_DIR))?;
+        let mut guard = SavedState::unlocked(openat::Dir::open(root)?)?;
+        let mut state = SavedState::default();
+        state.staged_deployment = Some("first".into());
+        guard.update_state(&state)?;
+        state.staged_deployment = Some("second".into());
+        guard.update_state(&state)?;
+
+        let statefile = root
+            .join(SavedState::STATEFILE_DIR)
+            .join(SavedState::STATEFILE_NAME);
+        let loaded = SavedState::load_from_disk(root)?.unwrap();
+        assert_eq!(loaded.staged_deployment.as_deref(), Some("second"));
+        let backup = root
+            .join(SavedState::STATEFILE_DIR)
+            .join(SavedState::STATEFILE_BACKUP_NAME);
+        assert!(std::fs::read_to_string(&backup)?.contains("first"));
+        // A truncated write falls back to the previous generation
+        std::fs::write(&statefile, r#"{"installed": {"#)?;
+        let loaded = SavedState::load_from_disk(root)?.unwrap();
+        assert_eq!(loaded.staged_deployment.as_deref(), Some("first"));
+        assert!(SavedState::load_summary_from_disk(root)?.is_some());
+        std::fs::remove_file(&statefile)?;
+        assert!(SavedState::load_from_disk(root)?.is_some());
+
+        std::fs::write(&backup, "")?;
+        let e = SavedState::load_from_disk(root).unwrap_err();
+        assert_eq!(
+            crate::errors::classify(&e),
+            crate::errors::ErrorKind::StateCorrupt
+        );
+        std::fs::remove_file(&backup)?;
+        assert!(SavedState::load_from_disk(root)?.is_none());
         Ok(())
     }
 }
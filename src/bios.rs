@@ -1,181 +1,365 @@
-use std::io::prelude::*;
-use std::path::Path;
-use std::process::Command;
+use std::path::{Path, PathBuf};
 use std::fs;
+use std::sync::Arc;
 
 use crate::component::*;
+use crate::distro::Profile;
+use crate::installer::{BootloaderInstaller, GrubInstall, Target};
 use crate::model::*;
-use crate::packagesystem;
+#[cfg(target_arch = "x86_64")]
+use crate::partition::PartitionType;
+use crate::system::{Host, System};
 use anyhow::{bail, Result};
 use crate::util;
-use serde::{Deserialize, Serialize};
 
-// grub-install file path
-pub(crate) const GRUB_BIN: &str = "usr/sbin/grub-install";
-
-#[derive(Serialize, Deserialize, Debug)]
-struct BlockDevice {
-    path: String,
-    pttype: Option<String>,
-    parttypename: Option<String>,
+/// The GRUB platform installed to the boot device
+#[cfg(target_arch = "x86_64")]
+const GRUB_PLATFORM: &str = "i386-pc";
+#[cfg(target_arch = "powerpc64")]
+const GRUB_PLATFORM: &str = "powerpc-ieee1275";
+
+/// The space GRUB's core image needs, with the modules we add: it's
+/// usually around 40 KiB, which doesn't fit in e.g. the 31.5 KiB before a
+/// first partition at sector 63.
+#[cfg(target_arch = "x86_64")]
+const MIN_EMBEDDING_AREA: u64 = 64 * 1024;
+
+/// The size of the BIOS boot partitions we create, as partitioning tools
+/// do, when there's room.
+#[cfg(target_arch = "x86_64")]
+const BIOS_BOOT_PARTITION_SIZE: u64 = 1024 * 1024;
+
+pub(crate) struct Bios {
+    system: Arc<dyn System>,
+    installer: Arc<dyn BootloaderInstaller>,
+    opts: Options,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Devices {
-    blockdevices: Vec<BlockDevice>,
+impl Default for Bios {
+    fn default() -> Self {
+        Self::new(Arc::new(Host))
+    }
 }
 
-#[derive(Default)]
-pub(crate) struct Bios {}
-
 impl Bios {
-    // Get target device for running update
-    fn get_device(&self) -> Result<String> {
-        let mut cmd: Command;
+    /// The BIOS component operating on `system`.
+    pub(crate) fn new(system: Arc<dyn System>) -> Self {
+        Self {
+            system,
+            installer: Arc::new(GrubInstall),
+            opts: Options::default(),
+        }
+    }
+
+    /// Write as `opts` tell, e.g. allowing a FAT /boot (usually the ESP
+    /// mounted at /boot by systems using systemd-boot).
+    pub(crate) fn with_options(mut self, opts: Options) -> Self {
+        self.opts = opts;
+        self
+    }
+
+    /// Install the bootloader with `installer` instead of `grub-install`.
+    #[cfg(all(test, target_arch = "x86_64"))]
+    pub(crate) fn with_installer(mut self, installer: Arc<dyn BootloaderInstaller>) -> Self {
+        self.installer = installer;
+        self
+    }
+
+    // Get target devices for running update
+    fn get_devices(&self) -> Result<Vec<PathBuf>> {
         #[cfg(target_arch = "x86_64")]
         {
-            // Find /boot partition
-            cmd = Command::new("findmnt");
-            cmd.arg("--noheadings")
-                .arg("--nofsroot")
-                .arg("--output")
-                .arg("SOURCE")
-                .arg("/boot");
-            let partition = util::cmd_output(&mut cmd)?;
-
-            // Use lsblk to find parent device
-            cmd = Command::new("lsblk");
-            cmd.arg("--paths")
-                .arg("--noheadings")
-                .arg("--output")
-                .arg("PKNAME")
-                .arg(partition.trim());
+            // The disks containing the /boot filesystem, which span several
+            // for a multi-device btrfs
+            let topology = self.system.topology()?;
+            let Some(boot) = topology.mounted_at("/boot") else {
+                return Err(crate::errors::Error::new(
+                    crate::errors::ErrorKind::DeviceNotFound,
+                    "Failed to find the device mounted at /boot",
+                )
+                .into());
+            };
+            Ok(topology
+                .filesystem_disks(&boot.path)?
+                .into_iter()
+                .map(Into::into)
+                .collect())
         }
 
         #[cfg(target_arch = "powerpc64")]
         {
+            use anyhow::Context;
+
             // Get PowerPC-PReP-boot partition
-            cmd = Command::new("realpath");
-            cmd.arg("/dev/disk/by-partlabel/PowerPC-PReP-boot");
+            let label = self
+                .system
+                .root()
+                .join("dev/disk/by-partlabel/PowerPC-PReP-boot");
+            if !label.exists() {
+                log::debug!("No {label:?}, searching by partition type");
+                // Only on the disks we boot from, not e.g. disks of other
+                // systems attached
+                let topology = self.system.topology()?;
+                let mut disks = std::collections::BTreeSet::new();
+                for mountpoint in ["/", "/sysroot", "/boot"] {
+                    if let Some(d) = topology.mounted_at(mountpoint) {
+                        disks.extend(topology.filesystem_disks(&d.path)?);
+                    }
+                }
+                if disks.is_empty() {
+                    return Err(crate::errors::Error::new(
+                        crate::errors::ErrorKind::DeviceNotFound,
+                        "Failed to find the devices mounted at / and /boot",
+                    )
+                    .into());
+                }
+                let found = topology.prep_partitions(&disks)?;
+                return Ok(found.into_iter().map(Into::into).collect());
+            }
+            if !util::have_program("realpath") {
+                let path = label.canonicalize().with_context(|| format!("Resolving {label:?}"))?;
+                return Ok(vec![path]);
+            }
+            let mut cmd = util::command("realpath");
+            cmd.arg(&label);
+            let out = self.system.output(&mut cmd)?;
+            if !out.status.success() {
+                return Err(util::tool_failed(&cmd, out.status, &out.stderr));
+            }
+            let path = String::from_utf8(out.stdout).context("Decoding realpath output")?;
+            Ok(vec![path.trim().into()])
         }
-
-        let device = util::cmd_output(&mut cmd)?;
-        Ok(device)
     }
 
-    // Returns `true` if grub modules are installed
-    fn check_grub_modules(&self) -> Result<bool> {
-        let usr_path = Path::new("/usr/lib64/grub");
-        #[cfg(target_arch = "x86_64")]
+    // Refuse to fill a FAT /boot (i.e. the ESP) with the GRUB modules,
+    // unless confirmed now, or when GRUB was installed there.
+    fn check_boot_fs(&self, boot_dir: &Path, grub_dir: &Path) -> Result<()> {
+        if !util::is_fat(boot_dir)?
+            || self.opts.allow_fat_boot
+            || grub_dir.join(GRUB_PLATFORM).exists()
         {
-            usr_path.join("i386-pc").try_exists().map_err(Into::into)
-        }
-        #[cfg(target_arch = "powerpc64")]
-        {
-            usr_path
-                .join("powerpc-ieee1275")
-                .try_exists()
-                .map_err(Into::into)
+            return Ok(());
         }
+        bail!(
+            "{:?} is a FAT filesystem, likely the ESP; pass --allow-fat-boot to install the GRUB modules there",
+            boot_dir
+        );
     }
 
-    // Run grub-install
-    fn run_grub_install(&self, dest_root: &str, device: &str) -> Result<()> {
-        if !self.check_grub_modules()? {
-            bail!("Failed to find grub modules");
+    // Whether a system booted via EFI has nowhere to embed GRUB in, so that
+    // it can't be adopted: there's no BIOS boot partition, and creating one
+    // isn't allowed.
+    #[cfg(target_arch = "x86_64")]
+    fn lacks_embedding_area(&self) -> Result<bool> {
+        Ok(self.system.is_efi_booted()?
+            && self.get_bios_boot_partition()?.is_none()
+            && !self.opts.create_bios_boot_partition)
+    }
+
+    // Fail early, with the space needed, if GRUB's core image doesn't fit
+    // on `device`; grub-install only finds out late, or falls back to
+    // blocklists.  When allowed, a missing BIOS boot partition is created
+    // instead.
+    #[cfg(target_arch = "x86_64")]
+    fn check_embedding_area(&self, device: &Path) -> Result<()> {
+        let topology = self.system.topology()?;
+        let required = MIN_EMBEDDING_AREA / 1024;
+        if topology.lacks_bios_boot_partition(device)? {
+            if self.opts.create_bios_boot_partition {
+                crate::partition::create(
+                    self.system.as_ref(),
+                    device,
+                    PartitionType::BiosBoot,
+                    MIN_EMBEDDING_AREA,
+                    BIOS_BOOT_PARTITION_SIZE,
+                )?;
+                return Ok(());
+            }
+            bail!(
+                "{} has no BIOS boot partition to embed GRUB in; one of at least {required} KiB is required (pass --create-bios-boot-partition to create one in free space)",
+                device.display()
+            );
         }
-        let grub_install = Path::new("/").join(GRUB_BIN);
-        if !grub_install.exists() {
-            bail!("Failed to find {:?}", grub_install);
+        let Some(area) = topology.embedding_area(device)? else {
+            return Ok(());
+        };
+        if area.size() < MIN_EMBEDDING_AREA {
+            bail!(
+                "Can't embed GRUB in {area} of {}: it has {} KiB, but at least {required} KiB are required",
+                device.display(),
+                area.size() / 1024
+            );
         }
+        Ok(())
+    }
+
+    // Install the bootloader and its modules
+    fn run_grub_install(&self, dest_root: &Path, device: &Path) -> Result<()> {
+        let name = device.to_string_lossy();
+        crate::spans::in_span(self.installer.name(), &[("device", &name)], || {
+            self.grub_install(dest_root, device)
+        })
+    }
 
-        let mut cmd = Command::new(grub_install);
-        let boot_dir = Path::new(dest_root).join("boot");
-        // Forcibly add mdraid1x and part_gpt
+    fn grub_install(&self, dest_root: &Path, device: &Path) -> Result<()> {
+        let root = self.system.root();
+        let profile = Profile::detect(root)?;
+        let modules = profile.grub_modules_dir(root, GRUB_PLATFORM)?;
+        let boot_dir = dest_root.join("boot");
+        let grub_dir = boot_dir.join(profile.boot_grub_dir(&boot_dir)?);
+        self.check_boot_fs(&boot_dir, &grub_dir)?;
         #[cfg(target_arch = "x86_64")]
-        cmd.args(["--target", "i386-pc"])
-            .args(["--boot-directory", boot_dir.to_str().unwrap()])
-            .args(["--modules", "mdraid1x part_gpt"])
-            .arg(device);
+        self.check_embedding_area(device)?;
+        let target = Target {
+            platform: GRUB_PLATFORM,
+            device,
+            modules: &modules,
+            boot_dir: &boot_dir,
+            grub_dir: &grub_dir,
+        };
+        self.installer.install(self.system.as_ref(), &target)?;
 
-        #[cfg(target_arch = "powerpc64")]
-        cmd.args(&["--target", "powerpc-ieee1275"])
-            .args(&["--boot-directory", boot_dir.to_str().unwrap()])
-            .arg("--no-nvram")
-            .arg(device);
-
-        let cmdout = cmd.output()?;
-        if !cmdout.status.success() {
-            std::io::stderr().write_all(&cmdout.stderr)?;
-            bail!("Failed to run {:?}", cmd);
+        let (source, destination) = self.modules_to_copy(profile, &boot_dir)?;
+        // Perform copying
+        copy_dir_all(&source, &destination)?;
+        log::info!("Directory {:?} successfully copied to {:?}", source, destination);
+
+        crate::selinux::relabel(root, dest_root)?;
+        // The modules are copied with plain writes
+        util::syncfs(&openat::Dir::open(&boot_dir)?)?;
+        if self.opts.verify_writes {
+            crate::readback::verify_copy(&source, &destination)?;
+            self.installer.verify(self.system.as_ref(), &target)?;
         }
+        Ok(())
+    }
 
+    /// The directory of GRUB modules copied to `/boot` once GRUB is
+    /// installed, and where to: below the GRUB directory in `boot_dir` on
+    /// x86_64, and `boot_dir` itself on PowerPC.
+    fn modules_to_copy(&self, profile: &Profile, boot_dir: &Path) -> Result<(PathBuf, PathBuf)> {
+        let root = self.system.root();
         #[cfg(target_arch = "x86_64")]
-        {
-            let source = Path::new("/usr/lib64/grub/x86_64-efi");
-            let destination = boot_dir.join("grub").join("x86_64-efi");
-
-            // Check if source directory exists
-            if !source.exists() {
-                bail!("Source directory {:?} not found", source);
-            }
+        return Ok((
+            profile.grub_modules_dir(root, "x86_64-efi")?,
+            boot_dir
+                .join(profile.boot_grub_dir(boot_dir)?)
+                .join("x86_64-efi"),
+        ));
+        #[cfg(target_arch = "powerpc64")]
+        Ok((
+            profile.grub_modules_dir(root, GRUB_PLATFORM)?,
+            boot_dir.join(GRUB_PLATFORM),
+        ))
+    }
 
-            // Perform copying
-            copy_dir_all(&source, &destination)?;
-            log::info!("Directory {:?} successfully copied to {:?}", source, destination);
+    /// The devices to update GRUB on: those `recorded` in state if they
+    /// still exist, or else those found again.  Nothing is recorded on
+    /// PowerPC, where the PReP partition is found by its label.
+    fn update_devices(&self, recorded: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        match crate::blockdev::resolve_ids(recorded) {
+            Some(devices) => Ok(devices),
+            None => self.get_devices(),
         }
+    }
 
+    /// Update the booted system, with grub-install to the devices
+    /// `recorded` in state if they still exist, or else to those found
+    /// again, or on PowerNV, by checking the entries petitboot scans.
+    /// Returns the devices to record.
+    fn update_boot(&self, recorded: &[PathBuf]) -> Result<Vec<PathBuf>> {
         #[cfg(target_arch = "powerpc64")]
-        {
-            let source = Path::new("/usr/lib64/grub/powerpc-ieee1275");
-            let destination = boot_dir.join("powerpc-ieee1275");
-
-            // Check if source directory exists
-            if !source.exists() {
-                bail!("Source directory {:?} not found", source);
-            }
-
-            // Perform copying
-            copy_dir_all(&source, &destination)?;
-            log::info!("Directory {:?} successfully copied to {:?}", source, destination);
+        if crate::petitboot::is_powernv() {
+            crate::petitboot::check(self.system.root())?;
+            return Ok(Vec::new());
         }
-
-        Ok(())
+        let devices = self.update_devices(recorded)?;
+        for device in devices.iter() {
+            log::info!("Installing GRUB to {}", device.display());
+            self.run_grub_install(self.system.root(), device)?;
+        }
+        #[cfg(target_arch = "x86_64")]
+        return Ok(crate::blockdev::stable_ids(&devices));
+        #[cfg(target_arch = "powerpc64")]
+        Ok(Vec::new())
     }
 
     // Check bios_boot partition on gpt type disk
+    #[cfg(target_arch = "x86_64")]
     fn get_bios_boot_partition(&self) -> Result<Option<String>> {
-        let target = self.get_device()?;
-        // Use lsblk to list children with bios_boot
-        let output = Command::new("lsblk")
-            .args([
-                "--json",
-                "--output",
-                "PATH,PTTYPE,PARTTYPENAME",
-                target.trim(),
-            ])
-            .output()?;
-        if !output.status.success() {
-            std::io::stderr().write_all(&output.stderr)?;
-            bail!("Failed to run lsblk");
-        }
+        let targets = self.get_devices()?;
+        let topology = self.system.topology()?;
+        // Find the BIOS boot partition
+        let partition = targets
+            .iter()
+            .flat_map(|target| topology.children(target))
+            .find(|d| {
+                d.is_bios_boot() && d.pttype.as_deref() == Some("gpt")
+            });
+        Ok(partition.map(|d| d.path.clone()))
+    }
+}
 
-        let output = String::from_utf8(output.stdout)?;
-        // Deserialize JSON string into Devices struct
-        let Ok(devices) = serde_json::from_str::<Devices>(&output) else {
-            bail!("Could not deserialize JSON output from lsblk");
-        };
+/// The bootloader in the boot sector of `device`, if any: GRUB by the
+/// messages of its boot image, or some other.
+fn boot_code(device: &Path) -> Option<DetectedBootloader> {
+    use std::io::Read;
 
-        // Find device with parttypename "BIOS boot"
-        for device in devices.blockdevices {
-            if let Some(parttypename) = &device.parttypename {
-                if parttypename == "BIOS boot" && device.pttype.as_deref() == Some("gpt") {
-                    return Ok(Some(device.path));
-                }
-            }
+    let mut sector = [0u8; 512];
+    if let Err(e) = fs::File::open(device).and_then(|mut f| f.read_exact(&mut sector)) {
+        log::debug!("Reading the boot sector of {}: {e}", device.display());
+        return None;
+    }
+    // The code precedes the disk signature and partition table
+    let code = &sector[..440];
+    if code.iter().all(|&b| b == 0) {
+        return None;
+    }
+    let is_grub = code.windows(4).any(|w| w == b"GRUB");
+    Some(DetectedBootloader {
+        name: if is_grub { "GRUB" } else { "unknown" }.into(),
+        location: Some(device.display().to_string()),
+    })
+}
+
+/// The first `len` bytes of the GRUB core image embedded on `device`,
+/// which its boot sector points to; `None` if GRUB isn't installed there.
+#[cfg(target_arch = "x86_64")]
+fn embedded_core_image(device: &Path, len: usize) -> Result<Option<Vec<u8>>> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    if !boot_code(device).is_some_and(|b| b.name == "GRUB") {
+        return Ok(None);
+    }
+    let mut f = fs::File::open(device)?;
+    let mut sector = [0u8; 512];
+    f.read_exact(&mut sector)?;
+    // GRUB_BOOT_MACHINE_KERNEL_SECTOR
+    let start = u64::from_le_bytes(sector[0x5c..0x64].try_into().unwrap());
+    let mut buf = Vec::with_capacity(len);
+    if let Some(offset) = start.checked_mul(512) {
+        f.seek(SeekFrom::Start(offset))?;
+        f.take(len as u64).read_to_end(&mut buf)?;
+    }
+    Ok(Some(buf))
+}
+
+/// Writing the files of `src` to `dest`, as [`copy_dir_all`] does.
+fn plan_copy_dir(src: &Path, dest: &Path) -> Result<Vec<crate::plan::Action>> {
+    if !src.exists() {
+        bail!("Directory {:?} not found", src);
+    }
+    let mut actions = Vec::new();
+    for entry in walkdir::WalkDir::new(src).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            actions.push(crate::plan::Action::WriteFile {
+                path: dest.join(entry.path().strip_prefix(src)?),
+                size: Some(entry.metadata()?.len()),
+            });
         }
-        Ok(None)
     }
+    Ok(actions)
 }
 
 /// Recursive directory copy function
@@ -195,7 +379,8 @@ fn copy_dir_all(src: &Path, dest: &Path) -> Result<()> {
         if file_type.is_dir() {
             copy_dir_all(&src_path, &dest_path)?;
         } else if file_type.is_file() {
-            fs::copy(&src_path, &dest_path)?;
+            util::copy_file(&src_path, &dest_path)?;
+            crate::events::file_written(&dest_path);
         } else {
             // Handle other file types (symlinks, etc.) if necessary
             log::warn!("Warning: Unsupported file type: {:?}", src_path);
@@ -214,40 +399,93 @@ impl Component for Bios {
         &self,
         src_root: &openat::Dir,
         dest_root: &str,
-        device: &str,
+        device: Option<&Path>,
         _update_firmware: bool,
     ) -> Result<InstalledContent> {
         let Some(meta) = get_component_update(src_root, self)? else {
-            anyhow::bail!("Update metadata for component {} not found", self.name());
+            return Err(crate::errors::Error::payload_missing(self.name()).into());
         };
 
-        self.run_grub_install(dest_root, device)?;
+        #[cfg(target_arch = "powerpc64")]
+        if crate::petitboot::is_powernv() {
+            crate::petitboot::check(Path::new(dest_root))?;
+            return Ok(InstalledContent {
+                meta,
+                filetree: None,
+                adopted_from: None,
+                devices: Vec::new(),
+            });
+        }
+        let Some(device) = device else {
+            bail!("A target device is required to install BIOS");
+        };
+        self.run_grub_install(Path::new(dest_root), device)?;
         Ok(InstalledContent {
             meta,
             filetree: None,
             adopted_from: None,
+            devices: Vec::new(),
         })
     }
 
     fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
-        let grub_install = Path::new(sysroot_path).join(GRUB_BIN);
+        let profile = Profile::detect(Path::new(sysroot_path))?;
+        let grub_install = Path::new(sysroot_path).join(profile.grub_install);
         if !grub_install.exists() {
             bail!("Failed to find {:?}", grub_install);
         }
 
-        // Query the rpm database and get package and build time information for /usr/sbin/grub-install
-        let meta = packagesystem::query_files(sysroot_path, [&grub_install])?;
-        write_update_metadata(sysroot_path, self, &meta)?;
+        // Query the package database and get package and build time information for grub-install
+        let meta = write_packaged_update_metadata(sysroot_path, self, [&grub_install])?;
         Ok(meta)
     }
 
     fn query_adopt(&self) -> Result<Option<Adoptable>> {
         #[cfg(target_arch = "x86_64")]
-        if crate::efi::is_efi_booted()? && self.get_bios_boot_partition()?.is_none() {
+        if self.lacks_embedding_area()? {
             log::debug!("Skipping adopt BIOS");
             return Ok(None);
         }
-        crate::component::query_adopt_state()
+        crate::component::query_adopt_state_in(self.system.root())
+    }
+
+    fn probe_adopt(&self) -> Result<ComponentAdoption> {
+        let Some(adoptable) = self.query_adopt()? else {
+            #[cfg(target_arch = "x86_64")]
+            if self.lacks_embedding_area()? {
+                return Ok(ComponentAdoption::not_adoptable(
+                    "Booted via EFI, and there's no BIOS boot partition to embed GRUB in (pass --create-bios-boot-partition to create one in free space)",
+                ));
+            }
+            return Ok(ComponentAdoption::not_adoptable("No existing installation found"));
+        };
+        let mut r = ComponentAdoption::new(AdoptionState::Adoptable(adoptable));
+        // Nothing is written on PowerNV, where petitboot reads the GRUB config
+        #[cfg(target_arch = "powerpc64")]
+        if crate::petitboot::is_powernv() {
+            r.detected = Some(DetectedBootloader {
+                name: "petitboot".into(),
+                location: None,
+            });
+            return Ok(r);
+        }
+        let devices = self.get_devices()?;
+        for device in devices.iter() {
+            #[cfg(target_arch = "x86_64")]
+            if self.opts.create_bios_boot_partition
+                && self.system.topology()?.lacks_bios_boot_partition(device)?
+            {
+                r.actions.push(AdoptionAction::CreateBiosBootPartition {
+                    disk: device.clone(),
+                });
+            }
+            r.actions.push(AdoptionAction::WriteDevice {
+                device: device.clone(),
+                installer: self.installer.name().into(),
+            });
+        }
+        r.detected = devices.first().map(PathBuf::as_path).and_then(boot_code);
+        Ok(r)
     }
 
     fn adopt_update(&self, _: &openat::Dir, update: &ContentMetadata) -> Result<InstalledContent> {
@@ -255,13 +493,12 @@ impl Component for Bios {
             anyhow::bail!("Failed to find adoptable system")
         };
 
-        let device = self.get_device()?;
-        let device = device.trim();
-        self.run_grub_install("/", device)?;
+        let devices = self.update_boot(&[])?;
         Ok(InstalledContent {
             meta: update.clone(),
             filetree: None,
             adopted_from: Some(meta.version),
+            devices,
         })
     }
 
@@ -269,24 +506,121 @@ impl Component for Bios {
         get_component_update(sysroot, self)
     }
 
-    fn run_update(&self, sysroot: &openat::Dir, _: &InstalledContent) -> Result<InstalledContent> {
+    fn run_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<InstalledContent> {
         let updatemeta = self.query_update(sysroot)?.expect("update available");
-        let device = self.get_device()?;
-        let device = device.trim();
-        self.run_grub_install("/", device)?;
+        let devices = self.update_boot(&current.devices)?;
 
         let adopted_from = None;
         Ok(InstalledContent {
             meta: updatemeta,
             filetree: None,
             adopted_from,
+            devices,
         })
     }
 
+    fn plan_update(
+        &self,
+        _sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Option<Vec<crate::plan::Action>>> {
+        // Nothing is written on PowerNV
+        #[cfg(target_arch = "powerpc64")]
+        if crate::petitboot::is_powernv() {
+            return Ok(Some(Vec::new()));
+        }
+        let root = self.system.root();
+        let profile = Profile::detect(root)?;
+        let (source, destination) = self.modules_to_copy(profile, &root.join("boot"))?;
+        let copies = plan_copy_dir(&source, &destination)?;
+        let mut actions = Vec::new();
+        for device in self.update_devices(&current.devices)? {
+            actions.push(crate::plan::Action::WriteDevice {
+                device,
+                installer: self.installer.name().into(),
+            });
+            actions.extend(copies.iter().cloned());
+        }
+        Ok(Some(actions))
+    }
+
     fn validate(&self, _: &InstalledContent) -> Result<ValidationResult> {
+        #[cfg(target_arch = "powerpc64")]
+        if crate::petitboot::is_powernv() {
+            let errs = crate::petitboot::problems(self.system.root())?;
+            if !errs.is_empty() {
+                return Ok(ValidationResult::Errors(errs));
+            }
+            return Ok(ValidationResult::Valid);
+        }
         Ok(ValidationResult::Skip)
     }
 
+    #[cfg(target_arch = "x86_64")]
+    fn inspect(&self, devices: &[PathBuf]) -> Result<crate::consistency::Inspection> {
+        use anyhow::Context;
+
+        let mut r = crate::consistency::Inspection::default();
+        // Only the devices recorded are read, rather than probing for them
+        let Some(devices) = crate::blockdev::resolve_ids(devices) else {
+            return Ok(r);
+        };
+        let root = self.system.root();
+        let boot_dir = root.join("boot");
+        let grub_dir = boot_dir.join(Profile::detect(root)?.boot_grub_dir(&boot_dir)?);
+        let path = grub_dir.join(GRUB_PLATFORM).join("core.img");
+        let built = match fs::read(&path) {
+            Ok(b) => b,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                log::debug!("No {}, not checking the core images", path.display());
+                return Ok(r);
+            }
+            Err(e) => return Err(e).with_context(|| format!("Reading {}", path.display())),
+        };
+        for device in devices {
+            let embedded = embedded_core_image(&device, built.len())
+                .with_context(|| format!("Reading the core image of {}", device.display()))?;
+            if let Some(embedded) = embedded {
+                r.core_images.push(crate::consistency::CoreImage {
+                    device,
+                    embedded,
+                    built: built.clone(),
+                });
+            }
+        }
+        Ok(r)
+    }
+
+    fn fingerprint_installed(&self, sysroot: &openat::Dir) -> Result<Option<InstalledContent>> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            // Which GRUB is embedded isn't known, only that it's there
+            let devices = self.get_devices()?;
+            let is_grub = |d: &PathBuf| boot_code(d).is_some_and(|b| b.name == "GRUB");
+            if devices.is_empty() || !devices.iter().all(is_grub) {
+                return Ok(None);
+            }
+            if self.query_update(sysroot)?.is_none() {
+                return Err(crate::errors::Error::payload_missing(self.name()).into());
+            }
+            Ok(Some(InstalledContent {
+                meta: unknown_version(),
+                filetree: None,
+                adopted_from: None,
+                devices: crate::blockdev::stable_ids(&devices),
+            }))
+        }
+        #[cfg(target_arch = "powerpc64")]
+        {
+            let _ = sysroot;
+            bail!("The GRUB installed for BIOS can't be fingerprinted; use `bootupctl adopt-and-update` to take it over");
+        }
+    }
+
     fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
         Ok(None)
     }
@@ -295,6 +629,7 @@ impl Component for Bios {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::blockdev::Devices;
     use tempfile::tempdir;
     use std::fs::{self, File};
     use std::io::Write;
@@ -310,6 +645,27 @@ mod tests {
         assert!(devices.blockdevices[0].parttypename.is_none());
     }
 
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_embedded_core_image() -> Result<()> {
+        let td = tempdir()?;
+        let device = td.path().join("disk");
+        let mut disk = vec![0u8; 4 * 512];
+        disk[..4].copy_from_slice(b"\xeb\x63\x90\x10");
+        disk[0x5c..0x64].copy_from_slice(&2u64.to_le_bytes());
+        disk[0x180..0x184].copy_from_slice(b"GRUB");
+        disk[2 * 512..2 * 512 + 6].copy_from_slice(b"kernel");
+        fs::write(&device, &disk)?;
+        let embedded = embedded_core_image(&device, 6)?.unwrap();
+        assert_eq!(embedded, b"kernel");
+        // Past the end of the device
+        assert_eq!(embedded_core_image(&device, 4096)?.unwrap().len(), 2 * 512);
+        disk[0x180..0x184].copy_from_slice(b"LILO");
+        fs::write(&device, &disk)?;
+        assert_eq!(embedded_core_image(&device, 6)?, None);
+        Ok(())
+    }
+
     #[test]
     fn test_copy_dir_all() -> Result<()> {
         let src_dir = tempdir()?;
@@ -342,4 +698,141 @@ mod tests {
         let result = copy_dir_all(src, dest);
         assert!(result.is_err());
     }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_mock_system() -> Result<()> {
+        use crate::system::Mock;
+
+        let td = tempdir()?;
+        let root = td.path();
+        for d in ["usr/sbin", "usr/lib/grub/i386-pc", "usr/lib/grub/x86_64-efi", "boot"] {
+            fs::create_dir_all(root.join(d))?;
+        }
+        File::create(root.join("usr/sbin/grub-install"))?;
+        fs::write(root.join("usr/lib/grub/x86_64-efi/normal.mod"), "normal")?;
+        let lsblk = r#"{"blockdevices": [
+            {"path": "/dev/vda", "type": "disk", "pttype": "gpt", "parttypename": null},
+            {"path": "/dev/vda1", "pkname": "/dev/vda", "type": "part", "pttype": "gpt",
+             "parttypename": "BIOS boot"},
+            {"path": "/dev/vda2", "pkname": "/dev/vda", "type": "part", "pttype": "gpt",
+             "parttypename": "Linux filesystem", "mountpoints": ["/boot"]}
+        ]}"#;
+
+        let system = Arc::new(Mock::new(root, lsblk)?.efi_booted(true));
+        let bios = Bios::new(system.clone());
+        assert_eq!(bios.get_devices()?, [PathBuf::from("/dev/vda")]);
+        assert_eq!(bios.get_bios_boot_partition()?.as_deref(), Some("/dev/vda1"));
+        assert!(bios.query_adopt()?.is_none());
+        fs::create_dir_all(root.join("ostree/deploy"))?;
+        assert!(bios.query_adopt()?.is_some());
+        // Booted via EFI, without a BIOS boot partition to install to
+        let without = lsblk.replace("BIOS boot", "EFI System");
+        let bios_efi = Bios::new(Arc::new(Mock::new(root, &without)?.efi_booted(true)));
+        assert!(bios_efi.query_adopt()?.is_none());
+        let probe = bios.probe_adopt()?;
+        assert!(matches!(probe.state, AdoptionState::Adoptable(_)), "{probe:?}");
+        assert_eq!(
+            probe.actions,
+            [AdoptionAction::WriteDevice {
+                device: "/dev/vda".into(),
+                installer: "grub-install".into(),
+            }]
+        );
+        // As query_adopt decided, telling why
+        let probe = bios_efi.probe_adopt()?;
+        assert!(
+            matches!(&probe.state, AdoptionState::NotAdoptable { reason } if reason.contains("BIOS boot partition")),
+            "{probe:?}"
+        );
+        assert!(probe.actions.is_empty());
+        // Unless one may be created
+        let opts = Options {
+            create_bios_boot_partition: true,
+            ..Default::default()
+        };
+        let bios_create =
+            Bios::new(Arc::new(Mock::new(root, &without)?.efi_booted(true))).with_options(opts);
+        assert!(bios_create.query_adopt()?.is_some());
+        let probe = bios_create.probe_adopt()?;
+        assert!(matches!(probe.state, AdoptionState::Adoptable(_)), "{probe:?}");
+        assert_eq!(
+            probe.actions,
+            [
+                AdoptionAction::CreateBiosBootPartition {
+                    disk: "/dev/vda".into(),
+                },
+                AdoptionAction::WriteDevice {
+                    device: "/dev/vda".into(),
+                    installer: "grub-install".into(),
+                }
+            ]
+        );
+        // Booted via BIOS, GRUB is embedded elsewhere
+        let bios_booted = Bios::new(Arc::new(Mock::new(root, &without)?));
+        let probe = bios_booted.probe_adopt()?;
+        assert!(matches!(probe.state, AdoptionState::Adoptable(_)), "{probe:?}");
+        assert_eq!(probe.actions.len(), 1);
+
+        bios.update_boot(&[])?;
+        let modules = root.join("usr/lib/grub/i386-pc");
+        assert_eq!(
+            system.commands(),
+            [format!(
+                "grub-install --target i386-pc --directory {} --boot-directory {} --modules mdraid1x part_gpt /dev/vda",
+                modules.display(),
+                root.join("boot").display()
+            )]
+        );
+        assert!(root.join("boot/grub/x86_64-efi/normal.mod").exists());
+
+        let failing = Mock::new(root, lsblk)?.with_output("grub-install", 1, "");
+        assert!(Bios::new(Arc::new(failing)).update_boot(&[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_installer() -> Result<()> {
+        use crate::system::Mock;
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<String>>);
+
+        impl BootloaderInstaller for Recorder {
+            fn name(&self) -> &'static str {
+                "recorder"
+            }
+
+            fn install(&self, _: &dyn System, target: &Target) -> Result<()> {
+                let line = format!("{} {}", target.platform, target.device.display());
+                self.0.lock().unwrap().push(line);
+                Ok(())
+            }
+        }
+
+        let td = tempdir()?;
+        let root = td.path();
+        for d in ["usr/lib/grub/i386-pc", "usr/lib/grub/x86_64-efi", "boot"] {
+            fs::create_dir_all(root.join(d))?;
+        }
+        fs::write(root.join("usr/lib/grub/x86_64-efi/normal.mod"), "normal")?;
+        let lsblk = r#"{"blockdevices": [
+            {"path": "/dev/vda", "type": "disk", "pttype": "gpt", "parttypename": null},
+            {"path": "/dev/vda1", "pkname": "/dev/vda", "type": "part", "pttype": "gpt",
+             "parttypename": "BIOS boot"},
+            {"path": "/dev/vda2", "pkname": "/dev/vda", "type": "part", "pttype": "gpt",
+             "parttypename": "Linux filesystem", "mountpoints": ["/boot"]}
+        ]}"#;
+        let system = Arc::new(Mock::new(root, lsblk)?);
+        let recorder = Arc::new(Recorder::default());
+        // No grub-install in the root, which isn't needed
+        let bios = Bios::new(system.clone()).with_installer(recorder.clone());
+        bios.update_boot(&[])?;
+        assert_eq!(*recorder.0.lock().unwrap(), ["i386-pc /dev/vda"]);
+        assert!(system.commands().is_empty());
+        assert!(root.join("boot/grub/x86_64-efi/normal.mod").exists());
+        Ok(())
+    }
 }
@@ -0,0 +1,1237 @@
+//! The block device topology of the running system.
+//!
+//! Finding e.g. the disk containing `/boot` used to take a chain of
+//! `findmnt` and `lsblk` calls in every component needing it, and the
+//! answers could disagree if devices changed during the run.  Instead, the
+//! topology is read with a single `lsblk` call on first use, and the same
+//! snapshot is shared by all components for the rest of the invocation.
+//!
+//! Stacked devices (LVM, dm-crypt, md RAID) are resolved down to their
+//! disks with the parents `lsblk` reports, falling back to the backing
+//! devices of their device-mapper table or array as listed in sysfs.  Disks
+//! which are paths of a dm-multipath map resolve to the map, as writing
+//! to a single path races with I/O through the others.
+//!
+//! Kernel names such as `/dev/nvme0n1` can change across reboots, so
+//! devices recorded in state are named by their `/dev/disk/by-id` links
+//! (see [`stable_ids`]) and resolved again on each operation.
+//!
+//! Without `lsblk`, e.g. in a minimal recovery image, the topology is read
+//! from sysfs and what udev recorded of the devices instead (see
+//! [`Topology::read_sysfs`]).
+
+#[cfg(any(have_bios, feature = "fuzzing", test))]
+use std::collections::BTreeSet;
+use std::path::Path;
+#[cfg(have_blockdev)]
+use std::path::PathBuf;
+#[cfg(have_blockdev)]
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result};
+#[cfg(have_blockdev)]
+use fn_error_context::context;
+use serde::Deserialize;
+
+#[cfg(have_blockdev)]
+use crate::util;
+
+/// The `lsblk` columns we read, matching the fields of [`BlockDevice`].
+#[cfg(have_blockdev)]
+const LSBLK_COLUMNS: &str =
+    "PATH,PKNAME,TYPE,PTTYPE,PARTTYPE,PARTTYPENAME,PARTLABEL,FSTYPE,UUID,MOUNTPOINTS";
+
+/// The unit of the partition starts and sizes in sysfs.
+#[cfg(all(have_bios, target_arch = "x86_64"))]
+const SECTOR_SIZE: u64 = 512;
+
+/// The partition types of a PowerPC PReP boot partition on MBR and GPT
+/// disks, as listed by `lsblk`.
+#[cfg(any(all(have_bios, target_arch = "powerpc64"), feature = "fuzzing", test))]
+const PREP_PARTTYPES: &[&str] = &["0x41", "9e1a2d38-c612-4316-aa26-8b49521e5a8b"];
+
+/// The partition type GUID of a BIOS boot partition on GPT disks.
+#[cfg(all(have_bios, target_arch = "x86_64"))]
+const BIOS_BOOT_PARTTYPE: &str = "21686148-6449-6e6f-744e-656564454649";
+
+/// Where udev links the persistent names of block devices.
+#[cfg(any(all(have_bios, target_arch = "x86_64"), have_efi, have_uboot))]
+const BY_ID_DIR: &str = "/dev/disk/by-id";
+
+/// Where udev records the properties of devices, e.g. their partition
+/// types, as read by `lsblk`.
+#[cfg(have_blockdev)]
+const UDEV_DATA_DIR: &str = "/run/udev/data";
+
+/// Device types [`Topology::underlying_disks`] stops at: multipath maps
+/// stand for their paths, which mustn't be written separately.
+#[cfg(have_bios)]
+const DISK_TYPES: &[&str] = &["disk", "mpath"];
+
+#[cfg(have_blockdev)]
+static TOPOLOGY: OnceLock<Topology> = OnceLock::new();
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BlockDevice {
+    pub(crate) path: String,
+    /// The parent device, e.g. the disk of a partition; stacked devices
+    /// such as LVM LVs are listed once per parent
+    #[serde(default)]
+    pub(crate) pkname: Option<String>,
+    /// e.g. `disk`, `part` or `lvm`
+    #[serde(default, rename = "type")]
+    pub(crate) devtype: Option<String>,
+    /// The partition table type of the disk, for disks and partitions
+    pub(crate) pttype: Option<String>,
+    /// The partition type GUID, or the type code on MBR disks
+    #[serde(default)]
+    pub(crate) parttype: Option<String>,
+    pub(crate) parttypename: Option<String>,
+    #[serde(default)]
+    pub(crate) partlabel: Option<String>,
+    #[serde(default)]
+    pub(crate) fstype: Option<String>,
+    /// The UUID of the filesystem, shared by all devices of a
+    /// multi-device filesystem
+    #[serde(default)]
+    pub(crate) uuid: Option<String>,
+    /// Where the device is mounted; `lsblk` lists `null` for unmounted devices
+    #[serde(default)]
+    pub(crate) mountpoints: Vec<Option<String>>,
+    /// The devices below this one, when `lsblk` lists them as a tree
+    /// rather than with `--list`; moved into the flat list by [`flatten`]
+    #[serde(default)]
+    children: Vec<BlockDevice>,
+}
+
+#[cfg(all(have_bios, target_arch = "x86_64"))]
+impl BlockDevice {
+    /// Whether this is a BIOS boot partition, by its type GUID where
+    /// known; the type names are translated in some versions of `lsblk`.
+    pub(crate) fn is_bios_boot(&self) -> bool {
+        match self.parttype.as_deref() {
+            Some(t) => t.eq_ignore_ascii_case(BIOS_BOOT_PARTTYPE),
+            None => self.parttypename.as_deref() == Some("BIOS boot"),
+        }
+    }
+}
+
+/// Move the devices nested as `children` into `devices`, after their
+/// parents, filling in the parent of those listed without `PKNAME`.
+fn flatten(devices: Vec<BlockDevice>, parent: Option<&str>, out: &mut Vec<BlockDevice>) {
+    for mut d in devices {
+        if d.pkname.is_none() {
+            d.pkname = parent.map(ToOwned::to_owned);
+        }
+        let children = std::mem::take(&mut d.children);
+        let path = d.path.clone();
+        out.push(d);
+        flatten(children, Some(&path), out);
+    }
+}
+
+/// Where GRUB embeds its core image for BIOS booting on a disk.
+#[cfg(all(have_bios, target_arch = "x86_64"))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum EmbeddingArea {
+    /// The gap between the MBR and the first partition, in bytes
+    MbrGap(u64),
+    /// A BIOS boot partition on a GPT disk, and its size in bytes
+    BiosBootPartition(String, u64),
+}
+
+#[cfg(all(have_bios, target_arch = "x86_64"))]
+impl EmbeddingArea {
+    pub(crate) fn size(&self) -> u64 {
+        match self {
+            EmbeddingArea::MbrGap(size) | EmbeddingArea::BiosBootPartition(_, size) => *size,
+        }
+    }
+}
+
+#[cfg(all(have_bios, target_arch = "x86_64"))]
+impl std::fmt::Display for EmbeddingArea {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmbeddingArea::MbrGap(_) => write!(f, "the gap before the first partition"),
+            EmbeddingArea::BiosBootPartition(path, _) => {
+                write!(f, "the BIOS boot partition {path}")
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Devices {
+    pub(crate) blockdevices: Vec<BlockDevice>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Topology {
+    devices: Vec<BlockDevice>,
+    /// Where sysfs is mounted
+    #[cfg(have_bios)]
+    sysfs: PathBuf,
+}
+
+impl Topology {
+    /// Return the topology of the running system, reading it on first use.
+    #[cfg(have_blockdev)]
+    pub(crate) fn get() -> Result<&'static Self> {
+        if let Some(t) = TOPOLOGY.get() {
+            return Ok(t);
+        }
+        let t = Self::read()?;
+        Ok(TOPOLOGY.get_or_init(|| t))
+    }
+
+    #[cfg(have_blockdev)]
+    #[context("Reading block devices")]
+    fn read() -> Result<Self> {
+        let _t = crate::timing::start(crate::timing::Phase::DeviceResolution);
+        if !util::have_program("lsblk") {
+            log::debug!("No lsblk, reading sysfs");
+            let mounts = crate::native::mounts()?;
+            return Self::read_sysfs(Path::new("/sys"), Path::new(UDEV_DATA_DIR), &mounts);
+        }
+        let out = util::cmd_output(util::command("lsblk").args([
+            "--json",
+            "--list",
+            "--paths",
+            "--output",
+            LSBLK_COLUMNS,
+        ]))?;
+        Self::parse(&out)
+    }
+
+    pub(crate) fn parse(lsblk: &str) -> Result<Self> {
+        let devices: Devices = serde_json::from_str(lsblk).context("Parsing lsblk output")?;
+        let mut flat = Vec::new();
+        flatten(devices.blockdevices, None, &mut flat);
+        Ok(Self {
+            devices: flat,
+            #[cfg(have_bios)]
+            sysfs: PathBuf::from("/sys"),
+        })
+    }
+
+    /// Read the topology from `sysfs` and the udev database in
+    /// `udev_data`, with the mount points of `mounts`.  Stacked devices are
+    /// listed without parents, which are then found in sysfs as usual.
+    /// Partition and filesystem types are only known from udev, except for
+    /// the partition tables read from the disks themselves on x86_64.
+    #[cfg(have_blockdev)]
+    pub(crate) fn read_sysfs(
+        sysfs: &Path,
+        udev_data: &Path,
+        mounts: &[crate::native::Mount],
+    ) -> Result<Self> {
+        let block = sysfs.join("class/block");
+        let sources = mounts
+            .iter()
+            .map(|m| Path::new(&m.source).canonicalize().ok())
+            .collect::<Vec<_>>();
+        let mut devices = Vec::new();
+        for entry in std::fs::read_dir(&block).with_context(|| format!("Reading {block:?}"))? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            let dir = block.join(&name);
+            let attr = |a: &str| {
+                let v = std::fs::read_to_string(dir.join(a)).ok()?;
+                Some(v.trim().to_string())
+            };
+            let devno = attr("dev").unwrap_or_default();
+            let udev = std::fs::read_to_string(udev_data.join(format!("b{devno}")));
+            let uevent = attr("uevent").unwrap_or_default();
+            let prop = |key: &str| {
+                let udev = udev.as_deref().unwrap_or_default().lines();
+                let mut props = udev.filter_map(|l| l.strip_prefix("E:"));
+                let prefix = format!("{key}=");
+                props.find_map(|l| l.strip_prefix(&prefix).map(ToOwned::to_owned))
+            };
+            let path = format!("/dev/{name}");
+            let is_partition = dir.join("partition").exists();
+            let (devtype, pkname, pttype) = if is_partition {
+                // Partitions are below their disk in the device hierarchy
+                let canonical = dir.canonicalize()?;
+                let disk = canonical.parent().and_then(|p| p.file_name());
+                let pkname = disk.map(|d| format!("/dev/{}", d.to_string_lossy()));
+                ("part".to_string(), pkname, prop("ID_PART_ENTRY_SCHEME"))
+            } else if let Some(uuid) = attr("dm/uuid") {
+                let devtype = match uuid.split('-').next() {
+                    Some("LVM") => "lvm",
+                    Some("CRYPT") => "crypt",
+                    Some("mpath") => "mpath",
+                    _ => "dm",
+                };
+                (devtype.to_string(), None, None)
+            } else if let Some(level) = attr("md/level") {
+                (level, None, None)
+            } else if dir.join("loop").exists() {
+                ("loop".to_string(), None, prop("ID_PART_TABLE_TYPE"))
+            } else {
+                ("disk".to_string(), None, prop("ID_PART_TABLE_TYPE"))
+            };
+            let partname = uevent.lines().find_map(|l| l.strip_prefix("PARTNAME="));
+            let mountpoints = mounts
+                .iter()
+                .zip(&sources)
+                .filter(|(m, source)| {
+                    m.devno == devno || source.as_deref() == Some(Path::new(&path))
+                })
+                .map(|(m, _)| Some(m.target.to_string_lossy().into_owned()))
+                .collect();
+            devices.push(BlockDevice {
+                pkname,
+                devtype: Some(devtype),
+                pttype,
+                parttype: prop("ID_PART_ENTRY_TYPE"),
+                parttypename: None,
+                partlabel: prop("ID_PART_ENTRY_NAME").or(partname.map(ToOwned::to_owned)),
+                fstype: prop("ID_FS_TYPE"),
+                uuid: prop("ID_FS_UUID"),
+                mountpoints,
+                children: Vec::new(),
+                path,
+            });
+        }
+        devices.sort_by(|a, b| (a.pkname.is_some(), &a.path).cmp(&(b.pkname.is_some(), &b.path)));
+        #[cfg(all(have_bios, target_arch = "x86_64"))]
+        Self::read_partition_tables(&mut devices, &block);
+        Ok(Self {
+            devices,
+            #[cfg(have_bios)]
+            sysfs: sysfs.to_owned(),
+        })
+    }
+
+    /// Fill in the partition types and labels udev didn't record from the
+    /// partition tables of the disks, whose sysfs entries are in `block`.
+    #[cfg(all(have_bios, target_arch = "x86_64"))]
+    fn read_partition_tables(devices: &mut [BlockDevice], block: &Path) {
+        use crate::partition::PartitionTable;
+
+        let disks = devices
+            .iter()
+            .filter(|d| d.devtype.as_deref() == Some("disk") && d.pttype.is_none())
+            .map(|d| d.path.clone())
+            .collect::<Vec<_>>();
+        for disk in disks {
+            let table = match crate::partition::read(Path::new(&disk)) {
+                Ok(t) => t,
+                Err(e) => {
+                    log::debug!("{e:#}");
+                    continue;
+                }
+            };
+            // By partition number: type, label
+            let (pttype, partitions) = match table {
+                PartitionTable::Gpt(gpt) => {
+                    let parts = gpt.partitions().into_iter();
+                    let parts = parts.map(|p| (p.number, p.type_guid.to_string(), Some(p.name)));
+                    ("gpt", parts.collect::<Vec<_>>())
+                }
+                PartitionTable::Mbr(mbr) => {
+                    let parts = mbr.partitions.into_iter();
+                    let parts = parts.map(|p| (p.number, format!("{:#04x}", p.kind), None));
+                    ("dos", parts.collect())
+                }
+            };
+            for d in devices.iter_mut() {
+                if d.path != disk && d.pkname.as_deref() != Some(disk.as_str()) {
+                    continue;
+                }
+                d.pttype = Some(pttype.to_string());
+                let Some(kname) = Path::new(&d.path).file_name() else {
+                    continue;
+                };
+                let number = std::fs::read_to_string(block.join(kname).join("partition"));
+                let number = number.ok().and_then(|n| n.trim().parse().ok());
+                if let Some((_, parttype, label)) = partitions.iter().find(|p| Some(p.0) == number)
+                {
+                    d.parttype.get_or_insert_with(|| parttype.clone());
+                    if d.partlabel.is_none() {
+                        d.partlabel.clone_from(label);
+                    }
+                }
+            }
+        }
+    }
+
+    /// All devices, parents before their children.
+    #[cfg(feature = "fuzzing")]
+    pub(crate) fn devices(&self) -> &[BlockDevice] {
+        &self.devices
+    }
+
+    /// Look up the device at `path`, which may be a symlink such as
+    /// `/dev/disk/by-partlabel/*`.
+    pub(crate) fn device(&self, path: impl AsRef<Path>) -> Result<&BlockDevice> {
+        let path = path.as_ref();
+        let find = |p: &Path| self.devices.iter().find(|d| Path::new(&d.path) == p);
+        if let Some(d) = find(path) {
+            return Ok(d);
+        }
+        let canonical = path
+            .canonicalize()
+            .with_context(|| format!("Resolving {}", path.display()))?;
+        find(&canonical).ok_or_else(|| {
+            crate::errors::Error::new(
+                crate::errors::ErrorKind::DeviceNotFound,
+                format!("Failed to find block device {}", path.display()),
+            )
+            .into()
+        })
+    }
+
+    /// Find the whole disk containing a partition.
+    #[cfg(any(have_efi, have_uboot, feature = "fuzzing"))]
+    pub(crate) fn parent_disk(&self, partition: &str) -> Result<&str> {
+        self.device(partition)?
+            .pkname
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("No parent device found for {partition}"))
+    }
+
+    /// The devices directly below `disk`, i.e. its partitions.
+    #[cfg(any(
+        all(have_bios, target_arch = "x86_64"),
+        have_uboot,
+        feature = "fuzzing"
+    ))]
+    pub(crate) fn children<'a>(
+        &'a self,
+        disk: &'a (impl AsRef<Path> + ?Sized),
+    ) -> impl Iterator<Item = &'a BlockDevice> {
+        let disk = disk.as_ref();
+        self.devices
+            .iter()
+            .filter(move |d| d.pkname.as_deref().map(Path::new) == Some(disk))
+    }
+
+    /// Whether GRUB has nowhere to embed its core image for BIOS booting
+    /// on `disk`: a GPT disk without a BIOS boot partition.
+    #[cfg(all(have_bios, target_arch = "x86_64"))]
+    pub(crate) fn lacks_bios_boot_partition(&self, disk: impl AsRef<Path>) -> Result<bool> {
+        let disk = self.device(disk)?;
+        if disk.pttype.as_deref() != Some("gpt") {
+            return Ok(false);
+        }
+        Ok(!self.children(&disk.path).any(BlockDevice::is_bios_boot))
+    }
+
+    /// The area GRUB can embed its core image in on `disk`, if there is one
+    /// and its size is known.
+    #[cfg(all(have_bios, target_arch = "x86_64"))]
+    pub(crate) fn embedding_area(&self, disk: impl AsRef<Path>) -> Result<Option<EmbeddingArea>> {
+        let disk = self.device(disk)?;
+        let mut partitions = self.children(&disk.path);
+        let sectors = |d: &BlockDevice, attr| self.sysfs_u64(&d.path, attr);
+        let r = match disk.pttype.as_deref() {
+            Some("gpt") => partitions.find(|d| d.is_bios_boot()).and_then(|d| {
+                let size = sectors(d, "size")? * SECTOR_SIZE;
+                Some(EmbeddingArea::BiosBootPartition(d.path.clone(), size))
+            }),
+            // Past the MBR itself
+            Some("dos") => partitions
+                .filter_map(|d| sectors(d, "start"))
+                .min()
+                .map(|start| EmbeddingArea::MbrGap(start.saturating_sub(1) * SECTOR_SIZE)),
+            _ => None,
+        };
+        Ok(r)
+    }
+
+    /// The PReP boot partitions on `disks`, for when their partlabel
+    /// symlink is missing, e.g. as the label was translated.  They are
+    /// found by partition type; if there are none, the error lists the
+    /// partitions there are.
+    #[cfg(any(all(have_bios, target_arch = "powerpc64"), feature = "fuzzing", test))]
+    pub(crate) fn prep_partitions(&self, disks: &BTreeSet<String>) -> Result<Vec<String>> {
+        let is_prep = |d: &&BlockDevice| {
+            d.parttype
+                .as_deref()
+                .is_some_and(|t| PREP_PARTTYPES.iter().any(|p| t.eq_ignore_ascii_case(p)))
+        };
+        let partitions = self
+            .devices
+            .iter()
+            .filter(|d| d.pkname.as_ref().is_some_and(|p| disks.contains(p)));
+        let found = partitions
+            .clone()
+            .filter(is_prep)
+            .map(|d| d.path.clone())
+            .collect::<BTreeSet<_>>();
+        if !found.is_empty() {
+            return Ok(found.into_iter().collect());
+        }
+        let candidates = partitions
+            .filter(|d| d.parttype.is_some())
+            .map(|d| {
+                let mut desc = format!("{} (type {}", d.path, d.parttype.as_deref().unwrap());
+                if let Some(name) = d.parttypename.as_deref() {
+                    desc.push_str(&format!(" \"{name}\""));
+                }
+                if let Some(label) = d.partlabel.as_deref() {
+                    desc.push_str(&format!(", label \"{label}\""));
+                }
+                desc.push(')');
+                desc
+            })
+            .collect::<Vec<_>>();
+        let disks = disks.iter().cloned().collect::<Vec<_>>().join(", ");
+        if candidates.is_empty() {
+            anyhow::bail!("No PReP boot partition found, and no partitions on {disks}");
+        }
+        anyhow::bail!(
+            "No PReP boot partition (type {}) found on {disks} among: {}",
+            PREP_PARTTYPES.join(" or "),
+            candidates.join(", ")
+        )
+    }
+
+    /// Find the partition labeled `label` on `disk`.
+    #[cfg(have_uboot)]
+    pub(crate) fn partition_by_label(&self, disk: &str, label: &str) -> Result<&str> {
+        let disk = self.device(disk)?.path.as_str();
+        self.children(disk)
+            .find(|d| d.partlabel.as_deref() == Some(label))
+            .map(|d| d.path.as_str())
+            .ok_or_else(|| anyhow::anyhow!("No partition labeled {label} on {disk}"))
+    }
+
+    /// The partitions on any disk labeled with one of `labels`, written as
+    /// in `/dev/disk/by-partlabel`.  Those on the paths of a multipath
+    /// map are left out, as they're also listed on the map.
+    #[cfg(have_fat)]
+    pub(crate) fn partitions_labeled(&self, labels: &[&str]) -> Vec<&str> {
+        let is_path = |disk: &str| {
+            self.devices
+                .iter()
+                .any(|d| d.pkname.as_deref() == Some(disk) && d.devtype.as_deref() == Some("mpath"))
+        };
+        let mut found = self
+            .devices
+            .iter()
+            .filter(|d| d.devtype.as_deref() == Some("part"))
+            .filter(|d| {
+                d.partlabel
+                    .as_deref()
+                    .is_some_and(|l| labels.contains(&l.replace(' ', "\\x20").as_str()))
+            })
+            .filter(|d| !d.pkname.as_deref().is_some_and(is_path))
+            .map(|d| d.path.as_str())
+            .collect::<Vec<_>>();
+        found.sort();
+        found.dedup();
+        found
+    }
+
+    /// The devices holding the filesystem on `device`: every member of a
+    /// multi-device btrfs filesystem (of which `lsblk` only lists the mount
+    /// points on one), otherwise just `device`.
+    #[cfg(any(have_bios, feature = "fuzzing"))]
+    pub(crate) fn filesystem_devices<'a>(
+        &'a self,
+        device: &'a BlockDevice,
+    ) -> Vec<&'a BlockDevice> {
+        let (Some("btrfs"), Some(uuid)) = (device.fstype.as_deref(), device.uuid.as_deref()) else {
+            return vec![device];
+        };
+        self.devices
+            .iter()
+            .filter(|d| d.fstype.as_deref() == Some("btrfs") && d.uuid.as_deref() == Some(uuid))
+            .collect()
+    }
+
+    /// The whole disks below `device`, walking stacked devices such as LVM
+    /// LVs, dm-crypt and md RAID down to the disks of their physical
+    /// volumes.
+    #[cfg(have_bios)]
+    pub(crate) fn underlying_disks(&self, device: &str) -> Result<BTreeSet<String>> {
+        let mut disks = BTreeSet::new();
+        let mut seen = BTreeSet::new();
+        let mut pending = vec![self.device(device)?.path.clone()];
+        while let Some(path) = pending.pop() {
+            if !seen.insert(path.clone()) {
+                continue;
+            }
+            let rows = self.devices.iter().filter(|d| d.path == path);
+            let is_disk = rows.clone().any(|d| {
+                d.devtype
+                    .as_deref()
+                    .is_some_and(|t| DISK_TYPES.contains(&t))
+            });
+            let mut parents = rows.filter_map(|d| d.pkname.clone()).collect::<Vec<_>>();
+            if !is_disk && parents.is_empty() {
+                parents = self.sysfs_parents(&path)?;
+            }
+            if is_disk || parents.is_empty() {
+                match self.multipath_map(&path)? {
+                    Some(map) => {
+                        log::info!("Using multipath map {map} rather than its path {path}");
+                        disks.insert(map);
+                    }
+                    None => {
+                        disks.insert(path);
+                    }
+                }
+            } else {
+                pending.extend(parents);
+            }
+        }
+        Ok(disks)
+    }
+
+    /// The dm-multipath map `disk` is a path of, if any.
+    #[cfg(have_bios)]
+    pub(crate) fn multipath_map(&self, disk: &str) -> Result<Option<String>> {
+        if let Some(map) = self
+            .devices
+            .iter()
+            .find(|d| d.pkname.as_deref() == Some(disk) && d.devtype.as_deref() == Some("mpath"))
+        {
+            return Ok(Some(map.path.clone()));
+        }
+        // Without parents from lsblk, look for a multipath holder in sysfs
+        let Some(dir) = self.sysfs_dir(disk) else {
+            return Ok(None);
+        };
+        let holders = match std::fs::read_dir(dir.join("holders")) {
+            Ok(h) => h,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Reading {dir:?}")),
+        };
+        for entry in holders {
+            let name = entry?.file_name();
+            if self.is_sysfs_multipath(&name.to_string_lossy()) {
+                return Ok(Some(format!("/dev/{}", name.to_string_lossy())));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The sysfs directory of the device node `path`.
+    #[cfg(have_bios)]
+    fn sysfs_dir(&self, path: &str) -> Option<PathBuf> {
+        let path = Path::new(path);
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        Some(self.sysfs.join("class/block").join(path.file_name()?))
+    }
+
+    /// A numeric attribute of the device node `path` in sysfs, e.g. `start`.
+    #[cfg(all(have_bios, target_arch = "x86_64"))]
+    fn sysfs_u64(&self, path: &str, attr: &str) -> Option<u64> {
+        let value = std::fs::read_to_string(self.sysfs_dir(path)?.join(attr)).ok()?;
+        value.trim().parse().ok()
+    }
+
+    /// Whether the device-mapper device `kname` is a multipath map.
+    #[cfg(have_bios)]
+    fn is_sysfs_multipath(&self, kname: &str) -> bool {
+        let uuid = self.sysfs.join("class/block").join(kname).join("dm/uuid");
+        std::fs::read_to_string(uuid).is_ok_and(|u| u.starts_with("mpath-"))
+    }
+
+    /// The devices below `path` according to sysfs: the backing devices of
+    /// the targets (crypt, linear, raid, ...) of a device-mapper device or
+    /// the members of an md array, or the disk of a partition.  Multipath
+    /// maps have none, see [`DISK_TYPES`].
+    #[cfg(have_bios)]
+    fn sysfs_parents(&self, path: &str) -> Result<Vec<String>> {
+        let Some(dir) = self.sysfs_dir(path) else {
+            return Ok(Vec::new());
+        };
+        if dir
+            .file_name()
+            .is_some_and(|k| self.is_sysfs_multipath(&k.to_string_lossy()))
+        {
+            return Ok(Vec::new());
+        }
+        let mut parents = Vec::new();
+        match std::fs::read_dir(dir.join("slaves")) {
+            Ok(entries) => {
+                for entry in entries {
+                    let name = entry?.file_name();
+                    parents.push(format!("/dev/{}", name.to_string_lossy()));
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Reading {dir:?}")),
+        }
+        if parents.is_empty() && dir.join("partition").exists() {
+            // Partitions are below their disk in the device hierarchy
+            let canonical = dir.canonicalize()?;
+            if let Some(disk) = canonical.parent().and_then(|p| p.file_name()) {
+                parents.push(format!("/dev/{}", disk.to_string_lossy()));
+            }
+        }
+        parents.sort();
+        Ok(parents)
+    }
+
+    /// The whole disks holding the filesystem on `device`, see
+    /// [`Self::filesystem_devices`] and [`Self::underlying_disks`].
+    #[cfg(have_bios)]
+    pub(crate) fn filesystem_disks(&self, device: &str) -> Result<Vec<String>> {
+        let device = self.device(device)?;
+        let mut disks = BTreeSet::new();
+        for d in self.filesystem_devices(device) {
+            disks.extend(self.underlying_disks(&d.path)?);
+        }
+        Ok(disks.into_iter().collect())
+    }
+
+    /// The device mounted at `mountpoint`, as of when the topology was read.
+    #[cfg(any(have_bios, feature = "fuzzing"))]
+    pub(crate) fn mounted_at(&self, mountpoint: &str) -> Option<&BlockDevice> {
+        self.devices
+            .iter()
+            .find(|d| d.mountpoints.iter().flatten().any(|m| m == mountpoint))
+    }
+}
+
+/// The link in `by_id` naming the device `path`, preferring its WWN.
+#[cfg(any(all(have_bios, target_arch = "x86_64"), have_efi, have_uboot))]
+fn stable_id_in(by_id: &Path, path: &Path) -> Result<Option<PathBuf>> {
+    let target = path
+        .canonicalize()
+        .with_context(|| format!("Resolving {}", path.display()))?;
+    let entries = match std::fs::read_dir(by_id) {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Reading {by_id:?}")),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.path().canonicalize().ok().as_ref() == Some(&target) {
+            names.push(entry.file_name());
+        }
+    }
+    names.sort_by_key(|n| (!n.to_string_lossy().starts_with("wwn-"), n.clone()));
+    Ok(names.first().map(|n| by_id.join(n)))
+}
+
+/// The names under `by_id` to record `devices` by; empty unless all of
+/// them have one, as e.g. loop devices don't.
+#[cfg(any(all(have_bios, target_arch = "x86_64"), have_efi, have_uboot))]
+fn stable_ids_in(by_id: &Path, devices: &[impl AsRef<Path>]) -> Vec<PathBuf> {
+    let mut ids = Vec::new();
+    for device in devices {
+        let device = device.as_ref();
+        match stable_id_in(by_id, device) {
+            Ok(Some(id)) => ids.push(id),
+            Ok(None) => {
+                log::debug!("No persistent name for {}", device.display());
+                return Vec::new();
+            }
+            Err(e) => {
+                log::debug!("Failed to name {}: {e:#}", device.display());
+                return Vec::new();
+            }
+        }
+    }
+    ids
+}
+
+/// Name `devices` by their `/dev/disk/by-id` links, which survive the
+/// renumbering of e.g. `/dev/nvme0n1` across boots, to be recorded in
+/// state; see [`resolve_ids`].
+#[cfg(any(all(have_bios, target_arch = "x86_64"), have_efi, have_uboot))]
+pub(crate) fn stable_ids(devices: &[impl AsRef<Path>]) -> Vec<PathBuf> {
+    stable_ids_in(Path::new(BY_ID_DIR), devices)
+}
+
+/// Resolve the names recorded by [`stable_ids`] to the current device
+/// nodes, or `None` if none were recorded or some of them are gone, in
+/// which case the devices should be found again.
+#[cfg(any(have_bios, have_uboot))]
+pub(crate) fn resolve_ids(ids: &[PathBuf]) -> Option<Vec<PathBuf>> {
+    if ids.is_empty() {
+        return None;
+    }
+    let mut devices = Vec::new();
+    for id in ids {
+        match id.canonicalize() {
+            Ok(p) => devices.push(p),
+            Err(e) => {
+                log::info!(
+                    "Recorded device {} not found ({e}), searching again",
+                    id.display()
+                );
+                return None;
+            }
+        }
+    }
+    Some(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_topology() -> Topology {
+        Topology::parse(
+            r#"{"blockdevices": [
+                {"path": "/dev/vda", "pkname": null, "pttype": "gpt", "parttypename": null,
+                 "partlabel": null, "mountpoints": [null]},
+                {"path": "/dev/vda1", "pkname": "/dev/vda", "pttype": "gpt",
+                 "parttypename": "BIOS boot", "partlabel": "BIOS-BOOT", "mountpoints": [null]},
+                {"path": "/dev/vda2", "pkname": "/dev/vda", "pttype": "gpt",
+                 "parttypename": "EFI System", "partlabel": "EFI-SYSTEM", "mountpoints": [null]},
+                {"path": "/dev/vda3", "pkname": "/dev/vda", "pttype": "gpt",
+                 "parttypename": "Linux filesystem", "partlabel": "boot", "mountpoints": ["/boot"]},
+                {"path": "/dev/vda4", "pkname": "/dev/vda", "pttype": "gpt",
+                 "parttypename": "Linux filesystem", "partlabel": "root",
+                 "mountpoints": ["/sysroot", "/var", "/etc"]}
+            ]}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_topology() -> Result<()> {
+        let t = example_topology();
+        assert!(t.device("/dev/nonexistent").is_err());
+        #[cfg(any(have_efi, have_uboot))]
+        {
+            assert_eq!(t.parent_disk("/dev/vda3")?, "/dev/vda");
+            assert!(t.parent_disk("/dev/vda").is_err());
+        }
+        #[cfg(have_uboot)]
+        {
+            assert_eq!(t.children("/dev/vda").count(), 4);
+            assert_eq!(t.partition_by_label("/dev/vda", "EFI-SYSTEM")?, "/dev/vda2");
+            assert!(t.partition_by_label("/dev/vda", "uboot").is_err());
+        }
+        #[cfg(all(have_bios, target_arch = "x86_64"))]
+        {
+            assert_eq!(t.children("/dev/vda").count(), 4);
+            assert_eq!(t.children(Path::new("/dev/vda")).count(), 4);
+            assert!(!t.lacks_bios_boot_partition("/dev/vda")?);
+            assert_eq!(t.mounted_at("/boot").unwrap().path, "/dev/vda3");
+            assert_eq!(t.mounted_at("/var").unwrap().path, "/dev/vda4");
+            assert!(t.mounted_at("/boot/efi").is_none());
+            assert_eq!(t.filesystem_disks("/dev/vda3")?, ["/dev/vda"]);
+            let t = Topology::parse(
+                r#"{"blockdevices": [
+                    {"path": "/dev/sda", "pttype": "gpt", "parttypename": null},
+                    {"path": "/dev/sda1", "pkname": "/dev/sda", "pttype": "gpt",
+                     "parttypename": "EFI System"},
+                    {"path": "/dev/sdb", "pttype": "dos", "parttypename": null}
+                ]}"#,
+            )?;
+            assert!(t.lacks_bios_boot_partition("/dev/sda")?);
+            // GRUB embeds itself after the MBR
+            assert!(!t.lacks_bios_boot_partition("/dev/sdb")?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(have_bios, target_arch = "x86_64"))]
+    fn test_btrfs_devices() -> Result<()> {
+        // /boot is a subvolume of a btrfs filesystem on two disks; without
+        // TYPE (as from older lsblk), disks are the devices without parents
+        let t = Topology::parse(
+            r#"{"blockdevices": [
+                {"path": "/dev/sda", "pkname": null, "pttype": "gpt", "parttypename": null},
+                {"path": "/dev/sda1", "pkname": "/dev/sda", "pttype": "gpt",
+                 "parttypename": "BIOS boot"},
+                {"path": "/dev/sda2", "pkname": "/dev/sda", "pttype": "gpt",
+                 "parttypename": "Linux filesystem", "fstype": "btrfs", "uuid": "1234",
+                 "mountpoints": ["/boot", "/"]},
+                {"path": "/dev/sdb", "pkname": null, "pttype": "gpt", "parttypename": null},
+                {"path": "/dev/sdb1", "pkname": "/dev/sdb", "pttype": "gpt",
+                 "parttypename": "BIOS boot"},
+                {"path": "/dev/sdb2", "pkname": "/dev/sdb", "pttype": "gpt",
+                 "parttypename": "Linux filesystem", "fstype": "btrfs", "uuid": "1234",
+                 "mountpoints": [null]},
+                {"path": "/dev/sdc1", "pkname": "/dev/sdc", "pttype": "gpt",
+                 "parttypename": "Linux filesystem", "fstype": "btrfs", "uuid": "5678",
+                 "mountpoints": ["/var"]}
+            ]}"#,
+        )?;
+        let boot = t.mounted_at("/boot").unwrap();
+        assert_eq!(t.filesystem_devices(boot).len(), 2);
+        assert_eq!(t.filesystem_disks(&boot.path)?, ["/dev/sda", "/dev/sdb"]);
+        assert_eq!(t.filesystem_disks("/dev/sdc1")?, ["/dev/sdc"]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(have_bios, target_arch = "x86_64"))]
+    fn test_lvm_devices() -> Result<()> {
+        // /boot is an LV of a VG with PVs on two disks, and a multipath disk
+        let t = Topology::parse(
+            r#"{"blockdevices": [
+                {"path": "/dev/sda", "pkname": null, "type": "disk", "pttype": "gpt",
+                 "parttypename": null},
+                {"path": "/dev/sda2", "pkname": "/dev/sda", "type": "part", "pttype": "gpt",
+                 "parttypename": "Linux LVM", "fstype": "LVM2_member"},
+                {"path": "/dev/sdb", "pkname": null, "type": "disk", "pttype": "gpt",
+                 "parttypename": null},
+                {"path": "/dev/sdb1", "pkname": "/dev/sdb", "type": "part", "pttype": "gpt",
+                 "parttypename": "Linux LVM", "fstype": "LVM2_member"},
+                {"path": "/dev/mapper/vg-boot", "pkname": "/dev/sda2", "type": "lvm",
+                 "pttype": null, "parttypename": null, "fstype": "xfs",
+                 "mountpoints": ["/boot"]},
+                {"path": "/dev/mapper/vg-boot", "pkname": "/dev/sdb1", "type": "lvm",
+                 "pttype": null, "parttypename": null, "fstype": "xfs",
+                 "mountpoints": ["/boot"]},
+                {"path": "/dev/sdc", "pkname": null, "type": "disk", "pttype": "gpt",
+                 "parttypename": null},
+                {"path": "/dev/sdd", "pkname": null, "type": "disk", "pttype": "gpt",
+                 "parttypename": null},
+                {"path": "/dev/mapper/mpatha", "pkname": "/dev/sdc", "type": "mpath",
+                 "pttype": "gpt", "parttypename": null},
+                {"path": "/dev/mapper/mpatha", "pkname": "/dev/sdd", "type": "mpath",
+                 "pttype": "gpt", "parttypename": null},
+                {"path": "/dev/mapper/mpatha1", "pkname": "/dev/mapper/mpatha", "type": "part",
+                 "pttype": "gpt", "parttypename": "Linux filesystem", "mountpoints": ["/var"]}
+            ]}"#,
+        )?;
+        let boot = t.mounted_at("/boot").unwrap();
+        assert_eq!(t.filesystem_disks(&boot.path)?, ["/dev/sda", "/dev/sdb"]);
+        assert_eq!(
+            t.filesystem_disks("/dev/mapper/mpatha1")?,
+            ["/dev/mapper/mpatha"]
+        );
+        // A path of the map resolves to the map
+        assert_eq!(
+            t.multipath_map("/dev/sdc")?.as_deref(),
+            Some("/dev/mapper/mpatha")
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(have_bios, target_arch = "x86_64"))]
+    fn test_sysfs_parents() -> Result<()> {
+        // An encrypted /boot on an LV, with a PV partition on /dev/sda,
+        // and an encrypted multipath disk, as listed by an lsblk without
+        // parents
+        let td = tempfile::tempdir()?;
+        let sysfs = td.path();
+        let block = sysfs.join("class/block");
+        for (dev, slave) in [("dm-1", "dm-0"), ("dm-0", "sda2"), ("dm-3", "dm-2")] {
+            std::fs::create_dir_all(block.join(dev).join("slaves").join(slave))?;
+        }
+        std::fs::create_dir_all(block.join("dm-2/dm"))?;
+        std::fs::write(block.join("dm-2/dm/uuid"), "mpath-3600a0980")?;
+        let sda = sysfs.join("devices/pci0000:00/sda");
+        std::fs::create_dir_all(sda.join("sda2"))?;
+        std::fs::write(sda.join("sda2/partition"), "2")?;
+        std::os::unix::fs::symlink(&sda, block.join("sda"))?;
+        std::os::unix::fs::symlink(sda.join("sda2"), block.join("sda2"))?;
+        let mut t = Topology::parse(
+            r#"{"blockdevices": [
+                {"path": "/dev/sda", "pttype": "gpt", "parttypename": null},
+                {"path": "/dev/sda2", "pttype": "gpt", "parttypename": "Linux LVM"},
+                {"path": "/dev/dm-0", "pttype": null, "parttypename": null},
+                {"path": "/dev/dm-1", "pttype": null, "parttypename": null,
+                 "mountpoints": ["/boot"]},
+                {"path": "/dev/dm-2", "pttype": null, "parttypename": null},
+                {"path": "/dev/dm-3", "pttype": null, "parttypename": null,
+                 "mountpoints": ["/var"]}
+            ]}"#,
+        )?;
+        t.sysfs = sysfs.to_owned();
+        assert_eq!(t.sysfs_parents("/dev/dm-1")?, ["/dev/dm-0"]);
+        assert_eq!(t.sysfs_parents("/dev/sda2")?, ["/dev/sda"]);
+        assert!(t.sysfs_parents("/dev/sda")?.is_empty());
+        assert_eq!(t.filesystem_disks("/dev/dm-1")?, ["/dev/sda"]);
+        assert_eq!(t.filesystem_disks("/dev/dm-3")?, ["/dev/dm-2"]);
+        // Paths of a multipath map resolve to the map
+        std::fs::create_dir_all(block.join("sdc/holders/dm-2"))?;
+        assert_eq!(t.multipath_map("/dev/sdc")?.as_deref(), Some("/dev/dm-2"));
+        assert_eq!(t.multipath_map("/dev/sda")?, None);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(have_bios, target_arch = "x86_64"))]
+    fn test_read_sysfs() -> Result<()> {
+        // A GPT disk with a BIOS boot partition known to udev and an
+        // unlabeled /boot partition, and a root LV
+        let td = tempfile::tempdir()?;
+        let (sysfs, udev) = (td.path().join("sys"), td.path().join("udev"));
+        let block = sysfs.join("class/block");
+        let vda = sysfs.join("devices/pci0000:00/vda");
+        std::fs::create_dir_all(&block)?;
+        for (dir, devno) in [
+            (vda.clone(), "252:0"),
+            (vda.join("vda1"), "252:1"),
+            (vda.join("vda2"), "252:2"),
+            (block.join("dm-0"), "253:0"),
+        ] {
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(dir.join("dev"), format!("{devno}\n"))?;
+            if dir.starts_with(&vda) {
+                std::os::unix::fs::symlink(&dir, block.join(dir.file_name().unwrap()))?;
+            }
+        }
+        std::fs::write(vda.join("vda1/partition"), "1\n")?;
+        std::fs::write(vda.join("vda2/partition"), "2\n")?;
+        std::fs::write(
+            vda.join("vda2/uevent"),
+            "DEVTYPE=partition\nPARTNAME=boot\n",
+        )?;
+        std::fs::create_dir_all(block.join("dm-0/dm"))?;
+        std::fs::write(block.join("dm-0/dm/uuid"), "LVM-abc\n")?;
+        std::fs::create_dir_all(&udev)?;
+        std::fs::write(
+            udev.join("b252:0"),
+            "S:disk/by-path/x\nE:ID_PART_TABLE_TYPE=gpt\n",
+        )?;
+        std::fs::write(
+            udev.join("b252:1"),
+            format!(
+                "E:ID_PART_ENTRY_SCHEME=gpt\nE:ID_PART_ENTRY_TYPE={BIOS_BOOT_PARTTYPE}\n\
+                 E:ID_PART_ENTRY_NAME=BIOS-BOOT\n"
+            ),
+        )?;
+        let mounts = crate::native::parse_mountinfo(
+            "1 0 253:0 / / rw - xfs /dev/mapper/root rw\n\
+             2 1 252:2 / /boot rw - ext4 /dev/vda2 rw\n",
+        )?;
+        let t = Topology::read_sysfs(&sysfs, &udev, &mounts)?;
+        let paths = t
+            .devices
+            .iter()
+            .map(|d| d.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["/dev/dm-0", "/dev/vda", "/dev/vda1", "/dev/vda2"]);
+        let vda1 = t.device("/dev/vda1")?;
+        assert!(vda1.is_bios_boot());
+        assert_eq!(vda1.partlabel.as_deref(), Some("BIOS-BOOT"));
+        let vda2 = t.device("/dev/vda2")?;
+        assert_eq!(vda2.pkname.as_deref(), Some("/dev/vda"));
+        assert_eq!(t.device("/dev/vda")?.pttype.as_deref(), Some("gpt"));
+        assert_eq!(t.device("/dev/dm-0")?.devtype.as_deref(), Some("lvm"));
+        let boot = t.mounted_at("/boot").unwrap();
+        assert_eq!(boot.partlabel.as_deref(), Some("boot"));
+        assert_eq!(t.mounted_at("/").unwrap().path, "/dev/dm-0");
+        assert_eq!(t.filesystem_disks("/dev/vda2")?, ["/dev/vda"]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(have_bios, target_arch = "x86_64"))]
+    fn test_embedding_area() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let block = td.path().join("class/block");
+        for (dev, attr, value) in [
+            ("sda1", "start", "63"),
+            ("sda2", "start", "2099200"),
+            ("vda1", "size", "2048"),
+        ] {
+            std::fs::create_dir_all(block.join(dev))?;
+            std::fs::write(block.join(dev).join(attr), format!("{value}\n"))?;
+        }
+        let mut t = Topology::parse(
+            r#"{"blockdevices": [
+                {"path": "/dev/sda", "pttype": "dos", "parttypename": null},
+                {"path": "/dev/sda2", "pkname": "/dev/sda", "pttype": "dos",
+                 "parttypename": "Linux"},
+                {"path": "/dev/sda1", "pkname": "/dev/sda", "pttype": "dos",
+                 "parttypename": "Linux"},
+                {"path": "/dev/vda", "pttype": "gpt", "parttypename": null},
+                {"path": "/dev/vda1", "pkname": "/dev/vda", "pttype": "gpt",
+                 "parttypename": "BIOS boot"},
+                {"path": "/dev/vdb", "pttype": "gpt", "parttypename": null}
+            ]}"#,
+        )?;
+        t.sysfs = td.path().to_owned();
+        assert_eq!(
+            t.embedding_area("/dev/sda")?,
+            Some(EmbeddingArea::MbrGap(62 * 512))
+        );
+        let area = t.embedding_area("/dev/vda")?.unwrap();
+        assert_eq!(area.size(), 1024 * 1024);
+        assert_eq!(area.to_string(), "the BIOS boot partition /dev/vda1");
+        assert_eq!(t.embedding_area("/dev/vdb")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prep_partitions() -> Result<()> {
+        let t = Topology::parse(
+            r#"{"blockdevices": [
+                {"path": "/dev/sda", "pttype": "gpt", "parttypename": null},
+                {"path": "/dev/sda1", "pkname": "/dev/sda", "pttype": "gpt",
+                 "parttype": "9E1A2D38-C612-4316-AA26-8B49521E5A8B",
+                 "parttypename": "PowerPC PReP boot", "partlabel": "PReP-Startpartition"},
+                {"path": "/dev/sdb", "pttype": "dos", "parttypename": null},
+                {"path": "/dev/sdb1", "pkname": "/dev/sdb", "pttype": "dos",
+                 "parttype": "0x41", "parttypename": "PPC PReP Boot"}
+            ]}"#,
+        )?;
+        let disks = |d: &[&str]| d.iter().map(|d| d.to_string()).collect::<BTreeSet<_>>();
+        assert_eq!(
+            t.prep_partitions(&disks(&["/dev/sda", "/dev/sdb"]))?,
+            ["/dev/sda1", "/dev/sdb1"]
+        );
+        // Only those on the given disks
+        assert_eq!(t.prep_partitions(&disks(&["/dev/sdb"]))?, ["/dev/sdb1"]);
+        let e = t.prep_partitions(&disks(&["/dev/sdc"])).unwrap_err();
+        assert!(e.to_string().contains("no partitions on /dev/sdc"), "{e}");
+        let e = example_topology()
+            .prep_partitions(&disks(&["/dev/vda"]))
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("No PReP boot partition"), "{e}");
+        let t = Topology::parse(
+            r#"{"blockdevices": [
+                {"path": "/dev/sda1", "pkname": "/dev/sda", "pttype": "gpt",
+                 "parttype": "0fc63daf-8483-4772-8e79-3d69d8477de4",
+                 "parttypename": "Linux filesystem", "partlabel": "root"}
+            ]}"#,
+        )?;
+        let e = t
+            .prep_partitions(&disks(&["/dev/sda"]))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.ends_with(": /dev/sda1 (type 0fc63daf-8483-4772-8e79-3d69d8477de4 \"Linux filesystem\", label \"root\")"),
+            "{e}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(have_fat)]
+    fn test_partitions_labeled() -> Result<()> {
+        let t = Topology::parse(
+            r#"{"blockdevices": [
+                {"path": "/dev/vda", "type": "disk", "pttype": "gpt", "parttypename": null},
+                {"path": "/dev/vda2", "pkname": "/dev/vda", "type": "part", "pttype": "gpt",
+                 "parttypename": "EFI System", "partlabel": "EFI-SYSTEM"},
+                {"path": "/dev/vdb", "type": "disk", "pttype": "gpt", "parttypename": null},
+                {"path": "/dev/vdb1", "pkname": "/dev/vdb", "type": "part", "pttype": "gpt",
+                 "parttypename": "EFI System", "partlabel": "EFI System Partition"},
+                {"path": "/dev/sdc", "type": "disk", "pttype": "gpt", "parttypename": null},
+                {"path": "/dev/sdc1", "pkname": "/dev/sdc", "type": "part", "pttype": "gpt",
+                 "parttypename": "EFI System", "partlabel": "EFI-SYSTEM"},
+                {"path": "/dev/mapper/mpatha", "pkname": "/dev/sdc", "type": "mpath",
+                 "pttype": "gpt", "parttypename": null},
+                {"path": "/dev/mapper/mpatha1", "pkname": "/dev/mapper/mpatha", "type": "part",
+                 "pttype": "gpt", "parttypename": "EFI System", "partlabel": "EFI-SYSTEM"}
+            ]}"#,
+        )?;
+        let labels = [
+            crate::efi::COREOS_ESP_PART_LABEL,
+            crate::efi::ANACONDA_ESP_PART_LABEL,
+        ];
+        assert_eq!(
+            t.partitions_labeled(&labels),
+            ["/dev/mapper/mpatha1", "/dev/vda2", "/dev/vdb1"]
+        );
+        assert_eq!(t.partitions_labeled(&["root"]), Vec::<&str>::new());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(any(all(have_bios, target_arch = "x86_64"), have_efi, have_uboot))]
+    fn test_stable_ids() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dev = td.path().canonicalize()?.join("dev");
+        let by_id = dev.join("disk/by-id");
+        std::fs::create_dir_all(&by_id)?;
+        for name in ["nvme0n1", "nvme1n1", "sda", "loop0"] {
+            std::fs::write(dev.join(name), "")?;
+        }
+        let link = |name: &str, target: &str| {
+            std::os::unix::fs::symlink(format!("../../{target}"), by_id.join(name))
+        };
+        link("nvme-eui.0025388b91b1a2c3", "nvme0n1")?;
+        link("nvme-Samsung_SSD_980_S64DNF0R", "nvme0n1")?;
+        link("ata-WDC_WD10EZEX", "sda")?;
+        link("wwn-0x50014ee2b5d8c3a1", "sda")?;
+        let path = |name: &str| dev.join(name);
+        let id = |name: &str| by_id.join(name);
+
+        let ids = stable_ids_in(&by_id, &[path("nvme0n1"), path("sda")]);
+        assert_eq!(
+            ids,
+            [
+                id("nvme-Samsung_SSD_980_S64DNF0R"),
+                id("wwn-0x50014ee2b5d8c3a1")
+            ]
+        );
+        // Nothing is recorded unless all devices can be named
+        assert!(stable_ids_in(&by_id, &[path("sda"), path("loop0")]).is_empty());
+        #[cfg(any(have_bios, have_uboot))]
+        {
+            assert_eq!(resolve_ids(&ids), Some(vec![path("nvme0n1"), path("sda")]));
+            // The disk is found under its new kernel name after renumbering
+            std::fs::remove_file(by_id.join("nvme-Samsung_SSD_980_S64DNF0R"))?;
+            link("nvme-Samsung_SSD_980_S64DNF0R", "nvme1n1")?;
+            assert_eq!(resolve_ids(&ids), Some(vec![path("nvme1n1"), path("sda")]));
+            // But if it's gone, the devices are searched for again
+            std::fs::remove_file(by_id.join("wwn-0x50014ee2b5d8c3a1"))?;
+            assert_eq!(resolve_ids(&ids), None);
+            assert_eq!(resolve_ids(&[]), None);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_older_lsblk() -> Result<()> {
+        // Without the optional columns
+        let data = include_str!("../tests/fixtures/example-lsblk-output.json");
+        let t = Topology::parse(data)?;
+        assert_eq!(t.devices.len(), 7);
+        assert!(t.devices.iter().all(|d| d.pkname.is_none()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_nested_lsblk() -> Result<()> {
+        // As listed without --list, with the partitions and the LVs on them
+        // nested below their disks
+        let data = include_str!("../tests/fixtures/example-lsblk-tree-output.json");
+        let t = Topology::parse(data)?;
+        assert_eq!(t.devices.len(), 7);
+        assert!(t.devices.iter().all(|d| d.children.is_empty()));
+        // Without PKNAME, the parent is where the device is nested
+        let parent = |p: &str| {
+            let d = t.devices.iter().find(|d| d.path == p);
+            d.and_then(|d| d.pkname.as_deref())
+        };
+        assert_eq!(parent("/dev/vda1"), Some("/dev/vda"));
+        assert_eq!(parent("/dev/mapper/vg-root"), Some("/dev/vda4"));
+        #[cfg(all(have_bios, target_arch = "x86_64"))]
+        {
+            assert_eq!(t.children("/dev/vda").count(), 4);
+            assert_eq!(t.filesystem_disks("/dev/mapper/vg-root")?, ["/dev/vda"]);
+            assert!(!t.lacks_bios_boot_partition("/dev/vda")?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(have_bios, target_arch = "x86_64"))]
+    fn test_parse_localized_lsblk() -> Result<()> {
+        // As listed by lsblk in a German locale, with translated type names
+        let data = include_str!("../tests/fixtures/example-lsblk-localized-output.json");
+        let mut t = Topology::parse(data)?;
+        let td = tempfile::tempdir()?;
+        std::fs::create_dir_all(td.path().join("class/block/vda1"))?;
+        std::fs::write(td.path().join("class/block/vda1/size"), "2048\n")?;
+        t.sysfs = td.path().to_owned();
+        assert!(!t.lacks_bios_boot_partition("/dev/vda")?);
+        assert_eq!(
+            t.embedding_area("/dev/vda")?,
+            Some(EmbeddingArea::BiosBootPartition(
+                "/dev/vda1".into(),
+                1024 * 1024
+            ))
+        );
+        assert_eq!(
+            t.mounted_at("/boot").map(|d| d.path.as_str()),
+            Some("/dev/vda3")
+        );
+        Ok(())
+    }
+}
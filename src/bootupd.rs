@@ -1,11 +1,16 @@
-#[cfg(any(target_arch = "x86_64", target_arch = "powerpc64"))]
+#[cfg(have_bios)]
 use crate::bios;
 use crate::component;
-use crate::component::{Component, ValidationResult};
+use crate::component::{Component, Options, ValidationResult};
 use crate::coreos;
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(have_efi)]
 use crate::efi;
-use crate::model::{ComponentStatus, ComponentUpdatable, ContentMetadata, SavedState, Status};
+use crate::errors::ComponentContext;
+use crate::events::{self, Observer, Operation};
+use crate::model::{
+    AdoptionAction, AdoptionReport, AdoptionState, ComponentAdoption, ComponentStatus,
+    ComponentUpdatable, ContentMetadata, SavedState, Status,
+};
 use crate::util;
 use anyhow::{anyhow, Context, Result};
 use clap::crate_version;
@@ -13,8 +18,12 @@ use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::path::Path;
+use std::sync::Arc;
 
-pub(crate) enum ConfigMode {
+/// Which static bootloader configs to install
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum ConfigMode {
     None,
     Static,
     WithUUID,
@@ -30,23 +39,95 @@ impl ConfigMode {
     }
 }
 
+/// Catch installing a component for the wrong firmware: BIOS to a disk GRUB
+/// can't be embedded in on a UEFI-booted machine is refused, while EFI
+/// content on a legacy-booted machine is only warned about, as the disk
+/// may well be meant to boot elsewhere.
+fn check_boot_mode(component: &str, device: Option<&Path>) -> Result<()> {
+    let efi_booted = crate::firmware::is_efi_booted()?;
+    match (component, device) {
+        #[cfg(all(have_bios, target_arch = "x86_64"))]
+        ("BIOS", Some(device)) if efi_booted => {
+            if crate::blockdev::Topology::get()?.lacks_bios_boot_partition(device)? {
+                anyhow::bail!(
+                    "Refusing to install BIOS: booted via UEFI, and {} has no BIOS boot \
+                     partition (use --force to install anyway)",
+                    device.display()
+                );
+            }
+        }
+        ("EFI", _) if !efi_booted => {
+            log::warn!(
+                "Installing EFI on a machine booted in legacy BIOS mode; it won't be used \
+                 unless the disk is booted via UEFI"
+            );
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Prints the components installed, for installer logs.
+struct InstallProgress {
+    dest_root: String,
+}
+
+impl Observer for InstallProgress {
+    fn on_component_start(&self, component: &str, _: Operation) {
+        println!("bootupd: Installing {component} to {}", self.dest_root);
+    }
+
+    fn on_component_complete(&self, component: &str, _: Operation, version: &ContentMetadata) {
+        println!("bootupd: Installed {component} {}", version.version);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn install(
     source_root: &str,
     dest_root: &str,
-    device: Option<&str>,
+    device: Option<&Path>,
     configs: ConfigMode,
     update_firmware: bool,
     target_components: Option<&[String]>,
     auto_components: bool,
+    log_progress: bool,
+    deterministic: bool,
+    force: bool,
+    opts: &Options,
 ) -> Result<()> {
-    // TODO: Change this to an Option<&str>; though this probably balloons into having
-    // DeviceComponent and FileBasedComponent
-    let device = device.unwrap_or("");
+    // Line-oriented progress output, e.g. for installer logs
+    let progress = |msg: std::fmt::Arguments| {
+        if log_progress {
+            println!("bootupd: {msg}");
+        }
+    };
+    let _progress = log_progress.then(|| {
+        events::register(Arc::new(InstallProgress {
+            dest_root: dest_root.to_string(),
+        }))
+    });
+    if deterministic && (update_firmware || auto_components) {
+        anyhow::bail!("Deterministic mode can't update firmware or inspect the host");
+    }
     let source_root = openat::Dir::open(source_root).context("Opening source root")?;
     SavedState::ensure_not_present(dest_root)
         .context("failed to install, invalid re-install attempted")?;
+    // Installing to a disk image goes through a loop device, detached once
+    // all else is torn down
+    let image = match device {
+        Some(d) if d.is_file() => Some(crate::cleanup::attach_loop(d)?),
+        _ => None,
+    };
+    let device = image.as_ref().map(|g| g.path()).or(device);
+    #[cfg(have_efi)]
+    if let Some(manager) = crate::entries::entry_manager(&source_root)? {
+        if configs.enabled_with_uuid().is_some() {
+            anyhow::bail!("Static configs conflict with boot entries managed by {manager}");
+        }
+    }
 
-    let all_components = get_components_impl(auto_components);
+    let all_components = get_components_impl(auto_components, opts);
     if all_components.is_empty() {
         println!("No components available for this platform.");
         return Ok(());
@@ -73,18 +154,40 @@ pub(crate) fn install(
     let mut state = SavedState::default();
     let mut installed_efi_vendor = None;
     for &component in target_components.iter() {
-        // skip for BIOS if device is empty
-        if component.name() == "BIOS" && device.is_empty() {
+        // skip for BIOS and U-Boot without a device; petitboot on PowerNV
+        // doesn't need one
+        #[cfg(all(have_bios, target_arch = "powerpc64"))]
+        let needs_device = !crate::petitboot::is_powernv();
+        #[cfg(not(all(have_bios, target_arch = "powerpc64")))]
+        let needs_device = true;
+        if needs_device && matches!(component.name(), "BIOS" | "UBOOT") && device.is_none() {
             println!(
                 "Skip installing component {} without target device",
                 component.name()
             );
             continue;
         }
+        if component.name() == "COREBOOT" && !update_firmware {
+            println!("Skip installing component COREBOOT without --update-firmware");
+            continue;
+        }
+        // Deterministic builds don't depend on the host
+        if !force && !deterministic {
+            check_boot_mode(component.name(), device)?;
+        }
 
-        let meta = component
-            .install(&source_root, dest_root, device, update_firmware)
-            .with_context(|| format!("installing component {}", component.name()))?;
+        let meta = crate::spans::in_span("install", &[("component", component.name())], || {
+            events::in_component(Operation::Install, component.name(), || {
+                component.install(&source_root, dest_root, device, update_firmware)
+            })
+        })
+        .with_context(|| {
+            let ctx = ComponentContext::new(component.name(), "install");
+            match device {
+                Some(device) => ctx.device(device.display().to_string()),
+                None => ctx,
+            }
+        })?;
         log::info!("Installed {} {}", component.name(), meta.meta.version);
         state.installed.insert(component.name().into(), meta);
         // Yes this is a hack...the Component thing just turns out to be too generic.
@@ -95,12 +198,33 @@ pub(crate) fn install(
     }
     let sysroot = &openat::Dir::open(dest_root)?;
 
+    // In deterministic mode, all timestamps are derived from the inputs
+    let fixed_time = if deterministic {
+        let newest_payload = state.installed.values().map(|i| i.meta.timestamp).max();
+        let t = match util::source_date_epoch()? {
+            Some(t) => t,
+            None => newest_payload
+                .map(Into::into)
+                .unwrap_or(std::time::UNIX_EPOCH),
+        };
+        Some(t)
+    } else {
+        None
+    };
+
     match configs.enabled_with_uuid() {
         Some(uuid) => {
-            let self_bin_meta =
-                std::fs::metadata("/proc/self/exe").context("Querying self meta")?;
+            let timestamp = match fixed_time {
+                Some(t) => t.into(),
+                None => util::clamp_to_source_date_epoch(
+                    std::fs::metadata("/proc/self/exe")
+                        .context("Querying self meta")?
+                        .modified()?
+                        .into(),
+                )?,
+            };
             let self_meta = ContentMetadata {
-                timestamp: self_bin_meta.modified()?.into(),
+                timestamp,
                 version: crate_version!().into(),
             };
             state.static_configs = Some(self_meta);
@@ -111,6 +235,13 @@ pub(crate) fn install(
             ))]
             crate::grubconfigs::install(sysroot, installed_efi_vendor.as_deref(), uuid)?;
             // On other architectures, assume that there's nothing to do.
+            #[cfg(not(any(
+                target_arch = "x86_64",
+                target_arch = "aarch64",
+                target_arch = "powerpc64"
+            )))]
+            let _ = uuid;
+            progress(format_args!("Installed static GRUB configs"));
         }
         None => {}
     }
@@ -123,6 +254,12 @@ pub(crate) fn install(
     state_guard
         .update_state(&state)
         .context("failed to update state")?;
+    progress(format_args!("Wrote state to {dest_root}"));
+    crate::selinux::relabel(&source_root.recover_path()?, Path::new(dest_root))?;
+    if let Some(t) = fixed_time {
+        util::set_times_recursive(&Path::new(dest_root).join("boot"), t)?;
+        progress(format_args!("Set timestamps in {dest_root}/boot"));
+    }
 
     Ok(())
 }
@@ -130,53 +267,151 @@ pub(crate) fn install(
 type Components = BTreeMap<&'static str, Box<dyn Component>>;
 
 #[allow(clippy::box_default)]
-/// Return the set of known components; if `auto` is specified then the system
-/// filters to the target booted state.
-pub(crate) fn get_components_impl(auto: bool) -> Components {
-    let mut components = BTreeMap::new();
+/// Return the set of known components, writing as `opts` tell; if `auto` is
+/// specified then the system filters to the target booted state.
+pub(crate) fn get_components_impl(auto: bool, opts: &Options) -> Components {
+    // Nothing is inserted without components
+    #[cfg(not(have_component))]
+    let components = Components::new();
+    #[cfg(have_component)]
+    let mut components = Components::new();
 
+    #[cfg(have_component)]
     fn insert_component(components: &mut Components, component: Box<dyn Component>) {
         components.insert(component.name(), component);
     }
 
+    // Only x86_64 machines boot in more than one way
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = auto;
     #[cfg(target_arch = "x86_64")]
     {
         if auto {
-            let is_efi_booted = crate::efi::is_efi_booted().unwrap();
+            let is_efi_booted = crate::firmware::is_efi_booted().unwrap();
             log::info!(
                 "System boot method: {}",
                 if is_efi_booted { "EFI" } else { "BIOS" }
             );
+            #[cfg(have_efi)]
             if is_efi_booted {
-                insert_component(&mut components, Box::new(efi::Efi::default()));
-            } else {
-                insert_component(&mut components, Box::new(bios::Bios::default()));
+                insert_component(&mut components, Box::new(efi::Efi::new(opts.clone())));
+            }
+            #[cfg(have_bios)]
+            if !is_efi_booted {
+                insert_component(
+                    &mut components,
+                    Box::new(bios::Bios::default().with_options(opts.clone())),
+                );
             }
         } else {
-            insert_component(&mut components, Box::new(bios::Bios::default()));
-            insert_component(&mut components, Box::new(efi::Efi::default()));
+            #[cfg(have_bios)]
+            insert_component(
+                &mut components,
+                Box::new(bios::Bios::default().with_options(opts.clone())),
+            );
+            #[cfg(have_efi)]
+            insert_component(&mut components, Box::new(efi::Efi::new(opts.clone())));
         }
     }
+    // The payload is only shipped by OSes targeting coreboot machines
+    #[cfg(have_coreboot)]
+    if Path::new("/").join(crate::coreboot::CONFIG_PATH).exists() {
+        insert_component(
+            &mut components,
+            Box::new(crate::coreboot::Coreboot::default()),
+        );
+    }
     #[cfg(target_arch = "aarch64")]
-    insert_component(&mut components, Box::new(efi::Efi::default()));
+    {
+        #[cfg(have_efi)]
+        insert_component(&mut components, Box::new(efi::Efi::new(opts.clone())));
+        #[cfg(have_rpi)]
+        if crate::rpi::is_shipped(Path::new("/")) {
+            insert_component(
+                &mut components,
+                Box::new(crate::rpi::Rpi::new(opts.clone())),
+            );
+        }
+        // Only on single-board computers shipping U-Boot profiles
+        #[cfg(have_uboot)]
+        if Path::new("/").join(crate::uboot::BOARDS_DIR).exists() {
+            insert_component(
+                &mut components,
+                Box::new(crate::uboot::Uboot::new(opts.clone())),
+            );
+        }
+    }
+
+    #[cfg(all(have_bios, target_arch = "powerpc64"))]
+    insert_component(
+        &mut components,
+        Box::new(bios::Bios::default().with_options(opts.clone())),
+    );
 
-    #[cfg(target_arch = "powerpc64")]
-    insert_component(&mut components, Box::new(bios::Bios::default()));
+    // Only if the OS ships a template to render
+    #[cfg(have_extlinux)]
+    if Path::new("/").join(crate::extlinux::TEMPLATE_PATH).exists() {
+        insert_component(
+            &mut components,
+            Box::new(crate::extlinux::Extlinux::new(opts.clone())),
+        );
+    }
+    #[cfg(not(have_readback))]
+    let _ = opts;
 
     components
 }
 
 pub(crate) fn get_components() -> Components {
-    get_components_impl(false)
+    get_components_with(&Options::default())
+}
+
+/// Like [`get_components`], with the components writing as `opts` tell.
+pub(crate) fn get_components_with(opts: &Options) -> Components {
+    get_components_impl(false, opts)
+}
+
+/// Refresh the update metadata after a package manager changed the
+/// bootloader files, so that `status` reports the update, and print the
+/// components which now have one.  Components without cached update
+/// metadata are left alone.
+pub(crate) fn trigger_payload_changed() -> Result<()> {
+    let sysroot = openat::Dir::open("/")?;
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let mut pending = Vec::new();
+    for (name, component) in get_components() {
+        let Some(previous) = component.query_update(&sysroot)? else {
+            log::debug!("No update metadata for {name}");
+            continue;
+        };
+        let meta = component.generate_update_metadata("/")?;
+        if meta != previous {
+            println!("Refreshed update metadata for {name}: {}", meta.version);
+        }
+        if let Some(inst) = state.installed.get(name) {
+            if inst.meta.can_upgrade_to(&meta) {
+                pending.push(name);
+            }
+        }
+    }
+    if !pending.is_empty() {
+        println!("Update pending for: {}", pending.join(" "));
+    }
+    Ok(())
 }
 
-pub(crate) fn generate_update_metadata(sysroot_path: &str) -> Result<()> {
+pub(crate) fn generate_update_metadata(sysroot_path: &str, compress: bool) -> Result<()> {
     // create bootupd update dir which will save component metadata files for both components
     let updates_dir = Path::new(sysroot_path).join(crate::model::BOOTUPD_UPDATES_DIR);
+    crate::ostreeutil::ensure_mutable_usr(&updates_dir)?;
     std::fs::create_dir_all(&updates_dir)
         .with_context(|| format!("Failed to create updates dir {:?}", &updates_dir))?;
+    let sysroot = openat::Dir::open(sysroot_path)?;
     for component in get_components().values() {
         let v = component.generate_update_metadata(sysroot_path)?;
+        if compress {
+            component::compress_update_metadata(&sysroot, component.as_ref())?;
+        }
         println!(
             "Generated update layout for {}: {}",
             component.name(),
@@ -190,7 +425,8 @@ pub(crate) fn generate_update_metadata(sysroot_path: &str) -> Result<()> {
 /// Return value from daemon → client for component update
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) enum ComponentUpdateResult {
+#[non_exhaustive]
+pub enum ComponentUpdateResult {
     AtLatestVersion,
     Updated {
         previous: ContentMetadata,
@@ -199,92 +435,300 @@ pub(crate) enum ComponentUpdateResult {
     },
 }
 
-fn ensure_writable_boot() -> Result<()> {
-    util::ensure_writable_mount("/boot")
+/// Make /boot writable until the returned guard is dropped.
+fn ensure_writable_boot() -> Result<util::WritableMount> {
+    util::WritableMount::new("/boot")
+}
+
+/// The default number of components updated concurrently.
+pub(crate) const DEFAULT_UPDATE_JOBS: usize = 2;
+
+/// Split `names` into successive groups which can run concurrently, where
+/// each component only appears after everything named in its `update_after`.
+fn update_waves<'a>(
+    names: &[&'a str],
+    after: impl Fn(&str) -> &'static [&'static str],
+) -> Result<Vec<Vec<&'a str>>> {
+    let mut remaining = names.to_vec();
+    let mut waves = Vec::new();
+    while !remaining.is_empty() {
+        let (ready, blocked): (Vec<&str>, Vec<&str>) = remaining.iter().partition(|&&name| {
+            after(name)
+                .iter()
+                .all(|dep| !remaining.iter().any(|r| r == dep))
+        });
+        if ready.is_empty() {
+            anyhow::bail!("Cyclic update ordering between: {}", blocked.join(" "));
+        }
+        waves.push(ready);
+        remaining = blocked;
+    }
+    Ok(waves)
+}
+
+/// daemon implementation of updating multiple components; components without
+/// ordering constraints between them are updated concurrently, using at most
+/// `jobs` threads.
+pub(crate) fn update_many(
+    names: &[String],
+    jobs: usize,
+    source: &openat::Dir,
+    opts: &Options,
+) -> Result<BTreeMap<String, ComponentUpdateResult>> {
+    update_many_impl(names, jobs, source, false, opts)
 }
 
-/// daemon implementation of component update
-pub(crate) fn update(name: &str) -> Result<ComponentUpdateResult> {
+/// Like [`update_many`], but if `allow_downgrade` is set, also apply
+/// payloads older than the installed version, e.g. to roll back.
+pub(crate) fn update_many_impl(
+    names: &[String],
+    jobs: usize,
+    source: &openat::Dir,
+    allow_downgrade: bool,
+    opts: &Options,
+) -> Result<BTreeMap<String, ComponentUpdateResult>> {
     let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
-    let component = component::new_from_name(name)?;
-    let inst = if let Some(inst) = state.installed.get(name) {
-        inst.clone()
-    } else {
-        anyhow::bail!("Component {} is not installed", name);
-    };
     let sysroot = openat::Dir::open("/")?;
-    let update = component.query_update(&sysroot)?;
-    let update = match update.as_ref() {
-        Some(p) if inst.meta.can_upgrade_to(p) => p,
-        _ => return Ok(ComponentUpdateResult::AtLatestVersion),
-    };
+    let mut results = BTreeMap::new();
+    let mut pending = BTreeMap::new();
+    for name in names {
+        let component = component::new_from_name_with(name, opts)?;
+        let Some(inst) = state.installed.get(name.as_str()) else {
+            anyhow::bail!("Component {} is not installed", name);
+        };
+        match component.query_update(source)? {
+            Some(p)
+                if inst.meta.can_upgrade_to(&p)
+                    || (allow_downgrade && p.version != inst.meta.version) =>
+            {
+                pending.insert(component.name(), (inst.clone(), p));
+            }
+            _ => {
+                results.insert(name.clone(), ComponentUpdateResult::AtLatestVersion);
+            }
+        }
+    }
+    if pending.is_empty() {
+        return Ok(results);
+    }
 
-    ensure_writable_boot()?;
+    let _boot = ensure_writable_boot()?;
 
     let mut pending_container = state.pending.take().unwrap_or_default();
-    let interrupted = pending_container.get(component.name()).cloned();
-    pending_container.insert(component.name().into(), update.clone());
+    let mut interrupted = BTreeMap::new();
+    for (&name, (_, update)) in pending.iter() {
+        if let Some(i) = pending_container.insert(name.into(), update.clone()) {
+            interrupted.insert(name, i);
+        }
+    }
+    state.pending = Some(pending_container);
     let mut state_guard =
         SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
     state_guard
         .update_state(&state)
         .context("Failed to update state")?;
 
-    let newinst = component
-        .run_update(&state_guard.sysroot, &inst)
-        .with_context(|| format!("Failed to update {}", component.name()))?;
-    state.installed.insert(component.name().into(), newinst);
-    pending_container.remove(component.name());
-    state_guard.update_state(&state)?;
+    let names: Vec<&str> = pending.keys().copied().collect();
+    let waves = update_waves(&names, |name| {
+        component::new_from_name(name)
+            .map(|c| c.update_after())
+            .unwrap_or_default()
+    })?;
+    let mut errors = Vec::new();
+    let mut remaining: Vec<&str> = names.clone();
+    'waves: for wave in waves {
+        for chunk in wave.chunks(jobs.max(1)) {
+            if let Err(e) = crate::backend::cancel::check() {
+                crate::backend::cancel::record_aborted("update", &remaining);
+                errors.push(e);
+                break 'waves;
+            }
+            remaining.retain(|n| !chunk.contains(n));
+            let outcomes = std::thread::scope(|s| {
+                let handles = chunk
+                    .iter()
+                    .map(|&name| {
+                        let inst = &pending[name].0;
+                        let context = crate::spans::current();
+                        let handle = s.spawn(move || -> Result<_> {
+                            let _attached = context.attach();
+                            // Components aren't thread safe, so each thread gets its own
+                            let component = component::new_from_name_with(name, opts)?;
+                            crate::spans::in_span("update", &[("component", name)], || {
+                                events::in_component(Operation::Update, name, || {
+                                    component.run_update(source, inst)
+                                })
+                            })
+                            .with_context(|| ComponentContext::new(name, "update"))
+                        });
+                        (name, handle)
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|(name, h)| {
+                        let r = h
+                            .join()
+                            .unwrap_or_else(|_| Err(anyhow!("Updating {name} panicked")));
+                        (name, r)
+                    })
+                    .collect::<Vec<_>>()
+            });
+            for (name, outcome) in outcomes {
+                match outcome {
+                    Ok(newinst) => {
+                        state.installed.insert(name.into(), newinst);
+                        if let Some(p) = state.pending.as_mut() {
+                            p.remove(name);
+                        }
+                        let (inst, update) = &pending[name];
+                        results.insert(
+                            name.to_string(),
+                            ComponentUpdateResult::Updated {
+                                previous: inst.meta.clone(),
+                                interrupted: interrupted.remove(name),
+                                new: update.clone(),
+                            },
+                        );
+                    }
+                    Err(e) => errors.push(e),
+                }
+            }
+            state_guard.update_state(&state)?;
+            // Don't start anything that may depend on a failed update
+            if !errors.is_empty() {
+                break 'waves;
+            }
+        }
+    }
+    let mut errors = errors.into_iter();
+    if let Some(e) = errors.next() {
+        for other in errors {
+            log::error!("{other:#}");
+        }
+        return Err(e);
+    }
 
-    Ok(ComponentUpdateResult::Updated {
-        previous: inst.meta,
-        interrupted,
-        new: update.clone(),
-    })
+    Ok(results)
 }
 
 /// daemon implementation of component adoption
-pub(crate) fn adopt_and_update(name: &str) -> Result<ContentMetadata> {
+pub(crate) fn adopt_and_update(name: &str, opts: &Options) -> Result<ContentMetadata> {
     let sysroot = openat::Dir::open("/")?;
     let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
-    let component = component::new_from_name(name)?;
+    let component = component::new_from_name_with(name, opts)?;
     if state.installed.contains_key(name) {
         anyhow::bail!("Component {} is already installed", name);
     };
 
-    ensure_writable_boot()?;
+    let _boot = ensure_writable_boot()?;
 
     let Some(update) = component.query_update(&sysroot)? else {
-        anyhow::bail!("Component {} has no available update", name);
+        return Err(crate::errors::Error::payload_missing(name).into());
     };
     let mut state_guard =
         SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
 
-    let inst = component
-        .adopt_update(&state_guard.sysroot, &update)
-        .context("Failed adopt and update")?;
+    let inst = crate::spans::in_span("adopt", &[("component", name)], || {
+        events::in_component(Operation::Adopt, name, || {
+            component.adopt_update(&state_guard.sysroot, &update)
+        })
+    })
+    .with_context(|| ComponentContext::new(name, "adopt and update"))?;
     state.installed.insert(component.name().into(), inst);
 
     state_guard.update_state(&state)?;
     Ok(update)
 }
 
+/// Provision a freshly booted golden image: adopt components which weren't
+/// installed via bootupd and create the firmware boot entries.  This is only
+/// done once; completion is recorded in the state file, or if provided, by
+/// creating `marker`.  The latter is useful with cloud-init, where firmware
+/// entries are per instance but the state file is part of the disk image.
+pub(crate) fn firstboot(marker: Option<&Path>) -> Result<()> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    match marker {
+        Some(marker) => {
+            if marker.try_exists()? {
+                println!("First boot provisioning already completed ({marker:?} exists)");
+                return Ok(());
+            }
+        }
+        None => {
+            if let Some(t) = state.firstboot_completed {
+                println!("First boot provisioning already completed at {t}");
+                return Ok(());
+            }
+        }
+    }
+    let status = status()?;
+    if status.adoptable.is_empty() {
+        println!("No components are adoptable.");
+    }
+    for (name, adoptable) in status.adoptable.iter() {
+        if adoptable.confident {
+            let r = adopt_and_update(name, &Options::default())?;
+            println!("Adopted and updated: {}: {}", name, r.version);
+        } else {
+            println!("Component {} requires explicit adopt-and-update", name);
+        }
+    }
+
+    let _boot = ensure_writable_boot()?;
+    let sysroot = openat::Dir::open("/")?;
+    // Adoption updated the state; reload it under the lock
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    for name in state.installed.keys() {
+        let component = component::new_from_name(name)?;
+        component
+            .update_firmware_entries(&state_guard.sysroot)
+            .with_context(|| ComponentContext::new(name, "register firmware entries for"))?;
+    }
+    let now = chrono::Utc::now();
+    state.firstboot_completed = Some(now);
+    state_guard.update_state(&state)?;
+    if let Some(marker) = marker {
+        if let Some(parent) = marker.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(marker, format!("{}\n", now.to_rfc3339()))
+            .with_context(|| format!("Writing {marker:?}"))?;
+    }
+    println!("First boot provisioning complete");
+    Ok(())
+}
+
 /// daemon implementation of component validate
-pub(crate) fn validate(name: &str) -> Result<ValidationResult> {
+pub(crate) fn validate(name: &str, opts: &Options) -> Result<ValidationResult> {
     let state = SavedState::load_from_disk("/")?.unwrap_or_default();
-    let component = component::new_from_name(name)?;
+    let component = component::new_from_name_with(name, opts)?;
     let Some(inst) = state.installed.get(name) else {
         anyhow::bail!("Component {} is not installed", name);
     };
-    component.validate(inst)
+    crate::spans::in_span("validate", &[("component", name)], || {
+        component.validate(inst)
+    })
 }
 
 pub(crate) fn status() -> Result<Status> {
+    status_with(true, &Options::default())
+}
+
+/// Like [`status`], with the components judged as `opts` tell (e.g. BIOS is
+/// only adoptable without a BIOS boot partition if one may be created), but
+/// only look for adoptable components if `adoptable` is set or nothing is
+/// installed.  Checking whether a component is adoptable can mean probing
+/// devices, e.g. for a BIOS boot partition, which is slow on hosts with
+/// many of them; the versions of installed components, as e.g. validation
+/// needs, only take the saved state and the update metadata.
+pub(crate) fn status_with(adoptable: bool, opts: &Options) -> Result<Status> {
     let mut ret: Status = Default::default();
-    let mut known_components = get_components();
+    let mut known_components = get_components_with(opts);
     let sysroot = openat::Dir::open("/")?;
-    let state = SavedState::load_from_disk("/")?;
+    let state = SavedState::load_summary_from_disk("/")?;
     if let Some(state) = state {
         for (name, ic) in state.installed.iter() {
             log::trace!("Gathering status for installed component: {}", name);
@@ -304,6 +748,7 @@ pub(crate) fn status() -> Result<Status> {
                     update,
                     updatable,
                     adopted_from,
+                    devices: ic.devices.clone(),
                 },
             );
         }
@@ -311,9 +756,22 @@ pub(crate) fn status() -> Result<Status> {
         log::trace!("No saved state");
     }
 
+    ret.firmware = Some(crate::firmware::query());
+
+    if !adoptable && !ret.components.is_empty() {
+        log::trace!("Skipping adoptable components");
+        return Ok(ret);
+    }
+
     // Process the remaining components not installed
     log::trace!("Remaining known components: {}", known_components.len());
     for (name, component) in known_components {
+        // Probing e.g. for a BIOS boot partition is pointless (and can fail
+        // in confusing ways on minimal systems) when there's no payload
+        if !component::is_shipped(&sysroot, name)? {
+            log::trace!("No update payload for {name}");
+            continue;
+        }
         if let Some(adopt_ver) = component.query_adopt()? {
             ret.adoptable.insert(name.to_string(), adopt_ver);
         } else {
@@ -324,6 +782,106 @@ pub(crate) fn status() -> Result<Status> {
     Ok(ret)
 }
 
+/// Record in `status` the inconsistencies between the installed components,
+/// as read back from where they're installed; see [`crate::consistency`].
+/// Devices are only read if recorded in the state, rather than probed for.
+pub(crate) fn check_consistency(status: &mut Status) -> Result<()> {
+    let Some(state) = SavedState::load_summary_from_disk("/")? else {
+        return Ok(());
+    };
+    let inconsistencies =
+        crate::consistency::check_installed(&state.installed, &Options::default())?;
+    status.inconsistencies = inconsistencies.iter().map(ToString::to_string).collect();
+    Ok(())
+}
+
+/// Probe whether each component is adoptable, and what adopting it would
+/// take, without adopting anything, as `opts` tell.
+pub(crate) fn probe_adoption(opts: &Options) -> Result<AdoptionReport> {
+    let sysroot = openat::Dir::open("/")?;
+    let state = SavedState::load_summary_from_disk("/")?;
+    let installed = state.map(|s| s.installed).unwrap_or_default();
+    let mut components = BTreeMap::new();
+    for (name, component) in get_components_with(opts) {
+        let probe = if installed.contains_key(name) {
+            ComponentAdoption::new(AdoptionState::Installed)
+        } else if !component::is_shipped(&sysroot, name)? {
+            ComponentAdoption::new(AdoptionState::NoPayload)
+        } else {
+            let mut probe = component
+                .probe_adopt()
+                .with_context(|| ComponentContext::new(name, "probe adoption of"))?;
+            if let AdoptionState::Adoptable(adoptable) = &probe.state {
+                if !adoptable.confident {
+                    probe.actions.insert(0, AdoptionAction::Confirm);
+                }
+            }
+            probe.update = component.query_update(&sysroot)?;
+            probe
+        };
+        components.insert(name.to_string(), probe);
+    }
+    Ok(AdoptionReport {
+        firmware: crate::firmware::query(),
+        components,
+    })
+}
+
+/// The overall result of `status --check`.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum UpdateCheck {
+    /// All components are at the latest version
+    UpToDate,
+    /// Updates (or confident adoptions) are available
+    Pending,
+    /// At least one component is newer than the available update
+    Drift,
+    /// The installed components don't fit together
+    Inconsistent,
+}
+
+impl UpdateCheck {
+    /// The process exit code corresponding to this result; `1` is left
+    /// for errors.
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self {
+            UpdateCheck::UpToDate => 0,
+            UpdateCheck::Pending => 2,
+            UpdateCheck::Drift => 3,
+            UpdateCheck::Inconsistent => 4,
+        }
+    }
+}
+
+/// Summarize the status into a single result; inconsistencies take
+/// precedence over drift, and drift over pending updates.
+pub(crate) fn check_status(status: &Status) -> UpdateCheck {
+    if !status.inconsistencies.is_empty() {
+        return UpdateCheck::Inconsistent;
+    }
+    let components = status.components.values().map(|c| {
+        if c.interrupted.is_some() {
+            return UpdateCheck::Pending;
+        }
+        match c.updatable {
+            ComponentUpdatable::NoUpdateAvailable | ComponentUpdatable::AtLatestVersion => {
+                UpdateCheck::UpToDate
+            }
+            ComponentUpdatable::Upgradable => UpdateCheck::Pending,
+            ComponentUpdatable::WouldDowngrade => UpdateCheck::Drift,
+        }
+    });
+    let adoptable = status
+        .adoptable
+        .values()
+        .filter(|a| a.confident)
+        .map(|_| UpdateCheck::Pending);
+    components
+        .chain(adoptable)
+        .max()
+        .unwrap_or(UpdateCheck::UpToDate)
+}
+
 pub(crate) fn print_status_avail(status: &Status) -> Result<()> {
     let mut avail = Vec::new();
     for (name, component) in status.components.iter() {
@@ -342,7 +900,9 @@ pub(crate) fn print_status_avail(status: &Status) -> Result<()> {
     Ok(())
 }
 
-pub(crate) fn print_status(status: &Status) -> Result<()> {
+/// Print `status` for humans; `adoptable` is whether it was gathered by
+/// probing for adoptable components.
+pub(crate) fn print_status(status: &Status, adoptable: bool) -> Result<()> {
     if status.components.is_empty() {
         println!("No components installed.");
     }
@@ -366,9 +926,26 @@ pub(crate) fn print_status(status: &Status) -> Result<()> {
             )),
         };
         println!("  Update: {}", msg);
+        if !component.devices.is_empty() {
+            let devices = component
+                .devices
+                .iter()
+                .map(|d| match d.canonicalize() {
+                    Ok(p) => format!("{} ({})", d.display(), p.display()),
+                    Err(_) => format!("{} (missing)", d.display()),
+                })
+                .collect::<Vec<_>>();
+            println!("  Devices: {}", devices.join(", "));
+        }
     }
 
-    if status.adoptable.is_empty() {
+    for i in status.inconsistencies.iter() {
+        println!("Inconsistent: {i}");
+    }
+
+    if !adoptable && !status.components.is_empty() {
+        println!("Adoptable components not checked (pass --adoptable to look for them).");
+    } else if status.adoptable.is_empty() {
         println!("No components are adoptable.");
     }
     for (name, adopt) in status.adoptable.iter() {
@@ -386,27 +963,160 @@ pub(crate) fn print_status(status: &Status) -> Result<()> {
 
     #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
     {
-        let boot_method = if efi::is_efi_booted()? { "EFI" } else { "BIOS" };
+        let boot_method = if crate::firmware::is_efi_booted()? {
+            "EFI"
+        } else {
+            "BIOS"
+        };
         println!("Boot method: {}", boot_method);
     }
+    if let Some(firmware) = status.firmware.as_ref() {
+        println!("Firmware: {}", crate::firmware::describe(firmware));
+    }
 
     Ok(())
 }
 
-pub(crate) fn client_run_update() -> Result<()> {
-    crate::try_fail_point!("update");
-    let status: Status = status()?;
+/// Render status as an aligned table, with optional color.
+/// Print `status` as a table, in `color` if set; `adoptable` is as for
+/// [`print_status`].
+pub(crate) fn print_status_table(status: &Status, color: bool, adoptable: bool) -> Result<()> {
+    use crate::output::{Style, Table};
+
+    let mut table = Table::new(["COMPONENT", "INSTALLED", "UPDATE", "STATE"]);
+    for (name, component) in status.components.iter() {
+        let update = component
+            .update
+            .as_ref()
+            .map(|u| u.version.as_str())
+            .unwrap_or("-");
+        let (state, style) = if component.interrupted.is_some() {
+            ("interrupted", Style::Warning)
+        } else {
+            match component.updatable {
+                ComponentUpdatable::NoUpdateAvailable | ComponentUpdatable::AtLatestVersion => {
+                    ("up-to-date", Style::Ok)
+                }
+                ComponentUpdatable::Upgradable => ("pending", Style::Pending),
+                ComponentUpdatable::WouldDowngrade => ("drift", Style::Warning),
+            }
+        };
+        table.push_row(vec![
+            (name.clone(), Style::Plain),
+            (component.installed.version.clone(), Style::Plain),
+            (update.to_string(), Style::Plain),
+            (state.to_string(), style),
+        ]);
+    }
+    for (name, adopt) in status.adoptable.iter() {
+        let state = if adopt.confident {
+            "adoptable"
+        } else {
+            "adoptable (manual)"
+        };
+        table.push_row(vec![
+            (name.clone(), Style::Plain),
+            (adopt.version.version.clone(), Style::Plain),
+            ("-".to_string(), Style::Plain),
+            (state.to_string(), Style::Info),
+        ]);
+    }
     if status.components.is_empty() && status.adoptable.is_empty() {
         println!("No components installed.");
-        return Ok(());
+    } else {
+        print!("{}", table.render(color));
+    }
+    for i in status.inconsistencies.iter() {
+        println!("Inconsistent: {i}");
+    }
+    if !adoptable && !status.components.is_empty() {
+        println!("Adoptable components not checked (pass --adoptable to look for them).");
+    }
+
+    if let Some(coreos_aleph) = coreos::get_aleph_version(Path::new("/"))? {
+        println!("CoreOS aleph version: {}", coreos_aleph.aleph.version);
     }
-    let mut updated = false;
-    for (name, cstatus) in status.components.iter() {
-        match cstatus.updatable {
-            ComponentUpdatable::Upgradable => {}
-            _ => continue,
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    {
+        let boot_method = if crate::firmware::is_efi_booted()? {
+            "EFI"
+        } else {
+            "BIOS"
+        };
+        println!("Boot method: {}", boot_method);
+    }
+    if let Some(firmware) = status.firmware.as_ref() {
+        println!("Firmware: {}", crate::firmware::describe(firmware));
+    }
+
+    Ok(())
+}
+
+/// Map user-provided component names (matched case-insensitively) to
+/// the names of known components.
+pub(crate) fn resolve_component_names<'a>(
+    known: impl IntoIterator<Item = &'a str> + Clone,
+    requested: &[String],
+) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    for name in requested {
+        let Some(found) = known
+            .clone()
+            .into_iter()
+            .find(|k| k.eq_ignore_ascii_case(name))
+        else {
+            let known = known.into_iter().collect::<Vec<_>>();
+            anyhow::bail!("Unknown component: {name} (known: {})", known.join(" "));
         };
-        match update(name)? {
+        if !r.iter().any(|n| n == found) {
+            r.push(found.to_string());
+        }
+    }
+    Ok(r)
+}
+
+/// On ostree systems, the bootloader update belongs with the OS content of
+/// a deployment.  If a deployment is staged, record it and return `true` so
+/// the update is deferred until it is booted; otherwise, clear any record of
+/// a previously staged deployment and return `false`.
+pub(crate) fn defer_to_staged_deployment() -> Result<bool> {
+    let Some(deployments) = crate::ostreeutil::deployment_state()? else {
+        return Ok(false);
+    };
+    let mut state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let previous = state.staged_deployment.clone();
+    if let Some(staged) = deployments.staged.as_ref() {
+        println!("Deferring bootloader update until staged deployment {staged} is booted");
+        if previous.as_ref() == Some(staged) {
+            return Ok(true);
+        }
+        state.staged_deployment = Some(staged.clone());
+    } else if let Some(previous) = previous.as_deref() {
+        if deployments.booted.as_deref() == Some(previous) {
+            println!("Finalizing bootloader update for deployment {previous}");
+        } else {
+            println!("Staged deployment {previous} was not booted; discarding");
+        }
+        state.staged_deployment = None;
+    } else {
+        return Ok(false);
+    }
+    let _boot = ensure_writable_boot()?;
+    let sysroot = openat::Dir::open("/")?;
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    state_guard.update_state(&state)?;
+    Ok(state.staged_deployment.is_some())
+}
+
+/// Print the outcome of [`update_many`], returning the updated components.
+pub(crate) fn print_update_results(
+    results: BTreeMap<String, ComponentUpdateResult>,
+) -> Vec<String> {
+    let mut updated = Vec::new();
+    for (name, result) in results {
+        match result {
             ComponentUpdateResult::AtLatestVersion => {
                 // Shouldn't happen unless we raced with another client
                 eprintln!(
@@ -430,37 +1140,317 @@ pub(crate) fn client_run_update() -> Result<()> {
                 println!("Updated {}: {}", name, new.version);
             }
         }
-        updated = true;
+        updated.push(name);
+    }
+    updated
+}
+
+/// Update all components, or only those in `selected` if provided.  The
+/// adoptable components are adopted too if bootupd is confident it can, or
+/// if `adopt` confirms it.
+pub(crate) fn client_run_update(
+    jobs: usize,
+    selected: Option<&[String]>,
+    adopt: bool,
+    opts: &Options,
+) -> Result<()> {
+    crate::try_fail_point!("update");
+    let status: Status = status_with(true, opts)?;
+    if status.components.is_empty() && status.adoptable.is_empty() {
+        println!("No components installed.");
+        return Ok(());
     }
+    let selected = selected
+        .map(|names| {
+            let known = status
+                .components
+                .keys()
+                .chain(status.adoptable.keys())
+                .map(|k| k.as_str());
+            resolve_component_names(known, names)
+        })
+        .transpose()?;
+    let is_selected = |name: &str| {
+        selected
+            .as_ref()
+            .map_or(true, |s| s.iter().any(|n| n == name))
+    };
+    if let Some(selected) = selected.as_ref() {
+        println!("Selected components: {}", selected.join(" "));
+    }
+    let mut updated = Vec::new();
+    let mut skipped = Vec::new();
+    let upgradable = status
+        .components
+        .iter()
+        .filter(|(name, _)| is_selected(name))
+        .filter_map(|(name, cstatus)| match cstatus.updatable {
+            ComponentUpdatable::Upgradable => Some(name.clone()),
+            _ => {
+                skipped.push(name.as_str());
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    let source = openat::Dir::open("/")?;
+    let results = update_many(&upgradable, jobs, &source, opts)?;
+    updated.extend(print_update_results(results));
     for (name, adoptable) in status.adoptable.iter() {
-        if adoptable.confident {
-            let r: ContentMetadata = adopt_and_update(name)?;
+        if !is_selected(name) {
+            continue;
+        }
+        if adoptable.confident || adopt {
+            let r: ContentMetadata = adopt_and_update(name, opts)?;
             println!("Adopted and updated: {}: {}", name, r.version);
-            updated = true;
+            updated.push(name.clone());
+        } else if selected.is_some() {
+            println!("Component {name} requires explicit adopt-and-update, or update --adopt");
         } else {
             println!("Component {} requires explicit adopt-and-update", name);
         }
     }
-    if !updated {
+    if updated.is_empty() {
         println!("No update available for any component.");
     }
+    if selected.is_some() {
+        println!(
+            "Summary: updated: {}; already current: {}",
+            if updated.is_empty() {
+                "none".to_string()
+            } else {
+                updated.join(" ")
+            },
+            if skipped.is_empty() {
+                "none".to_string()
+            } else {
+                skipped.join(" ")
+            }
+        );
+    }
     Ok(())
 }
 
-pub(crate) fn client_run_adopt_and_update() -> Result<()> {
-    let status: Status = status()?;
+/// What [`client_run_update`] would do with the same components, without
+/// changing anything; printed by `bootupctl update --plan --json`.
+pub(crate) fn client_plan_update(
+    selected: Option<&[String]>,
+    adopt: bool,
+    ignore_staged: bool,
+    opts: &Options,
+) -> Result<crate::plan::UpdatePlan> {
+    use crate::plan::{ComponentPlan, UpdatePlan};
+
+    let mut plan = UpdatePlan::default();
+    if !ignore_staged {
+        if let Some(staged) = crate::ostreeutil::deployment_state()?.and_then(|d| d.staged) {
+            plan.deferred_until = Some(staged);
+            return Ok(plan);
+        }
+    }
+    let status: Status = status_with(true, opts)?;
+    let selected = selected
+        .map(|names| {
+            let known = status
+                .components
+                .keys()
+                .chain(status.adoptable.keys())
+                .map(|k| k.as_str());
+            resolve_component_names(known, names)
+        })
+        .transpose()?;
+    let is_selected = |name: &str| {
+        selected
+            .as_ref()
+            .map_or(true, |s| s.iter().any(|n| n == name))
+    };
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let sysroot = openat::Dir::open("/")?;
+    let mut upgradable = BTreeMap::new();
+    for (name, cstatus) in status.components.iter().filter(|(n, _)| is_selected(n)) {
+        match (&cstatus.updatable, state.installed.get(name)) {
+            (ComponentUpdatable::Upgradable, Some(inst)) => {
+                upgradable.insert(name.as_str(), inst);
+            }
+            _ => plan.skipped.push(name.clone()),
+        }
+    }
+    let names: Vec<&str> = upgradable.keys().copied().collect();
+    let waves = update_waves(&names, |name| {
+        component::new_from_name(name)
+            .map(|c| c.update_after())
+            .unwrap_or_default()
+    })?;
+    for (wave, names) in waves.iter().enumerate() {
+        for &name in names {
+            let inst = upgradable[name];
+            let component = component::new_from_name_with(name, opts)?;
+            let Some(to) = component.query_update(&sysroot)? else {
+                continue;
+            };
+            let actions = component
+                .plan_update(&sysroot, inst)
+                .with_context(|| ComponentContext::new(name, "plan the update of"))?;
+            plan.components.push(ComponentPlan {
+                component: name.into(),
+                wave,
+                adopt: false,
+                from: Some(inst.meta.clone()),
+                to,
+                actions,
+            });
+        }
+    }
+    // Adoption follows, one component at a time
+    let mut wave = waves.len();
+    for (name, adoptable) in status.adoptable.iter().filter(|(n, _)| is_selected(n)) {
+        let component = component::new_from_name_with(name, opts)?;
+        match component.query_update(&sysroot)? {
+            Some(to) if adoptable.confident || adopt => {
+                plan.components.push(ComponentPlan {
+                    component: name.clone(),
+                    wave,
+                    adopt: true,
+                    from: None,
+                    to,
+                    actions: None,
+                });
+                wave += 1;
+            }
+            _ => plan.skipped.push(name.clone()),
+        }
+    }
+    Ok(plan)
+}
+
+/// Update components from an exported payload bundle rather than the
+/// payloads shipped in `/usr`.
+pub(crate) fn client_run_update_from_payload(
+    jobs: usize,
+    selected: Option<&[String]>,
+    bundle: &Path,
+    verify_key: Option<&Path>,
+    allow_unsigned: bool,
+    opts: &Options,
+) -> Result<()> {
+    let bundle = crate::payload::Bundle::open(bundle, verify_key, allow_unsigned)?;
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let known = bundle.manifest.components.keys().map(|k| k.as_str());
+    let selected = selected
+        .map(|names| resolve_component_names(known, names))
+        .transpose()?;
+    let mut upgradable = Vec::new();
+    for (name, c) in bundle.manifest.components.iter() {
+        if !selected.as_ref().map_or(true, |s| s.contains(name)) {
+            continue;
+        }
+        // Components without payload files (e.g. BIOS) install from the
+        // host's own files, so the bundle can't provide an update for them.
+        if c.filetree.is_none() {
+            println!("Skipping {name}: bundle has no payload files");
+            continue;
+        }
+        match state.installed.get(name) {
+            Some(inst) if inst.meta.can_upgrade_to(&c.meta) => upgradable.push(name.clone()),
+            Some(_) => println!("Component {name} is already at {} or newer", c.meta.version),
+            None => println!("Skipping {name}: not installed"),
+        }
+    }
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    bundle.apply_deltas(&upgradable, |name| match name {
+        #[cfg(have_efi)]
+        "EFI" => efi::Efi::default().open_esp(),
+        _ => anyhow::bail!("Deltas are not supported for {name}"),
+    })?;
+    let source = bundle.open_root()?;
+    let updated = print_update_results(update_many(&upgradable, jobs, &source, opts)?);
+    if updated.is_empty() {
+        println!("No update available for any component.");
+    }
+    Ok(())
+}
+
+/// Adopt and update all adoptable components, as `opts` tell.
+pub(crate) fn client_run_adopt_and_update(opts: &Options) -> Result<()> {
+    let status: Status = status_with(true, opts)?;
     if status.adoptable.is_empty() {
         println!("No components are adoptable.");
     } else {
         for (name, _) in status.adoptable.iter() {
-            let r: ContentMetadata = adopt_and_update(name)?;
+            let r: ContentMetadata = adopt_and_update(name, opts)?;
             println!("Adopted and updated: {}: {}", name, r.version);
         }
     }
     Ok(())
 }
 
-pub(crate) fn client_run_validate() -> Result<()> {
+/// Reconstruct the saved state from the installed content, for when it's
+/// lost or corrupt; see [`Component::fingerprint_installed`].
+pub(crate) fn rebuild_state() -> Result<()> {
+    let sysroot = openat::Dir::open("/")?;
+    // Keep what isn't derived from the installed content, if it can be read
+    let previous = SavedState::load_from_disk("/")
+        .unwrap_or_else(|e| {
+            log::warn!("{e:#}");
+            None
+        })
+        .unwrap_or_default();
+    let _boot = ensure_writable_boot()?;
+    let mut state_guard =
+        SavedState::acquire_write_lock(sysroot).context("Failed to acquire write lock")?;
+    let mut state = SavedState {
+        installed: BTreeMap::new(),
+        pending: None,
+        ..previous
+    };
+    // Rebuild what can be, then fail for the rest
+    let mut failed = Vec::new();
+    for (name, component) in get_components() {
+        match component.fingerprint_installed(&state_guard.sysroot) {
+            Ok(Some(inst)) => {
+                println!("Rebuilt: {name}: {}", inst.meta.version);
+                state.installed.insert(name.to_string(), inst);
+            }
+            Ok(None) => {
+                if component.query_adopt()?.is_some() {
+                    println!(
+                        "Not recorded: {name}; use `bootupctl adopt-and-update` to take it over"
+                    );
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to rebuild the state of {name}: {e:#}");
+                failed.push(name);
+            }
+        }
+    }
+    state_guard.update_state(&state)?;
+    if !failed.is_empty() {
+        anyhow::bail!("Failed to rebuild the state of: {}", failed.join(" "));
+    }
+    Ok(())
+}
+
+/// Leave a single GRUB directory in `/boot`, following the convention
+/// already in use; see [`crate::distro::consolidate_grub_dirs`].
+pub(crate) fn migrate_grub_dir() -> Result<()> {
+    let boot = Path::new("/boot");
+    let _boot = ensure_writable_boot()?;
+    let _state_guard = SavedState::acquire_write_lock(openat::Dir::open("/")?)
+        .context("Failed to acquire write lock")?;
+    let grub_dir = crate::distro::Profile::detect(Path::new("/"))?.boot_grub_dir(boot)?;
+    match crate::distro::consolidate_grub_dirs(boot, grub_dir)? {
+        Some(legacy) => {
+            crate::selinux::relabel(Path::new("/"), Path::new("/"))?;
+            println!("Moved /boot/{legacy} to /boot/{grub_dir}");
+        }
+        None => println!("Only /boot/{grub_dir} is in use"),
+    }
+    Ok(())
+}
+
+/// Validate the installed components, as `opts` tell, and with `fix`
+/// restore the SELinux labels which don't match the policy.
+pub(crate) fn client_run_validate(fix: bool, opts: &Options) -> Result<()> {
     let status: Status = status()?;
     if status.components.is_empty() {
         println!("No components installed.");
@@ -468,7 +1458,7 @@ pub(crate) fn client_run_validate() -> Result<()> {
     }
     let mut caught_validation_error = false;
     for (name, _) in status.components.iter() {
-        match validate(name)? {
+        match validate(name, opts)? {
             ValidationResult::Valid => {
                 println!("Validated: {}", name);
             }
@@ -483,9 +1473,33 @@ pub(crate) fn client_run_validate() -> Result<()> {
             }
         }
     }
+    let mislabeled = crate::selinux::mislabeled()?;
+    if !mislabeled.is_empty() {
+        if fix {
+            let _boot = ensure_writable_boot()?;
+            crate::selinux::relabel(Path::new("/"), Path::new("/"))?;
+            for m in mislabeled {
+                println!("Relabeled: {m}");
+            }
+        } else {
+            for m in mislabeled {
+                eprintln!("Mislabeled: {m}");
+            }
+            caught_validation_error = true;
+        }
+    }
+    // Whether or not each component is valid on its own
+    let state = SavedState::load_summary_from_disk("/")?.unwrap_or_default();
+    let inconsistencies = crate::consistency::check_installed(&state.installed, opts)?;
+    for i in inconsistencies.iter() {
+        eprintln!("Inconsistent: {}", i);
+    }
     if caught_validation_error {
         anyhow::bail!("Caught validation errors");
     }
+    if !inconsistencies.is_empty() {
+        anyhow::bail!("Caught cross-component consistency errors");
+    }
     Ok(())
 }
 
@@ -493,11 +1507,96 @@ pub(crate) fn client_run_validate() -> Result<()> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_update_waves() -> Result<()> {
+        let after = |name: &str| -> &'static [&'static str] {
+            match name {
+                "EFI" => &["BIOS"],
+                "cyclic" => &["cyclic"],
+                _ => &[],
+            }
+        };
+        assert_eq!(
+            update_waves(&["BIOS", "EFI", "other"], after)?,
+            vec![vec!["BIOS", "other"], vec!["EFI"]]
+        );
+        // Constraints on components not being updated are ignored
+        assert_eq!(update_waves(&["EFI"], after)?, vec![vec!["EFI"]]);
+        assert!(update_waves(&["cyclic", "BIOS"], after).is_err());
+        // Constraints are followed transitively
+        let chained = |name: &str| -> &'static [&'static str] {
+            match name {
+                "late" => &["EFI"],
+                name => after(name),
+            }
+        };
+        assert_eq!(
+            update_waves(&["late", "EFI", "BIOS"], chained)?,
+            vec![vec!["BIOS"], vec!["EFI"], vec!["late"]]
+        );
+
+        // The components we ship don't depend on each other, so all of them
+        // update in a single wave rather than one after the other
+        let components = get_components();
+        let names = components.keys().copied().collect::<Vec<_>>();
+        let waves = update_waves(&names, |name| components[name].update_after())?;
+        assert!(waves.len() <= 1, "{waves:?}");
+        assert_eq!(waves.concat(), names);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_status() {
+        let meta = ContentMetadata {
+            timestamp: chrono::Utc::now(),
+            version: "1".into(),
+        };
+        let cstatus = |updatable| ComponentStatus {
+            installed: meta.clone(),
+            interrupted: None,
+            update: None,
+            updatable,
+            adopted_from: None,
+            devices: Vec::new(),
+        };
+        let mut status = Status::default();
+        assert_eq!(check_status(&status), UpdateCheck::UpToDate);
+        status
+            .components
+            .insert("EFI".into(), cstatus(ComponentUpdatable::AtLatestVersion));
+        assert_eq!(check_status(&status), UpdateCheck::UpToDate);
+        status
+            .components
+            .insert("BIOS".into(), cstatus(ComponentUpdatable::Upgradable));
+        assert_eq!(check_status(&status), UpdateCheck::Pending);
+        status
+            .components
+            .insert("EFI".into(), cstatus(ComponentUpdatable::WouldDowngrade));
+        assert_eq!(check_status(&status).exit_code(), 3);
+        status
+            .inconsistencies
+            .push("Fallback BOOT/BOOTX64.EFI does not match fedora/shimx64.efi".into());
+        assert_eq!(check_status(&status), UpdateCheck::Inconsistent);
+        assert_eq!(check_status(&status).exit_code(), 4);
+    }
+
+    #[test]
+    fn test_resolve_component_names() -> Result<()> {
+        let known = ["BIOS", "EFI"];
+        let names = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            resolve_component_names(known, &names(&["efi", "BIOS", "Efi"]))?,
+            names(&["EFI", "BIOS"])
+        );
+        assert!(resolve_component_names(known, &names(&["uboot"])).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_failpoint_update() {
         let guard = fail::FailScenario::setup();
         fail::cfg("update", "return").unwrap();
-        let r = client_run_update();
+        let r = client_run_update(DEFAULT_UPDATE_JOBS, None, false, &Options::default());
         assert_eq!(r.is_err(), true);
         guard.teardown();
     }
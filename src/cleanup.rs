@@ -0,0 +1,375 @@
+//! Teardown of temporary artifacts.
+//!
+//! Updates stage files next to their targets and may mount the ESP, and
+//! installs to disk images attach them to loop devices.  Each such
+//! artifact is held by a [`Guard`] which tears it down when dropped, so
+//! that failures, including cancellation by a signal (see
+//! [`crate::backend::cancel`]), don't leave it behind.  After [`start`],
+//! the artifacts of the process are also recorded in a directory while they
+//! exist, so that the next invocation tears down what a crashed or killed
+//! one left.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use serde::{Deserialize, Serialize};
+
+use crate::util::CommandRunExt;
+
+/// Where the artifacts of running invocations are recorded, as `<pid>.json`.
+pub(crate) const RECORD_DIR: &str = "/run/bootupd/cleanup";
+
+/// Something to tear down once it's no longer needed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", tag = "kind")]
+pub(crate) enum Artifact {
+    /// A filesystem we mounted, with the device number of the mounted
+    /// filesystem, so that a later mount on the same path isn't torn down
+    Mount { path: PathBuf, dev: u64 },
+    /// Files staged below a directory, named with
+    /// [`crate::filetree::TMP_PREFIX`]
+    Staging { path: PathBuf },
+    /// A loop device we attached, with its backing file, so that the device
+    /// isn't detached once it's reused for another file
+    Loop { device: PathBuf, backing: PathBuf },
+}
+
+impl Artifact {
+    /// The filesystem mounted on `path`, which was just mounted.
+    #[cfg(have_efi)]
+    pub(crate) fn mount(path: PathBuf) -> Result<Self> {
+        use std::os::unix::fs::MetadataExt;
+        let dev = path.metadata()?.dev();
+        Ok(Artifact::Mount { path, dev })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        match self {
+            Artifact::Mount { path, .. } | Artifact::Staging { path } => path,
+            Artifact::Loop { device, .. } => device,
+        }
+    }
+
+    /// Tear down the artifact; it's fine if it's already gone.
+    fn teardown(&self) -> Result<()> {
+        match self {
+            Artifact::Mount { path: p, dev } => {
+                use std::os::unix::fs::MetadataExt;
+                if !p.exists() || !crate::util::is_mountpoint(p)? {
+                    return Ok(());
+                }
+                if p.metadata()?.dev() != *dev {
+                    log::debug!("Not unmounting {p:?}, which was mounted again since");
+                    return Ok(());
+                }
+                if !crate::util::have_program("umount") {
+                    return crate::native::unmount(p);
+                }
+                let status = crate::util::command("umount").arg(p).status()?;
+                if !status.success() {
+                    anyhow::bail!("Failed to unmount {p:?}: {status:?}");
+                }
+            }
+            Artifact::Loop { device, backing } => {
+                if loop_backing_file(device)?.as_ref() != Some(backing) {
+                    return Ok(());
+                }
+                crate::util::command("losetup")
+                    .arg("--detach")
+                    .arg(device)
+                    .run()
+                    .with_context(|| format!("Detaching {device:?}"))?;
+            }
+            Artifact::Staging { path: p } => {
+                if !p.exists() {
+                    return Ok(());
+                }
+                // Updates are only staged where the file trees are supported
+                #[cfg(have_filetree)]
+                {
+                    let dir = openat::Dir::open(p).with_context(|| format!("Opening {p:?}"))?;
+                    crate::filetree::cleanup_tmp(&dir)
+                        .with_context(|| format!("Removing staged files in {p:?}"))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The file backing the loop device `device`, if it's attached.
+fn loop_backing_file(device: &Path) -> Result<Option<PathBuf>> {
+    let Some(name) = device.file_name() else {
+        anyhow::bail!("Invalid loop device {device:?}");
+    };
+    let path = Path::new("/sys/block").join(name).join("loop/backing_file");
+    match std::fs::read_to_string(&path) {
+        Ok(f) => Ok(Some(PathBuf::from(f.trim_end_matches('\n')))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Reading {path:?}")),
+    }
+}
+
+/// Attach the disk image `image` to a loop device, with its partitions, for
+/// as long as the returned guard is held.
+#[context("Attaching {image:?} to a loop device")]
+pub(crate) fn attach_loop(image: &Path) -> Result<Guard> {
+    let backing = image.canonicalize()?;
+    let device = crate::util::cmd_output(
+        crate::util::command("losetup")
+            .args(["--find", "--show", "--partscan"])
+            .arg(&backing),
+    )?;
+    let device = PathBuf::from(device.trim());
+    let guard = Guard::new(Artifact::Loop {
+        device: device.clone(),
+        backing,
+    });
+    // For lsblk to see the partitions with their properties
+    if crate::util::have_program("udevadm") {
+        crate::util::command("udevadm").arg("settle").run()?;
+    }
+    log::debug!("Attached {image:?} to {device:?}");
+    Ok(guard)
+}
+
+static RECORDS: OnceLock<PathBuf> = OnceLock::new();
+/// The artifacts of this process, by guard
+static ARTIFACTS: Mutex<BTreeMap<u64, Artifact>> = Mutex::new(BTreeMap::new());
+
+/// Record artifacts in `dir` for the rest of the process, after tearing
+/// down those recorded there by invocations which are no longer running.
+pub(crate) fn start(dir: impl Into<PathBuf>) {
+    let dir = dir.into();
+    if let Err(e) = remove_orphans(&dir) {
+        log::warn!("Failed to clean up after previous runs: {e:#}");
+    }
+    let _ = RECORDS.set(dir);
+}
+
+/// Tear down the artifacts recorded in `dir` by processes which have exited.
+/// Records of artifacts which fail to be torn down are kept for the next run.
+fn remove_orphans(dir: &Path) -> Result<()> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Reading {dir:?}")),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let Some(pid) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(".json"))
+            .and_then(|n| n.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        if pid == std::process::id() || Path::new("/proc").join(pid.to_string()).exists() {
+            continue;
+        }
+        let artifacts: Vec<Artifact> = match std::fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|buf| Ok(serde_json::from_slice(&buf)?))
+        {
+            Ok(a) => a,
+            Err(e) => {
+                log::warn!("Discarding invalid record {path:?}: {e}");
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+        };
+        let mut remaining = Vec::new();
+        // In the reverse order of creation, e.g. staged files before the
+        // mount they're on
+        for a in artifacts.into_iter().rev() {
+            log::info!("Cleaning up {:?} left by process {pid}", a.path());
+            if let Err(e) = a.teardown() {
+                log::warn!("{e:#}");
+                remaining.insert(0, a);
+            }
+        }
+        if remaining.is_empty() {
+            std::fs::remove_file(&path)?;
+        } else {
+            std::fs::write(&path, serde_json::to_vec(&remaining)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write out the artifacts of this process, if recording.
+fn save(artifacts: &BTreeMap<u64, Artifact>) {
+    let Some(dir) = RECORDS.get() else {
+        return;
+    };
+    let path = dir.join(format!("{}.json", std::process::id()));
+    let r = if artifacts.is_empty() {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            r => r.map_err(Into::into),
+        }
+    } else {
+        let artifacts = artifacts.values().collect::<Vec<_>>();
+        let tmp = path.with_extension("json.tmp");
+        std::fs::create_dir_all(dir)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| Ok(std::fs::write(&tmp, serde_json::to_vec(&artifacts)?)?))
+            .and_then(|_| Ok(std::fs::rename(&tmp, &path)?))
+    };
+    // Only the cleanup after a crash depends on this
+    if let Err(e) = r {
+        log::debug!("Failed to record temporary artifacts in {path:?}: {e}");
+    }
+}
+
+/// Tears down its artifact when dropped, unless disarmed.
+#[must_use]
+pub(crate) struct Guard {
+    id: u64,
+    artifact: Option<Artifact>,
+}
+
+impl Guard {
+    /// Take charge of `artifact`, which was just created.
+    pub(crate) fn new(artifact: Artifact) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let mut artifacts = ARTIFACTS.lock().unwrap_or_else(|e| e.into_inner());
+        artifacts.insert(id, artifact.clone());
+        save(&artifacts);
+        Self {
+            id,
+            artifact: Some(artifact),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        // Unwrap safety: only taken when consumed
+        self.artifact.as_ref().unwrap().path()
+    }
+
+    fn forget(&self) {
+        let mut artifacts = ARTIFACTS.lock().unwrap_or_else(|e| e.into_inner());
+        artifacts.remove(&self.id);
+        save(&artifacts);
+    }
+
+    /// Keep the artifact, which is no longer temporary or already gone.
+    #[cfg(have_filetree)]
+    pub(crate) fn disarm(mut self) {
+        self.artifact = None;
+        self.forget();
+    }
+
+    /// Tear down the artifact now.
+    #[cfg(have_efi)]
+    pub(crate) fn teardown(mut self) -> Result<()> {
+        // Unwrap safety: only taken when consumed
+        let artifact = self.artifact.take().unwrap();
+        artifact.teardown()?;
+        self.forget();
+        Ok(())
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let Some(artifact) = self.artifact.take() else {
+            return;
+        };
+        match artifact.teardown() {
+            Ok(()) => self.forget(),
+            // Left recorded, for the next run
+            Err(e) => log::warn!("{e:#}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    #[cfg(have_filetree)]
+    fn test_guard() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let staged = td.path().join("EFI/.btmp.fedora");
+        std::fs::create_dir_all(&staged)?;
+        std::fs::write(td.path().join("EFI/grubx64.efi"), "grub")?;
+        let guard = Guard::new(Artifact::Staging {
+            path: td.path().into(),
+        });
+        assert_eq!(guard.path(), td.path());
+        drop(guard);
+        assert!(!staged.exists());
+        assert!(td.path().join("EFI/grubx64.efi").exists());
+
+        std::fs::create_dir_all(&staged)?;
+        Guard::new(Artifact::Staging {
+            path: td.path().into(),
+        })
+        .disarm();
+        assert!(staged.exists());
+        Ok(())
+    }
+
+    // Requires privileges to attach loop devices
+    #[test]
+    #[ignore]
+    fn test_loop() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let image = td.path().join("disk.img");
+        std::fs::File::create(&image)?.set_len(1024 * 1024)?;
+        let guard = attach_loop(&image)?;
+        let device = guard.path().to_owned();
+        assert_eq!(loop_backing_file(&device)?, Some(image.canonicalize()?));
+        drop(guard);
+        assert_eq!(loop_backing_file(&device)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_orphans() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let records = td.path().join("records");
+        remove_orphans(&records)?;
+        std::fs::create_dir_all(&records)?;
+        let esp = td.path().join("esp");
+        std::fs::create_dir_all(esp.join("EFI/.btmp.fedora"))?;
+        let artifacts = [
+            Artifact::Mount {
+                path: esp.clone(),
+                dev: esp.metadata()?.dev(),
+            },
+            Artifact::Staging { path: esp.clone() },
+            Artifact::Loop {
+                device: "/dev/loop-bootupd-test".into(),
+                backing: td.path().join("disk.img"),
+            },
+        ];
+        let record = serde_json::to_vec(&artifacts)?;
+        // No such process
+        let dead = records.join(format!("{}.json", u32::MAX));
+        std::fs::write(&dead, &record)?;
+        // Still running
+        let alive = records.join("1.json");
+        std::fs::write(&alive, &record)?;
+        std::fs::write(records.join("99999999.json.tmp"), "")?;
+        remove_orphans(&records)?;
+        #[cfg(have_filetree)]
+        assert!(!esp.join("EFI/.btmp.fedora").exists());
+        assert!(!dead.exists());
+        assert!(alive.exists());
+        assert_eq!(
+            serde_json::to_value(&artifacts[0])?,
+            serde_json::json!({"kind": "mount", "path": esp, "dev": esp.metadata()?.dev()})
+        );
+        Ok(())
+    }
+}
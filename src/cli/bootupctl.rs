@@ -4,6 +4,7 @@ use clap::Parser;
 use log::LevelFilter;
 
 use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 use std::process::{Command, Stdio};
 
 static SYSTEMD_ARGS_BOOTUPD: &[&str] = &["--unit", "bootupd", "--pipe"];
@@ -27,6 +28,45 @@ pub struct CtlCommand {
     #[clap(short = 'v', action = clap::ArgAction::Count, global = true)]
     verbosity: u8,
 
+    /// Format used to report a failure on stderr.
+    #[clap(long, value_enum, default_value_t, global = true)]
+    pub(crate) error_format: super::ErrorFormat,
+
+    /// Print a JSON summary of the time spent in each phase to stderr.
+    #[clap(long, global = true)]
+    pub(crate) timing: bool,
+
+    /// Where to log: stderr, the journal with structured fields, or a
+    /// rotating file in /var/log/bootupd; overrides /etc/bootupd/logging.conf.
+    #[clap(long, value_enum, global = true, value_name = "BACKEND")]
+    pub(crate) log_backend: Option<crate::logging::Backend>,
+
+    /// Log levels per module, with the syntax of RUST_LOG, e.g.
+    /// `bootupd::efi=debug`.
+    #[clap(
+        long,
+        global = true,
+        value_name = "FILTER",
+        value_parser = crate::logging::parse_filter
+    )]
+    pub(crate) log_filter: Option<String>,
+
+    /// Record the spans of operations in the journal (`journal`), or
+    /// append them to the file PATH.
+    #[cfg(feature = "spans")]
+    #[clap(long, global = true, value_name = "journal|PATH")]
+    pub(crate) trace_spans: Option<crate::spans::Sink>,
+
+    /// Operate on the host system from inside a privileged container
+    /// (which must share the host PID namespace).
+    #[clap(long, global = true)]
+    host: bool,
+
+    /// Simulate the operation against the prepared fake root DIR, without
+    /// privileges or touching devices; see README-devel.md.
+    #[clap(long, global = true, value_name = "DIR", conflicts_with = "host")]
+    pub(crate) simulate: Option<PathBuf>,
+
     /// CLI sub-command.
     #[clap(subcommand)]
     pub cmd: CtlVerb,
@@ -53,12 +93,60 @@ pub enum CtlVerb {
     Backend(CtlBackend),
     #[clap(name = "status", about = "Show components status")]
     Status(StatusOpts),
-    #[clap(name = "update", about = "Update all components")]
-    Update,
+    #[clap(name = "update", about = "Update all (or the selected) components")]
+    Update(UpdateOpts),
     #[clap(name = "adopt-and-update", about = "Update all adoptable components")]
-    AdoptAndUpdate,
+    AdoptAndUpdate(AdoptAndUpdateOpts),
     #[clap(name = "validate", about = "Validate system state")]
-    Validate,
+    Validate(ValidateOpts),
+    #[clap(name = "export", about = "Export installed boot artifacts", subcommand)]
+    Export(CtlExport),
+    #[clap(name = "state", about = "Manage the saved state", subcommand)]
+    State(CtlState),
+    #[clap(
+        name = "schema",
+        about = "Print the JSON Schema of a machine-readable output",
+        subcommand
+    )]
+    Schema(CtlSchema),
+    #[clap(
+        name = "migrate-grub-dir",
+        about = "Consolidate /boot/grub and /boot/grub2 into the directory in use"
+    )]
+    MigrateGrubDir,
+}
+
+#[derive(Debug, Parser)]
+pub enum CtlState {
+    #[clap(
+        name = "rebuild",
+        about = "Reconstruct the saved state by comparing the installed files to the update payloads"
+    )]
+    Rebuild,
+}
+
+#[derive(Debug, Parser)]
+pub enum CtlSchema {
+    #[clap(name = "status", about = "The document output by `status --json`")]
+    Status,
+    #[clap(name = "plan", about = "The document output by `update --plan --json`")]
+    Plan,
+}
+
+#[derive(Debug, Parser)]
+pub enum CtlExport {
+    #[clap(
+        name = "pxe",
+        about = "Export the EFI loaders and GRUB config for network boot"
+    )]
+    Pxe(ExportPxeOpts),
+}
+
+#[derive(Debug, Parser)]
+pub struct ExportPxeOpts {
+    /// Directory to write the files and manifest to
+    #[clap(value_name = "DIR")]
+    dest: PathBuf,
 }
 
 #[derive(Debug, Parser)]
@@ -67,6 +155,119 @@ pub enum CtlBackend {
     Generate(super::bootupd::GenerateOpts),
     #[clap(name = "install", hide = true)]
     Install(super::bootupd::InstallOpts),
+    #[clap(name = "export-payload", hide = true)]
+    ExportPayload(super::bootupd::ExportPayloadOpts),
+    #[clap(name = "build-iso-images", hide = true)]
+    BuildIsoImages(super::bootupd::BuildIsoImagesOpts),
+}
+
+#[derive(Debug, Parser)]
+pub struct UpdateOpts {
+    /// Maximum number of independent components to update concurrently
+    #[clap(long, default_value_t = bootupd::DEFAULT_UPDATE_JOBS)]
+    jobs: usize,
+
+    /// Only update these components (may be repeated)
+    #[clap(long = "component")]
+    components: Vec<String>,
+
+    /// Only update these components
+    #[clap(value_name = "COMPONENT")]
+    args: Vec<String>,
+
+    /// Apply the payloads from a bundle created by `backend export-payload`
+    #[clap(long, value_name = "BUNDLE")]
+    from_payload: Option<PathBuf>,
+
+    /// Require the bundle to be signed by the private key matching this PEM public key
+    #[clap(long, value_name = "PEM", requires = "from_payload")]
+    verify_key: Option<PathBuf>,
+
+    /// Accept a bundle without verifying its signature, if no key is provided
+    #[clap(long, requires = "from_payload", conflicts_with = "verify_key")]
+    allow_unsigned: bool,
+
+    /// Update now even if an ostree deployment is staged, rather than
+    /// deferring until it is booted
+    #[clap(long)]
+    ignore_staged: bool,
+
+    /// Also adopt the components which bootupd isn't confident it can take
+    /// over, as `adopt-and-update` does; without it, they are skipped even
+    /// if selected
+    #[clap(long, conflicts_with = "from_payload")]
+    adopt: bool,
+
+    /// Limit writing to RATE bytes per second (with a K, M or G suffix for
+    /// powers of 1024), to avoid starving other workloads on the same disk
+    #[clap(long, value_name = "RATE", value_parser = crate::throttle::parse_rate)]
+    write_rate_limit: Option<u64>,
+
+    /// Update even if Secure Boot is enabled and the new EFI loader isn't
+    /// signed by a certificate in the firmware db
+    #[clap(long)]
+    force: bool,
+
+    /// Read back everything written once it is synced, and fail unless it
+    /// matches what was written, e.g. on unreliable SD or eMMC media
+    #[clap(long)]
+    verify_writes: bool,
+
+    /// Update the ESP by writing its FAT filesystem through the partition
+    /// device, without mounting it; the ESP must not be mounted
+    #[cfg(have_fat)]
+    #[clap(long, conflicts_with = "plan")]
+    direct_esp: bool,
+
+    /// Print the actions the update would take, without updating; see
+    /// `bootupctl schema plan`
+    #[clap(long, requires = "json", conflicts_with = "from_payload")]
+    plan: bool,
+
+    /// Print the plan as JSON, its only format
+    #[clap(long, requires = "plan")]
+    json: bool,
+}
+
+impl UpdateOpts {
+    /// The explicitly selected components, if any.
+    fn selected(&self) -> Option<Vec<String>> {
+        let r: Vec<_> = self.components.iter().chain(&self.args).cloned().collect();
+        (!r.is_empty()).then_some(r)
+    }
+}
+
+#[derive(Debug, Parser)]
+pub struct AdoptAndUpdateOpts {
+    /// Update even if Secure Boot is enabled and the new EFI loader isn't
+    /// signed by a certificate in the firmware db
+    #[clap(long)]
+    force: bool,
+
+    /// Read back everything written once it is synced, and fail unless it
+    /// matches what was written, e.g. on unreliable SD or eMMC media
+    #[clap(long)]
+    verify_writes: bool,
+
+    /// Create a BIOS boot partition in the free space of GPT disks which
+    /// lack one, so that BIOS booting can be adopted on machines converted
+    /// from UEFI-only layouts
+    #[cfg(all(have_bios, target_arch = "x86_64"))]
+    #[clap(long)]
+    create_bios_boot_partition: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct ValidateOpts {
+    /// Hash every file, rather than trusting the cached digests of files
+    /// with an unchanged size and modification time
+    #[clap(long)]
+    thorough: bool,
+
+    /// Restore the SELinux labels of our files in /boot which don't match
+    /// the policy
+    #[clap(long)]
+    fix: bool,
 }
 
 #[derive(Debug, Parser)]
@@ -80,61 +281,232 @@ pub struct StatusOpts {
     /// Output JSON
     #[clap(long, action)]
     json: bool,
+
+    /// Use the plain line-oriented format even when writing to a terminal
+    #[clap(long, action, conflicts_with = "json")]
+    plain: bool,
+
+    /// Disable colored output (also honors `NO_COLOR`)
+    #[clap(long, action)]
+    no_color: bool,
+
+    /// Print nothing; exit with 0 if up to date, 2 if updates are
+    /// available, 3 if an installed component is newer than its update and
+    /// 4 if the installed components don't fit together
+    #[clap(long, action, conflicts_with_all = ["json", "print_if_available", "plain"])]
+    check: bool,
+
+    /// Also look for components which could be adopted, which may probe
+    /// block devices; implied by `--json`, `--check` and
+    /// `--print-if-available`, and when no component is installed
+    #[clap(long, action)]
+    adoptable: bool,
 }
 
 impl CtlCommand {
     /// Run CLI application.
     pub fn run(self) -> Result<()> {
+        let host = self.host;
+        if host {
+            crate::host::enter()?;
+        }
         match self.cmd {
-            CtlVerb::Status(opts) => Self::run_status(opts),
-            CtlVerb::Update => Self::run_update(),
-            CtlVerb::AdoptAndUpdate => Self::run_adopt_and_update(),
-            CtlVerb::Validate => Self::run_validate(),
+            CtlVerb::Status(opts) => Self::run_status(opts, host),
+            CtlVerb::Update(opts) => Self::run_update(opts, host),
+            CtlVerb::AdoptAndUpdate(opts) => Self::run_adopt_and_update(opts, host),
+            CtlVerb::Validate(opts) => Self::run_validate(opts, host),
+            CtlVerb::Export(CtlExport::Pxe(opts)) => Self::run_export_pxe(opts, host),
+            CtlVerb::State(CtlState::Rebuild) => Self::run_state_rebuild(host),
+            CtlVerb::Schema(schema) => Self::run_schema(schema),
+            CtlVerb::MigrateGrubDir => Self::run_migrate_grub_dir(host),
             CtlVerb::Backend(CtlBackend::Generate(opts)) => {
                 super::bootupd::DCommand::run_generate_meta(opts)
             }
             CtlVerb::Backend(CtlBackend::Install(opts)) => {
                 super::bootupd::DCommand::run_install(opts)
             }
+            CtlVerb::Backend(CtlBackend::ExportPayload(opts)) => {
+                super::bootupd::DCommand::run_export_payload(opts)
+            }
+            CtlVerb::Backend(CtlBackend::BuildIsoImages(opts)) => {
+                super::bootupd::DCommand::run_build_iso_images(opts)
+            }
         }
     }
 
     /// Runner for `status` verb.
-    fn run_status(opts: StatusOpts) -> Result<()> {
-        if crate::util::running_in_container() {
+    fn run_status(opts: StatusOpts, host: bool) -> Result<()> {
+        if !host && crate::util::running_in_container() {
+            if opts.check {
+                anyhow::bail!("--check is not supported in a container; use --host");
+            }
             return run_status_in_container(opts.json);
         }
-        ensure_running_in_systemd()?;
-        let r = bootupd::status()?;
-        if opts.json {
+        ensure_running_in_systemd(host)?;
+        let adoptable = opts.adoptable || opts.check || opts.json || opts.print_if_available;
+        let mut r = bootupd::status_with(adoptable, &Default::default())?;
+        if let Err(e) = bootupd::check_consistency(&mut r) {
+            log::warn!("Failed to check consistency: {e:#}");
+        }
+        if opts.check {
+            let code = bootupd::check_status(&r).exit_code();
+            crate::timing::emit();
+            std::process::exit(code);
+        } else if opts.json {
             let stdout = std::io::stdout();
             let mut stdout = stdout.lock();
             serde_json::to_writer_pretty(&mut stdout, &r)?;
         } else if opts.print_if_available {
             bootupd::print_status_avail(&r)?;
+        } else if !opts.plain && crate::output::use_table() {
+            let color = crate::output::use_color(opts.no_color);
+            bootupd::print_status_table(&r, color, adoptable)?;
         } else {
-            bootupd::print_status(&r)?;
+            bootupd::print_status(&r, adoptable)?;
         }
 
         Ok(())
     }
 
     /// Runner for `update` verb.
-    fn run_update() -> Result<()> {
-        ensure_running_in_systemd()?;
-        bootupd::client_run_update()
+    fn run_update(opts: UpdateOpts, host: bool) -> Result<()> {
+        // We may re-exec via systemd-run, which doesn't preserve our working directory
+        for p in opts.from_payload.iter().chain(opts.verify_key.iter()) {
+            if !p.is_absolute() {
+                anyhow::bail!("Path must be absolute: {p:?}");
+            }
+        }
+        ensure_running_in_systemd(host)?;
+        let selected = opts.selected();
+        let wopts = crate::api::UpdateOptions {
+            force: opts.force,
+            verify_writes: opts.verify_writes,
+            write_rate_limit: opts.write_rate_limit,
+            #[cfg(have_fat)]
+            direct_esp: opts.direct_esp,
+            ..Default::default()
+        }
+        .component_options();
+        // Planning only reads, like `status`
+        if opts.plan {
+            use std::io::Write;
+            let plan = bootupd::client_plan_update(
+                selected.as_deref(),
+                opts.adopt,
+                opts.ignore_staged,
+                &wopts,
+            )?;
+            let stdout = std::io::stdout();
+            let mut stdout = stdout.lock();
+            serde_json::to_writer_pretty(&mut stdout, &plan)?;
+            writeln!(stdout)?;
+            return Ok(());
+        }
+        crate::preflight::check(MUTATING_REQUIREMENTS)?;
+        crate::cleanup::start(crate::cleanup::RECORD_DIR);
+        // Payloads from a bundle aren't tied to a deployment
+        if !opts.ignore_staged
+            && opts.from_payload.is_none()
+            && bootupd::defer_to_staged_deployment()?
+        {
+            return Ok(());
+        }
+        match opts.from_payload.as_deref() {
+            Some(bundle) => bootupd::client_run_update_from_payload(
+                opts.jobs,
+                selected.as_deref(),
+                bundle,
+                opts.verify_key.as_deref(),
+                opts.allow_unsigned,
+                &wopts,
+            ),
+            None => bootupd::client_run_update(opts.jobs, selected.as_deref(), opts.adopt, &wopts),
+        }
     }
 
-    /// Runner for `update` verb.
-    fn run_adopt_and_update() -> Result<()> {
-        ensure_running_in_systemd()?;
-        bootupd::client_run_adopt_and_update()
+    /// Runner for `adopt-and-update` verb.
+    fn run_adopt_and_update(opts: AdoptAndUpdateOpts, host: bool) -> Result<()> {
+        ensure_running_in_systemd(host)?;
+        crate::preflight::check(MUTATING_REQUIREMENTS)?;
+        crate::cleanup::start(crate::cleanup::RECORD_DIR);
+        let wopts = crate::api::AdoptOptions {
+            force: opts.force,
+            verify_writes: opts.verify_writes,
+            #[cfg(all(have_bios, target_arch = "x86_64"))]
+            create_bios_boot_partition: opts.create_bios_boot_partition,
+            ..Default::default()
+        }
+        .component_options();
+        bootupd::client_run_adopt_and_update(&wopts)
     }
 
     /// Runner for `validate` verb.
-    fn run_validate() -> Result<()> {
-        ensure_running_in_systemd()?;
-        bootupd::client_run_validate()
+    fn run_validate(opts: ValidateOpts, host: bool) -> Result<()> {
+        ensure_running_in_systemd(host)?;
+        if opts.fix {
+            crate::preflight::check(MUTATING_REQUIREMENTS)?;
+        } else {
+            crate::preflight::check(READONLY_REQUIREMENTS)?;
+        }
+        crate::cleanup::start(crate::cleanup::RECORD_DIR);
+        #[cfg(have_efi)]
+        let wopts = crate::component::Options {
+            thorough: opts.thorough,
+            ..Default::default()
+        };
+        // Only EFI trusts cached digests when validating
+        #[cfg(not(have_efi))]
+        let wopts = {
+            let _ = opts.thorough;
+            crate::component::Options::default()
+        };
+        bootupd::client_run_validate(opts.fix, &wopts)
+    }
+
+    /// Runner for `state rebuild` verb.
+    fn run_state_rebuild(host: bool) -> Result<()> {
+        ensure_running_in_systemd(host)?;
+        crate::preflight::check(MUTATING_REQUIREMENTS)?;
+        crate::cleanup::start(crate::cleanup::RECORD_DIR);
+        bootupd::rebuild_state()
+    }
+
+    /// Runner for `schema` verb.
+    fn run_schema(schema: CtlSchema) -> Result<()> {
+        use std::io::Write;
+        let schema = match schema {
+            CtlSchema::Status => crate::model::status_schema(),
+            CtlSchema::Plan => crate::plan::plan_schema(),
+        };
+        let stdout = std::io::stdout();
+        let mut stdout = stdout.lock();
+        serde_json::to_writer_pretty(&mut stdout, &schema)?;
+        writeln!(stdout)?;
+        Ok(())
+    }
+
+    /// Runner for `migrate-grub-dir` verb.
+    fn run_migrate_grub_dir(host: bool) -> Result<()> {
+        ensure_running_in_systemd(host)?;
+        crate::preflight::check(MUTATING_REQUIREMENTS)?;
+        bootupd::migrate_grub_dir()
+    }
+
+    /// Runner for `export pxe` verb.
+    fn run_export_pxe(opts: ExportPxeOpts, host: bool) -> Result<()> {
+        // We may re-exec via systemd-run, which doesn't preserve our working directory
+        if !opts.dest.is_absolute() {
+            anyhow::bail!("Path must be absolute: {:?}", opts.dest);
+        }
+        ensure_running_in_systemd(host)?;
+        #[cfg(have_efi)]
+        {
+            crate::pxe::export(&opts.dest)
+        }
+        #[cfg(not(have_efi))]
+        {
+            anyhow::bail!("PXE export is only supported for EFI")
+        }
     }
 }
 
@@ -144,6 +516,16 @@ fn running_in_systemd() -> bool {
     std::env::var_os("INVOCATION_ID").is_some()
 }
 
+/// What operating on the booted system needs, e.g. to mount the ESP.
+const MUTATING_REQUIREMENTS: &[crate::preflight::Requirement] = &[
+    crate::preflight::Requirement::Mount,
+    crate::preflight::Requirement::Devices,
+];
+
+/// What only reading the booted system needs, e.g. the ESP mounted.
+const READONLY_REQUIREMENTS: &[crate::preflight::Requirement] =
+    &[crate::preflight::Requirement::Mount];
+
 /// Require root permission
 fn require_root_permission() -> Result<()> {
     if !rustix::process::getuid().is_root() {
@@ -154,10 +536,30 @@ fn require_root_permission() -> Result<()> {
 
 /// Detect if we're running in systemd; if we're not, we re-exec ourselves via
 /// systemd-run. Then we can just directly run code in what is now the daemon.
-fn ensure_running_in_systemd() -> Result<()> {
+/// In `--host` mode our binary isn't visible to the host's systemd, so we
+/// always run directly, as when simulating in a fake root or when systemd
+/// isn't running at all.
+fn ensure_running_in_systemd(host: bool) -> Result<()> {
     require_root_permission()?;
-    let running_in_systemd = running_in_systemd();
-    if !running_in_systemd {
+    let running_in_systemd = running_in_systemd() || crate::simulate::active();
+    // There's no systemd to re-exec via in an application container
+    if !running_in_systemd
+        && !host
+        && crate::util::running_in_container()
+        && !std::path::Path::new("/run/systemd/system").exists()
+    {
+        anyhow::bail!(
+            "Operating on the host from a container requires --host; {}",
+            crate::host::CONTAINER_HINT
+        );
+    }
+    // Nor without systemd as init, e.g. in a minimal recovery image, where
+    // there's no daemon to race with either
+    if !running_in_systemd && !host && !std::path::Path::new("/run/systemd/system").exists() {
+        log::debug!("systemd isn't running; running directly");
+        return Ok(());
+    }
+    if !running_in_systemd && !host {
         // Clear any failure status that may have happened previously
         let _r = Command::new("systemctl")
             .arg("reset-failed")
@@ -184,10 +586,16 @@ fn ensure_running_in_systemd() -> Result<()> {
 /// If running in container, just print the available payloads
 fn run_status_in_container(json_format: bool) -> Result<()> {
     let all_components = crate::bootupd::get_components();
-    if all_components.is_empty() {
+    let sysroot = openat::Dir::open("/")?;
+    let mut avail = Vec::new();
+    for name in all_components.keys() {
+        if crate::component::is_shipped(&sysroot, name)? {
+            avail.push(*name);
+        }
+    }
+    if avail.is_empty() {
         return Ok(());
     }
-    let avail: Vec<_> = all_components.keys().cloned().collect();
     if json_format {
         let stdout = std::io::stdout();
         let mut stdout = stdout.lock();
@@ -11,11 +11,93 @@ pub struct DCommand {
     #[clap(short = 'v', action = clap::ArgAction::Count, global = true)]
     verbosity: u8,
 
+    /// Format used to report a failure on stderr.
+    #[clap(long, value_enum, default_value_t, global = true)]
+    pub(crate) error_format: super::ErrorFormat,
+
+    /// Print a JSON summary of the time spent in each phase to stderr.
+    #[clap(long, global = true)]
+    pub(crate) timing: bool,
+
+    /// Where to log: stderr, the journal with structured fields, or a
+    /// rotating file in /var/log/bootupd; overrides /etc/bootupd/logging.conf.
+    #[clap(long, value_enum, global = true, value_name = "BACKEND")]
+    pub(crate) log_backend: Option<crate::logging::Backend>,
+
+    /// Log levels per module, with the syntax of RUST_LOG, e.g.
+    /// `bootupd::efi=debug`.
+    #[clap(
+        long,
+        global = true,
+        value_name = "FILTER",
+        value_parser = crate::logging::parse_filter
+    )]
+    pub(crate) log_filter: Option<String>,
+
+    /// Record the spans of operations in the journal (`journal`), or
+    /// append them to the file PATH.
+    #[cfg(feature = "spans")]
+    #[clap(long, global = true, value_name = "journal|PATH")]
+    pub(crate) trace_spans: Option<crate::spans::Sink>,
+
+    /// Simulate the operation against the prepared fake root DIR, without
+    /// privileges or touching devices; see README-devel.md.
+    #[clap(long, global = true, value_name = "DIR")]
+    pub(crate) simulate: Option<std::path::PathBuf>,
+
     /// CLI sub-command.
     #[clap(subcommand)]
     pub cmd: DVerb,
 }
 
+#[derive(Debug, Parser)]
+pub struct ExportPayloadOpts {
+    /// Source root
+    #[clap(long, value_parser, default_value_t = String::from("/"))]
+    src_root: String,
+
+    /// Sign the bundle manifest with this PEM private key
+    #[clap(long)]
+    sign_key: Option<std::path::PathBuf>,
+
+    /// Ship large changed files as deltas against the payloads in this
+    /// root, e.g. a checkout of the previous OS version; hosts must have
+    /// that version installed to apply the bundle
+    #[clap(long, value_name = "ROOT")]
+    delta_from: Option<std::path::PathBuf>,
+
+    /// Path of the bundle (tarball) to write
+    #[clap(value_parser)]
+    dest: std::path::PathBuf,
+}
+
+#[derive(Debug, Parser)]
+pub struct BuildIsoImagesOpts {
+    /// Source root
+    #[clap(long, value_parser, default_value_t = String::from("/"))]
+    src_root: String,
+
+    /// Directory to write the images to
+    #[clap(value_parser)]
+    dest: std::path::PathBuf,
+}
+
+#[derive(Debug, Parser)]
+pub struct HealthCheckOpts {
+    /// Instead of checking, roll the bootloader back to the payloads of the
+    /// ostree rollback deployment; for use as a greenboot red-boot handler
+    #[clap(long)]
+    rollback: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct FirstbootOpts {
+    /// Record completion by creating this file rather than in the state
+    /// file; e.g. a path under /var/lib/cloud/instance for cloud-init
+    #[clap(long)]
+    marker: Option<std::path::PathBuf>,
+}
+
 impl DCommand {
     /// Return the log-level set via command-line flags.
     pub(crate) fn loglevel(&self) -> LevelFilter {
@@ -35,6 +117,22 @@ pub enum DVerb {
     GenerateUpdateMetadata(GenerateOpts),
     #[clap(name = "install", about = "Install components")]
     Install(InstallOpts),
+    #[clap(name = "export-payload", about = "Export update payloads as a bundle")]
+    ExportPayload(ExportPayloadOpts),
+    #[clap(
+        name = "build-iso-images",
+        about = "Build El Torito and EFI boot images for ISO media"
+    )]
+    BuildIsoImages(BuildIsoImagesOpts),
+    #[clap(name = "firstboot", about = "Provision components on first boot")]
+    Firstboot(FirstbootOpts),
+    #[clap(
+        name = "trigger-payload-changed",
+        about = "Refresh update metadata after a package update"
+    )]
+    TriggerPayloadChanged,
+    #[clap(name = "health-check", about = "Check the health of the boot chain")]
+    HealthCheck(HealthCheckOpts),
 }
 
 #[derive(Debug, Parser)]
@@ -46,9 +144,10 @@ pub struct InstallOpts {
     #[clap(value_parser)]
     dest_root: String,
 
-    /// Target device, used by bios bootloader installation
+    /// Target device, used by bios bootloader installation; a disk image
+    /// is attached to a loop device while installing
     #[clap(long)]
-    device: Option<String>,
+    device: Option<std::path::PathBuf>,
 
     /// Enable installation of the built-in static config files
     #[clap(long)]
@@ -73,6 +172,50 @@ pub struct InstallOpts {
     /// then only enable installation to the ESP.
     #[clap(long)]
     auto: bool,
+
+    /// Run from an OS installer (e.g. in a kickstart `%post` section).
+    ///
+    /// The payloads are taken from the target root unless `--src-root` is
+    /// given, firmware boot entries are created, and progress is printed
+    /// for the installer logs.
+    #[clap(long, requires = "device")]
+    from_installer: bool,
+
+    /// Produce identical output for identical inputs, e.g. in image build
+    /// pipelines.
+    ///
+    /// Files are written in a stable order and all timestamps are set to
+    /// `SOURCE_DATE_EPOCH`, or if unset, the newest payload build time.
+    #[clap(long, conflicts_with_all = ["update_firmware", "auto", "from_installer"])]
+    deterministic: bool,
+
+    /// Install components which don't match the firmware the system is
+    /// booted with, e.g. BIOS to a disk without a BIOS boot partition on a
+    /// UEFI-booted machine.
+    #[clap(long)]
+    force: bool,
+
+    /// Install the BIOS GRUB modules to /boot even if it's a FAT
+    /// filesystem, such as the ESP mounted at /boot.
+    #[clap(long)]
+    allow_fat_boot: bool,
+
+    /// Create a BIOS boot partition in the free space of a GPT `--device`
+    /// which lacks one.
+    #[cfg(all(have_bios, target_arch = "x86_64"))]
+    #[clap(long)]
+    create_bios_boot_partition: bool,
+
+    /// Install U-Boot for this board (the name of its profile), rather
+    /// than the only one the target root has a profile for.
+    #[cfg(have_uboot)]
+    #[clap(long, value_name = "BOARD")]
+    uboot_board: Option<String>,
+
+    /// Read back everything written once it is synced, and fail unless it
+    /// matches what was written, e.g. on unreliable SD or eMMC media
+    #[clap(long)]
+    verify_writes: bool,
 }
 
 #[derive(Debug, Parser)]
@@ -80,6 +223,10 @@ pub struct GenerateOpts {
     /// Physical root mountpoint
     #[clap(value_parser)]
     sysroot: Option<String>,
+
+    /// Store the metadata zstd-compressed.
+    #[clap(long)]
+    compress: bool,
 }
 
 impl DCommand {
@@ -88,6 +235,11 @@ impl DCommand {
         match self.cmd {
             DVerb::Install(opts) => Self::run_install(opts),
             DVerb::GenerateUpdateMetadata(opts) => Self::run_generate_meta(opts),
+            DVerb::ExportPayload(opts) => Self::run_export_payload(opts),
+            DVerb::BuildIsoImages(opts) => Self::run_build_iso_images(opts),
+            DVerb::Firstboot(opts) => Self::run_firstboot(opts),
+            DVerb::TriggerPayloadChanged => Self::run_trigger_payload_changed(),
+            DVerb::HealthCheck(opts) => Self::run_health_check(opts),
         }
     }
 
@@ -97,7 +249,8 @@ impl DCommand {
         if sysroot != "/" {
             anyhow::bail!("Using a non-default sysroot is not supported: {}", sysroot);
         }
-        bootupd::generate_update_metadata(sysroot).context("generating metadata failed")?;
+        bootupd::generate_update_metadata(sysroot, opts.compress)
+            .context("generating metadata failed")?;
         Ok(())
     }
 
@@ -110,16 +263,95 @@ impl DCommand {
         } else {
             ConfigMode::None
         };
+        // The installer environment isn't the target system, so
+        // default to the payloads in the target root.
+        let src_root = if opts.from_installer && opts.src_root == "/" {
+            opts.dest_root.as_str()
+        } else {
+            opts.src_root.as_str()
+        };
+        let mut requirements = Vec::new();
+        if opts.device.is_some() {
+            requirements.push(crate::preflight::Requirement::Devices);
+        }
+        if opts.update_firmware || opts.from_installer {
+            requirements.push(crate::preflight::Requirement::Efivars);
+        }
+        crate::preflight::check(&requirements)?;
+        crate::cleanup::start(crate::cleanup::RECORD_DIR);
+        let wopts = crate::api::InstallOptions {
+            allow_fat_boot: opts.allow_fat_boot,
+            #[cfg(all(have_bios, target_arch = "x86_64"))]
+            create_bios_boot_partition: opts.create_bios_boot_partition,
+            verify_writes: opts.verify_writes,
+            #[cfg(have_uboot)]
+            uboot_board: opts.uboot_board.clone(),
+            ..Default::default()
+        }
+        .component_options();
         bootupd::install(
-            &opts.src_root,
+            src_root,
             &opts.dest_root,
             opts.device.as_deref(),
             configmode,
-            opts.update_firmware,
+            opts.update_firmware || opts.from_installer,
             opts.components.as_deref(),
             opts.auto,
+            opts.from_installer,
+            opts.deterministic,
+            opts.force,
+            &wopts,
         )
         .context("boot data installation failed")?;
         Ok(())
     }
+
+    /// Runner for `export-payload` verb.
+    pub(crate) fn run_export_payload(opts: ExportPayloadOpts) -> Result<()> {
+        crate::payload::export(
+            &opts.src_root,
+            &opts.dest,
+            opts.sign_key.as_deref(),
+            opts.delta_from.as_deref(),
+        )
+        .context("payload export failed")?;
+        Ok(())
+    }
+
+    /// Runner for `build-iso-images` verb.
+    pub(crate) fn run_build_iso_images(opts: BuildIsoImagesOpts) -> Result<()> {
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        {
+            crate::iso::build_images(&opts.src_root, &opts.dest)
+                .context("building ISO boot images failed")
+        }
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+        {
+            let _ = opts;
+            anyhow::bail!("ISO boot images are not supported on this architecture")
+        }
+    }
+
+    /// Runner for `firstboot` verb.
+    pub(crate) fn run_firstboot(opts: FirstbootOpts) -> Result<()> {
+        crate::cleanup::start(crate::cleanup::RECORD_DIR);
+        bootupd::firstboot(opts.marker.as_deref()).context("first boot provisioning failed")?;
+        Ok(())
+    }
+
+    /// Runner for `trigger-payload-changed` verb.
+    pub(crate) fn run_trigger_payload_changed() -> Result<()> {
+        bootupd::trigger_payload_changed().context("refreshing update metadata failed")?;
+        Ok(())
+    }
+
+    /// Runner for `health-check` verb.
+    pub(crate) fn run_health_check(opts: HealthCheckOpts) -> Result<()> {
+        crate::cleanup::start(crate::cleanup::RECORD_DIR);
+        if opts.rollback {
+            crate::health::rollback().context("bootloader rollback failed")
+        } else {
+            crate::health::check().context("health check failed")
+        }
+    }
 }
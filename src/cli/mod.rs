@@ -6,6 +6,16 @@ use log::LevelFilter;
 mod bootupctl;
 mod bootupd;
 
+/// How a fatal error is reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorFormat {
+    /// A single human-readable line
+    #[default]
+    Human,
+    /// A JSON object, see `ErrorReport`
+    Json,
+}
+
 /// Top-level multicall CLI.
 #[derive(Debug, Parser)]
 pub enum MultiCall {
@@ -38,6 +48,55 @@ impl MultiCall {
         }
     }
 
+    /// Return the requested error format.
+    pub fn error_format(&self) -> ErrorFormat {
+        match self {
+            MultiCall::Ctl(cmd) => cmd.error_format,
+            MultiCall::D(cmd) => cmd.error_format,
+        }
+    }
+
+    /// Return whether `--timing` was given.
+    pub fn timing(&self) -> bool {
+        match self {
+            MultiCall::Ctl(cmd) => cmd.timing,
+            MultiCall::D(cmd) => cmd.timing,
+        }
+    }
+
+    /// Return where `--trace-spans` asked to record spans.
+    #[cfg(feature = "spans")]
+    pub(crate) fn trace_spans(&self) -> Option<crate::spans::Sink> {
+        match self {
+            MultiCall::Ctl(cmd) => cmd.trace_spans.clone(),
+            MultiCall::D(cmd) => cmd.trace_spans.clone(),
+        }
+    }
+
+    /// Return the fake root `--simulate` asked to operate on.
+    pub(crate) fn simulate(&self) -> Option<std::path::PathBuf> {
+        match self {
+            MultiCall::Ctl(cmd) => cmd.simulate.clone(),
+            MultiCall::D(cmd) => cmd.simulate.clone(),
+        }
+    }
+
+    /// Return the logging backend given by `--log-backend`.
+    pub(crate) fn log_backend(&self) -> Option<crate::logging::Backend> {
+        match self {
+            MultiCall::Ctl(cmd) => cmd.log_backend,
+            MultiCall::D(cmd) => cmd.log_backend,
+        }
+    }
+
+    /// Return the filter given by `--log-filter`.
+    pub(crate) fn log_filter(&self) -> Option<&String> {
+        match self {
+            MultiCall::Ctl(cmd) => cmd.log_filter.as_ref(),
+            MultiCall::D(cmd) => cmd.log_filter.as_ref(),
+        }
+    }
+
     /// Return the log-level set via command-line flags.
     pub fn loglevel(&self) -> LevelFilter {
         match self {
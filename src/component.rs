@@ -4,22 +4,81 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use anyhow::{Context, Result};
+#[cfg(have_component)]
+use anyhow::Context;
+use anyhow::Result;
 use fn_error_context::context;
 use openat_ext::OpenatDirExt;
 use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 
 use crate::model::*;
+#[cfg(have_component)]
+use crate::sha512string::SHA512String;
 
+/// The result of validating the installed files of a component.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) enum ValidationResult {
+#[non_exhaustive]
+pub enum ValidationResult {
     Valid,
+    /// The component doesn't support validation
     Skip,
+    /// The differences from what was installed
     Errors(Vec<String>),
 }
 
+/// How the components write, as chosen by the caller of an operation (e.g.
+/// with the options of `bootupctl update`); passed to each component when
+/// it's created, see [`new_from_name_with`].  The defaults are those of
+/// `bootupctl`.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Options {
+    /// Read back everything written once it's synced; see [`crate::readback`]
+    #[cfg(have_readback)]
+    pub(crate) verify_writes: bool,
+    /// Limit copying files to this many bytes per second; see
+    /// [`crate::throttle`]
+    #[cfg(have_filetree)]
+    pub(crate) write_rate_limit: Option<u64>,
+    /// Install first stage loaders even if the firmware wouldn't run them;
+    /// see [`crate::secureboot`]
+    #[cfg(have_efi)]
+    pub(crate) force: bool,
+    /// Update the ESP through its partition device instead of mounting it;
+    /// see [`crate::fatesp`]
+    #[cfg(have_fat)]
+    pub(crate) direct_esp: bool,
+    /// Hash every file when validating, rather than trusting the cached
+    /// digests; see [`crate::digestcache`]
+    #[cfg(have_efi)]
+    pub(crate) thorough: bool,
+    /// Install the BIOS GRUB modules to /boot even on FAT
+    #[cfg(have_bios)]
+    pub(crate) allow_fat_boot: bool,
+    /// Create a BIOS boot partition on GPT disks lacking one, so that BIOS
+    /// can be installed or adopted; see [`crate::partition`]
+    #[cfg(all(have_bios, target_arch = "x86_64"))]
+    pub(crate) create_bios_boot_partition: bool,
+    /// The U-Boot board to install for, rather than the only one with a
+    /// profile
+    #[cfg(have_uboot)]
+    pub(crate) uboot_board: Option<String>,
+}
+
+#[cfg(have_filetree)]
+impl Options {
+    /// The options for applying diffs with [`crate::filetree::apply_diff`].
+    pub(crate) fn apply_options(&self) -> crate::filetree::ApplyUpdateOptions<'static> {
+        crate::filetree::ApplyUpdateOptions {
+            verify_writes: self.verify_writes,
+            write_rate_limit: self.write_rate_limit,
+            ..Default::default()
+        }
+    }
+}
+
 /// A component along with a possible update
 pub(crate) trait Component {
     /// Returns the name of the component; this will be used for serialization
@@ -31,6 +90,16 @@ pub(crate) trait Component {
     /// and "synthesize" content metadata from it.
     fn query_adopt(&self) -> Result<Option<Adoptable>>;
 
+    /// Like [`Component::query_adopt`], but telling why the component isn't
+    /// adoptable, the bootloader found and what adopting would take, for
+    /// `api::probe_adoption`.  By default only the former is known.
+    fn probe_adopt(&self) -> Result<ComponentAdoption> {
+        Ok(match self.query_adopt()? {
+            Some(adoptable) => ComponentAdoption::new(AdoptionState::Adoptable(adoptable)),
+            None => ComponentAdoption::not_adoptable("No existing installation found"),
+        })
+    }
+
     /// Given an adoptable system and an update, perform the update.
     fn adopt_update(
         &self,
@@ -49,7 +118,7 @@ pub(crate) trait Component {
         &self,
         src_root: &openat::Dir,
         dest_root: &str,
-        device: &str,
+        device: Option<&Path>,
         update_firmware: bool,
     ) -> Result<InstalledContent>;
 
@@ -70,37 +139,88 @@ pub(crate) trait Component {
         current: &InstalledContent,
     ) -> Result<InstalledContent>;
 
+    /// The actions [`Component::run_update`] would take to update from
+    /// `current`, in order, for `bootupctl update --plan`; `None` if they
+    /// can't be told in advance, the default.
+    fn plan_update(
+        &self,
+        _sysroot: &openat::Dir,
+        _current: &InstalledContent,
+    ) -> Result<Option<Vec<crate::plan::Action>>> {
+        Ok(None)
+    }
+
     /// Used on the client to validate an installed version.
     fn validate(&self, current: &InstalledContent) -> Result<ValidationResult>;
 
+    /// Read back what is installed, from the `devices` recorded in state if
+    /// any, for the checks of [`crate::consistency`] across components; by
+    /// default nothing.
+    fn inspect(&self, _devices: &[PathBuf]) -> Result<crate::consistency::Inspection> {
+        Ok(Default::default())
+    }
+
     /// Locating efi vendor dir
     fn get_efi_vendor(&self, sysroot: &openat::Dir) -> Result<Option<String>>;
+
+    /// Register the installed component with the firmware, e.g. create
+    /// EFI boot entries; the default is a no-op.
+    fn update_firmware_entries(&self, _sysroot: &openat::Dir) -> Result<()> {
+        Ok(())
+    }
+
+    /// Reconstruct the record of the installed content by comparing it to
+    /// the update payload, for `bootupctl state rebuild`; `None` if the
+    /// component isn't installed (or what's installed isn't ours).  Fails
+    /// if the installed content can't be told apart.
+    fn fingerprint_installed(&self, sysroot: &openat::Dir) -> Result<Option<InstalledContent>>;
+
+    /// Names of components which must finish updating before this one
+    /// starts; components without a relationship may be updated concurrently.
+    fn update_after(&self) -> &'static [&'static str] {
+        &[]
+    }
 }
 
 /// Given a component name, create an implementation.
 pub(crate) fn new_from_name(name: &str) -> Result<Box<dyn Component>> {
-    let r: Box<dyn Component> = match name {
-        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-        #[allow(clippy::box_default)]
-        "EFI" => Box::new(crate::efi::Efi::default()),
-        #[cfg(any(target_arch = "x86_64", target_arch = "powerpc64"))]
+    new_from_name_with(name, &Options::default())
+}
+
+/// Like [`new_from_name`], with the component writing as `opts` tell.
+pub(crate) fn new_from_name_with(name: &str, opts: &Options) -> Result<Box<dyn Component>> {
+    #[cfg(not(have_readback))]
+    let _ = opts;
+    match name {
+        #[cfg(have_efi)]
+        "EFI" => Ok(Box::new(crate::efi::Efi::new(opts.clone()))),
+        #[cfg(have_bios)]
+        "BIOS" => Ok(Box::new(
+            crate::bios::Bios::default().with_options(opts.clone()),
+        )),
+        #[cfg(have_coreboot)]
         #[allow(clippy::box_default)]
-        "BIOS" => Box::new(crate::bios::Bios::default()),
+        "COREBOOT" => Ok(Box::new(crate::coreboot::Coreboot::default())),
+        #[cfg(have_rpi)]
+        "RPI" => Ok(Box::new(crate::rpi::Rpi::new(opts.clone()))),
+        #[cfg(have_uboot)]
+        "UBOOT" => Ok(Box::new(crate::uboot::Uboot::new(opts.clone()))),
+        #[cfg(have_extlinux)]
+        "EXTLINUX" => Ok(Box::new(crate::extlinux::Extlinux::new(opts.clone()))),
         _ => anyhow::bail!("No component {}", name),
-    };
-    Ok(r)
+    }
 }
 
 /// Returns the path to the payload directory for an available update for
 /// a component.
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64", have_extlinux))]
 pub(crate) fn component_updatedirname(component: &dyn Component) -> PathBuf {
     Path::new(BOOTUPD_UPDATES_DIR).join(component.name())
 }
 
 /// Returns the path to the payload directory for an available update for
 /// a component.
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(any(have_efi, have_rpi, have_extlinux, have_coreboot, have_uboot))]
 pub(crate) fn component_updatedir(sysroot: &str, component: &dyn Component) -> PathBuf {
     Path::new(sysroot).join(component_updatedirname(component))
 }
@@ -111,43 +231,232 @@ fn component_update_data_name(component: &dyn Component) -> PathBuf {
     Path::new(&format!("{}.json", component.name())).into()
 }
 
-/// Helper method for writing an update file
-pub(crate) fn write_update_metadata(
-    sysroot: &str,
+/// Returns the name of the zstd-compressed variant of the update metadata
+/// file `name`.
+fn compressed_name(name: &Path) -> PathBuf {
+    let mut r = name.as_os_str().to_owned();
+    r.push(".zst");
+    r.into()
+}
+
+/// Open the update metadata file `name` in `dir`; if only its compressed
+/// variant exists, the decompressed content is returned instead.
+#[cfg(have_component)]
+fn open_update_data(dir: &openat::Dir, name: &Path) -> Result<Option<Box<dyn Read>>> {
+    if let Some(f) = dir.open_file_optional(name)? {
+        return Ok(Some(Box::new(std::io::BufReader::new(f))));
+    }
+    let compressed = compressed_name(name);
+    let Some(f) = dir.open_file_optional(&compressed)? else {
+        return Ok(None);
+    };
+    let data = zstd::stream::decode_all(std::io::BufReader::new(f))
+        .with_context(|| format!("Decompressing {compressed:?}"))?;
+    Ok(Some(Box::new(std::io::Cursor::new(data))))
+}
+
+/// Write the update metadata file `name` in `dir`, zstd-compressed if
+/// `compress` is set, replacing any copy in the other format.
+fn write_update_data(dir: &openat::Dir, name: &Path, data: &[u8], compress: bool) -> Result<()> {
+    let compressed = compressed_name(name);
+    let (target, other) = if compress {
+        (compressed.as_path(), name)
+    } else {
+        (name, compressed.as_path())
+    };
+    let data = if compress {
+        std::borrow::Cow::Owned(zstd::stream::encode_all(data, 19)?)
+    } else {
+        std::borrow::Cow::Borrowed(data)
+    };
+    // Replaced atomically, so readers see either version in full
+    dir.write_file_with_sync(target, 0o644, |w| w.write_all(&data))?;
+    dir.remove_file_optional(other)?;
+    crate::util::fsync_dir(dir, ".")?;
+    Ok(())
+}
+
+/// Replace the update metadata file of `component` with a zstd-compressed
+/// copy.  Metadata refreshed later (e.g. by `trigger-payload-changed`)
+/// stays compressed.
+#[context("Compressing update metadata for {}", component.name())]
+pub(crate) fn compress_update_metadata(
+    sysroot: &openat::Dir,
     component: &dyn Component,
-    meta: &ContentMetadata,
 ) -> Result<()> {
-    let sysroot = openat::Dir::open(sysroot)?;
     let dir = sysroot.sub_dir(BOOTUPD_UPDATES_DIR)?;
+    let _lock = crate::util::lock_dir(&dir)?;
     let name = component_update_data_name(component);
-    dir.write_file_with(name, 0o644, |w| -> Result<_> {
-        Ok(serde_json::to_writer(w, &meta)?)
-    })?;
-    Ok(())
+    let Some(mut f) = dir.open_file_optional(&name)? else {
+        // Missing, or already compressed
+        return Ok(());
+    };
+    let mut data = Vec::new();
+    f.read_to_end(&mut data)?;
+    write_update_data(&dir, &name, &data, true)
+}
+
+/// The content of the update metadata file of a component.
+#[cfg(have_component)]
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct UpdateMetadataFile {
+    #[serde(flatten)]
+    meta: ContentMetadata,
+    /// What the metadata was queried from; see
+    /// [`crate::packagesystem::query_inputs_digest`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    inputs: Option<SHA512String>,
+}
+
+/// Query the package system for the packages owning `files`, and write the
+/// result as the update metadata of `component`.  If neither the files nor
+/// the package database changed since the metadata was last written, it's
+/// reused without querying.
+#[cfg(have_component)]
+pub(crate) fn write_packaged_update_metadata<T: AsRef<Path>>(
+    sysroot_path: &str,
+    component: &dyn Component,
+    files: impl IntoIterator<Item = T>,
+) -> Result<ContentMetadata> {
+    let files = files
+        .into_iter()
+        .map(|f| f.as_ref().to_owned())
+        .collect::<Vec<_>>();
+    let inputs = crate::packagesystem::query_inputs_digest(sysroot_path, &files)?;
+    let sysroot = openat::Dir::open(sysroot_path)?;
+    let dir = sysroot.sub_dir(BOOTUPD_UPDATES_DIR)?;
+    // Held from checking whether the metadata is current until it's
+    // rewritten, against concurrent generation
+    let _lock = crate::util::lock_dir(&dir)?;
+    let name = component_update_data_name(component);
+    let compress = dir.exists(&compressed_name(&name))?;
+    if let Some(f) = open_update_data(&dir, &name)? {
+        // Anything unparseable is just regenerated
+        let previous: Option<UpdateMetadataFile> = serde_json::from_reader(f).ok();
+        if let Some(previous) = previous.filter(|p| p.inputs.as_ref() == Some(&inputs)) {
+            log::debug!("Update metadata for {} is current", component.name());
+            return Ok(previous.meta);
+        }
+    }
+    let meta = crate::packagesystem::query_files(sysroot_path, &files)?;
+    // Failed queries aren't recorded as current, so they're retried
+    let inputs = (meta.version != "unknown").then_some(inputs);
+    let content = UpdateMetadataFile {
+        meta: meta.clone(),
+        inputs,
+    };
+    write_update_data(&dir, &name, &serde_json::to_vec(&content)?, compress)?;
+    Ok(meta)
+}
+
+/// Parse the content of an update metadata file.
+#[cfg(any(have_component, feature = "fuzzing"))]
+pub(crate) fn parse_update_metadata(r: impl Read) -> serde_json::Result<ContentMetadata> {
+    serde_json::from_reader(r)
 }
 
 /// Given a component, return metadata on the available update (if any)
+#[cfg(have_component)]
 #[context("Loading update for component {}", component.name())]
 pub(crate) fn get_component_update(
     sysroot: &openat::Dir,
     component: &dyn Component,
 ) -> Result<Option<ContentMetadata>> {
     let name = component_update_data_name(component);
-    let path = Path::new(BOOTUPD_UPDATES_DIR).join(name);
-    if let Some(f) = sysroot.open_file_optional(&path)? {
-        let mut f = std::io::BufReader::new(f);
-        let u = serde_json::from_reader(&mut f)
-            .with_context(|| format!("failed to parse {:?}", &path))?;
+    let Some(dir) = sysroot.sub_dir_optional(BOOTUPD_UPDATES_DIR)? else {
+        return Ok(None);
+    };
+    if let Some(f) = open_update_data(&dir, &name)? {
+        let u = parse_update_metadata(f).with_context(|| {
+            format!(
+                "failed to parse {:?}",
+                Path::new(BOOTUPD_UPDATES_DIR).join(&name)
+            )
+        })?;
         Ok(Some(u))
     } else {
         Ok(None)
     }
 }
 
+/// Returns the name of the update metadata file shipped for the component
+/// `name` in `sysroot`, relative to [`BOOTUPD_UPDATES_DIR`].
+pub(crate) fn shipped_update_data_name(
+    sysroot: &openat::Dir,
+    name: &str,
+) -> Result<Option<PathBuf>> {
+    let base = PathBuf::from(format!("{name}.json"));
+    for n in [compressed_name(&base), base] {
+        if sysroot.exists(&Path::new(BOOTUPD_UPDATES_DIR).join(&n))? {
+            return Ok(Some(n));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns `true` if the OS in `sysroot` ships an update payload for the
+/// component `name`; without one, the component can't be adopted or updated.
+pub(crate) fn is_shipped(sysroot: &openat::Dir, name: &str) -> Result<bool> {
+    Ok(shipped_update_data_name(sysroot, name)?.is_some())
+}
+
+/// The metadata recorded for installed content of an unknown version,
+/// which any update replaces.
+#[cfg(any(
+    have_efi,
+    have_rpi,
+    have_extlinux,
+    all(have_bios, target_arch = "x86_64"),
+    feature = "fuzzing"
+))]
+pub(crate) fn unknown_version() -> ContentMetadata {
+    ContentMetadata {
+        timestamp: std::time::UNIX_EPOCH.into(),
+        version: "unknown".to_string(),
+    }
+}
+
+/// Fingerprint the files of the payload `updatef` (of version `update`)
+/// found in `dest`, for [`Component::fingerprint_installed`]: they're of
+/// that version if all are there unchanged, or else of an unknown one.
+/// `None` if there are none.
+#[cfg(have_filetree)]
+pub(crate) fn fingerprint_tree(
+    updatef: &crate::filetree::FileTree,
+    update: ContentMetadata,
+    dest: &openat::Dir,
+) -> Result<Option<InstalledContent>> {
+    // Only the files we ship; others in `dest` aren't ours
+    let installedf = updatef.subset_in(dest)?;
+    if installedf.children.is_empty() {
+        return Ok(None);
+    }
+    let meta = if &installedf == updatef {
+        update
+    } else {
+        unknown_version()
+    };
+    Ok(Some(InstalledContent {
+        meta,
+        filetree: Some(installedf),
+        adopted_from: None,
+        devices: Vec::new(),
+    }))
+}
+
+#[cfg(any(have_efi, have_rpi, have_extlinux, have_coreboot, have_uboot))]
 #[context("Querying adoptable state")]
 pub(crate) fn query_adopt_state() -> Result<Option<Adoptable>> {
+    query_adopt_state_in(Path::new("/"))
+}
+
+/// Query whether the system with the root filesystem `root` was installed
+/// in a way we can adopt.
+#[cfg(have_component)]
+pub(crate) fn query_adopt_state_in(root: &Path) -> Result<Option<Adoptable>> {
     // This would be extended with support for other operating systems later
-    if let Some(coreos_aleph) = crate::coreos::get_aleph_version(Path::new("/"))? {
+    if let Some(coreos_aleph) = crate::coreos::get_aleph_version(root)? {
         let meta = ContentMetadata {
             timestamp: coreos_aleph.ts,
             version: coreos_aleph.aleph.version,
@@ -160,7 +469,7 @@ pub(crate) fn query_adopt_state() -> Result<Option<Adoptable>> {
     } else {
         log::trace!("No CoreOS aleph detected");
     }
-    let ostree_deploy_dir = Path::new("/ostree/deploy");
+    let ostree_deploy_dir = root.join("ostree/deploy");
     if ostree_deploy_dir.exists() {
         let btime = ostree_deploy_dir.metadata()?.created()?;
         let timestamp = chrono::DateTime::from(btime);
@@ -180,6 +489,108 @@ pub(crate) fn query_adopt_state() -> Result<Option<Adoptable>> {
 mod tests {
     use super::*;
 
+    #[test]
+    #[cfg(have_efi)]
+    fn test_write_packaged_update_metadata_current() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path().to_str().unwrap();
+        let updates = td.path().join(BOOTUPD_UPDATES_DIR);
+        std::fs::create_dir_all(&updates)?;
+        let component = new_from_name("EFI")?;
+        let files = ["/boot/efi/EFI/fedora/shimx64.efi"];
+        let inputs = crate::packagesystem::query_inputs_digest(
+            root,
+            &files.iter().map(PathBuf::from).collect::<Vec<_>>(),
+        )?;
+        let meta = ContentMetadata {
+            timestamp: chrono::Utc::now(),
+            version: "shim-x64-15.8-3.x86_64".into(),
+        };
+        let content = UpdateMetadataFile {
+            meta: meta.clone(),
+            inputs: Some(inputs),
+        };
+        std::fs::write(updates.join("EFI.json"), serde_json::to_vec(&content)?)?;
+        // Reused without querying the (nonexistent) package database
+        let r = write_packaged_update_metadata(root, component.as_ref(), files)?;
+        assert_eq!(r, meta);
+        // And still readable as plain metadata
+        let sysroot = openat::Dir::open(td.path())?;
+        assert_eq!(
+            get_component_update(&sysroot, component.as_ref())?,
+            Some(meta)
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(have_efi)]
+    fn test_compressed_update_metadata() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let updates = td.path().join(BOOTUPD_UPDATES_DIR);
+        std::fs::create_dir_all(&updates)?;
+        let meta = ContentMetadata {
+            timestamp: chrono::Utc::now(),
+            version: "shim-x64-15.8-3.x86_64".into(),
+        };
+        std::fs::write(updates.join("EFI.json"), serde_json::to_vec(&meta)?)?;
+        let sysroot = openat::Dir::open(td.path())?;
+        let component = new_from_name("EFI")?;
+        compress_update_metadata(&sysroot, component.as_ref())?;
+        assert!(!updates.join("EFI.json").exists());
+        assert!(updates.join("EFI.json.zst").exists());
+        assert!(is_shipped(&sysroot, "EFI")?);
+        assert_eq!(
+            get_component_update(&sysroot, component.as_ref())?,
+            Some(meta)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_shipped() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let updates = td.path().join(BOOTUPD_UPDATES_DIR);
+        std::fs::create_dir_all(&updates)?;
+        std::fs::write(updates.join("EFI.json"), "{}")?;
+        let root = openat::Dir::open(td.path())?;
+        assert!(is_shipped(&root, "EFI")?);
+        assert!(!is_shipped(&root, "BIOS")?);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(have_efi)]
+    fn test_fingerprint_tree() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let payload = td.path().join("payload");
+        let dest = td.path().join("dest");
+        std::fs::create_dir_all(payload.join("EFI/fedora"))?;
+        std::fs::create_dir_all(dest.join("EFI/fedora"))?;
+        std::fs::write(payload.join("EFI/fedora/shimx64.efi"), "shim")?;
+        std::fs::write(payload.join("EFI/fedora/grubx64.efi"), "grub")?;
+        let updatef = crate::filetree::FileTree::new_from_dir(&openat::Dir::open(&payload)?)?;
+        let meta = ContentMetadata {
+            timestamp: chrono::Utc::now(),
+            version: "shim-x64-15.8-3.x86_64".into(),
+        };
+        let destdir = openat::Dir::open(&dest)?;
+        assert!(fingerprint_tree(&updatef, meta.clone(), &destdir)?.is_none());
+        // Partially installed, with a file which isn't ours
+        std::fs::write(dest.join("EFI/fedora/shimx64.efi"), "shim")?;
+        std::fs::write(dest.join("EFI/fedora/BOOTX64.CSV"), "csv")?;
+        let inst = fingerprint_tree(&updatef, meta.clone(), &destdir)?.unwrap();
+        assert_eq!(inst.meta, unknown_version());
+        let ft = inst.filetree.unwrap();
+        assert_eq!(ft.children.len(), 1);
+        assert!(ft.children.contains_key("EFI/fedora/shimx64.efi"));
+        std::fs::write(dest.join("EFI/fedora/grubx64.efi"), "grub")?;
+        let inst = fingerprint_tree(&updatef, meta.clone(), &destdir)?.unwrap();
+        assert_eq!(inst.meta, meta);
+        assert_eq!(inst.filetree.as_ref(), Some(&updatef));
+        Ok(())
+    }
+
     #[test]
     fn test_get_efi_vendor() -> Result<()> {
         let td = tempfile::tempdir()?;
@@ -190,11 +601,11 @@ mod tests {
         std::fs::create_dir_all(tdp_updates.join("EFI/fedora"))?;
         std::fs::create_dir_all(tdp_updates.join("EFI/centos"))?;
         std::fs::write(
-            tdp_updates.join("EFI/fedora").join(crate::efi::SHIM),
+            tdp_updates.join("EFI/fedora").join(crate::distro::SHIM),
             "shim data",
         )?;
         std::fs::write(
-            tdp_updates.join("EFI/centos").join(crate::efi::SHIM),
+            tdp_updates.join("EFI/centos").join(crate::distro::SHIM),
             "shim data",
         )?;
 
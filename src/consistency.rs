@@ -0,0 +1,353 @@
+//! Checks that the installed components fit together, beyond what
+//! validating each component on its own can detect.
+//!
+//! Each component reads back what it installed (see
+//! [`crate::component::Component::inspect`]): the EFI loaders on the ESP,
+//! and the GRUB core images embedded for BIOS.  The removable-media
+//! fallback must be a copy of the vendor shim, the GRUB next to shim must
+//! be signed by its vendor certificate, and the embedded core image must
+//! be the one grub-install built along with the modules it installed to
+//! /boot, which it leaves next to them as `core.img`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::Result;
+
+use crate::component::Options;
+use crate::model::InstalledSummary;
+
+/// Removable-media fallback loaders on the ESP and the vendor shim they
+/// are expected to be a copy of.
+const FALLBACK_SHIMS: &[(&str, &str)] = &[
+    ("BOOT/BOOTX64.EFI", "shimx64.efi"),
+    ("BOOT/BOOTAA64.EFI", "shimaa64.efi"),
+    // openSUSE
+    ("BOOT/BOOTX64.EFI", "shim.efi"),
+    ("BOOT/BOOTAA64.EFI", "shim.efi"),
+];
+
+/// The vendor shims and the GRUB each runs, from the same directory.
+#[cfg(have_efi)]
+const SHIM_LOADERS: &[(&str, &str)] = &[
+    ("shimx64.efi", "grubx64.efi"),
+    ("shimaa64.efi", "grubaa64.efi"),
+    // openSUSE
+    ("shim.efi", "grub.efi"),
+];
+
+/// The size of the first sector of a GRUB core image, whose block lists
+/// grub-install fills in when embedding it.
+const CORE_IMAGE_SECTOR: usize = 512;
+
+/// What a component has installed, as read back from where it's installed.
+#[derive(Debug, Default)]
+pub(crate) struct Inspection {
+    /// The contents of the EFI loaders, by path below the `EFI` directory
+    /// of the ESP; see [`is_loader`]
+    pub(crate) loaders: BTreeMap<String, Vec<u8>>,
+    /// The GRUB core images embedded on devices
+    pub(crate) core_images: Vec<CoreImage>,
+}
+
+/// A GRUB core image embedded on a device.
+#[derive(Debug)]
+pub(crate) struct CoreImage {
+    pub(crate) device: PathBuf,
+    /// As read from the device, of the size of `built`
+    pub(crate) embedded: Vec<u8>,
+    /// The image grub-install left next to the modules in /boot
+    pub(crate) built: Vec<u8>,
+}
+
+/// Whether the file `path`, below the `EFI` directory of the ESP, is one of
+/// the loaders whose contents [`check`] compares.
+#[cfg(have_efi)]
+pub(crate) fn is_loader(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    FALLBACK_SHIMS.iter().any(|&(fallback, _)| fallback == path)
+        || SHIM_LOADERS
+            .iter()
+            .any(|&(shim, grub)| name == shim || name == grub)
+}
+
+/// A mismatch between installed boot components.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Inconsistency {
+    /// The fallback loader doesn't match the vendor copy of shim.
+    FallbackMismatch { fallback: String, vendor: String },
+    /// Components were installed from different GRUB builds.
+    GrubVersionMismatch(BTreeMap<String, String>),
+    /// The GRUB next to shim isn't signed by its vendor certificate.
+    #[cfg(have_efi)]
+    UntrustedLoader { loader: String, shim: String },
+    /// The core image embedded on a device isn't the one built with the
+    /// GRUB modules in /boot.
+    CoreImageMismatch { device: PathBuf },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::FallbackMismatch { fallback, vendor } => {
+                write!(f, "Fallback {fallback} does not match {vendor}")
+            }
+            Inconsistency::GrubVersionMismatch(versions) => {
+                let versions = versions
+                    .iter()
+                    .map(|(c, v)| format!("{c}={v}"))
+                    .collect::<Vec<_>>();
+                write!(
+                    f,
+                    "GRUB version differs between components: {}",
+                    versions.join(" ")
+                )
+            }
+            #[cfg(have_efi)]
+            Inconsistency::UntrustedLoader { loader, shim } => {
+                write!(
+                    f,
+                    "{loader} is not signed by the vendor certificate of {shim}"
+                )
+            }
+            Inconsistency::CoreImageMismatch { device } => write!(
+                f,
+                "The GRUB core image on {} was not built with the modules in /boot",
+                device.display()
+            ),
+        }
+    }
+}
+
+/// Given an RPM NEVRA like `grub2-efi-x64-1:2.06-95.fc38.x86_64`, return
+/// the name and `epoch:version-release`.
+fn split_nevra(nevra: &str) -> Option<(&str, &str)> {
+    let (nevr, _arch) = nevra.rsplit_once('.')?;
+    let (nev, _release) = nevr.rsplit_once('-')?;
+    let (name, _ev) = nev.rsplit_once('-')?;
+    Some((name, &nevr[name.len() + 1..]))
+}
+
+/// Return the GRUB `epoch:version-release` recorded in a component version
+/// string, if any.
+fn grub_evr(version: &str) -> Option<&str> {
+    version
+        .split(',')
+        .filter_map(split_nevra)
+        .find(|(name, _)| name.starts_with("grub2-"))
+        .map(|(_, evr)| evr)
+}
+
+/// Check that the fallback loaders are copies of the vendor shims.
+fn check_fallbacks(loaders: &BTreeMap<&str, &[u8]>, r: &mut Vec<Inconsistency>) {
+    for &(fallback, shim) in FALLBACK_SHIMS {
+        let Some(fallback_content) = loaders.get(fallback) else {
+            continue;
+        };
+        let vendor_shims = loaders
+            .iter()
+            .filter(|(k, _)| !k.starts_with("BOOT/") && k.rsplit('/').next() == Some(shim));
+        for (vendor, content) in vendor_shims {
+            if content != fallback_content {
+                r.push(Inconsistency::FallbackMismatch {
+                    fallback: fallback.to_string(),
+                    vendor: vendor.to_string(),
+                });
+            }
+        }
+    }
+}
+
+/// Check that each GRUB next to a vendor shim is signed by it.
+#[cfg(have_efi)]
+fn check_signers(loaders: &BTreeMap<&str, &[u8]>, r: &mut Vec<Inconsistency>) -> Result<()> {
+    for (&path, &shim) in loaders.iter() {
+        let Some((dir, name)) = path.rsplit_once('/') else {
+            continue;
+        };
+        let Some(&(_, grub)) = SHIM_LOADERS.iter().find(|(s, _)| *s == name) else {
+            continue;
+        };
+        let loader = format!("{dir}/{grub}");
+        let Some(&image) = loaders.get(loader.as_str()) else {
+            continue;
+        };
+        if crate::secureboot::vendor_trusts(shim, image)? == Some(false) {
+            r.push(Inconsistency::UntrustedLoader {
+                loader,
+                shim: path.to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Return all detected inconsistencies between the `installed` components,
+/// read back as `inspections` tell.
+pub(crate) fn check(
+    installed: &BTreeMap<String, InstalledSummary>,
+    inspections: &BTreeMap<String, Inspection>,
+) -> Result<Vec<Inconsistency>> {
+    let mut r = Vec::new();
+    let loaders = inspections
+        .values()
+        .flat_map(|i| i.loaders.iter())
+        .map(|(k, v)| (k.as_str(), v.as_slice()))
+        .collect::<BTreeMap<_, _>>();
+    check_fallbacks(&loaders, &mut r);
+    #[cfg(have_efi)]
+    check_signers(&loaders, &mut r)?;
+    for core in inspections.values().flat_map(|i| i.core_images.iter()) {
+        let embedded = core.embedded.get(CORE_IMAGE_SECTOR..);
+        if embedded.is_none() || embedded != core.built.get(CORE_IMAGE_SECTOR..) {
+            r.push(Inconsistency::CoreImageMismatch {
+                device: core.device.clone(),
+            });
+        }
+    }
+    let grub_versions = installed
+        .iter()
+        .filter_map(|(name, inst)| {
+            let evr = grub_evr(&inst.meta.version)?;
+            Some((name.clone(), evr.to_string()))
+        })
+        .collect::<BTreeMap<_, _>>();
+    let mut distinct = grub_versions.values().collect::<Vec<_>>();
+    distinct.dedup();
+    if distinct.len() > 1 {
+        r.push(Inconsistency::GrubVersionMismatch(grub_versions));
+    }
+    Ok(r)
+}
+
+/// Read back the `installed` components, created as `opts` tell, and
+/// return the inconsistencies between them.
+pub(crate) fn check_installed(
+    installed: &BTreeMap<String, InstalledSummary>,
+    opts: &Options,
+) -> Result<Vec<Inconsistency>> {
+    let mut inspections = BTreeMap::new();
+    for (name, c) in installed.iter() {
+        let component = crate::component::new_from_name_with(name, opts)?;
+        inspections.insert(name.clone(), component.inspect(&c.devices)?);
+    }
+    check(installed, &inspections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{SavedState, StateSummary};
+
+    #[test]
+    fn test_split_nevra() {
+        assert_eq!(
+            split_nevra("grub2-efi-x64-1:2.06-95.fc38.x86_64"),
+            Some(("grub2-efi-x64", "1:2.06-95.fc38"))
+        );
+        assert_eq!(
+            split_nevra("shim-x64-15.6-2.x86_64"),
+            Some(("shim-x64", "15.6-2"))
+        );
+        assert_eq!(split_nevra("unknown"), None);
+        assert_eq!(
+            grub_evr("grub2-efi-x64-1:2.04-23.fc32.x86_64,shim-x64-15-8.x86_64"),
+            Some("1:2.04-23.fc32")
+        );
+    }
+
+    #[test]
+    #[cfg(have_efi)]
+    fn test_is_loader() {
+        assert!(is_loader("fedora/shimx64.efi"));
+        assert!(is_loader("fedora/grubx64.efi"));
+        assert!(is_loader("BOOT/BOOTX64.EFI"));
+        assert!(is_loader("opensuse/grub.efi"));
+        assert!(!is_loader("fedora/mmx64.efi"));
+        assert!(!is_loader("fedora/grub.cfg"));
+    }
+
+    #[test]
+    fn test_check() -> Result<()> {
+        let data = include_str!("../tests/fixtures/example-state-v0.json");
+        let state: SavedState = serde_json::from_str(data)?;
+        let mut installed = StateSummary::from(state).installed;
+        let mut inspections = BTreeMap::new();
+        assert_eq!(check(&installed, &inspections)?, []);
+
+        let mut bios = installed.get("EFI").unwrap().clone();
+        bios.meta.version = "grub2-tools-1:2.04-23.fc32.x86_64".into();
+        installed.insert("BIOS".into(), bios.clone());
+        assert_eq!(check(&installed, &inspections)?, []);
+
+        bios.meta.version = "grub2-tools-1:2.06-95.fc38.x86_64".into();
+        installed.insert("BIOS".into(), bios);
+        let r = check(&installed, &inspections)?;
+        assert_eq!(r.len(), 1);
+        assert!(matches!(r[0], Inconsistency::GrubVersionMismatch(_)));
+
+        let mut efi = Inspection::default();
+        for path in ["fedora/shimx64.efi", "BOOT/BOOTX64.EFI"] {
+            efi.loaders.insert(path.into(), b"shim".to_vec());
+        }
+        // Not PE images, so whether GRUB is signed can't be told
+        efi.loaders
+            .insert("fedora/grubx64.efi".into(), b"grub".to_vec());
+        inspections.insert("EFI".to_string(), efi);
+        assert_eq!(check(&installed, &inspections)?.len(), 1);
+        let efi = inspections.get_mut("EFI").unwrap();
+        efi.loaders
+            .insert("BOOT/BOOTX64.EFI".into(), b"shim 2".to_vec());
+        assert_eq!(
+            check(&installed, &inspections)?[0],
+            Inconsistency::FallbackMismatch {
+                fallback: "BOOT/BOOTX64.EFI".into(),
+                vendor: "fedora/shimx64.efi".into(),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_core_images() -> Result<()> {
+        let installed = BTreeMap::new();
+        let built = [vec![0u8; CORE_IMAGE_SECTOR], b"kernel".to_vec()].concat();
+        // grub-install fills in the block lists of the embedded image
+        let mut embedded = built.clone();
+        embedded[CORE_IMAGE_SECTOR - 12..CORE_IMAGE_SECTOR].fill(0xff);
+        let mut bios = Inspection::default();
+        bios.core_images.push(CoreImage {
+            device: "/dev/vda".into(),
+            embedded: embedded.clone(),
+            built: built.clone(),
+        });
+        let mut inspections = BTreeMap::from([("BIOS".to_string(), bios)]);
+        assert_eq!(check(&installed, &inspections)?, []);
+
+        let bios = inspections.get_mut("BIOS").unwrap();
+        *embedded.last_mut().unwrap() = b'L';
+        bios.core_images.push(CoreImage {
+            device: "/dev/vdb".into(),
+            embedded,
+            built: built.clone(),
+        });
+        bios.core_images.push(CoreImage {
+            device: "/dev/vdc".into(),
+            embedded: vec![0u8; 16],
+            built,
+        });
+        assert_eq!(
+            check(&installed, &inspections)?,
+            [
+                Inconsistency::CoreImageMismatch {
+                    device: "/dev/vdb".into()
+                },
+                Inconsistency::CoreImageMismatch {
+                    device: "/dev/vdc".into()
+                }
+            ]
+        );
+        Ok(())
+    }
+}
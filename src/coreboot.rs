@@ -0,0 +1,354 @@
+//! The payload (e.g. GRUB or SeaBIOS) of coreboot firmware, replaced in
+//! the CBFS of the flash image using `cbfstool` and `flashrom`.
+//!
+//! As a failed write can leave the machine unbootable, every update reads
+//! the flash twice, verifies that the reads match, and keeps a backup of
+//! the image in [`BACKUP_DIR`] before anything is written.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+use serde::Deserialize;
+
+use crate::component::*;
+use crate::filetree::FileTree;
+use crate::model::*;
+use crate::util::{self, CommandRunExt};
+
+/// Describes the payload shipped by the OS, relative to the root.
+pub(crate) const CONFIG_PATH: &str = "usr/lib/bootupd/coreboot.json";
+
+/// Backups of the flash contents, one per update.
+const BACKUP_DIR: &str = "/var/lib/bootupd/coreboot";
+
+const FLASHROM: &str = "flashrom";
+const CBFSTOOL: &str = "cbfstool";
+/// The flashrom programmer for the flash of the running machine
+const PROGRAMMER: &str = "internal";
+
+fn default_cbfs_name() -> String {
+    "fallback/payload".into()
+}
+
+fn default_region() -> String {
+    "COREBOOT".into()
+}
+
+/// The content of [`CONFIG_PATH`].
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// The payload ELF, relative to the root
+    payload: String,
+    /// Name of the payload in CBFS
+    #[serde(default = "default_cbfs_name")]
+    cbfs_name: String,
+    /// The FMAP region containing the CBFS
+    #[serde(default = "default_region")]
+    region: String,
+}
+
+impl Config {
+    fn load(root: &openat::Dir) -> Result<Option<Self>> {
+        let Some(f) = root.open_file_optional(CONFIG_PATH)? else {
+            return Ok(None);
+        };
+        let config = serde_json::from_reader(std::io::BufReader::new(f))
+            .with_context(|| format!("Parsing {CONFIG_PATH}"))?;
+        Ok(Some(config))
+    }
+
+    /// File name of the payload in the update directory.
+    fn payload_name(&self) -> Result<&str> {
+        Path::new(&self.payload)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid payload path {}", self.payload))
+    }
+}
+
+/// Return `true` if the running firmware is coreboot.
+pub(crate) fn is_coreboot() -> bool {
+    std::fs::read_to_string("/sys/class/dmi/id/bios_vendor")
+        .map(|v| v.trim() == "coreboot")
+        .unwrap_or_default()
+}
+
+/// Whether the machine is running on battery, where a power loss during
+/// the flash write is most likely.
+fn on_battery() -> Result<bool> {
+    let dir = Path::new("/sys/class/power_supply");
+    if !dir.exists() {
+        return Ok(false);
+    }
+    let mut have_battery = false;
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let read = |f: &str| std::fs::read_to_string(path.join(f)).unwrap_or_default();
+        match read("type").trim() {
+            "Mains" | "USB" if read("online").trim() == "1" => return Ok(false),
+            "Battery" => have_battery = true,
+            _ => {}
+        }
+    }
+    Ok(have_battery)
+}
+
+/// Whether `cbfstool print` output lists a file named `name`.
+fn cbfs_contains(print: &str, name: &str) -> bool {
+    print
+        .lines()
+        .filter_map(|l| l.split_whitespace().next())
+        .any(|n| n == name)
+}
+
+/// Read the whole flash into `dest`.
+fn flash_read(dest: &Path) -> Result<()> {
+    crate::util::command(FLASHROM)
+        .args(["--programmer", PROGRAMMER, "--read"])
+        .arg(dest)
+        .run()
+}
+
+#[derive(Default)]
+pub(crate) struct Coreboot {}
+
+impl Coreboot {
+    /// Run the checks before writing, returning the verified flash image
+    /// read into `workdir`.
+    #[context("Pre-flight checks")]
+    fn preflight(&self, config: &Config, workdir: &Path) -> Result<PathBuf> {
+        if !is_coreboot() {
+            bail!("Not running on coreboot firmware");
+        }
+        if on_battery()? {
+            bail!("Refusing to write the flash while running on battery power");
+        }
+        // Fails if no (or an unsupported) flash chip is found
+        crate::util::command(FLASHROM)
+            .args(["--programmer", PROGRAMMER, "--flash-name"])
+            .run()
+            .context("Detecting flash chip")?;
+
+        let image = workdir.join("flash.rom");
+        let verify = workdir.join("verify.rom");
+        flash_read(&image)?;
+        flash_read(&verify)?;
+        if std::fs::read(&image)? != std::fs::read(&verify)? {
+            bail!("Reading the flash twice returned different contents");
+        }
+        std::fs::remove_file(&verify)?;
+
+        let print = util::cmd_output(crate::util::command(CBFSTOOL).arg(&image).args([
+            "print",
+            "-r",
+            &config.region,
+        ]))?;
+        if !cbfs_contains(&print, &config.cbfs_name) {
+            bail!(
+                "No {} in the {} region of the flash",
+                config.cbfs_name,
+                config.region
+            );
+        }
+
+        std::fs::create_dir_all(BACKUP_DIR)?;
+        let backup = Path::new(BACKUP_DIR).join(format!(
+            "flash-{}.rom",
+            chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+        ));
+        std::fs::copy(&image, &backup).with_context(|| format!("Writing backup {backup:?}"))?;
+        println!("Saved a backup of the flash to {}", backup.display());
+        Ok(image)
+    }
+
+    /// Replace the payload in the flash with the one in the update
+    /// directory of `sysroot`.
+    #[context("Updating coreboot payload")]
+    fn write_payload(&self, sysroot: &openat::Dir) -> Result<FileTree> {
+        let Some(config) = Config::load(sysroot)? else {
+            bail!("Missing {CONFIG_PATH}");
+        };
+        let updatedir = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let ft = crate::digestcache::payload_tree(&updatedir)?;
+        let payload = updatedir.recover_path()?.join(config.payload_name()?);
+
+        let td = tempfile::tempdir()?;
+        let image = self.preflight(&config, td.path())?;
+        let size = std::fs::metadata(&image)?.len();
+        let region = config.region.as_str();
+        let name = config.cbfs_name.as_str();
+        crate::util::command(CBFSTOOL)
+            .arg(&image)
+            .args(["remove", "-r", region, "-n", name])
+            .run()?;
+        crate::util::command(CBFSTOOL)
+            .arg(&image)
+            .args(["add-payload", "-r", region, "-n", name, "-c", "lzma", "-f"])
+            .arg(&payload)
+            .run()
+            .context("Adding payload (does it fit?)")?;
+        if std::fs::metadata(&image)?.len() != size {
+            bail!("Modified flash image changed size");
+        }
+        // flashrom verifies the written region by reading it back
+        crate::util::command(FLASHROM)
+            .args([
+                "--programmer",
+                PROGRAMMER,
+                "--fmap",
+                "--image",
+                region,
+                "--write",
+            ])
+            .arg(&image)
+            .run()
+            .context("Writing flash")?;
+        Ok(ft)
+    }
+}
+
+impl Component for Coreboot {
+    fn name(&self) -> &'static str {
+        "COREBOOT"
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        if !is_coreboot() {
+            return Ok(None);
+        }
+        // Rewriting the flash is never automatic
+        Ok(crate::component::query_adopt_state()?.map(|a| Adoptable {
+            confident: false,
+            ..a
+        }))
+    }
+
+    fn adopt_update(
+        &self,
+        sysroot: &openat::Dir,
+        update: &ContentMetadata,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = self.query_adopt()? else {
+            anyhow::bail!("Failed to find adoptable system")
+        };
+        let ft = self.write_payload(sysroot)?;
+        Ok(InstalledContent {
+            meta: update.clone(),
+            filetree: Some(ft),
+            adopted_from: Some(meta.version),
+            devices: Vec::new(),
+        })
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        _dest_root: &str,
+        _device: Option<&Path>,
+        _update_firmware: bool,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = get_component_update(src_root, self)? else {
+            return Err(crate::errors::Error::payload_missing(self.name()).into());
+        };
+        let ft = self.write_payload(src_root)?;
+        Ok(InstalledContent {
+            meta,
+            filetree: Some(ft),
+            adopted_from: None,
+            devices: Vec::new(),
+        })
+    }
+
+    fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
+        let Some(config) = Config::load(&openat::Dir::open(sysroot_path)?)? else {
+            bail!("Missing {CONFIG_PATH}");
+        };
+        let dest = component_updatedir(sysroot_path, self);
+        std::fs::create_dir_all(&dest)?;
+        let src = Path::new(sysroot_path).join(&config.payload);
+        std::fs::copy(&src, dest.join(config.payload_name()?))
+            .with_context(|| format!("Copying {src:?}"))?;
+        let meta = write_packaged_update_metadata(
+            sysroot_path,
+            self,
+            [Path::new("/").join(&config.payload)],
+        )?;
+        Ok(meta)
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        sysroot: &openat::Dir,
+        _current: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        let updatemeta = self.query_update(sysroot)?.expect("update available");
+        let ft = self.write_payload(sysroot)?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(ft),
+            adopted_from: None,
+            devices: Vec::new(),
+        })
+    }
+
+    fn validate(&self, _: &InstalledContent) -> Result<ValidationResult> {
+        // Reading back the flash is slow; writes are verified by flashrom
+        Ok(ValidationResult::Skip)
+    }
+
+    fn fingerprint_installed(&self, _: &openat::Dir) -> Result<Option<InstalledContent>> {
+        if !is_coreboot() {
+            return Ok(None);
+        }
+        // The payload in the flash isn't read back, as for validate
+        bail!("The coreboot payload in the flash can't be fingerprinted; use `bootupctl adopt-and-update` to take it over");
+    }
+
+    fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let path = td.path().join(CONFIG_PATH);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let root = openat::Dir::open(td.path())?;
+        assert_eq!(Config::load(&root)?, None);
+        std::fs::write(&path, r#"{"payload": "usr/share/seabios/bios.elf"}"#)?;
+        let config = Config::load(&root)?.unwrap();
+        assert_eq!(config.cbfs_name, "fallback/payload");
+        assert_eq!(config.region, "COREBOOT");
+        assert_eq!(config.payload_name()?, "bios.elf");
+        Ok(())
+    }
+
+    #[test]
+    fn test_cbfs_contains() {
+        let print = "FMAP REGION: COREBOOT
+Name                           Offset     Type           Size   Comp
+cbfs master header             0x0        cbfs header        32 none
+fallback/romstage              0x80       stage           58012 none
+fallback/ramstage              0xe380     stage          103718 LZMA (222456 decompressed)
+fallback/payload               0x27a00    simple elf      67622 none
+(empty)                        0x38280    null          3062232 none
+";
+        assert!(cbfs_contains(print, "fallback/payload"));
+        assert!(!cbfs_contains(print, "img/seabios"));
+    }
+}
@@ -0,0 +1,369 @@
+//! A persistent cache of the digests of update payload files.
+//!
+//! Hashing the payloads in `/usr/lib/bootupd/updates` is the bulk of the
+//! work of updating or adopting a component, and the content is the same
+//! on every run until the OS is updated.  Digests are therefore cached in
+//! [`CACHE_PATH`], keyed by the identity of each file: device, inode,
+//! size, and modification and change times.  Replacing a file (as an
+//! ostree deployment or package update does) changes its identity, so
+//! stale digests are never used; and as the entries of a payload
+//! directory are replaced whenever it's hashed, removed files drop out.
+//!
+//! Otherwise, only content below `/usr` is cached, as elsewhere rehashing
+//! is usually the point.  The exception is routine validation of the ESP
+//! (see [`esp_metadata`]), where files whose size and modification time
+//! are unchanged are assumed to be intact unless `--thorough` is given;
+//! files without a modification time are always hashed.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use openat_ext::OpenatDirExt;
+use serde::{Deserialize, Serialize};
+
+use crate::filetree::{FileMetadata, FileTree};
+use crate::sha512string::SHA512String;
+
+/// The cache file.
+pub(crate) const CACHE_PATH: &str = "/var/cache/bootupd/digests.json";
+/// The current format of the cache; other versions are discarded.
+const CACHE_VERSION: u32 = 1;
+/// Directories below this prefix are cached.
+const CACHED_PREFIX: &str = "/usr/";
+/// Modification times before this are taken to be unset: FAT can't store
+/// times before 1980, and a zeroed date reads as 1980-01-01 in local time.
+#[cfg(have_efi)]
+const FAT_UNSET_MTIME: i64 = 315_619_200;
+
+/// What identifies the content of a file without reading it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+struct Identity {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl Identity {
+    fn new(meta: &std::fs::Metadata) -> Self {
+        Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            size: meta.size(),
+            mtime: (meta.mtime(), meta.mtime_nsec()),
+            ctime: (meta.ctime(), meta.ctime_nsec()),
+        }
+    }
+
+    /// The identity of a file on FAT, which only has stable sizes and
+    /// modification times: inode numbers are assigned at mount time, and
+    /// there is no separate change time.  Files without a modification
+    /// time, as some tools write them, have none: changed content may well
+    /// have the same size.
+    #[cfg(have_efi)]
+    fn new_fat(meta: &std::fs::Metadata) -> Option<Self> {
+        if meta.mtime() < FAT_UNSET_MTIME {
+            return None;
+        }
+        Some(Self {
+            dev: 0,
+            ino: 0,
+            size: meta.size(),
+            mtime: (meta.mtime(), meta.mtime_nsec()),
+            ctime: (0, 0),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+struct Entry {
+    identity: Identity,
+    sha512: SHA512String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+struct CacheContent {
+    version: u32,
+    /// Entries by directory, then by path relative to the directory
+    dirs: BTreeMap<String, BTreeMap<String, Entry>>,
+}
+
+pub(crate) struct DigestCache {
+    path: PathBuf,
+    content: CacheContent,
+    /// The directories hashed since loading, to write back
+    updated: BTreeSet<String>,
+}
+
+impl DigestCache {
+    /// Load the cache from `path`; a missing or unreadable cache is empty.
+    pub(crate) fn load(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let content = match std::fs::read(&path) {
+            Ok(buf) => match serde_json::from_slice::<CacheContent>(&buf) {
+                Ok(c) if c.version == CACHE_VERSION => c,
+                Ok(_) => {
+                    log::debug!("Discarding digest cache with a different version");
+                    Default::default()
+                }
+                Err(e) => {
+                    log::warn!("Discarding invalid digest cache {path:?}: {e}");
+                    Default::default()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(e) => {
+                log::warn!("Failed to read digest cache {path:?}: {e}");
+                Default::default()
+            }
+        };
+        Self {
+            path,
+            content,
+            updated: BTreeSet::new(),
+        }
+    }
+
+    /// Create a FileTree from the directory `dir`, stored as `key`,
+    /// hashing only the files which aren't in the cache.
+    pub(crate) fn tree_for(&mut self, key: &str, dir: &openat::Dir) -> Result<FileTree> {
+        let mut names = Vec::new();
+        FileTree::list_files(dir, "", &mut names)?;
+        let children = self.metadata_for(key, dir, &names, |m| Some(Identity::new(m)))?;
+        Ok(FileTree { children })
+    }
+
+    /// Compute the metadata of the files `names` in `dir`, stored as `key`,
+    /// hashing only the files for which `identity` isn't in the cache; those
+    /// without one are always hashed.
+    fn metadata_for(
+        &mut self,
+        key: &str,
+        dir: &openat::Dir,
+        names: &[String],
+        identity: fn(&std::fs::Metadata) -> Option<Identity>,
+    ) -> Result<BTreeMap<String, FileMetadata>> {
+        let previous = self.content.dirs.remove(key).unwrap_or_default();
+        let entries = Mutex::new(BTreeMap::new());
+        let hits = AtomicUsize::new(0);
+        let children = FileTree::hash_files(names, |name| {
+            let f = dir.open_file(name)?;
+            let Some(identity) = identity(&f.metadata()?) else {
+                return FileMetadata::new_from_file(f);
+            };
+            let meta = match previous.get(name) {
+                Some(e) if e.identity == identity => {
+                    hits.fetch_add(1, Ordering::Relaxed);
+                    FileMetadata {
+                        size: identity.size,
+                        sha512: e.sha512.clone(),
+                    }
+                }
+                _ => FileMetadata::new_from_file(f)?,
+            };
+            let entry = Entry {
+                identity,
+                sha512: meta.sha512.clone(),
+            };
+            entries.lock().unwrap().insert(name.to_string(), entry);
+            Ok(meta)
+        })?;
+        log::debug!(
+            "Reused {} of {} cached digests for {key}",
+            hits.into_inner(),
+            names.len()
+        );
+        self.content
+            .dirs
+            .insert(key.to_string(), entries.into_inner().unwrap());
+        self.updated.insert(key.to_string());
+        Ok(children)
+    }
+
+    /// Write the directories hashed since loading back to disk.  Other
+    /// invocations may save theirs meanwhile, e.g. `status` during an
+    /// update, so the cache is reread and atomically replaced under a lock
+    /// on its directory, as the state is; see [`crate::util::lock_dir`].
+    pub(crate) fn save(&mut self) -> Result<()> {
+        let parent = self.path.parent().unwrap_or(Path::new("/"));
+        std::fs::create_dir_all(parent)?;
+        let name = self
+            .path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("Invalid cache path {:?}", self.path))?;
+        let parent = openat::Dir::open(parent)?;
+        let _lock = crate::util::lock_dir(&parent)?;
+        let mut content = Self::load(&self.path).content;
+        content.version = CACHE_VERSION;
+        for key in self.updated.iter() {
+            if let Some(entries) = self.content.dirs.get(key) {
+                content.dirs.insert(key.clone(), entries.clone());
+            }
+        }
+        parent.write_file_with_sync(name, 0o644, |w| -> Result<()> {
+            serde_json::to_writer(&mut *w, &content)?;
+            Ok(())
+        })?;
+        self.content = content;
+        Ok(())
+    }
+}
+
+/// Compute the metadata of the files `names` on the ESP directory `dir`
+/// for validation, reusing the digests in [`CACHE_PATH`] for files with an
+/// unchanged size and modification time, unless `thorough`.
+#[cfg(have_efi)]
+pub(crate) fn esp_metadata(
+    dir: &openat::Dir,
+    names: &[String],
+    thorough: bool,
+) -> Result<BTreeMap<String, FileMetadata>> {
+    let path = dir.recover_path()?;
+    let key = path.to_string_lossy();
+    let mut cache = DigestCache::load(CACHE_PATH);
+    if thorough {
+        // Still refresh the cache for the next routine run
+        cache.content.dirs.remove(key.as_ref());
+    }
+    let r = cache.metadata_for(&key, dir, names, Identity::new_fat)?;
+    if let Err(e) = cache.save() {
+        log::warn!("Failed to write digest cache {CACHE_PATH}: {e}");
+    }
+    Ok(r)
+}
+
+/// Create a FileTree from the update payload in `dir`, reusing the
+/// digests in [`CACHE_PATH`] for unchanged files.
+pub(crate) fn payload_tree(dir: &openat::Dir) -> Result<FileTree> {
+    let path = dir.recover_path()?;
+    let Some(key) = path.to_str().filter(|p| p.starts_with(CACHED_PREFIX)) else {
+        return FileTree::new_from_dir(dir);
+    };
+    let mut cache = DigestCache::load(CACHE_PATH);
+    let tree = cache.tree_for(key, dir)?;
+    // The cache is only an optimization
+    if let Err(e) = cache.save() {
+        log::warn!("Failed to write digest cache {CACHE_PATH}: {e}");
+    }
+    Ok(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_cache() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let payload = td.path().join("payload");
+        std::fs::create_dir_all(payload.join("EFI/fedora"))?;
+        std::fs::write(payload.join("EFI/fedora/shimx64.efi"), "shim")?;
+        std::fs::write(payload.join("EFI/fedora/grubx64.efi"), "grub")?;
+        let dir = openat::Dir::open(&payload)?;
+        let cachepath = td.path().join("cache/digests.json");
+
+        let mut cache = DigestCache::load(&cachepath);
+        let tree = cache.tree_for("payload", &dir)?;
+        assert_eq!(tree, FileTree::new_from_dir(&dir)?);
+        cache.save()?;
+
+        // Entries with a matching identity are used without rehashing
+        let mut cache = DigestCache::load(&cachepath);
+        let bogus = SHA512String("bogus".into());
+        for e in cache.content.dirs.get_mut("payload").unwrap().values_mut() {
+            e.sha512 = bogus.clone();
+        }
+        let tree = cache.tree_for("payload", &dir)?;
+        assert!(tree.children.values().all(|m| m.sha512 == bogus));
+
+        // Replaced and removed files are picked up
+        std::fs::remove_file(payload.join("EFI/fedora/grubx64.efi"))?;
+        std::fs::write(payload.join("EFI/fedora/shimx64.efi.new"), "shim2")?;
+        std::fs::rename(
+            payload.join("EFI/fedora/shimx64.efi.new"),
+            payload.join("EFI/fedora/shimx64.efi"),
+        )?;
+        let tree = cache.tree_for("payload", &dir)?;
+        assert_eq!(tree, FileTree::new_from_dir(&dir)?);
+        assert_eq!(cache.content.dirs["payload"].len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(have_efi)]
+    fn test_fat_identity() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        std::fs::create_dir_all(td.path().join("fedora"))?;
+        std::fs::write(td.path().join("fedora/grub.cfg"), "config")?;
+        let dir = openat::Dir::open(td.path())?;
+        let names = vec!["fedora/grub.cfg".to_string()];
+        let mut cache = DigestCache::load(td.path().join("digests.json"));
+        let expected = FileTree::new_from_dir(&dir)?.children;
+        assert_eq!(
+            cache.metadata_for("esp", &dir, &names, Identity::new_fat)?,
+            expected
+        );
+        // Files with the same size and mtime are assumed to be unchanged
+        let bogus = SHA512String("bogus".into());
+        for e in cache.content.dirs.get_mut("esp").unwrap().values_mut() {
+            e.sha512 = bogus.clone();
+        }
+        let r = cache.metadata_for("esp", &dir, &names, Identity::new_fat)?;
+        assert_eq!(r["fedora/grub.cfg"].sha512, bogus);
+        // But not with the strict identity
+        let r = cache.metadata_for("esp", &dir, &names, |m| Some(Identity::new(m)))?;
+        assert_eq!(r, expected);
+
+        // Nor without a modification time, when they aren't cached at all
+        for e in cache.content.dirs.get_mut("esp").unwrap().values_mut() {
+            e.sha512 = bogus.clone();
+        }
+        std::fs::File::options()
+            .write(true)
+            .open(td.path().join("fedora/grub.cfg"))?
+            .set_modified(std::time::SystemTime::UNIX_EPOCH)?;
+        let r = cache.metadata_for("esp", &dir, &names, Identity::new_fat)?;
+        assert_eq!(r, expected);
+        assert!(cache.content.dirs["esp"].is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_save() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        for d in ["a", "b"] {
+            std::fs::create_dir_all(td.path().join(d))?;
+            std::fs::write(td.path().join(d).join("shimx64.efi"), d)?;
+        }
+        let cachepath = td.path().join("cache/digests.json");
+        let mut first = DigestCache::load(&cachepath);
+        let mut second = DigestCache::load(&cachepath);
+        first.tree_for("a", &openat::Dir::open(&td.path().join("a"))?)?;
+        second.tree_for("b", &openat::Dir::open(&td.path().join("b"))?)?;
+        first.save()?;
+        // Keeps what the first one saved
+        second.save()?;
+        let cache = DigestCache::load(&cachepath);
+        assert_eq!(cache.content.dirs.keys().collect::<Vec<_>>(), ["a", "b"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_cache() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let cachepath = td.path().join("digests.json");
+        std::fs::write(&cachepath, "{")?;
+        assert!(DigestCache::load(&cachepath).content.dirs.is_empty());
+        std::fs::write(&cachepath, r#"{"version": 0, "dirs": {"a": {}}}"#)?;
+        assert!(DigestCache::load(&cachepath).content.dirs.is_empty());
+        Ok(())
+    }
+}
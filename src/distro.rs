@@ -0,0 +1,415 @@
+//! Distribution-specific paths and naming, so that the differences between
+//! e.g. Fedora and Debian derivatives live in one place.
+
+use std::path::Path;
+#[cfg(any(
+    target_arch = "x86_64",
+    all(have_bios, target_arch = "powerpc64"),
+    have_efi
+))]
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use os_release::OsRelease;
+
+/// The package manager owning the bootloader files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PackageSystem {
+    Rpm,
+    Dpkg,
+    Pacman,
+}
+
+/// A set of distribution conventions.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Profile {
+    pub(crate) name: &'static str,
+    pub(crate) package_system: PackageSystem,
+    /// Path to grub-install, relative to the root
+    pub(crate) grub_install: &'static str,
+    /// Directories which may contain the GRUB platform directories (e.g.
+    /// `i386-pc`), relative to the root, in order of preference
+    grub_modules: &'static [&'static str],
+    /// The GRUB directory in `/boot`
+    pub(crate) grub_dir: &'static str,
+    /// File names of the first stage loader (usually shim) on the ESP, in
+    /// order of preference
+    pub(crate) loaders: &'static [&'static str],
+    /// Candidate ESP mount points relative to the root, in order of preference
+    pub(crate) esp_mounts: &'static [&'static str],
+    /// Maps a file name in the vendor directory on the ESP to the path
+    /// it is packaged as, if it isn't packaged on the ESP directly
+    packaged_esp_files: &'static [(&'static str, &'static str)],
+}
+
+/// The architecture-specific name of shim used by most distributions
+#[cfg(target_arch = "aarch64")]
+pub(crate) const SHIM: &str = "shimaa64.efi";
+#[cfg(not(target_arch = "aarch64"))]
+pub(crate) const SHIM: &str = "shimx64.efi";
+
+/// The names of the GRUB directory in `/boot` used by distributions.
+const GRUB_DIRS: &[&str] = &["grub2", "grub"];
+
+/// Suffix of a GRUB directory set aside by [`consolidate_grub_dirs`].
+const GRUB_DIR_BACKUP_SUFFIX: &str = ".bootupd-old";
+
+/// Whether `path` is a directory (rather than a symlink to one) with
+/// something in it.
+fn is_populated_dir(path: &Path) -> Result<bool> {
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.is_dir() => Ok(std::fs::read_dir(path)?.next().is_some()),
+        Ok(_) => Ok(false),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Make `grub_dir` in `boot_dir` the only GRUB directory: the content of
+/// the other one is moved into it, and the other one replaced by a symlink
+/// to it, so that tools using either name (e.g. a grub-install built for
+/// the other convention) find the same files.  Entries present in both are
+/// kept from `grub_dir`, and the rest of the other directory set aside with
+/// a `.bootupd-old` suffix.  Returns the name of the directory migrated, if
+/// any.
+pub(crate) fn consolidate_grub_dirs(boot_dir: &Path, grub_dir: &str) -> Result<Option<String>> {
+    let target = boot_dir.join(grub_dir);
+    let Some(legacy) = GRUB_DIRS
+        .iter()
+        .filter(|d| **d != grub_dir)
+        .find(|d| matches!(std::fs::symlink_metadata(boot_dir.join(d)), Ok(m) if m.is_dir()))
+    else {
+        return Ok(None);
+    };
+    let legacy_path = boot_dir.join(legacy);
+    std::fs::create_dir_all(&target)?;
+    let mut conflicts = false;
+    for entry in std::fs::read_dir(&legacy_path)? {
+        let name = entry?.file_name();
+        let dest = target.join(&name);
+        if dest.symlink_metadata().is_ok() {
+            conflicts = true;
+            continue;
+        }
+        std::fs::rename(legacy_path.join(&name), &dest)
+            .with_context(|| format!("Moving {name:?} to {target:?}"))?;
+    }
+    if conflicts {
+        let backup = boot_dir.join(format!("{legacy}{GRUB_DIR_BACKUP_SUFFIX}"));
+        std::fs::rename(&legacy_path, &backup)
+            .with_context(|| format!("Renaming {legacy_path:?}"))?;
+        log::warn!("Kept the conflicting files of {legacy_path:?} in {backup:?}");
+    } else {
+        std::fs::remove_dir(&legacy_path)?;
+    }
+    match std::os::unix::fs::symlink(grub_dir, &legacy_path) {
+        Ok(()) => {}
+        // e.g. on FAT
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+            log::warn!("Failed to link {legacy_path:?} to {grub_dir}: {e}");
+        }
+        Err(e) => return Err(e).with_context(|| format!("Linking {legacy_path:?}")),
+    }
+    crate::util::fsync_dir(&openat::Dir::open(boot_dir)?, ".")?;
+    Ok(Some(legacy.to_string()))
+}
+
+/// Well-known paths to the ESP that may have been mounted external to us.
+const ESP_MOUNTS: &[&str] = &["boot/efi", "efi", "boot"];
+
+pub(crate) const FEDORA: Profile = Profile {
+    name: "fedora",
+    package_system: PackageSystem::Rpm,
+    grub_install: "usr/sbin/grub-install",
+    grub_modules: &["usr/lib/grub", "usr/lib64/grub"],
+    grub_dir: "grub",
+    loaders: &[SHIM],
+    esp_mounts: ESP_MOUNTS,
+    packaged_esp_files: &[],
+};
+
+/// Debian and Ubuntu ship the signed binaries outside of the ESP; they are
+/// copied there by grub-install.
+pub(crate) const DEBIAN: Profile = Profile {
+    name: "debian",
+    package_system: PackageSystem::Dpkg,
+    grub_install: "usr/sbin/grub-install",
+    grub_modules: &["usr/lib/grub"],
+    grub_dir: "grub",
+    loaders: &[SHIM],
+    esp_mounts: ESP_MOUNTS,
+    packaged_esp_files: &[
+        ("shimx64.efi", "usr/lib/shim/shimx64.efi.signed"),
+        ("mmx64.efi", "usr/lib/shim/mmx64.efi.signed"),
+        ("fbx64.efi", "usr/lib/shim/fbx64.efi.signed"),
+        (
+            "grubx64.efi",
+            "usr/lib/grub/x86_64-efi-signed/grubx64.efi.signed",
+        ),
+        ("shimaa64.efi", "usr/lib/shim/shimaa64.efi.signed"),
+        ("mmaa64.efi", "usr/lib/shim/mmaa64.efi.signed"),
+        ("fbaa64.efi", "usr/lib/shim/fbaa64.efi.signed"),
+        (
+            "grubaa64.efi",
+            "usr/lib/grub/arm64-efi-signed/grubaa64.efi.signed",
+        ),
+    ],
+};
+
+/// openSUSE (including MicroOS) installs shim and GRUB (using the plain
+/// `shim.efi` and `grub.efi` names) from /usr/share/efi via shim-install.
+pub(crate) const OPENSUSE: Profile = Profile {
+    name: "opensuse",
+    package_system: PackageSystem::Rpm,
+    grub_install: "usr/sbin/grub2-install",
+    grub_modules: &["usr/share/grub2", "usr/lib/grub2"],
+    grub_dir: "grub2",
+    loaders: &["shim.efi"],
+    esp_mounts: ESP_MOUNTS,
+    #[cfg(target_arch = "aarch64")]
+    packaged_esp_files: &[
+        ("shim.efi", "usr/share/efi/aarch64/shim.efi"),
+        ("grub.efi", "usr/share/efi/aarch64/grub.efi"),
+        ("MokManager.efi", "usr/share/efi/aarch64/MokManager.efi"),
+    ],
+    #[cfg(not(target_arch = "aarch64"))]
+    packaged_esp_files: &[
+        ("shim.efi", "usr/share/efi/x86_64/shim.efi"),
+        ("grub.efi", "usr/share/efi/x86_64/grub.efi"),
+        ("MokManager.efi", "usr/share/efi/x86_64/MokManager.efi"),
+    ],
+};
+
+/// Arch Linux and derivatives don't use shim; the ESP is conventionally
+/// mounted at /boot and contains either an unsigned GRUB generated by
+/// grub-install or systemd-boot.
+pub(crate) const ARCH: Profile = Profile {
+    name: "arch",
+    package_system: PackageSystem::Pacman,
+    grub_install: "usr/bin/grub-install",
+    grub_modules: &["usr/lib/grub"],
+    grub_dir: "grub",
+    #[cfg(target_arch = "aarch64")]
+    loaders: &["grubaa64.efi", "systemd-bootaa64.efi"],
+    #[cfg(not(target_arch = "aarch64"))]
+    loaders: &["grubx64.efi", "systemd-bootx64.efi"],
+    esp_mounts: &["boot", "efi", "boot/efi"],
+    packaged_esp_files: &[
+        ("grubx64.efi", "usr/lib/grub/x86_64-efi"),
+        (
+            "systemd-bootx64.efi",
+            "usr/lib/systemd/boot/efi/systemd-bootx64.efi",
+        ),
+        ("grubaa64.efi", "usr/lib/grub/arm64-efi"),
+        (
+            "systemd-bootaa64.efi",
+            "usr/lib/systemd/boot/efi/systemd-bootaa64.efi",
+        ),
+    ],
+};
+
+impl Profile {
+    fn from_os_release(id: &str, id_like: &str) -> &'static Profile {
+        let mut ids = std::iter::once(id).chain(id_like.split_whitespace());
+        let r = ids.find_map(|id| match id {
+            "debian" | "ubuntu" => Some(&DEBIAN),
+            "arch" => Some(&ARCH),
+            "sles" | "suse" => Some(&OPENSUSE),
+            id if id.starts_with("opensuse") || id.starts_with("sle-") => Some(&OPENSUSE),
+            _ => None,
+        });
+        r.unwrap_or(&FEDORA)
+    }
+
+    /// Determine the profile for the OS in `root`, defaulting to Fedora.
+    pub(crate) fn detect(root: &Path) -> Result<&'static Profile> {
+        for p in ["etc/os-release", "usr/lib/os-release"] {
+            let p = root.join(p);
+            if p.exists() {
+                let release = OsRelease::new_from(&p)?;
+                let r = Self::from_os_release(&release.id, &release.id_like);
+                log::debug!("Using {} profile", r.name);
+                return Ok(r);
+            }
+        }
+        Ok(&FEDORA)
+    }
+
+    /// Find the directory of the GRUB modules for `platform` (e.g.
+    /// `i386-pc`) in `root`.
+    #[cfg(any(target_arch = "x86_64", all(have_bios, target_arch = "powerpc64")))]
+    pub(crate) fn grub_modules_dir(&self, root: &Path, platform: &str) -> Result<PathBuf> {
+        for d in self.grub_modules {
+            let p = root.join(d).join(platform);
+            if p.try_exists()? {
+                return Ok(p);
+            }
+        }
+        anyhow::bail!(
+            "Failed to find GRUB modules for {platform} in {root:?} (looked in {:?})",
+            self.grub_modules
+        )
+    }
+
+    /// The GRUB directory in `boot_dir` to use: the one already populated,
+    /// e.g. by the installer of the system, or else the one of this
+    /// profile.  If both are, ours wins; see [`consolidate_grub_dirs`].
+    pub(crate) fn boot_grub_dir(&self, boot_dir: &Path) -> Result<&'static str> {
+        let candidates = std::iter::once(self.grub_dir)
+            .chain(GRUB_DIRS.iter().copied().filter(|d| *d != self.grub_dir));
+        for d in candidates {
+            if is_populated_dir(&boot_dir.join(d))? {
+                return Ok(d);
+            }
+        }
+        Ok(self.grub_dir)
+    }
+
+    /// Return the paths to query the package manager with for the content
+    /// of `efidir`, a copy of the `EFI` directory on the ESP.
+    #[cfg(have_efi)]
+    pub(crate) fn esp_query_paths(&self, efidir: &Path) -> Result<Vec<PathBuf>> {
+        if self.packaged_esp_files.is_empty() {
+            // The files are packaged in place on the ESP
+            let efidir = openat::Dir::open(efidir)?;
+            let mut r = crate::util::filenames(&efidir)?
+                .into_iter()
+                .map(|f| Path::new("/boot/efi/EFI").join(f.trim_start_matches('/')))
+                .collect::<Vec<_>>();
+            r.sort();
+            return Ok(r);
+        }
+        let mut r = Vec::new();
+        for entry in walkdir::WalkDir::new(efidir) {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy();
+            if let Some(p) = self.packaged_esp_path(&name) {
+                r.push(p);
+            }
+        }
+        r.sort();
+        r.dedup();
+        Ok(r)
+    }
+
+    /// Look up where a file found on the ESP is packaged, if elsewhere.
+    #[cfg(have_efi)]
+    fn packaged_esp_path(&self, filename: &str) -> Option<PathBuf> {
+        self.packaged_esp_files
+            .iter()
+            .find(|(f, _)| *f == filename)
+            .map(|(_, p)| Path::new("/").join(p))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        assert_eq!(Profile::detect(td.path())?, &FEDORA);
+        std::fs::create_dir_all(td.path().join("etc"))?;
+        std::fs::write(
+            td.path().join("etc/os-release"),
+            "NAME=\"Ubuntu\"\nID=ubuntu\nID_LIKE=debian\n",
+        )?;
+        assert_eq!(Profile::detect(td.path())?, &DEBIAN);
+        assert_eq!(
+            Profile::from_os_release("linuxmint", "ubuntu debian"),
+            &DEBIAN
+        );
+        assert_eq!(Profile::from_os_release("centos", "rhel fedora"), &FEDORA);
+        assert_eq!(
+            Profile::from_os_release("opensuse-microos", "suse opensuse opensuse-tumbleweed"),
+            &OPENSUSE
+        );
+        assert_eq!(Profile::from_os_release("sle-micro", "suse"), &OPENSUSE);
+        assert_eq!(Profile::from_os_release("endeavouros", "arch"), &ARCH);
+        Ok(())
+    }
+
+    #[test]
+    fn test_grub_dirs() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let boot = td.path();
+        assert_eq!(FEDORA.boot_grub_dir(boot)?, "grub");
+        std::fs::create_dir_all(boot.join("grub2/i386-pc"))?;
+        std::fs::write(boot.join("grub2/grub.cfg"), "grub2")?;
+        std::fs::write(boot.join("grub2/grubenv"), "env")?;
+        assert_eq!(FEDORA.boot_grub_dir(boot)?, "grub2");
+        assert_eq!(OPENSUSE.boot_grub_dir(boot)?, "grub2");
+        // Half-populated by a grub-install of the other convention
+        std::fs::create_dir_all(boot.join("grub/i386-pc"))?;
+        std::fs::write(boot.join("grub/grub.cfg"), "grub")?;
+        assert_eq!(FEDORA.boot_grub_dir(boot)?, "grub");
+
+        assert_eq!(
+            consolidate_grub_dirs(boot, "grub2")?.as_deref(),
+            Some("grub")
+        );
+        assert_eq!(std::fs::read_link(boot.join("grub"))?, Path::new("grub2"));
+        assert_eq!(
+            std::fs::read_to_string(boot.join("grub2/grub.cfg"))?,
+            "grub2"
+        );
+        assert_eq!(std::fs::read_to_string(boot.join("grub/grubenv"))?, "env");
+        assert!(boot.join("grub.bootupd-old/grub.cfg").exists());
+        assert_eq!(FEDORA.boot_grub_dir(boot)?, "grub2");
+        // Nothing left to do
+        assert_eq!(consolidate_grub_dirs(boot, "grub2")?, None);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", all(have_bios, target_arch = "powerpc64")))]
+    fn test_grub_modules_dir() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        assert!(FEDORA.grub_modules_dir(td.path(), "i386-pc").is_err());
+        std::fs::create_dir_all(td.path().join("usr/lib64/grub/i386-pc"))?;
+        assert_eq!(
+            FEDORA.grub_modules_dir(td.path(), "i386-pc")?,
+            td.path().join("usr/lib64/grub/i386-pc")
+        );
+        std::fs::create_dir_all(td.path().join("usr/lib/grub/i386-pc"))?;
+        assert_eq!(
+            FEDORA.grub_modules_dir(td.path(), "i386-pc")?,
+            td.path().join("usr/lib/grub/i386-pc")
+        );
+        assert!(OPENSUSE.grub_modules_dir(td.path(), "i386-pc").is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(have_efi)]
+    fn test_esp_query_paths() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let efidir = td.path();
+        std::fs::create_dir_all(efidir.join("BOOT"))?;
+        std::fs::create_dir_all(efidir.join("debian"))?;
+        for f in ["BOOT/BOOTX64.EFI", "debian/shimx64.efi", "debian/grub.cfg"] {
+            std::fs::write(efidir.join(f), "")?;
+        }
+        assert_eq!(
+            FEDORA.esp_query_paths(efidir)?,
+            [
+                Path::new("/boot/efi/EFI/BOOT/BOOTX64.EFI"),
+                Path::new("/boot/efi/EFI/debian/grub.cfg"),
+                Path::new("/boot/efi/EFI/debian/shimx64.efi"),
+            ]
+        );
+        assert_eq!(
+            DEBIAN.esp_query_paths(efidir)?,
+            [Path::new("/usr/lib/shim/shimx64.efi.signed")]
+        );
+        std::fs::write(efidir.join("debian/shim.efi"), "")?;
+        assert_eq!(OPENSUSE.esp_query_paths(efidir)?.len(), 1);
+        std::fs::write(efidir.join("BOOT/grubx64.efi"), "")?;
+        assert_eq!(
+            ARCH.esp_query_paths(efidir)?,
+            [Path::new("/usr/lib/grub/x86_64-efi")]
+        );
+        Ok(())
+    }
+}
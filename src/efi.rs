@@ -4,12 +4,10 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 use std::fs;
-use std::io::prelude::*;
 
 use std::cell::RefCell;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 
 use anyhow::{bail, Context, Result};
 use cap_std::fs::Dir;
@@ -21,51 +19,60 @@ use rustix::fd::BorrowedFd;
 use walkdir::WalkDir;
 use widestring::U16CString;
 
+use crate::cleanup;
+use crate::component::*;
+use crate::distro::Profile;
+use crate::entries;
+use crate::errors;
 use crate::filetree;
 use crate::model::*;
 use crate::ostreeutil;
 use crate::util::{self, CommandRunExt};
-use crate::{component::*, packagesystem};
-
-/// Well-known paths to the ESP that may have been mounted external to us.
-pub(crate) const ESP_MOUNTS: &[&str] = &["boot/efi", "efi", "boot"];
 
 /// The binary to change EFI boot ordering
 const EFIBOOTMGR: &str = "efibootmgr";
-#[cfg(target_arch = "aarch64")]
-pub(crate) const SHIM: &str = "shimaa64.efi";
-
-#[cfg(target_arch = "x86_64")]
-pub(crate) const SHIM: &str = "shimx64.efi";
+/// Detect the distribution profile for a root directory.
+fn profile_for(root: &openat::Dir) -> Result<&'static Profile> {
+    Profile::detect(&root.recover_path()?)
+}
 
 /// The ESP partition label on Fedora CoreOS derivatives
 pub(crate) const COREOS_ESP_PART_LABEL: &str = "EFI-SYSTEM";
 pub(crate) const ANACONDA_ESP_PART_LABEL: &str = "EFI\\x20System\\x20Partition";
 
+/// The directory of the fallback loader, in the `EFI` directory of the ESP
+const FALLBACK_DIR: &str = "BOOT";
+
 /// Systemd boot loader info EFI variable names
 const LOADER_INFO_VAR_STR: &str = "LoaderInfo-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
 const STUB_INFO_VAR_STR: &str = "StubInfo-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
 
-/// Return `true` if the system is booted via EFI
-pub(crate) fn is_efi_booted() -> Result<bool> {
-    Path::new("/sys/firmware/efi")
-        .try_exists()
-        .map_err(Into::into)
-}
-
 #[derive(Default)]
 pub(crate) struct Efi {
-    mountpoint: RefCell<Option<PathBuf>>,
+    /// The ESP, if we mounted it
+    mount: RefCell<Option<cleanup::Guard>>,
+    /// The existing ESP mount we reuse, if we made it writable
+    writable: RefCell<Option<util::WritableMount>>,
+    opts: crate::component::Options,
 }
 
 impl Efi {
+    /// The EFI component, writing as `opts` tell.
+    pub(crate) fn new(opts: crate::component::Options) -> Self {
+        Self {
+            mount: Default::default(),
+            writable: Default::default(),
+            opts,
+        }
+    }
+
     fn esp_path(&self) -> Result<PathBuf> {
         self.ensure_mounted_esp(Path::new("/"))
             .map(|v| v.join("EFI"))
     }
 
     fn open_esp_optional(&self) -> Result<Option<openat::Dir>> {
-        if !is_efi_booted()? && self.get_esp_device().is_none() {
+        if !crate::firmware::is_efi_booted()? && self.get_esp_device().is_none() {
             log::debug!("Skip EFI");
             return Ok(None);
         }
@@ -74,13 +81,33 @@ impl Efi {
         Ok(esp)
     }
 
-    fn open_esp(&self) -> Result<openat::Dir> {
+    pub(crate) fn open_esp(&self) -> Result<openat::Dir> {
         self.ensure_mounted_esp(Path::new("/"))?;
         let sysroot = openat::Dir::open("/")?;
         let esp = sysroot.sub_dir(&self.esp_path()?)?;
         Ok(esp)
     }
 
+    /// The disk holding the ESP `espdir`, which needn't be the one holding
+    /// /boot.
+    fn esp_disk(espdir: &openat::Dir) -> Result<String> {
+        let fsinfo = crate::filesystem::inspect_filesystem(espdir, ".")?;
+        let disk = crate::blockdev::Topology::get()?.parent_disk(&fsinfo.source)?;
+        Ok(disk.to_string())
+    }
+
+    /// The persistent name of the disk holding the ESP `espdir` to record in
+    /// state, if it can be found.
+    fn esp_disk_ids(espdir: &openat::Dir) -> Vec<PathBuf> {
+        match Self::esp_disk(espdir) {
+            Ok(disk) => crate::blockdev::stable_ids(&[disk]),
+            Err(e) => {
+                log::debug!("Failed to find the disk of the ESP: {e:#}");
+                Vec::new()
+            }
+        }
+    }
+
     fn get_esp_device(&self) -> Option<PathBuf> {
         let esp_devices = [COREOS_ESP_PART_LABEL, ANACONDA_ESP_PART_LABEL]
             .into_iter()
@@ -96,71 +123,243 @@ impl Efi {
     }
 
     pub(crate) fn ensure_mounted_esp(&self, root: &Path) -> Result<PathBuf> {
-        let mut mountpoint = self.mountpoint.borrow_mut();
-        if let Some(mountpoint) = mountpoint.as_deref() {
-            return Ok(mountpoint.to_owned());
+        let mut mount = self.mount.borrow_mut();
+        if let Some(mount) = mount.as_ref() {
+            return Ok(mount.path().to_owned());
         }
-        for &mnt in ESP_MOUNTS {
+        let esp_mounts = Profile::detect(root)?.esp_mounts;
+        for &mnt in esp_mounts {
             let mnt = root.join(mnt);
             if !mnt.exists() {
                 continue;
             }
-            let st =
-                rustix::fs::statfs(&mnt).with_context(|| format!("statfs failed for {mnt:?}"))?;
-            if st.f_type != libc::MSDOS_SUPER_MAGIC {
+            if !util::is_fat(&mnt)? {
                 continue;
             }
-            util::ensure_writable_mount(&mnt)?;
+            // With the ESP mounted at /boot, boot/efi resolves to the
+            // EFI directory on the case-insensitive filesystem.
+            if !util::is_mountpoint(&mnt)? {
+                log::debug!("Skipping {mnt:?}, which is not a mount point");
+                continue;
+            }
+            let mut writable = self.writable.borrow_mut();
+            if writable.is_none() {
+                *writable = Some(util::WritableMount::new(&mnt)?);
+            }
             log::debug!("Reusing existing {mnt:?}");
             return Ok(mnt);
         }
 
-        let esp_device = self
-            .get_esp_device()
-            .ok_or_else(|| anyhow::anyhow!("Failed to find ESP device"))?;
-        for &mnt in ESP_MOUNTS.iter() {
+        let esp_device = self.get_esp_device().ok_or_else(|| {
+            crate::errors::Error::new(
+                errors::ErrorKind::DeviceNotFound,
+                "Failed to find the ESP device",
+            )
+        })?;
+        for &mnt in esp_mounts.iter() {
             let mnt = root.join(mnt);
             if !mnt.exists() {
                 continue;
             }
-            let status = std::process::Command::new("mount")
-                .arg(&esp_device)
-                .arg(&mnt)
-                .status()?;
-            if !status.success() {
-                anyhow::bail!("Failed to mount {:?}", esp_device);
+            if util::have_program("mount") {
+                let mut cmd = crate::util::command("mount");
+                cmd.arg(&esp_device).arg(&mnt);
+                let out = util::output_retrying(&mut cmd)?;
+                if !out.status.success() {
+                    return Err(util::tool_failed(&cmd, out.status, &out.stderr))
+                        .with_context(|| format!("Failed to mount {esp_device:?}"));
+                }
+            } else {
+                crate::native::mount(&esp_device, &mnt, "vfat")?;
             }
             log::debug!("Mounted at {mnt:?}");
-            *mountpoint = Some(mnt);
+            *mount = Some(cleanup::Guard::new(cleanup::Artifact::mount(mnt)?));
             break;
         }
-        Ok(mountpoint.as_deref().unwrap().to_owned())
+        Ok(mount.as_ref().unwrap().path().to_owned())
     }
 
     fn unmount(&self) -> Result<()> {
-        if let Some(mount) = self.mountpoint.borrow_mut().take() {
-            let status = Command::new("umount").arg(&mount).status()?;
-            if !status.success() {
-                anyhow::bail!("Failed to unmount {mount:?}: {status:?}");
-            }
+        if let Some(mount) = self.mount.borrow_mut().take() {
+            mount.teardown()?;
             log::trace!("Unmounted");
         }
         Ok(())
     }
 
+    /// Create the boot entry for `loader` on the ESP `espdir`, on whichever
+    /// disk holds it rather than the target device of e.g. BIOS.
     #[context("Updating EFI firmware variables")]
-    fn update_firmware(&self, device: &str, espdir: &openat::Dir, vendordir: &str) -> Result<()> {
-        if !is_efi_booted()? {
+    fn update_firmware(&self, espdir: &openat::Dir, vendordir: &str, loader: &str) -> Result<()> {
+        if !crate::firmware::is_efi_booted()? {
             log::debug!("Not booted via EFI, skipping firmware update");
             return Ok(());
         }
+        let device = Self::esp_disk(espdir)?;
+        log::info!("Creating the EFI boot entry on {device}");
         let sysroot = Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
         let product_name = get_product_name(&sysroot)?;
         log::debug!("Get product name: {product_name}");
         assert!(product_name.len() > 0);
         // clear all the boot entries that match the target name
         clear_efi_target(&product_name)?;
-        create_efi_boot_entry(device, espdir, vendordir, &product_name)
+        create_efi_boot_entry(&device, espdir, vendordir, loader, &product_name)
+    }
+
+    /// Refuse to apply `diff` from the payload `updated` if it replaces the
+    /// first stage loader with one the firmware won't run; see
+    /// [`crate::secureboot`].
+    fn check_loader_update(
+        &self,
+        sysroot: &openat::Dir,
+        updated: &openat::Dir,
+        diff: &filetree::FileTreeDiff,
+    ) -> Result<()> {
+        let (vendordir, loader) = self.find_loader(sysroot)?;
+        let path = format!("{vendordir}/{loader}");
+        if !diff.changes.contains(&path) && !diff.additions.contains(&path) {
+            return Ok(());
+        }
+        let Some(f) = updated.open_file_optional(&path)? else {
+            return Ok(());
+        };
+        let mut buf = Vec::new();
+        std::io::Read::read_to_end(&mut std::io::BufReader::new(f), &mut buf)?;
+        crate::secureboot::check_loader(&format!("EFI/{path}"), &buf, self.opts.force)
+    }
+
+    /// The `EFI` directory of the mounted ESP, checked to be FAT.
+    fn open_esp_checked(&self) -> Result<openat::Dir> {
+        let destdir = self.open_esp().context("opening EFI dir")?;
+        validate_esp(&destdir)?;
+        Ok(destdir)
+    }
+
+    /// The partition devices of every ESP, on whichever disks they are.
+    #[cfg(have_fat)]
+    fn esp_devices(&self) -> Result<Vec<PathBuf>> {
+        let labels = [COREOS_ESP_PART_LABEL, ANACONDA_ESP_PART_LABEL];
+        let devices = crate::blockdev::Topology::get()?
+            .partitions_labeled(&labels)
+            .into_iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        if !devices.is_empty() {
+            return Ok(devices);
+        }
+        let device = self.get_esp_device().ok_or_else(|| {
+            errors::Error::new(
+                errors::ErrorKind::DeviceNotFound,
+                "Failed to find the ESP device",
+            )
+        })?;
+        Ok(vec![device.canonicalize()?])
+    }
+
+    /// Update every ESP through its partition device, without mounting
+    /// it; see [`crate::fatesp`].
+    #[cfg(have_fat)]
+    fn run_update_direct(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        let updatemeta = self.query_update(sysroot)?.expect("update available");
+        let (vendordir, _) = self.find_loader(sysroot)?;
+        let mut updatef = None;
+        let mut disks = Vec::new();
+        for device in self.esp_devices()? {
+            let esp = crate::fatesp::FatEsp::open(&device)?;
+            let (updated, tree, diff) =
+                self.update_diff(sysroot, current, &EspAccess::Direct(&esp))?;
+            log::trace!("applying diff to {device:?}: {}", &diff);
+            esp.apply_diff(&updated, &diff, &tree, Some(FALLBACK_DIR))
+                .map_err(errors::esp_full)
+                .context("applying filesystem changes")?;
+            let (manifest, sig) = crate::espmanifest::render(&updatemeta, &tree)?;
+            let manifest_path = format!("{vendordir}/{}", crate::espmanifest::MANIFEST_NAME);
+            esp.write_file(&manifest_path, &manifest)?;
+            let sig_path = format!("{vendordir}/{}", crate::espmanifest::SIGNATURE_NAME);
+            match sig {
+                Some(sig) => esp.write_file(&sig_path, &sig)?,
+                None => esp.remove_file_optional(&sig_path)?,
+            }
+            esp.close()?;
+            let device = device.to_string_lossy();
+            match crate::blockdev::Topology::get().and_then(|t| t.parent_disk(&device)) {
+                Ok(disk) => disks.push(disk.to_string()),
+                Err(e) => log::debug!("Failed to find the disk of {device}: {e:#}"),
+            }
+            updatef = Some(tree);
+        }
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: updatef,
+            adopted_from: None,
+            devices: crate::blockdev::stable_ids(&disks),
+        })
+    }
+
+    /// The update payload directory and its tree, and what updating
+    /// `current` changes in the ESP `esp`, failing if the new loader may
+    /// not be installed.
+    fn update_diff(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+        esp: &EspAccess,
+    ) -> Result<(openat::Dir, filetree::FileTree, filetree::FileTreeDiff)> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let mut updatef =
+            crate::digestcache::payload_tree(&updated).context("reading update dir")?;
+        let diff = if let Some(manager) = entries::entry_manager(&openat::Dir::open("/")?)? {
+            let Some(destdir) = esp.mounted() else {
+                bail!("Boot entries are managed by {manager}, which isn't supported when updating the ESP directly");
+            };
+            log::info!("Boot entries are managed by {manager}; only updating binaries");
+            let mut currentf = currentf.clone();
+            entries::strip_entry_configs(&mut currentf);
+            entries::strip_entry_configs(&mut updatef);
+            let diff = esp_diff(esp, &currentf, &updatef)?;
+            entries::check_conflicts(&manager, &diff, Some(&currentf), destdir)?;
+            diff
+        } else {
+            esp_diff(esp, currentf, &updatef)?
+        };
+        self.check_loader_update(sysroot, &updated, &diff)?;
+        Ok((updated, updatef, diff))
+    }
+
+    /// Find the first stage loader in the update payload, returning the
+    /// vendor directory containing it and its file name.
+    fn find_loader(&self, sysroot: &openat::Dir) -> Result<(String, &'static str)> {
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let updated = updated.recover_path()?;
+        let loaders = profile_for(sysroot)?.loaders;
+        for &loader in loaders {
+            let files = find_file_recursive(&updated, loader)?;
+            // Does not support multiple loaders for efi
+            if files.len() > 1 {
+                anyhow::bail!("Found multiple {loader} in the image");
+            }
+            if let Some(p) = files.first() {
+                let p = p
+                    .parent()
+                    .unwrap()
+                    .file_name()
+                    .ok_or_else(|| anyhow::anyhow!("No file name found"))?;
+                return Ok((p.to_string_lossy().into_owned(), loader));
+            }
+        }
+        anyhow::bail!("Failed to find {} in the image", loaders.join(" or "))
     }
 }
 
@@ -241,11 +440,87 @@ fn skip_systemd_bootloaders() -> bool {
     false
 }
 
+/// The loaders recognized in the ESP by their file names, preferred first.
+const KNOWN_LOADERS: &[(&str, &str)] = &[
+    ("shim", "shim"),
+    ("grub", "GRUB"),
+    ("systemd-boot", "systemd-boot"),
+];
+
+/// The loader in the `EFI` directory `efidir` of the ESP the firmware most
+/// likely boots, e.g. shim rather than the GRUB it chainloads.
+fn find_loader(efidir: &Path) -> Result<Option<DetectedBootloader>> {
+    let mut found = Vec::new();
+    for vendor in fs::read_dir(efidir).with_context(|| format!("Reading {efidir:?}"))? {
+        let vendor = vendor?;
+        if !vendor.file_type()?.is_dir() {
+            continue;
+        }
+        for f in fs::read_dir(vendor.path())? {
+            let f = f?.file_name();
+            let name = f.to_string_lossy().to_ascii_lowercase();
+            if !name.ends_with(".efi") {
+                continue;
+            }
+            if let Some(i) = KNOWN_LOADERS.iter().position(|(p, _)| name.starts_with(p)) {
+                let path = format!(
+                    "EFI/{}/{}",
+                    vendor.file_name().to_string_lossy(),
+                    f.to_string_lossy()
+                );
+                found.push((i, path));
+            }
+        }
+    }
+    found.sort();
+    Ok(found
+        .into_iter()
+        .next()
+        .map(|(i, path)| DetectedBootloader {
+            name: KNOWN_LOADERS[i].1.to_string(),
+            location: Some(path),
+        }))
+}
+
 impl Component for Efi {
     fn name(&self) -> &'static str {
         "EFI"
     }
 
+    fn probe_adopt(&self) -> Result<ComponentAdoption> {
+        // Only telling why, and what was found
+        let adoptable = self.query_adopt()?;
+        if self.open_esp_optional()?.is_none() {
+            return Ok(ComponentAdoption::not_adoptable("No ESP found"));
+        }
+        let efidir = self.esp_path()?;
+        let mut detected = find_loader(&efidir)?;
+        let mut r = match adoptable {
+            Some(adoptable) => {
+                let mut r = ComponentAdoption::new(AdoptionState::Adoptable(adoptable));
+                r.actions
+                    .push(AdoptionAction::ReplaceFiles { path: efidir });
+                r
+            }
+            // As reported by itself
+            None => match get_loader_info().or_else(get_stub_info) {
+                Some(loader) if skip_systemd_bootloaders() => {
+                    let r = ComponentAdoption::not_adoptable(format!(
+                        "Booted via {loader}, which is managed with bootctl"
+                    ));
+                    detected = Some(DetectedBootloader {
+                        name: loader,
+                        location: None,
+                    });
+                    r
+                }
+                _ => ComponentAdoption::not_adoptable("No existing installation found"),
+            },
+        };
+        r.detected = detected;
+        Ok(r)
+    }
+
     fn query_adopt(&self) -> Result<Option<Adoptable>> {
         let esp = self.open_esp_optional()?;
         if esp.is_none() {
@@ -276,15 +551,25 @@ impl Component for Efi {
         let updated = sysroot
             .sub_dir(&component_updatedirname(self))
             .context("opening update dir")?;
-        let updatef = filetree::FileTree::new_from_dir(&updated).context("reading update dir")?;
+        let mut updatef =
+            crate::digestcache::payload_tree(&updated).context("reading update dir")?;
+        if entries::entry_manager(sysroot)?.is_some() {
+            entries::strip_entry_configs(&mut updatef);
+        }
         // For adoption, we should only touch files that we know about.
         let diff = updatef.relative_diff_to(&esp)?;
+        self.check_loader_update(sysroot, &updated, &diff)?;
         log::trace!("applying adoption diff: {}", &diff);
-        filetree::apply_diff(&updated, &esp, &diff, None).context("applying filesystem changes")?;
+        filetree::apply_diff(&updated, &esp, &diff, Some(&self.opts.apply_options()))
+            .map_err(errors::esp_full)
+            .context("applying filesystem changes")?;
+        let (vendordir, _) = self.find_loader(sysroot)?;
+        crate::espmanifest::write(&esp, &vendordir, updatemeta, &updatef)?;
         Ok(InstalledContent {
             meta: updatemeta.clone(),
             filetree: Some(updatef),
             adopted_from: Some(meta.version),
+            devices: Self::esp_disk_ids(&esp),
         })
     }
 
@@ -293,43 +578,57 @@ impl Component for Efi {
         &self,
         src_root: &openat::Dir,
         dest_root: &str,
-        device: &str,
+        _device: Option<&Path>,
         update_firmware: bool,
     ) -> Result<InstalledContent> {
         let Some(meta) = get_component_update(src_root, self)? else {
-            anyhow::bail!("No update metadata for component {} found", self.name());
+            return Err(crate::errors::Error::payload_missing(self.name()).into());
         };
         log::debug!("Found metadata {}", meta.version);
         let srcdir_name = component_updatedirname(self);
-        let ft = crate::filetree::FileTree::new_from_dir(&src_root.sub_dir(&srcdir_name)?)?;
+        let srcdir = src_root.sub_dir(&srcdir_name)?;
+        let mut ft = crate::filetree::FileTree::new_from_dir(&srcdir)?;
         let destdir = &self.ensure_mounted_esp(Path::new(dest_root))?;
 
         let destd = &openat::Dir::open(destdir)
             .with_context(|| format!("opening dest dir {}", destdir.display()))?;
         validate_esp(destd)?;
 
-        // TODO - add some sort of API that allows directly setting the working
-        // directory to a file descriptor.
-        let r = std::process::Command::new("cp")
-            .args(["-rp", "--reflink=auto"])
-            .arg(&srcdir_name)
-            .arg(destdir)
-            .current_dir(format!("/proc/self/fd/{}", src_root.as_raw_fd()))
-            .status()?;
-        if !r.success() {
-            anyhow::bail!("Failed to copy");
+        if let Some(manager) = entries::entry_manager(src_root)? {
+            // Only install the binaries, next to the existing entries
+            entries::strip_entry_configs(&mut ft);
+            let empty = filetree::FileTree {
+                children: Default::default(),
+            };
+            let diff = empty.diff(&ft)?;
+            destd.ensure_dir_all("EFI", 0o755)?;
+            let efidir = destd.sub_dir("EFI")?;
+            entries::check_conflicts(&manager, &diff, None, &efidir)?;
+            filetree::apply_diff(&srcdir, &efidir, &diff, Some(&self.opts.apply_options()))
+                .map_err(errors::esp_full)
+                .context("applying filesystem changes")?;
+        } else {
+            // Copy in a stable order, as that determines the layout of the
+            // directories on the ESP.
+            copy_dir_all(&srcdir.recover_path()?, &destdir.join("EFI"))
+                .map_err(errors::esp_full)
+                .context("Failed to copy")?;
         }
 
         #[cfg(target_arch = "x86_64")]
         {
-            let source = Path::new("/usr/lib64/grub/x86_64-efi");
-            let destination = Path::new(dest_root).join("boot/grub/x86_64-efi");
-
-            if !source.exists() {
-                bail!("Source directory {:?} not found", source);
-            }
+            let profile = profile_for(src_root)?;
+            let source = &profile.grub_modules_dir(&src_root.recover_path()?, "x86_64-efi")?;
+            let boot_dir = Path::new(dest_root).join("boot");
+            let destination = boot_dir
+                .join(profile.boot_grub_dir(&boot_dir)?)
+                .join("x86_64-efi");
 
             copy_dir_all(&source, &destination)?;
+            util::syncfs(&openat::Dir::open(&destination)?)?;
+            if self.opts.verify_writes {
+                crate::readback::verify_copy(source, &destination)?;
+            }
             log::info!(
                 "Directory {:?} successfully copied to {:?}",
                 source,
@@ -337,15 +636,23 @@ impl Component for Efi {
             );
         }
 
+        let (vendordir, loader) = self.find_loader(src_root)?;
+        crate::espmanifest::write(&destd.sub_dir("EFI")?, &vendordir, &meta, &ft)?;
+
+        // The firmware entries must only point at files on disk
+        util::syncfs(destd)?;
+        if self.opts.verify_writes {
+            let files = ft.children.iter().map(|(k, v)| (k.as_str(), v));
+            crate::readback::verify_files(&destd.sub_dir("EFI")?, files)?;
+        }
         if update_firmware {
-            if let Some(vendordir) = self.get_efi_vendor(&src_root)? {
-                self.update_firmware(device, destd, &vendordir)?
-            }
+            self.update_firmware(destd, &vendordir, loader)?
         }
         Ok(InstalledContent {
             meta,
             filetree: Some(ft),
             adopted_from: None,
+            devices: Vec::new(),
         })
     }
 
@@ -354,30 +661,81 @@ impl Component for Efi {
         sysroot: &openat::Dir,
         current: &InstalledContent,
     ) -> Result<InstalledContent> {
-        let currentf = current
-            .filetree
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+        #[cfg(have_fat)]
+        if self.opts.direct_esp {
+            return self.run_update_direct(sysroot, current);
+        }
         let updatemeta = self.query_update(sysroot)?.expect("update available");
-        let updated = sysroot
-            .sub_dir(&component_updatedirname(self))
-            .context("opening update dir")?;
-        let updatef = filetree::FileTree::new_from_dir(&updated).context("reading update dir")?;
-        let diff = currentf.diff(&updatef)?;
-        self.ensure_mounted_esp(Path::new("/"))?;
-        let destdir = self.open_esp().context("opening EFI dir")?;
-        validate_esp(&destdir)?;
+        let destdir = self.open_esp_checked()?;
+        let (updated, updatef, diff) =
+            self.update_diff(sysroot, current, &EspAccess::Mounted(&destdir))?;
         log::trace!("applying diff: {}", &diff);
-        filetree::apply_diff(&updated, &destdir, &diff, None)
+        // The fallback loader in BOOT loads from the vendor directory, so
+        // it's exchanged last, once the vendor directory is complete.  The
+        // payload digests may come from the cache, so check them against
+        // what's actually copied.
+        let opts = filetree::ApplyUpdateOptions {
+            last: Some(FALLBACK_DIR),
+            expected: Some(&updatef),
+            ..self.opts.apply_options()
+        };
+        filetree::apply_diff(&updated, &destdir, &diff, Some(&opts))
+            .map_err(errors::esp_full)
             .context("applying filesystem changes")?;
+        let (vendordir, _) = self.find_loader(sysroot)?;
+        crate::espmanifest::write(&destdir, &vendordir, &updatemeta, &updatef)?;
         let adopted_from = None;
         Ok(InstalledContent {
             meta: updatemeta,
             filetree: Some(updatef),
             adopted_from,
+            devices: Self::esp_disk_ids(&destdir),
         })
     }
 
+    fn plan_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<Option<Vec<crate::plan::Action>>> {
+        use crate::plan::Action;
+
+        let updatemeta = self.query_update(sysroot)?.expect("update available");
+        let destdir = self.open_esp_checked()?;
+        let (_, updatef, diff) =
+            self.update_diff(sysroot, current, &EspAccess::Mounted(&destdir))?;
+        let esp = self.esp_path()?;
+        let (writes, removals) = filetree::plan_diff(&esp, &diff)?;
+        let mut actions = Vec::new();
+        for path in writes {
+            let meta = updatef
+                .children
+                .get(path.as_str())
+                .ok_or_else(|| anyhow::anyhow!("Missing {path} in update"))?;
+            actions.push(Action::WriteFile {
+                path: esp.join(path),
+                size: Some(meta.size),
+            });
+        }
+        actions.extend(removals.into_iter().map(|path| Action::DeleteFile {
+            path: esp.join(path),
+        }));
+        let (vendordir, _) = self.find_loader(sysroot)?;
+        let vendordir = esp.join(vendordir);
+        let (manifest, sig) = crate::espmanifest::render(&updatemeta, &updatef)?;
+        actions.push(Action::WriteFile {
+            path: vendordir.join(crate::espmanifest::MANIFEST_NAME),
+            size: Some(manifest.len() as u64),
+        });
+        let path = vendordir.join(crate::espmanifest::SIGNATURE_NAME);
+        actions.push(match sig {
+            // Signatures differ in length by a byte or two
+            Some(_) => Action::WriteFile { path, size: None },
+            None => Action::DeleteFile { path },
+        });
+        Ok(Some(actions))
+    }
+
     fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
         let ostreebootdir = Path::new(sysroot_path).join(ostreeutil::BOOT_PREFIX);
         let dest_efidir = component_updatedir(sysroot_path, self);
@@ -398,17 +756,19 @@ impl Component for Efi {
 
             // Fork off mv() because on overlayfs one can't rename() a lower level
             // directory today, and this will handle the copy fallback.
-            Command::new("mv").args([&efisrc, &dest_efidir]).run()?;
+            if util::have_program("mv") {
+                crate::util::command("mv")
+                    .args([&efisrc, &dest_efidir])
+                    .run()?;
+            } else {
+                crate::native::move_tree(&efisrc, &dest_efidir)?;
+            }
         }
 
-        let efidir = openat::Dir::open(&dest_efidir)?;
-        let files = crate::util::filenames(&efidir)?.into_iter().map(|mut f| {
-            f.insert_str(0, "/boot/efi/EFI/");
-            f
-        });
+        let profile = crate::distro::Profile::detect(Path::new(sysroot_path))?;
+        let files = profile.esp_query_paths(&dest_efidir)?;
 
-        let meta = packagesystem::query_files(sysroot_path, files)?;
-        write_update_metadata(sysroot_path, self, &meta)?;
+        let meta = write_packaged_update_metadata(sysroot_path, self, files)?;
         Ok(meta)
     }
 
@@ -417,7 +777,7 @@ impl Component for Efi {
     }
 
     fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
-        if !is_efi_booted()? && self.get_esp_device().is_none() {
+        if !crate::firmware::is_efi_booted()? && self.get_esp_device().is_none() {
             return Ok(ValidationResult::Skip);
         }
         let currentf = current
@@ -426,7 +786,9 @@ impl Component for Efi {
             .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
         self.ensure_mounted_esp(Path::new("/"))?;
         let efidir = self.open_esp()?;
-        let diff = currentf.relative_diff_to(&efidir)?;
+        let diff = currentf.relative_diff_with(&efidir, |files| {
+            crate::digestcache::esp_metadata(&efidir, files, self.opts.thorough)
+        })?;
         let mut errs = Vec::new();
         for f in diff.changes.iter() {
             errs.push(format!("Changed: {}", f));
@@ -442,26 +804,84 @@ impl Component for Efi {
         }
     }
 
-    fn get_efi_vendor(&self, sysroot: &openat::Dir) -> Result<Option<String>> {
+    fn inspect(&self, _: &[PathBuf]) -> Result<crate::consistency::Inspection> {
+        let mut r = crate::consistency::Inspection::default();
+        if !crate::firmware::is_efi_booted()? && self.get_esp_device().is_none() {
+            return Ok(r);
+        }
+        // The loaders at the paths of the payload, as the saved summary of
+        // the installed files has no filetree, and those of other vendors
+        // don't matter
+        let sysroot = openat::Dir::open("/")?;
+        let Some(updated) = sysroot.sub_dir_optional(&component_updatedirname(self))? else {
+            return Ok(r);
+        };
+        let mut loaders = Vec::new();
+        for vendor in fs::read_dir(updated.recover_path()?)? {
+            let vendor = vendor?;
+            if !vendor.file_type()?.is_dir() {
+                continue;
+            }
+            for f in fs::read_dir(vendor.path())? {
+                let path = format!(
+                    "{}/{}",
+                    vendor.file_name().to_string_lossy(),
+                    f?.file_name().to_string_lossy()
+                );
+                if crate::consistency::is_loader(&path) {
+                    loaders.push(path);
+                }
+            }
+        }
+        let efidir = self.open_esp()?;
+        for path in loaders {
+            // Missing files are for validation to report
+            let Some(f) = efidir.open_file_optional(&path)? else {
+                continue;
+            };
+            let mut buf = Vec::new();
+            std::io::Read::read_to_end(&mut std::io::BufReader::new(f), &mut buf)
+                .with_context(|| format!("Reading {path}"))?;
+            r.loaders.insert(path, buf);
+        }
+        Ok(r)
+    }
+
+    fn fingerprint_installed(&self, sysroot: &openat::Dir) -> Result<Option<InstalledContent>> {
+        let Some(esp) = self.open_esp_optional()? else {
+            return Ok(None);
+        };
+        let Some(updatemeta) = self.query_update(sysroot)? else {
+            return Err(errors::Error::payload_missing(self.name()).into());
+        };
         let updated = sysroot
             .sub_dir(&component_updatedirname(self))
             .context("opening update dir")?;
-        let shim_files = find_file_recursive(updated.recover_path()?, SHIM)?;
-
-        // Does not support multiple shim for efi
-        if shim_files.len() > 1 {
-            anyhow::bail!("Found multiple {SHIM} in the image");
-        }
-        if let Some(p) = shim_files.first() {
-            let p = p
-                .parent()
-                .unwrap()
-                .file_name()
-                .ok_or_else(|| anyhow::anyhow!("No file name found"))?;
-            Ok(Some(p.to_string_lossy().into_owned()))
-        } else {
-            anyhow::bail!("Failed to find {SHIM} in the image")
+        let mut updatef =
+            crate::digestcache::payload_tree(&updated).context("reading update dir")?;
+        if entries::entry_manager(sysroot)?.is_some() {
+            entries::strip_entry_configs(&mut updatef);
         }
+        let Some(inst) = fingerprint_tree(&updatef, updatemeta, &esp)? else {
+            return Ok(None);
+        };
+        Ok(Some(InstalledContent {
+            devices: Self::esp_disk_ids(&esp),
+            ..inst
+        }))
+    }
+
+    fn get_efi_vendor(&self, sysroot: &openat::Dir) -> Result<Option<String>> {
+        self.find_loader(sysroot)
+            .map(|(vendordir, _)| Some(vendordir))
+    }
+
+    fn update_firmware_entries(&self, sysroot: &openat::Dir) -> Result<()> {
+        let (vendordir, loader) = self.find_loader(sysroot)?;
+        let espmount = self.ensure_mounted_esp(Path::new("/"))?;
+        let espdir = openat::Dir::open(&espmount)
+            .with_context(|| format!("opening {}", espmount.display()))?;
+        self.update_firmware(&espdir, &vendordir, loader)
     }
 }
 
@@ -472,8 +892,9 @@ fn copy_dir_all(src: &Path, dest: &Path) -> Result<()> {
 
     fs::create_dir_all(dest)?;
 
-    for entry_result in fs::read_dir(src)? {
-        let entry = entry_result?;
+    let mut entries = fs::read_dir(src)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
         let file_type = entry.file_type()?;
         let src_path = entry.path();
         let dest_path = dest.join(entry.file_name());
@@ -481,7 +902,14 @@ fn copy_dir_all(src: &Path, dest: &Path) -> Result<()> {
         if file_type.is_dir() {
             copy_dir_all(&src_path, &dest_path)?;
         } else if file_type.is_file() {
-            fs::copy(&src_path, &dest_path)?;
+            util::copy_file(&src_path, &dest_path)?;
+            crate::events::file_written(&dest_path);
+            // Like `cp -p`
+            let mtime = entry.metadata()?.modified()?;
+            fs::File::options()
+                .write(true)
+                .open(&dest_path)?
+                .set_modified(mtime)?;
         } else {
             // Handle other file types (symlinks, etc.) if necessary
             log::warn!("Warning: Unsupported file type: {:?}", src_path);
@@ -498,7 +926,57 @@ impl Drop for Efi {
     }
 }
 
+/// The `EFI` directory of an ESP, as it's accessed.
+enum EspAccess<'a> {
+    /// Through the filesystem mounted by the kernel
+    Mounted(&'a openat::Dir),
+    /// Directly on its partition device
+    #[cfg(have_fat)]
+    Direct(&'a crate::fatesp::FatEsp),
+}
+
+impl EspAccess<'_> {
+    fn mounted(&self) -> Option<&openat::Dir> {
+        match self {
+            EspAccess::Mounted(d) => Some(d),
+            #[cfg(have_fat)]
+            EspAccess::Direct(_) => None,
+        }
+    }
+
+    /// The files of `tree` which exist in the ESP, as they are there.
+    fn subset(&self, tree: &filetree::FileTree) -> Result<filetree::FileTree> {
+        match self {
+            EspAccess::Mounted(d) => tree.subset_in(d),
+            #[cfg(have_fat)]
+            EspAccess::Direct(esp) => esp.subset(tree),
+        }
+    }
+}
+
+/// What updating the ESP `esp` from `currentf` to `updatef` changes in
+/// it.  This is computed from the files actually on the ESP rather than
+/// from `currentf` alone, so that an ESP which differs from what was
+/// recorded still gets every file it needs.
+fn esp_diff(
+    esp: &EspAccess,
+    currentf: &filetree::FileTree,
+    updatef: &filetree::FileTree,
+) -> Result<filetree::FileTreeDiff> {
+    let mut tracked = currentf.clone();
+    tracked.children.extend(updatef.children.clone());
+    let installed = esp.subset(&tracked)?;
+    Ok(installed.diff(updatef)?)
+}
+
 fn validate_esp(dir: &openat::Dir) -> Result<()> {
+    if crate::simulate::active() {
+        let path = dir.recover_path()?;
+        if !crate::simulate::is_fat(&path) {
+            bail!("EFI mount {path:?} is not a simulated msdos filesystem");
+        }
+        return Ok(());
+    }
     let dir = unsafe { BorrowedFd::borrow_raw(dir.as_raw_fd()) };
     let stat = rustix::fs::fstatfs(&dir)?;
     if stat.f_type != libc::MSDOS_SUPER_MAGIC {
@@ -540,8 +1018,9 @@ fn parse_boot_entries(output: &str) -> Vec<BootEntry> {
 
 #[context("Clearing EFI boot entries that match target {target}")]
 pub(crate) fn clear_efi_target(target: &str) -> Result<()> {
+    let _t = crate::timing::start(crate::timing::Phase::Nvram);
     let target = target.to_lowercase();
-    let output = Command::new(EFIBOOTMGR).output()?;
+    let output = crate::util::command(EFIBOOTMGR).output()?;
     if !output.status.success() {
         anyhow::bail!("Failed to invoke {EFIBOOTMGR}")
     }
@@ -551,7 +1030,7 @@ pub(crate) fn clear_efi_target(target: &str) -> Result<()> {
     for entry in boot_entries {
         if entry.name.to_lowercase() == target {
             log::debug!("Deleting matched target {:?}", entry);
-            let output = Command::new(EFIBOOTMGR)
+            let output = crate::util::command(EFIBOOTMGR)
                 .args(["-b", entry.id.as_str(), "-B"])
                 .output()?;
             let st = output.status;
@@ -573,8 +1052,10 @@ pub(crate) fn create_efi_boot_entry(
     device: &str,
     espdir: &openat::Dir,
     vendordir: &str,
+    loader: &str,
     target: &str,
 ) -> Result<()> {
+    let _t = crate::timing::start(crate::timing::Phase::Nvram);
     let fsinfo = crate::filesystem::inspect_filesystem(espdir, ".")?;
     let source = fsinfo.source;
     let devname = source
@@ -584,13 +1065,13 @@ pub(crate) fn create_efi_boot_entry(
     let partition_path = format!("/sys/class/block/{devname}/partition");
     let partition_number = std::fs::read_to_string(&partition_path)
         .with_context(|| format!("Failed to read {partition_path}"))?;
-    let shim = format!("{vendordir}/{SHIM}");
-    if espdir.exists(&shim)? {
-        anyhow::bail!("Failed to find {SHIM}");
+    let loaderpath = format!("{vendordir}/{loader}");
+    if espdir.exists(&loaderpath)? {
+        anyhow::bail!("Failed to find {loader}");
     }
-    let loader = format!("\\EFI\\{}\\{SHIM}", vendordir);
+    let loader = crate::pathnorm::to_efi_path(&format!("EFI/{loaderpath}"));
     log::debug!("Creating new EFI boot entry using '{target}'");
-    let st = Command::new(EFIBOOTMGR)
+    let st = crate::util::command(EFIBOOTMGR)
         .args([
             "--create",
             "--disk",
@@ -739,4 +1220,64 @@ Boot0003* test";
         }
         Ok(())
     }
+
+    #[test]
+    fn test_find_loader() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let efidir = td.path();
+        assert_eq!(find_loader(efidir)?, None);
+        fs::create_dir_all(efidir.join("BOOT"))?;
+        fs::create_dir_all(efidir.join("fedora"))?;
+        fs::write(efidir.join("BOOT/BOOTX64.EFI"), "")?;
+        fs::write(efidir.join("fedora/grubx64.efi"), "")?;
+        let found = find_loader(efidir)?.unwrap();
+        assert_eq!(found.name, "GRUB");
+        // shim is what the firmware runs, chainloading GRUB
+        fs::write(efidir.join("fedora/SHIMX64.EFI"), "")?;
+        let found = find_loader(efidir)?.unwrap();
+        assert_eq!(found.name, "shim");
+        assert_eq!(found.location.as_deref(), Some("EFI/fedora/SHIMX64.EFI"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_esp_diff() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let payload = td.path().join("payload");
+        let esp = td.path().join("esp");
+        for d in [&payload, &esp] {
+            fs::create_dir_all(d.join("fedora"))?;
+            fs::write(d.join("fedora/shimx64.efi"), "shim")?;
+            fs::write(d.join("fedora/grubx64.efi"), "grub")?;
+            fs::write(d.join("fedora/mmx64.efi"), "mm")?;
+        }
+        let esp = openat::Dir::open(&esp)?;
+        let currentf = filetree::FileTree::new_from_dir(&esp)?;
+        fs::write(payload.join("fedora/grubx64.efi"), "grub2")?;
+        fs::remove_file(payload.join("fedora/mmx64.efi"))?;
+        let updatef = filetree::FileTree::new_from_path(&payload)?;
+        let diff = esp_diff(&EspAccess::Mounted(&esp), &currentf, &updatef)?;
+        assert_eq!(
+            diff.changes,
+            std::collections::BTreeSet::from(["fedora/grubx64.efi".to_string()])
+        );
+        assert_eq!(
+            diff.removals,
+            std::collections::BTreeSet::from(["fedora/mmx64.efi".to_string()])
+        );
+
+        // This ESP differs from what was recorded: shim was damaged, and
+        // the new GRUB is already there
+        fs::write(td.path().join("esp/fedora/shimx64.efi"), "damaged")?;
+        fs::write(td.path().join("esp/fedora/grubx64.efi"), "grub2")?;
+        fs::remove_file(td.path().join("esp/fedora/mmx64.efi"))?;
+        let diff = esp_diff(&EspAccess::Mounted(&esp), &currentf, &updatef)?;
+        assert_eq!(
+            diff.changes,
+            std::collections::BTreeSet::from(["fedora/shimx64.efi".to_string()])
+        );
+        assert!(diff.removals.is_empty());
+        assert!(diff.additions.is_empty());
+        Ok(())
+    }
 }
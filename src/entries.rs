@@ -0,0 +1,124 @@
+//! Coexistence with an external boot entry manager, such as the NixOS
+//! generation tooling which rewrites the bootloader configuration for each
+//! system generation.
+//!
+//! An OS opts into this mode by shipping [`ENTRY_MANAGER_PATH`], containing
+//! the name of the tool managing the entries.  bootupd then only manages
+//! the bootloader binaries: entry configuration is dropped from the update
+//! payloads, and updates refuse to overwrite files on the ESP which bootupd
+//! didn't install.
+
+use std::collections::BTreeSet;
+
+use anyhow::{Context, Result};
+use openat_ext::OpenatDirExt;
+
+use crate::filetree::{FileTree, FileTreeDiff};
+
+/// If present, names the external tool managing the boot entries.
+pub(crate) const ENTRY_MANAGER_PATH: &str = "usr/lib/bootupd/entry-manager";
+
+/// File names of bootloader entry configuration.
+const ENTRY_CONFIG_NAMES: &[&str] = &["grub.cfg", "grubenv", "bootuuid.cfg", "loader.conf"];
+
+/// Return the name of the external entry manager declared by the OS in `root`.
+pub(crate) fn entry_manager(root: &openat::Dir) -> Result<Option<String>> {
+    let Some(name) = root
+        .read_to_string_optional(ENTRY_MANAGER_PATH)
+        .with_context(|| format!("Reading {ENTRY_MANAGER_PATH}"))?
+    else {
+        return Ok(None);
+    };
+    let name = name.trim();
+    if name.is_empty() {
+        anyhow::bail!("{ENTRY_MANAGER_PATH} is empty");
+    }
+    Ok(Some(name.to_string()))
+}
+
+/// Whether `path` (relative to the payload root) is entry configuration.
+pub(crate) fn is_entry_config(path: &str) -> bool {
+    let mut components = path.split('/');
+    let name = components.next_back().unwrap_or_default();
+    ENTRY_CONFIG_NAMES.contains(&name) || components.any(|c| c == "entries")
+}
+
+/// Drop all entry configuration from `tree`.
+pub(crate) fn strip_entry_configs(tree: &mut FileTree) {
+    tree.children.retain(|k, _| !is_entry_config(k));
+}
+
+/// Error out if applying `diff` to `dest` would overwrite files which are
+/// not tracked in `current`, i.e. which bootupd didn't install.
+pub(crate) fn check_conflicts(
+    manager: &str,
+    diff: &FileTreeDiff,
+    current: Option<&FileTree>,
+    dest: &openat::Dir,
+) -> Result<()> {
+    let mut conflicts = BTreeSet::new();
+    for path in diff.additions.iter().chain(diff.changes.iter()) {
+        if current.is_some_and(|t| t.children.contains_key(path)) {
+            continue;
+        }
+        if is_entry_config(path) || dest.exists(path.as_str())? {
+            conflicts.insert(path.as_str());
+        }
+    }
+    if !conflicts.is_empty() {
+        let conflicts = conflicts.into_iter().collect::<Vec<_>>();
+        anyhow::bail!(
+            "Refusing to overwrite files managed by {manager}: {}",
+            conflicts.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_is_entry_config() {
+        assert!(is_entry_config("fedora/grub.cfg"));
+        assert!(is_entry_config("loader/entries/nixos-generation-42.conf"));
+        assert!(is_entry_config("grubenv"));
+        assert!(!is_entry_config("nixos/grubx64.efi"));
+        assert!(!is_entry_config("BOOT/BOOTX64.EFI"));
+    }
+
+    #[test]
+    fn test_check_conflicts() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let src = td.path().join("src");
+        let dest = td.path().join("dest");
+        fs::create_dir_all(src.join("nixos"))?;
+        fs::create_dir_all(dest.join("nixos"))?;
+        fs::write(src.join("nixos/grubx64.efi"), "grub")?;
+        let srcd = openat::Dir::open(&src)?;
+        let destd = openat::Dir::open(&dest)?;
+        let empty = FileTree {
+            children: Default::default(),
+        };
+        let updated = FileTree::new_from_dir(&srcd)?;
+        let diff = empty.diff(&updated)?;
+        check_conflicts("nixos", &diff, None, &destd)?;
+
+        // A file installed by the entry manager
+        fs::write(dest.join("nixos/grubx64.efi"), "other grub")?;
+        let e = check_conflicts("nixos", &diff, None, &destd).unwrap_err();
+        assert!(e.to_string().contains("nixos/grubx64.efi"));
+        // ...but it's fine to update our own files
+        check_conflicts("nixos", &diff, Some(&updated), &destd)?;
+
+        fs::write(src.join("nixos/grub.cfg"), "config")?;
+        let mut with_config = FileTree::new_from_dir(&srcd)?;
+        let diff = empty.diff(&with_config)?;
+        assert!(check_conflicts("nixos", &diff, Some(&updated), &destd).is_err());
+        strip_entry_configs(&mut with_config);
+        assert_eq!(with_config, updated);
+        Ok(())
+    }
+}
@@ -0,0 +1,341 @@
+//! Machine-readable error reporting.
+
+use std::fmt;
+use std::process::ExitStatus;
+
+use serde::Serialize;
+
+/// Error context identifying the component (and device) an operation
+/// was acting on; attach it via `anyhow::Context`.
+#[derive(Debug, Clone)]
+pub(crate) struct ComponentContext {
+    pub(crate) component: String,
+    pub(crate) operation: &'static str,
+    pub(crate) device: Option<String>,
+}
+
+impl ComponentContext {
+    pub(crate) fn new(component: impl Into<String>, operation: &'static str) -> Self {
+        Self {
+            component: component.into(),
+            operation,
+            device: None,
+        }
+    }
+
+    pub(crate) fn device(mut self, device: impl Into<String>) -> Self {
+        self.device = Some(device.into());
+        self
+    }
+}
+
+impl fmt::Display for ComponentContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to {} {}", self.operation, self.component)?;
+        if let Some(device) = self.device.as_deref() {
+            write!(f, " on {device}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A failure which users can act on, raised in place of a plain message
+/// so that the CLI can classify it reliably; context is added as usual.
+#[derive(Debug)]
+pub(crate) struct Error {
+    pub(crate) kind: ErrorKind,
+    message: String,
+    /// The error output of a failed external command
+    stderr: Option<String>,
+}
+
+impl Error {
+    pub(crate) fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            stderr: None,
+        }
+    }
+
+    /// The OS image has no update payload for `component`.
+    pub(crate) fn payload_missing(component: &str) -> Self {
+        Self::new(
+            ErrorKind::PayloadMissing,
+            format!("No update metadata for component {component} found"),
+        )
+    }
+
+    /// The external command `command` exited with `status`, having written
+    /// `stderr`.
+    pub(crate) fn tool_failed(command: String, status: ExitStatus, stderr: String) -> Self {
+        // Still busy after retrying
+        let kind = if crate::util::is_transient(&stderr) {
+            ErrorKind::DeviceBusy
+        } else {
+            ErrorKind::ExternalToolFailed
+        };
+        Self {
+            kind,
+            message: format!("{command} failed with {status}"),
+            stderr: Some(stderr),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Add an [`ErrorKind::EspFull`] error to `e` if it was caused by running
+/// out of space.
+#[cfg(have_efi)]
+pub(crate) fn esp_full(e: anyhow::Error) -> anyhow::Error {
+    match errno(&e).and_then(ErrorKind::from_errno) {
+        Some(ErrorKind::NoSpace) => {
+            e.context(Error::new(ErrorKind::EspFull, "No space left on the ESP"))
+        }
+        _ => e,
+    }
+}
+
+/// The OS error which caused `e`, if any.
+pub(crate) fn errno(e: &anyhow::Error) -> Option<i32> {
+    e.chain().find_map(|cause| {
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            e.raw_os_error()
+        } else {
+            cause
+                .downcast_ref::<rustix::io::Errno>()
+                .map(|e| e.raw_os_error())
+        }
+    })
+}
+
+/// The kind of a failure, as classified in the JSON error reports of
+/// `bootupctl --error-format=json`; stable for programmatic use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// A device, e.g. the ESP, could not be found
+    DeviceNotFound,
+    /// Writing to the ESP ran out of space
+    EspFull,
+    /// Secure Boot is enabled, and the loader isn't signed or trusted
+    SecureBootMismatch,
+    /// The saved state can't be parsed
+    StateCorrupt,
+    /// A path to write is on the read-only `/usr` of a booted ostree
+    /// deployment
+    ImmutableUsr,
+    /// An external command failed
+    ExternalToolFailed,
+    /// A filesystem ran out of space
+    NoSpace,
+    /// A device was busy, even after retrying
+    DeviceBusy,
+    /// A privilege or permission the operation needs is missing
+    PermissionDenied,
+    /// A filesystem to write is read-only
+    ReadOnly,
+    /// A file or device doesn't exist
+    NotFound,
+    /// The OS image has no update payload for the component
+    PayloadMissing,
+    /// What was read back after writing doesn't match what was written
+    ReadbackMismatch,
+    /// The operation was interrupted by a signal
+    Cancelled,
+    /// Any other failure
+    Other,
+}
+
+impl ErrorKind {
+    fn from_errno(errno: i32) -> Option<Self> {
+        let r = match errno {
+            libc::ENOSPC | libc::EDQUOT => ErrorKind::NoSpace,
+            libc::EBUSY => ErrorKind::DeviceBusy,
+            libc::EPERM | libc::EACCES => ErrorKind::PermissionDenied,
+            libc::EROFS => ErrorKind::ReadOnly,
+            libc::ENOENT => ErrorKind::NotFound,
+            _ => return None,
+        };
+        Some(r)
+    }
+
+    /// A short remediation hint for users.
+    pub fn hint(&self) -> Option<&'static str> {
+        let r = match self {
+            ErrorKind::DeviceNotFound => {
+                "Check that the boot disks are attached and visible in `lsblk`"
+            }
+            ErrorKind::EspFull => {
+                "Remove unneeded files (e.g. other vendors' loaders) from the ESP and retry"
+            }
+            ErrorKind::SecureBootMismatch => {
+                "Install a signed loader (e.g. shim), or disable Secure Boot in the firmware"
+            }
+            ErrorKind::StateCorrupt => {
+                "Run `bootupctl state rebuild` to reconstruct it from the installed files"
+            }
+            ErrorKind::ImmutableUsr => {
+                "Generate update metadata when building the OS image, e.g. in its container build"
+            }
+            ErrorKind::ExternalToolFailed => {
+                "Check the error output of the command; rerun with `-vv` for details"
+            }
+            ErrorKind::NoSpace => "Free up space on the target filesystem (e.g. the ESP) and retry",
+            ErrorKind::DeviceBusy => "Another process is using the device; retry once it is idle",
+            ErrorKind::PermissionDenied => "Run as root with full privileges",
+            ErrorKind::ReadOnly => "Ensure the target filesystem can be mounted read-write",
+            ErrorKind::NotFound => "Check that the expected files and devices are present",
+            ErrorKind::PayloadMissing => {
+                "Run `bootupctl backend generate-update-metadata` when building the OS image"
+            }
+            ErrorKind::ReadbackMismatch => {
+                "The boot media may be failing; check or replace it (e.g. the SD card) and retry"
+            }
+            ErrorKind::Cancelled => "The operation was interrupted; rerun it to complete",
+            ErrorKind::Other => return None,
+        };
+        Some(r)
+    }
+}
+
+/// A structured description of a failed operation.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ErrorReport {
+    pub(crate) class: ErrorKind,
+    pub(crate) message: String,
+    pub(crate) causes: Vec<String>,
+    pub(crate) component: Option<String>,
+    pub(crate) device: Option<String>,
+    pub(crate) errno: Option<i32>,
+    /// The error output of a failed external command
+    pub(crate) stderr: Option<String>,
+    pub(crate) hint: Option<&'static str>,
+}
+
+/// The kind of `e`: that of the typed error in it if any, or else of the
+/// OS error which caused it.
+pub(crate) fn classify(e: &anyhow::Error) -> ErrorKind {
+    e.downcast_ref::<Error>()
+        .map(|e| e.kind)
+        .or_else(|| errno(e).and_then(ErrorKind::from_errno))
+        .unwrap_or(ErrorKind::Other)
+}
+
+/// The error output of the failed external command in `e`, if any.
+pub(crate) fn stderr(e: &anyhow::Error) -> Option<&str> {
+    e.downcast_ref::<Error>()
+        .and_then(|e| e.stderr.as_deref())
+        .filter(|s| !s.is_empty())
+}
+
+impl ErrorReport {
+    pub(crate) fn new(e: &anyhow::Error) -> Self {
+        let class = classify(e);
+        let ctx = e.downcast_ref::<ComponentContext>();
+        Self {
+            class,
+            message: format!("{e:#}"),
+            causes: e.chain().map(|c| c.to_string()).collect(),
+            component: ctx.map(|c| c.component.clone()),
+            device: ctx.and_then(|c| c.device.clone()),
+            errno: errno(e),
+            stderr: stderr(e).map(ToOwned::to_owned),
+            hint: class.hint(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_error_report() {
+        let e = std::io::Error::from_raw_os_error(libc::ENOSPC);
+        let e = Err::<(), _>(e)
+            .context("copying grubx64.efi")
+            .with_context(|| ComponentContext::new("EFI", "update").device("/dev/vda"))
+            .context("update failed")
+            .unwrap_err();
+        let r = ErrorReport::new(&e);
+        assert_eq!(r.class, ErrorKind::NoSpace);
+        assert_eq!(r.errno, Some(libc::ENOSPC));
+        assert_eq!(r.component.as_deref(), Some("EFI"));
+        assert_eq!(r.device.as_deref(), Some("/dev/vda"));
+        assert_eq!(r.causes[1], "Failed to update EFI on /dev/vda");
+        assert!(r.hint.is_some());
+
+        let e = anyhow::Error::from(Error::payload_missing("EFI"));
+        let r = ErrorReport::new(&e);
+        assert_eq!(r.class, ErrorKind::PayloadMissing);
+        assert_eq!(r.component, None);
+        let v = serde_json::to_value(&r).unwrap();
+        assert_eq!(v["class"], "payload-missing");
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        let e = crate::util::cmd_output(
+            std::process::Command::new("sh").args(["-c", "echo oops >&2; exit 3"]),
+        )
+        .context("Reading block devices")
+        .unwrap_err();
+        let r = ErrorReport::new(&e);
+        assert_eq!(r.class, ErrorKind::ExternalToolFailed);
+        assert_eq!(r.stderr.as_deref(), Some("oops"));
+        let v = serde_json::to_value(&r).unwrap();
+        assert_eq!(v["class"], "external-tool-failed");
+        assert_eq!(v["stderr"], "oops");
+        let e = crate::util::cmd_output(
+            std::process::Command::new("sh")
+                .args(["-c", "echo 'Device or resource busy' >&2; exit 1"]),
+        )
+        .unwrap_err();
+        assert_eq!(ErrorReport::new(&e).class, ErrorKind::DeviceBusy);
+
+        let e = anyhow::Error::from(Error::new(
+            ErrorKind::DeviceNotFound,
+            "Failed to find the ESP device",
+        ));
+        assert_eq!(e.to_string(), "Failed to find the ESP device");
+        assert_eq!(ErrorReport::new(&e).class, ErrorKind::DeviceNotFound);
+
+        // Only typed errors are classified, whatever their message
+        let e = anyhow::anyhow!("Operation cancelled by signal 15");
+        assert_eq!(ErrorReport::new(&e).class, ErrorKind::Other);
+
+        let e = anyhow::Error::from(Error::new(
+            ErrorKind::ImmutableUsr,
+            "/usr/lib/bootupd/updates is read-only in this ostree deployment",
+        ));
+        let r = ErrorReport::new(&e);
+        assert_eq!(r.class, ErrorKind::ImmutableUsr);
+        assert_eq!(r.hint, ErrorKind::ImmutableUsr.hint());
+    }
+
+    #[test]
+    #[cfg(have_efi)]
+    fn test_esp_full() {
+        // Typed errors take precedence over the errno
+        let e = std::io::Error::from_raw_os_error(libc::ENOSPC);
+        let e = esp_full(anyhow::Error::from(e).context("copying shimx64.efi"))
+            .context("applying filesystem changes");
+        let r = ErrorReport::new(&e);
+        assert_eq!(r.class, ErrorKind::EspFull);
+        assert_eq!(r.errno, Some(libc::ENOSPC));
+        assert_eq!(r.hint, ErrorKind::EspFull.hint());
+        let e = esp_full(anyhow::anyhow!("copying shimx64.efi"));
+        assert!(e.downcast_ref::<Error>().is_none());
+    }
+}
@@ -0,0 +1,164 @@
+//! A manifest of the installed EFI files, kept on the ESP itself.
+//!
+//! The state recording what's installed lives in `/boot`, which recovery
+//! environments and attestation tools may not be able to read.  Whenever
+//! the EFI component is installed, adopted or updated, a manifest with the
+//! installed version and the digest of every file we own is written to the
+//! vendor directory, where it can be checked against the ESP alone.  If a
+//! private key is provisioned at [`SIGNING_KEY`], the manifest is signed
+//! with it too, in a detached signature as for payload bundles.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+use openssl::pkey::PKey;
+use serde::{Deserialize, Serialize};
+
+use crate::filetree::{FileMetadata, FileTree};
+use crate::model::ContentMetadata;
+
+/// The name of the manifest in the vendor directory.
+pub(crate) const MANIFEST_NAME: &str = "bootupd-manifest.json";
+/// The name of the detached manifest signature in the vendor directory.
+pub(crate) const SIGNATURE_NAME: &str = "bootupd-manifest.json.sig";
+/// The PEM private key the manifest is signed with, if present.
+const SIGNING_KEY: &str = "/etc/bootupd/esp-manifest-key.pem";
+/// The current manifest format.
+const MANIFEST_VERSION: u32 = 1;
+
+/// Describes the installed content of the ESP.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct EspManifest {
+    pub(crate) version: u32,
+    /// The installed EFI component
+    pub(crate) installed: ContentMetadata,
+    /// When the manifest was written
+    pub(crate) written: DateTime<Utc>,
+    /// The installed files, by path below `EFI` on the ESP
+    pub(crate) files: BTreeMap<String, FileMetadata>,
+}
+
+impl EspManifest {
+    fn new(installed: &ContentMetadata, tree: &FileTree) -> Result<Self> {
+        Ok(Self {
+            version: MANIFEST_VERSION,
+            installed: installed.clone(),
+            written: crate::util::build_time()?,
+            files: tree.children.clone(),
+        })
+    }
+}
+
+/// The manifest of `tree`, installed as `installed`, and its signature
+/// with `key_path` if it exists.
+fn render_in(
+    installed: &ContentMetadata,
+    tree: &FileTree,
+    key_path: &Path,
+) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+    let manifest = serde_json::to_vec_pretty(&EspManifest::new(installed, tree)?)?;
+    let sig = match std::fs::read(key_path) {
+        Ok(pem) => {
+            let key = PKey::private_key_from_pem(&pem)
+                .with_context(|| format!("Parsing {key_path:?}"))?;
+            Some(crate::payload::sign_manifest(&manifest, &key)?)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).with_context(|| format!("Reading {key_path:?}")),
+    };
+    Ok((manifest, sig))
+}
+
+/// The manifest of `tree`, installed as `installed`, and its signature if
+/// a key is provisioned; for writing the ESP other than through a mount,
+/// or planning an update.
+pub(crate) fn render(
+    installed: &ContentMetadata,
+    tree: &FileTree,
+) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+    render_in(installed, tree, Path::new(SIGNING_KEY))
+}
+
+/// Write the manifest of `tree`, installed as `installed`, to `vendor`
+/// in the `EFI` directory `efidir` of the ESP, signing it with `key_path`
+/// if it exists.
+fn write_in(
+    efidir: &openat::Dir,
+    vendor: &str,
+    installed: &ContentMetadata,
+    tree: &FileTree,
+    key_path: &Path,
+) -> Result<()> {
+    let (manifest, sig) = render_in(installed, tree, key_path)?;
+    let vendordir = efidir.sub_dir(vendor)?;
+    vendordir.write_file_with_sync(MANIFEST_NAME, 0o644, |w| w.write_all(&manifest))?;
+    match sig {
+        Some(sig) => {
+            vendordir.write_file_with_sync(SIGNATURE_NAME, 0o644, |w| w.write_all(&sig))?
+        }
+        // Not left behind describing an older manifest
+        None => {
+            vendordir.remove_file_optional(SIGNATURE_NAME)?;
+        }
+    }
+    crate::util::fsync_dir(&vendordir, ".")
+}
+
+/// Write the manifest of `tree`, installed as `installed`, to `vendor` in
+/// the `EFI` directory `efidir` of the ESP.
+#[context("Writing the ESP manifest")]
+pub(crate) fn write(
+    efidir: &openat::Dir,
+    vendor: &str,
+    installed: &ContentMetadata,
+    tree: &FileTree,
+) -> Result<()> {
+    write_in(efidir, vendor, installed, tree, Path::new(SIGNING_KEY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+
+    #[test]
+    fn test_write() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        std::fs::create_dir_all(td.path().join("fedora"))?;
+        std::fs::write(td.path().join("fedora/shimx64.efi"), "shim")?;
+        let efidir = openat::Dir::open(td.path())?;
+        let tree = FileTree::new_from_dir(&efidir)?;
+        let installed = ContentMetadata {
+            timestamp: chrono::Utc::now(),
+            version: "shim-x64-15.8-3.x86_64".into(),
+        };
+        let key_path = td.path().join("key.pem");
+        write_in(&efidir, "fedora", &installed, &tree, &key_path)?;
+        let manifest_path = td.path().join("fedora").join(MANIFEST_NAME);
+        let data = std::fs::read(&manifest_path)?;
+        let manifest: EspManifest = serde_json::from_slice(&data)?;
+        assert_eq!(manifest.installed, installed);
+        assert_eq!(
+            manifest.files.keys().collect::<Vec<_>>(),
+            ["fedora/shimx64.efi"]
+        );
+        assert!(!td.path().join("fedora").join(SIGNATURE_NAME).exists());
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        std::fs::write(&key_path, key.private_key_to_pem_pkcs8()?)?;
+        write_in(&efidir, "fedora", &installed, &tree, &key_path)?;
+        let data = std::fs::read(&manifest_path)?;
+        let sig = std::fs::read(td.path().join("fedora").join(SIGNATURE_NAME))?;
+        let public = PKey::public_key_from_pem(&key.public_key_to_pem()?)?;
+        crate::payload::verify_manifest(&data, &sig, &public)?;
+        Ok(())
+    }
+}
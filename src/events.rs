@@ -0,0 +1,221 @@
+//! Notifications of the progress of installs and updates, for building
+//! progress UIs and logging on.
+//!
+//! Observers are registered for the process with [`register`] (or
+//! [`crate::api::add_observer`]) and receive the events of every component
+//! operated on until their [`Registration`] is dropped.  The events of a
+//! component come from the thread operating on it, so those of components
+//! updated concurrently interleave.  The progress that
+//! `bootupctl backend install --from-installer` prints is an observer too.
+
+use std::cell::RefCell;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use anyhow::Result;
+
+use crate::model::{ContentMetadata, InstalledContent};
+
+/// An operation on a component.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Operation {
+    /// Installing to a target root
+    Install,
+    /// Updating the installed component
+    Update,
+    /// Adopting a component which wasn't installed by bootupd, and updating it
+    Adopt,
+}
+
+/// Receives the events of the operations on components; all methods do
+/// nothing by default.  They are called synchronously, so they should be
+/// quick.
+pub trait Observer: Send + Sync {
+    /// `operation` on `component` started.
+    fn on_component_start(&self, _component: &str, _operation: Operation) {}
+
+    /// The file `path` (e.g. on the ESP) was written for `component`.
+    fn on_file_written(&self, _component: &str, _path: &Path) {}
+
+    /// `operation` on `component` succeeded, installing `version`.
+    fn on_component_complete(
+        &self,
+        _component: &str,
+        _operation: Operation,
+        _version: &ContentMetadata,
+    ) {
+    }
+
+    /// `operation` on `component` failed with `error`.
+    fn on_error(&self, _component: &str, _operation: Operation, _error: &anyhow::Error) {}
+}
+
+static OBSERVERS: RwLock<Vec<(u64, Arc<dyn Observer>)>> = RwLock::new(Vec::new());
+/// The number of [`OBSERVERS`], to skip preparing events without any.
+static OBSERVER_COUNT: AtomicUsize = AtomicUsize::new(0);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// The component operated on by this thread.
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Keeps an observer registered; it's removed when this is dropped.
+#[must_use = "the observer is removed when this is dropped"]
+pub struct Registration {
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut observers = OBSERVERS.write().unwrap_or_else(|e| e.into_inner());
+        observers.retain(|(id, _)| *id != self.id);
+        OBSERVER_COUNT.store(observers.len(), Ordering::Relaxed);
+    }
+}
+
+/// Send the events of all components to `observer`, until the returned
+/// registration is dropped.
+pub(crate) fn register(observer: Arc<dyn Observer>) -> Registration {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut observers = OBSERVERS.write().unwrap_or_else(|e| e.into_inner());
+    observers.push((id, observer));
+    OBSERVER_COUNT.store(observers.len(), Ordering::Relaxed);
+    Registration { id }
+}
+
+/// Whether any observers are registered.
+pub(crate) fn observing() -> bool {
+    OBSERVER_COUNT.load(Ordering::Relaxed) > 0
+}
+
+fn notify(f: impl Fn(&dyn Observer)) {
+    if !observing() {
+        return;
+    }
+    // Not locked while notifying, so that observers may register others
+    let observers = OBSERVERS
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|(_, o)| Arc::clone(o))
+        .collect::<Vec<_>>();
+    for o in observers {
+        f(o.as_ref());
+    }
+}
+
+/// Run `operation` on `component` with `f`, notifying observers of its start
+/// and outcome, and of the files written by this thread meanwhile.
+pub(crate) fn in_component(
+    operation: Operation,
+    component: &str,
+    f: impl FnOnce() -> Result<InstalledContent>,
+) -> Result<InstalledContent> {
+    let previous = CURRENT.with(|c| c.replace(Some(component.to_string())));
+    notify(|o| o.on_component_start(component, operation));
+    let r = f();
+    match &r {
+        Ok(installed) => notify(|o| o.on_component_complete(component, operation, &installed.meta)),
+        Err(e) => notify(|o| o.on_error(component, operation, e)),
+    }
+    CURRENT.with(|c| c.replace(previous));
+    r
+}
+
+/// The component this thread operates on, if any.
+pub(crate) fn current_component() -> Option<String> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+/// Notify observers that the file `path` was written, if for a component.
+#[cfg(have_filecopy)]
+pub(crate) fn file_written(path: &Path) {
+    if !observing() {
+        return;
+    }
+    let Some(component) = CURRENT.with(|c| c.borrow().clone()) else {
+        return;
+    };
+    notify(|o| o.on_file_written(&component, path));
+}
+
+#[cfg(test)]
+#[cfg(have_filecopy)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl Observer for Recorder {
+        fn on_component_start(&self, component: &str, operation: Operation) {
+            if component == "TEST" {
+                self.0.lock().unwrap().push(format!("start {operation:?}"));
+            }
+        }
+
+        fn on_file_written(&self, component: &str, path: &Path) {
+            if component == "TEST" {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("wrote {}", path.display()));
+            }
+        }
+
+        fn on_component_complete(&self, component: &str, _: Operation, v: &ContentMetadata) {
+            if component == "TEST" {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("complete {}", v.version));
+            }
+        }
+
+        fn on_error(&self, component: &str, _: Operation, error: &anyhow::Error) {
+            if component == "TEST" {
+                self.0.lock().unwrap().push(format!("error {error}"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_observer() -> Result<()> {
+        let recorder = Arc::new(Recorder::default());
+        let registration = register(recorder.clone());
+        let installed = InstalledContent {
+            meta: ContentMetadata {
+                timestamp: std::time::UNIX_EPOCH.into(),
+                version: "unknown".into(),
+            },
+            filetree: None,
+            adopted_from: None,
+            devices: Vec::new(),
+        };
+        in_component(Operation::Update, "TEST", || {
+            file_written(Path::new("/boot/efi/EFI/fedora/shimx64.efi"));
+            Ok(installed.clone())
+        })?;
+        // Outside of components
+        file_written(Path::new("/boot/efi/EFI/fedora/grubx64.efi"));
+        let r = in_component(Operation::Install, "TEST", || anyhow::bail!("ESP full"));
+        assert!(r.is_err());
+        drop(registration);
+        in_component(Operation::Update, "TEST", || Ok(installed.clone()))?;
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                "start Update",
+                "wrote /boot/efi/EFI/fedora/shimx64.efi",
+                "complete unknown",
+                "start Install",
+                "error ESP full"
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,432 @@
+//! `extlinux.conf` for 32-bit ARM boards booting via the syslinux support
+//! of U-Boot (`sysboot`/distro boot).
+//!
+//! The OS ships a template at [`TEMPLATE_PATH`]; the config in
+//! `/boot/extlinux` is rendered from it for the installed kernel and
+//! tracked in a filetree.  The following variables are replaced:
+//!
+//! - `@KERNEL@`, `@INITRD@`: the kernel and initramfs
+//! - `@FDTDIR@`: the directory containing device trees for the kernel
+//! - `@CMDLINE@`: the content of `/etc/kernel/cmdline`
+//! - `@VERSION@`: the kernel version
+//!
+//! Lines referencing an optional file which doesn't exist (`@INITRD@`,
+//! `@FDTDIR@`) are dropped.  Paths are relative to the boot partition.
+
+use std::cmp::Ordering;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+
+use crate::component::*;
+use crate::filetree::{self, FileTree};
+use crate::model::*;
+use crate::util;
+
+/// The template shipped by the OS, relative to the root.
+pub(crate) const TEMPLATE_PATH: &str = "usr/lib/bootupd/extlinux/extlinux.conf.in";
+/// The template in the update payload.
+const TEMPLATE_NAME: &str = "extlinux.conf.in";
+/// The directory containing the config, relative to the root.
+const EXTLINUX_DIR: &str = "boot/extlinux";
+const CONFIG_NAME: &str = "extlinux.conf";
+
+/// Kernel image names in /boot, followed by the version
+const KERNEL_PREFIXES: &[&str] = &["vmlinuz-", "zImage-"];
+/// Initramfs names in /boot, with `{}` replaced by the kernel version
+const INITRD_NAMES: &[&str] = &["initramfs-{}.img", "initrd.img-{}"];
+/// Device tree directories in /boot, with `{}` replaced by the kernel version
+const FDTDIR_NAMES: &[&str] = &["dtb-{}", "dtbs/{}", "dtb"];
+
+/// The files referenced by the config.
+#[derive(Debug, PartialEq, Eq)]
+struct Kernel {
+    version: String,
+    /// The kernel, relative to `/boot`
+    kernel: String,
+    initrd: Option<String>,
+    fdtdir: Option<String>,
+}
+
+/// Compare versions, ordering runs of digits numerically.
+fn version_cmp(a: &str, b: &str) -> Ordering {
+    let chunks = |s: &str| {
+        let mut r: Vec<(bool, String)> = Vec::new();
+        for c in s.chars() {
+            let digit = c.is_ascii_digit();
+            match r.last_mut() {
+                Some((d, chunk)) if *d == digit => chunk.push(c),
+                _ => r.push((digit, c.to_string())),
+            }
+        }
+        r
+    };
+    for (x, y) in chunks(a).iter().zip(chunks(b).iter()) {
+        let o = match (x, y) {
+            ((true, x), (true, y)) => x
+                .trim_start_matches('0')
+                .len()
+                .cmp(&y.trim_start_matches('0').len())
+                .then_with(|| x.trim_start_matches('0').cmp(y.trim_start_matches('0'))),
+            ((_, x), (_, y)) => x.cmp(y),
+        };
+        if o != Ordering::Equal {
+            return o;
+        }
+    }
+    chunks(a).len().cmp(&chunks(b).len())
+}
+
+/// Find the newest kernel in `boot`, along with its initramfs and device trees.
+fn find_kernel(boot: &Path) -> Result<Kernel> {
+    let mut newest: Option<(String, String)> = None;
+    for entry in std::fs::read_dir(boot).with_context(|| format!("Reading {boot:?}"))? {
+        let name = entry?.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let Some(version) = KERNEL_PREFIXES.iter().find_map(|p| name.strip_prefix(p)) else {
+            continue;
+        };
+        // e.g. the rescue kernel on Fedora
+        if version.contains("rescue") {
+            continue;
+        }
+        if newest
+            .as_ref()
+            .map_or(true, |(v, _)| version_cmp(version, v) == Ordering::Greater)
+        {
+            newest = Some((version.to_string(), name.to_string()));
+        }
+    }
+    let Some((version, kernel)) = newest else {
+        bail!("No kernel found in {boot:?}");
+    };
+    let find = |names: &[&str]| {
+        names
+            .iter()
+            .map(|n| n.replace("{}", &version))
+            .find(|n| boot.join(n).exists())
+    };
+    Ok(Kernel {
+        initrd: find(INITRD_NAMES),
+        fdtdir: find(FDTDIR_NAMES),
+        version,
+        kernel,
+    })
+}
+
+/// Render `template` for `kernel`, with paths prefixed by `prefix`.
+fn render(template: &str, kernel: &Kernel, prefix: &str, cmdline: &str) -> String {
+    let path = |p: &str| format!("{prefix}/{p}");
+    let vars = [
+        ("@KERNEL@", Some(path(&kernel.kernel))),
+        ("@INITRD@", kernel.initrd.as_deref().map(path)),
+        ("@FDTDIR@", kernel.fdtdir.as_deref().map(path)),
+        ("@CMDLINE@", Some(cmdline.to_string())),
+        ("@VERSION@", Some(kernel.version.clone())),
+    ];
+    let mut r = String::new();
+    'lines: for line in template.lines() {
+        let mut line = line.to_string();
+        for (var, value) in vars.iter() {
+            if !line.contains(var) {
+                continue;
+            }
+            let Some(value) = value else {
+                continue 'lines;
+            };
+            line = line.replace(var, value);
+        }
+        r.push_str(&line);
+        r.push('\n');
+    }
+    r
+}
+
+/// Return the paths referenced by `kernel`, `initrd` and `fdtdir` lines
+/// of `config`.
+fn referenced_paths(config: &str) -> Vec<&str> {
+    config
+        .lines()
+        .filter_map(|l| l.trim().split_once(char::is_whitespace))
+        .filter(|(k, _)| {
+            matches!(
+                k.to_ascii_lowercase().as_str(),
+                "kernel" | "linux" | "initrd" | "fdtdir" | "fdt"
+            )
+        })
+        .flat_map(|(_, v)| v.split(',').map(str::trim))
+        .collect()
+}
+
+#[derive(Default)]
+pub(crate) struct Extlinux {
+    opts: crate::component::Options,
+}
+
+impl Extlinux {
+    /// The extlinux component, writing as `opts` tell.
+    pub(crate) fn new(opts: crate::component::Options) -> Self {
+        Self { opts }
+    }
+
+    /// Render the template in the update payload of `src_root` for the newest
+    /// kernel in `dest_root`, into `workdir`.
+    #[context("Rendering {CONFIG_NAME}")]
+    fn render_to(&self, src_root: &openat::Dir, dest_root: &Path, workdir: &Path) -> Result<()> {
+        let template = src_root
+            .recover_path()?
+            .join(component_updatedirname(self))
+            .join(TEMPLATE_NAME);
+        let template =
+            std::fs::read_to_string(&template).with_context(|| format!("Reading {template:?}"))?;
+        let cmdline_path = dest_root.join("etc/kernel/cmdline");
+        let cmdline = std::fs::read_to_string(&cmdline_path)
+            .with_context(|| format!("Reading {cmdline_path:?}"))?;
+        let boot = dest_root.join("boot");
+        let kernel = find_kernel(&boot)?;
+        let prefix = if util::boot_partition_root(dest_root)? == boot {
+            ""
+        } else {
+            "/boot"
+        };
+        let config = render(&template, &kernel, prefix, cmdline.trim());
+        std::fs::write(workdir.join(CONFIG_NAME), config)?;
+        Ok(())
+    }
+
+    /// Render the config and apply it to `dest_root`, given the currently
+    /// tracked tree (if any).
+    fn write_config(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &Path,
+        current: Option<&FileTree>,
+    ) -> Result<FileTree> {
+        let td = tempfile::tempdir()?;
+        self.render_to(src_root, dest_root, td.path())?;
+        let rendered = openat::Dir::open(td.path())?;
+        let ft = FileTree::new_from_dir(&rendered)?;
+        let destpath = dest_root.join(EXTLINUX_DIR);
+        std::fs::create_dir_all(&destpath)?;
+        let dest = openat::Dir::open(&destpath)?;
+        let diff = match current {
+            Some(current) => current.diff(&ft)?,
+            // Replace whatever is there
+            None => ft.relative_diff_to(&dest)?,
+        };
+        log::trace!("applying diff: {}", &diff);
+        filetree::apply_diff(&rendered, &dest, &diff, Some(&self.opts.apply_options()))
+            .context("applying filesystem changes")?;
+        Ok(ft)
+    }
+}
+
+impl Component for Extlinux {
+    fn name(&self) -> &'static str {
+        "EXTLINUX"
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        if !Path::new("/").join(EXTLINUX_DIR).join(CONFIG_NAME).exists() {
+            log::trace!("No {CONFIG_NAME} found");
+            return Ok(None);
+        }
+        crate::component::query_adopt_state()
+    }
+
+    fn adopt_update(
+        &self,
+        sysroot: &openat::Dir,
+        update: &ContentMetadata,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = self.query_adopt()? else {
+            anyhow::bail!("Failed to find adoptable system")
+        };
+        let ft = self.write_config(sysroot, Path::new("/"), None)?;
+        Ok(InstalledContent {
+            meta: update.clone(),
+            filetree: Some(ft),
+            adopted_from: Some(meta.version),
+            devices: Vec::new(),
+        })
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        _device: Option<&Path>,
+        _update_firmware: bool,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = get_component_update(src_root, self)? else {
+            return Err(crate::errors::Error::payload_missing(self.name()).into());
+        };
+        let ft = self.write_config(src_root, Path::new(dest_root), None)?;
+        Ok(InstalledContent {
+            meta,
+            filetree: Some(ft),
+            adopted_from: None,
+            devices: Vec::new(),
+        })
+    }
+
+    fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
+        let src = Path::new(sysroot_path).join(TEMPLATE_PATH);
+        let dest = component_updatedir(sysroot_path, self);
+        std::fs::create_dir_all(&dest)?;
+        std::fs::copy(&src, dest.join(TEMPLATE_NAME))
+            .with_context(|| format!("Copying {src:?}"))?;
+        let meta = write_packaged_update_metadata(
+            sysroot_path,
+            self,
+            [Path::new("/").join(TEMPLATE_PATH)],
+        )?;
+        Ok(meta)
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EXTLINUX found!"))?;
+        let updatemeta = self.query_update(sysroot)?.expect("update available");
+        let ft = self.write_config(sysroot, Path::new("/"), Some(currentf))?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(ft),
+            adopted_from: None,
+            devices: Vec::new(),
+        })
+    }
+
+    fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed EXTLINUX found!"))?;
+        let root = Path::new("/");
+        let dest = openat::Dir::open(&root.join(EXTLINUX_DIR))?;
+        let diff = currentf.relative_diff_to(&dest)?;
+        let mut errs = Vec::new();
+        for f in diff.changes.iter() {
+            errs.push(format!("Changed: {}", f));
+        }
+        for f in diff.removals.iter() {
+            errs.push(format!("Removed: {}", f));
+        }
+        if diff.removals.is_empty() {
+            let partroot = util::boot_partition_root(root)?;
+            let config = std::fs::read_to_string(root.join(EXTLINUX_DIR).join(CONFIG_NAME))?;
+            for p in referenced_paths(&config) {
+                if !partroot.join(p.trim_start_matches('/')).exists() {
+                    errs.push(format!("Missing: {p}"));
+                }
+            }
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+
+    fn fingerprint_installed(&self, sysroot: &openat::Dir) -> Result<Option<InstalledContent>> {
+        let root = Path::new("/");
+        if !root.join(EXTLINUX_DIR).join(CONFIG_NAME).exists() {
+            return Ok(None);
+        }
+        let Some(updatemeta) = self.query_update(sysroot)? else {
+            return Err(crate::errors::Error::payload_missing(self.name()).into());
+        };
+        // What we'd write now, for the current kernel
+        let td = tempfile::tempdir()?;
+        self.render_to(sysroot, root, td.path())?;
+        let rendered = FileTree::new_from_dir(&openat::Dir::open(td.path())?)?;
+        let dest = openat::Dir::open(&root.join(EXTLINUX_DIR))?;
+        fingerprint_tree(&rendered, updatemeta, &dest)
+    }
+
+    fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATE: &str = "default linux
+label linux
+    kernel @KERNEL@
+    initrd @INITRD@
+    fdtdir @FDTDIR@
+    append @CMDLINE@
+";
+
+    #[test]
+    fn test_version_cmp() {
+        assert_eq!(version_cmp("6.10.1", "6.9.12"), Ordering::Greater);
+        assert_eq!(
+            version_cmp("6.9.2-200.fc40", "6.9.12-200.fc40"),
+            Ordering::Less
+        );
+        assert_eq!(version_cmp("6.9", "6.9.1"), Ordering::Less);
+        assert_eq!(version_cmp("6.9.1", "6.9.1"), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_find_kernel_and_render() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let boot = td.path();
+        for f in [
+            "vmlinuz-6.9.12-200.fc40.armv7hl",
+            "vmlinuz-6.10.3-200.fc40.armv7hl",
+            "vmlinuz-0-rescue-0123456789abcdef",
+            "initramfs-6.10.3-200.fc40.armv7hl.img",
+        ] {
+            std::fs::write(boot.join(f), "")?;
+        }
+        std::fs::create_dir(boot.join("dtb-6.10.3-200.fc40.armv7hl"))?;
+        let kernel = find_kernel(boot)?;
+        assert_eq!(
+            kernel,
+            Kernel {
+                version: "6.10.3-200.fc40.armv7hl".into(),
+                kernel: "vmlinuz-6.10.3-200.fc40.armv7hl".into(),
+                initrd: Some("initramfs-6.10.3-200.fc40.armv7hl.img".into()),
+                fdtdir: Some("dtb-6.10.3-200.fc40.armv7hl".into()),
+            }
+        );
+        let config = render(TEMPLATE, &kernel, "", "root=LABEL=root ro");
+        assert!(config.contains("    kernel /vmlinuz-6.10.3-200.fc40.armv7hl\n"));
+        assert!(config.contains("    append root=LABEL=root ro\n"));
+        assert_eq!(
+            referenced_paths(&config),
+            [
+                "/vmlinuz-6.10.3-200.fc40.armv7hl",
+                "/initramfs-6.10.3-200.fc40.armv7hl.img",
+                "/dtb-6.10.3-200.fc40.armv7hl"
+            ]
+        );
+
+        let kernel = Kernel {
+            initrd: None,
+            ..kernel
+        };
+        let config = render(TEMPLATE, &kernel, "/boot", "ro");
+        assert!(!config.contains("initrd"));
+        assert!(config.contains("    fdtdir /boot/dtb-6.10.3-200.fc40.armv7hl\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,452 @@
+//! Updating the ESP through its partition device, without mounting it.
+//!
+//! Where mounting is impossible (some containers, early boot) or the vfat
+//! driver misbehaves, `bootupctl update --direct-esp` writes the EFI files
+//! with the FAT implementation of the `fatfs` crate instead.  The device
+//! is opened exclusively, which fails while the kernel has it mounted:
+//! both writing the same filesystem would corrupt it.
+//!
+//! Updates are staged as for a mounted ESP: the new files are written
+//! under temporary names, synced and read back, and only then renamed into
+//! place, before the files which were removed are deleted.  FAT can't
+//! rename over an existing file, so each changed file is briefly missing,
+//! as with the kernel driver.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use openssl::hash::{Hasher, MessageDigest};
+
+use crate::filetree::{FileMetadata, FileTree, FileTreeDiff, TMP_PREFIX};
+use crate::sha512string::SHA512String;
+
+/// The directory of the EFI files, which diffs are relative to.
+const EFI_DIR: &str = "EFI";
+/// The size of the reads and writes of file contents.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The temporary name of `path` while staged.
+fn staged_name(path: &str) -> String {
+    match path.rsplit_once('/') {
+        Some((parent, name)) => format!("{parent}/{TMP_PREFIX}{name}"),
+        None => format!("{TMP_PREFIX}{path}"),
+    }
+}
+
+/// Compute the metadata of the content of `r`.
+fn metadata_of(mut r: impl Read) -> Result<FileMetadata> {
+    let mut hasher = Hasher::new(MessageDigest::sha512())?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let n = r.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n])?;
+        size += n as u64;
+    }
+    Ok(FileMetadata {
+        size,
+        sha512: SHA512String::from_hasher(&mut hasher),
+    })
+}
+
+/// The files `diff` writes, in the order to rename them into place, with
+/// those below `last` at the end; and the files it removes, except those
+/// replaced by a write differing only in case.
+fn commit_order<'a>(diff: &'a FileTreeDiff, last: Option<&str>) -> (Vec<&'a str>, Vec<&'a str>) {
+    let (mut writes, removals) = crate::filetree::write_order(diff, true);
+    writes.sort_by_key(|p| (p.components().next().map(|c| c.as_str()) == last, *p));
+    let writes = writes.into_iter().map(|p| p.as_str()).collect();
+    (writes, removals.into_iter().map(|p| p.as_str()).collect())
+}
+
+/// A FAT filesystem accessed directly on its device (or image).
+pub(crate) struct FatEsp {
+    device: PathBuf,
+    /// The device, to sync the writes of `fs` through
+    file: File,
+    fs: fatfs::FileSystem<File>,
+}
+
+type FatDir<'a> = fatfs::Dir<'a, File>;
+
+impl FatEsp {
+    /// Open the FAT filesystem on `device`, which must not be mounted.
+    #[context("Opening {device:?} for direct access")]
+    pub(crate) fn open(device: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            // Fails with EBUSY for mounted block devices
+            .custom_flags(libc::O_EXCL)
+            .open(device)
+            .with_context(|| format!("Opening {device:?} (is it mounted?)"))?;
+        let fs = fatfs::FileSystem::new(file.try_clone()?, fatfs::FsOptions::new())
+            .context("Reading the FAT filesystem")?;
+        Ok(Self {
+            device: device.to_owned(),
+            file,
+            fs,
+        })
+    }
+
+    fn efi_dir(&self) -> Result<FatDir<'_>> {
+        let root = self.fs.root_dir();
+        match root.open_dir(EFI_DIR) {
+            Ok(d) => Ok(d),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(root.create_dir(EFI_DIR)?),
+            Err(e) => Err(e).context("Opening EFI"),
+        }
+    }
+
+    /// Create the parent directories of `path` in `dir`.
+    fn ensure_parents(dir: &FatDir, path: &str) -> Result<()> {
+        let Some((parent, _)) = path.rsplit_once('/') else {
+            return Ok(());
+        };
+        let mut current = String::new();
+        for component in parent.split('/') {
+            if !current.is_empty() {
+                current.push('/');
+            }
+            current.push_str(component);
+            if let Err(e) = dir.open_dir(&current) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    return Err(e).with_context(|| format!("Opening {current}"));
+                }
+                dir.create_dir(&current)
+                    .with_context(|| format!("Creating {current}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove `path` from `dir`, if it exists.
+    fn remove_optional(dir: &FatDir, path: &str) -> Result<()> {
+        match dir.remove(path) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("Removing {path}")),
+        }
+    }
+
+    /// Write `content` to `path` in `dir`, replacing any previous file.
+    fn write_in(dir: &FatDir, path: &str, mut content: impl Read) -> Result<()> {
+        Self::ensure_parents(dir, path)?;
+        let mut f = dir
+            .create_file(path)
+            .with_context(|| format!("Creating {path}"))?;
+        f.truncate()?;
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        loop {
+            let n = content.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            f.write_all(&buf[..n])?;
+        }
+        f.flush()?;
+        Ok(())
+    }
+
+    /// Fail with ENOSPC unless `needed` bytes fit in the free space.
+    fn check_space(&self, needed: u64) -> Result<()> {
+        let stats = self.fs.stats()?;
+        let free = u64::from(stats.free_clusters()) * u64::from(stats.cluster_size());
+        if needed > free {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOSPC))
+                .with_context(|| format!("Writing {needed} bytes with {free} bytes free"));
+        }
+        Ok(())
+    }
+
+    /// The metadata of the files below `EFI` named in `paths`.
+    fn read_metadata<'p>(
+        dir: &FatDir,
+        paths: impl Iterator<Item = &'p str>,
+    ) -> Result<Vec<(String, FileMetadata)>> {
+        paths
+            .map(|p| {
+                let f = dir.open_file(p).with_context(|| format!("Opening {p}"))?;
+                Ok((p.to_string(), metadata_of(f)?))
+            })
+            .collect()
+    }
+
+    /// The files of `tree` which exist below `EFI`, with their metadata as
+    /// on the filesystem; see [`FileTree::subset_in`].
+    pub(crate) fn subset(&self, tree: &FileTree) -> Result<FileTree> {
+        let dir = self.efi_dir()?;
+        let mut children = std::collections::BTreeMap::new();
+        for path in tree.children.keys() {
+            match dir.open_file(path) {
+                Ok(f) => {
+                    children.insert(path.clone(), metadata_of(f)?);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("Opening {path}")),
+            }
+        }
+        Ok(FileTree { children })
+    }
+
+    /// Apply `diff` from `srcdir` to the `EFI` directory, checking the
+    /// written files against `expected`.  The files below the top-level
+    /// directory `last` are renamed into place after all others, as by
+    /// [`crate::filetree::apply_diff`].  On failure, the staged files are
+    /// removed again.
+    #[context("Applying changes to {:?} directly", self.device)]
+    pub(crate) fn apply_diff(
+        &self,
+        srcdir: &openat::Dir,
+        diff: &FileTreeDiff,
+        expected: &FileTree,
+        last: Option<&str>,
+    ) -> Result<()> {
+        let dir = self.efi_dir()?;
+        let (paths, removals) = commit_order(diff, last);
+        let needed = paths
+            .iter()
+            .filter_map(|p| expected.children.get(*p))
+            .map(|m| m.size)
+            .sum();
+        self.check_space(needed)?;
+        let mut staged = Vec::new();
+        let r = self.stage(&dir, srcdir, &paths, expected, &mut staged);
+        if let Err(e) = r {
+            for name in staged.iter() {
+                if let Err(e) = Self::remove_optional(&dir, name) {
+                    log::warn!("{e:#}");
+                }
+            }
+            if let Err(e) = self.sync() {
+                log::warn!("{e:#}");
+            }
+            return Err(e);
+        }
+
+        for (path, staged) in paths.iter().zip(staged.iter()) {
+            log::trace!("renaming {staged} to {path}");
+            Self::remove_optional(&dir, path)?;
+            dir.rename(staged, &dir, path)
+                .with_context(|| format!("rename for {staged} and {path}"))?;
+            crate::events::file_written(&Path::new(EFI_DIR).join(path));
+        }
+        for path in removals {
+            Self::remove_optional(&dir, path)?;
+        }
+        self.sync()
+    }
+
+    /// Write `paths` from `srcdir` to `dir` under their staged names, which
+    /// are added to `staged` as they're created, and check them against
+    /// `expected` once synced.
+    fn stage(
+        &self,
+        dir: &FatDir,
+        srcdir: &openat::Dir,
+        paths: &[&str],
+        expected: &FileTree,
+        staged: &mut Vec<String>,
+    ) -> Result<()> {
+        for path in paths.iter() {
+            crate::backend::cancel::check()?;
+            let src = srcdir
+                .open_file(*path)
+                .with_context(|| format!("Opening {path} in the payload"))?;
+            staged.push(staged_name(path));
+            Self::write_in(dir, staged.last().unwrap(), std::io::BufReader::new(src))?;
+        }
+        self.sync()?;
+        let written = Self::read_metadata(dir, staged.iter().map(String::as_str))?;
+        for (path, (staged, meta)) in paths.iter().zip(written.iter()) {
+            if expected.children.get(*path) != Some(meta) {
+                bail!("{staged} doesn't match the payload after writing");
+            }
+        }
+        // Past this point we're committing
+        crate::backend::cancel::check()
+    }
+
+    /// Write `content` to `path` below `EFI`, replacing it.
+    pub(crate) fn write_file(&self, path: &str, content: &[u8]) -> Result<()> {
+        let dir = self.efi_dir()?;
+        let staged = staged_name(path);
+        Self::write_in(&dir, &staged, content)?;
+        Self::remove_optional(&dir, path)?;
+        dir.rename(&staged, &dir, path)
+            .with_context(|| format!("rename for {staged} and {path}"))?;
+        Ok(())
+    }
+
+    /// Remove `path` below `EFI`, if it exists.
+    pub(crate) fn remove_file_optional(&self, path: &str) -> Result<()> {
+        Self::remove_optional(&self.efi_dir()?, path)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.file
+            .sync_all()
+            .with_context(|| format!("Syncing {:?}", self.device))
+    }
+
+    /// Finish the writes, marking the filesystem clean.
+    pub(crate) fn close(self) -> Result<()> {
+        let Self { device, file, fs } = self;
+        fs.unmount().context("Unmounting the FAT filesystem")?;
+        file.sync_all()
+            .with_context(|| format!("Syncing {device:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A FAT image of `size` bytes in `dir`.
+    fn image(dir: &Path, size: u64) -> Result<PathBuf> {
+        let path = dir.join("esp.img");
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        f.set_len(size)?;
+        fatfs::format_volume(&f, fatfs::FormatVolumeOptions::new())?;
+        Ok(path)
+    }
+
+    #[test]
+    fn test_staged_name() {
+        assert_eq!(
+            staged_name("fedora/shimx64.efi"),
+            "fedora/.btmp.shimx64.efi"
+        );
+        assert_eq!(staged_name("BOOT.CSV"), ".btmp.BOOT.CSV");
+    }
+
+    #[test]
+    fn test_commit_order() {
+        let diff = FileTreeDiff {
+            additions: ["BOOT/BOOTX64.EFI", "fedora/grubx64.efi"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            removals: ["fedora/mmx64.efi"].into_iter().map(String::from).collect(),
+            changes: ["BOOT/fbx64.efi", "fedora/shimx64.efi"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        };
+        let (writes, removals) = commit_order(&diff, Some("BOOT"));
+        assert_eq!(
+            writes,
+            [
+                "fedora/grubx64.efi",
+                "fedora/shimx64.efi",
+                "BOOT/BOOTX64.EFI",
+                "BOOT/fbx64.efi"
+            ]
+        );
+        assert_eq!(removals, ["fedora/mmx64.efi"]);
+        let (writes, _) = commit_order(&diff, None);
+        assert_eq!(writes[0], "BOOT/BOOTX64.EFI");
+    }
+
+    #[test]
+    fn test_apply_diff() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let img = image(td.path(), 32 * 1024 * 1024)?;
+        let src = td.path().join("src");
+        std::fs::create_dir_all(src.join("fedora"))?;
+        std::fs::create_dir_all(src.join("BOOT"))?;
+        std::fs::write(src.join("fedora/shimx64.efi"), "shim 1")?;
+        std::fs::write(src.join("fedora/mmx64.efi"), "mm")?;
+        let srcdir = openat::Dir::open(&src)?;
+        let empty = FileTree {
+            children: Default::default(),
+        };
+        let v1 = FileTree::new_from_dir(&srcdir)?;
+
+        let esp = FatEsp::open(&img)?;
+        esp.apply_diff(&srcdir, &empty.diff(&v1)?, &v1, None)?;
+        esp.close()?;
+
+        std::fs::write(src.join("fedora/shimx64.efi"), "shim 2")?;
+        std::fs::remove_file(src.join("fedora/mmx64.efi"))?;
+        std::fs::write(src.join("BOOT/BOOTX64.EFI"), "shim 2")?;
+        let v2 = FileTree::new_from_dir(&srcdir)?;
+        let esp = FatEsp::open(&img)?;
+        // Only the files there are, as written
+        assert_eq!(esp.subset(&v1)?, v1);
+        assert_eq!(esp.subset(&v2)?.children.len(), 1);
+        esp.apply_diff(&srcdir, &v1.diff(&v2)?, &v2, Some("BOOT"))?;
+        esp.write_file("fedora/bootupd-manifest.json", b"{}")?;
+        // The payload changed after its tree was computed
+        std::fs::write(src.join("BOOT/BOOTX64.EFI"), "corrupted")?;
+        let mut v3 = v2.clone();
+        v3.children.remove("BOOT/BOOTX64.EFI");
+        assert!(esp
+            .apply_diff(&srcdir, &v3.diff(&v2)?, &v2, Some("BOOT"))
+            .is_err());
+        esp.close()?;
+
+        let fs = fatfs::FileSystem::new(File::open(&img)?, fatfs::FsOptions::new())?;
+        let efi = fs.root_dir().open_dir(EFI_DIR)?;
+        let mut names = Vec::new();
+        for d in ["fedora", "BOOT"] {
+            for e in efi.open_dir(d)?.iter() {
+                let name = e?.file_name();
+                // The staged files are gone after the failure
+                if name != "." && name != ".." {
+                    names.push(format!("{d}/{name}"));
+                }
+            }
+        }
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "BOOT/BOOTX64.EFI",
+                "fedora/bootupd-manifest.json",
+                "fedora/shimx64.efi"
+            ]
+        );
+        let mut shim = String::new();
+        efi.open_file("fedora/shimx64.efi")?
+            .read_to_string(&mut shim)?;
+        assert_eq!(shim, "shim 2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_space() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let img = image(td.path(), 1024 * 1024)?;
+        let src = td.path().join("src");
+        std::fs::create_dir_all(&src)?;
+        std::fs::write(src.join("big.efi"), vec![0u8; 2 * 1024 * 1024])?;
+        let srcdir = openat::Dir::open(&src)?;
+        let tree = FileTree::new_from_dir(&srcdir)?;
+        let empty = FileTree {
+            children: Default::default(),
+        };
+        let esp = FatEsp::open(&img)?;
+        let e = esp
+            .apply_diff(&srcdir, &empty.diff(&tree)?, &tree, None)
+            .map_err(crate::errors::esp_full)
+            .unwrap_err();
+        assert_eq!(
+            crate::errors::classify(&e),
+            crate::errors::ErrorKind::EspFull,
+            "{e:#}"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//! C bindings of the [`crate::api`] operations, for installers written in
+//! languages without Rust interop; see `include/bootupd.h`.
+//!
+//! Each function takes its options (if any) as a JSON object and returns
+//! 0 with the JSON result in `*out`, or -1 with a JSON error report in
+//! `*out`, as printed by `bootupctl --error-format=json`.  The returned
+//! strings must be released with `bootupd_free`.  Everything runs on the
+//! calling thread; if it's interrupted by a panic, that's reported as an
+//! error too.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_int, CStr, CString};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::api;
+
+/// The options of [`bootupd_update`].
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct UpdateRequest {
+    components: Option<Vec<String>>,
+    jobs: Option<usize>,
+}
+
+/// The options of [`bootupd_validate`].
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ValidateRequest {
+    components: Option<Vec<String>>,
+}
+
+/// Parse the options `options`, which may be null for the defaults.
+///
+/// # Safety
+///
+/// `options` must be null or a NUL-terminated string.
+unsafe fn parse_options<T: Default + for<'de> Deserialize<'de>>(
+    options: *const c_char,
+) -> Result<T> {
+    if options.is_null() {
+        return Ok(T::default());
+    }
+    let options = CStr::from_ptr(options)
+        .to_str()
+        .context("Options are invalid UTF-8")?;
+    serde_json::from_str(options).context("Parsing options")
+}
+
+/// Run `f`, storing its result or error as JSON in `out`.
+///
+/// # Safety
+///
+/// `out` must be null or valid for writes.
+unsafe fn call<T: Serialize>(out: *mut *mut c_char, f: impl FnOnce() -> api::Result<T>) -> c_int {
+    let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("bootupd panicked").into()));
+    let r = r.and_then(|v| serde_json::to_string(&v).map_err(|e| anyhow::Error::from(e).into()));
+    let (code, json) = match r {
+        Ok(json) => (0, json),
+        Err(e) => {
+            let report = e.report();
+            let json = serde_json::to_string(&report).unwrap_or_else(|_| "{}".into());
+            (-1, json)
+        }
+    };
+    if !out.is_null() {
+        // Serialized JSON has no NUL bytes
+        *out = CString::new(json).unwrap_or_default().into_raw();
+    }
+    code
+}
+
+/// Store the status of the installed and adoptable components in `out`,
+/// as `bootupctl status --json` prints it.
+///
+/// # Safety
+///
+/// `out` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn bootupd_status(out: *mut *mut c_char) -> c_int {
+    call(out, api::status)
+}
+
+/// Update the installed components with an available update, with the
+/// options `options` (e.g. `{"components": ["EFI"], "jobs": 1}`), storing
+/// the results by component in `out`.
+///
+/// # Safety
+///
+/// `options` must be null or a NUL-terminated string, and `out` must be
+/// null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn bootupd_update(options: *const c_char, out: *mut *mut c_char) -> c_int {
+    call(out, || {
+        let req: UpdateRequest = parse_options(options)?;
+        let mut opts = api::UpdateOptions::default();
+        opts.components = req.components;
+        if let Some(jobs) = req.jobs {
+            opts.jobs = jobs;
+        }
+        api::update(&opts)
+    })
+}
+
+/// Validate the installed components, or those in the options `options`
+/// (e.g. `{"components": ["EFI"]}`), storing the results by component in
+/// `out`.
+///
+/// # Safety
+///
+/// `options` must be null or a NUL-terminated string, and `out` must be
+/// null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn bootupd_validate(options: *const c_char, out: *mut *mut c_char) -> c_int {
+    call(out, || -> api::Result<BTreeMap<_, _>> {
+        let req: ValidateRequest = parse_options(options)?;
+        api::validate(req.components.as_deref())
+    })
+}
+
+/// Release a string returned by the other functions.
+///
+/// # Safety
+///
+/// `s` must be null or a string returned by them, not yet released.
+#[no_mangle]
+pub unsafe extern "C" fn bootupd_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take(s: *mut c_char) -> serde_json::Value {
+        let v = serde_json::from_slice(unsafe { CStr::from_ptr(s) }.to_bytes()).unwrap();
+        unsafe { bootupd_free(s) };
+        v
+    }
+
+    #[test]
+    fn test_call() -> Result<()> {
+        let mut out = std::ptr::null_mut();
+        assert_eq!(unsafe { call(&mut out, || Ok(vec!["EFI"])) }, 0);
+        assert_eq!(take(out), serde_json::json!(["EFI"]));
+        let r = unsafe {
+            call::<()>(&mut out, || {
+                Err(anyhow::Error::from(crate::errors::Error::payload_missing("EFI")).into())
+            })
+        };
+        assert_eq!(r, -1);
+        assert_eq!(take(out)["class"], "payload-missing");
+        assert_eq!(unsafe { call::<()>(&mut out, || panic!("oops")) }, -1);
+        assert_eq!(take(out)["message"], "bootupd panicked");
+
+        let options = CString::new(r#"{"components": ["EFI"]}"#)?;
+        let req: ValidateRequest = unsafe { parse_options(options.as_ptr())? };
+        assert_eq!(req.components.as_deref(), Some(&["EFI".to_string()][..]));
+        let options = CString::new(r#"{"component": "EFI"}"#)?;
+        assert!(unsafe { parse_options::<ValidateRequest>(options.as_ptr()) }.is_err());
+        let req: UpdateRequest = unsafe { parse_options(std::ptr::null())? };
+        assert!(req.components.is_none() && req.jobs.is_none());
+        Ok(())
+    }
+}
@@ -1,7 +1,6 @@
 use std::io::Write;
 use std::os::fd::AsRawFd;
 use std::os::unix::process::CommandExt;
-use std::process::Command;
 
 use anyhow::{Context, Result};
 use fn_error_context::context;
@@ -25,10 +24,21 @@ pub(crate) struct Findmnt {
 
 #[context("Inspecting filesystem {path:?}")]
 pub(crate) fn inspect_filesystem(root: &openat::Dir, path: &str) -> Result<Filesystem> {
+    if !crate::util::have_program("findmnt") {
+        log::debug!("No findmnt, reading the mount table");
+        let mounts = crate::native::mounts()?;
+        let mount = crate::native::find_mount(&mounts, &root.recover_path()?.join(path))?;
+        return Ok(Filesystem {
+            source: mount.source.clone(),
+            fstype: mount.fstype.clone(),
+            options: mount.options.clone(),
+            uuid: crate::native::filesystem_uuid(&mount.source),
+        });
+    }
     let rootfd = unsafe { BorrowedFd::borrow_raw(root.as_raw_fd()) };
     // SAFETY: This is unsafe just for the pre_exec, when we port to cap-std we can use cap-std-ext
     let o = unsafe {
-        Command::new("findmnt")
+        crate::util::command("findmnt")
             .args(["-J", "-v", "--output=SOURCE,FSTYPE,OPTIONS,UUID", path])
             .pre_exec(move || rustix::process::fchdir(rootfd).map_err(Into::into))
             .output()?
@@ -40,8 +50,33 @@ pub(crate) fn inspect_filesystem(root: &openat::Dir, path: &str) -> Result<Files
     }
     let o: Findmnt = serde_json::from_reader(std::io::Cursor::new(&o.stdout))
         .context("Parsing findmnt output")?;
-    o.filesystems
+    let mut fs = o
+        .filesystems
         .into_iter()
         .next()
-        .ok_or_else(|| anyhow::anyhow!("findmnt returned no data"))
+        .ok_or_else(|| anyhow::anyhow!("findmnt returned no data"))?;
+    fs.source = source_device(&fs.source).to_string();
+    Ok(fs)
+}
+
+/// Strip the `[/subvolume]` suffix of the source of e.g. btrfs subvolume
+/// mounts, which older findmnt versions print despite `-v`.
+fn source_device(source: &str) -> &str {
+    match source.find('[') {
+        Some(i) if source.ends_with(']') => &source[..i],
+        _ => source,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_device() {
+        assert_eq!(source_device("/dev/vda4[/root]"), "/dev/vda4");
+        assert_eq!(source_device("/dev/sda2[/@boot]"), "/dev/sda2");
+        assert_eq!(source_device("/dev/vda3"), "/dev/vda3");
+        assert_eq!(source_device("tmpfs"), "tmpfs");
+    }
 }
@@ -4,59 +4,97 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
 use anyhow::{bail, Context, Result};
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(have_filetree)]
 use camino::{Utf8Path, Utf8PathBuf};
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
 use openat_ext::OpenatDirExt;
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
 use openssl::hash::{Hasher, MessageDigest};
+#[cfg(have_filetree)]
 use rustix::fd::BorrowedFd;
 use serde::{Deserialize, Serialize};
 #[allow(unused_imports)]
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::Display;
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(have_filetree)]
 use std::os::unix::io::AsRawFd;
+#[cfg(have_filetree)]
 use std::os::unix::process::CommandExt;
-use std::process::Command;
 
 /// The prefix we apply to our temporary files.
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
 pub(crate) const TMP_PREFIX: &str = ".btmp.";
 // This module doesn't handle modes right now, because
 // we're only targeting FAT filesystems for UEFI.
 // In FAT there are no unix permission bits, usually
 // they're set by mount options.
 // See also https://github.com/coreos/fedora-coreos-config/commit/8863c2b34095a2ae5eae6fbbd121768a5f592091
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(have_filetree)]
 const DEFAULT_FILE_MODE: u32 = 0o700;
+/// Maximum number of files hashed concurrently; hashing is mostly bound by
+/// reading, so more threads don't help on typical boot media.
+const HASH_JOBS: usize = 4;
+/// Memory for read buffers when hashing, shared by all jobs.  Large reads
+/// keep readahead effective on big files like UKIs, while peak memory use
+/// stays the same regardless of file sizes.
+const HASH_MEMORY_BUDGET: usize = 4 * 1024 * 1024;
+/// Size of the chunks passed from reading to writing when copying files.
+#[cfg(have_filetree)]
+const COPY_CHUNK_SIZE: usize = 1024 * 1024;
+/// Maximum number of chunks read ahead of writing, bounding the memory
+/// used by a copy regardless of file sizes.
+#[cfg(have_filetree)]
+const COPY_READ_AHEAD: usize = 8;
 
+use crate::pathnorm;
 use crate::sha512string::SHA512String;
 
 /// Metadata for a single file
 #[derive(Clone, Serialize, Deserialize, Debug, Hash, PartialEq)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) struct FileMetadata {
+#[non_exhaustive]
+pub struct FileMetadata {
     /// File size in bytes
-    pub(crate) size: u64,
+    pub size: u64,
     /// Content checksum; chose SHA-512 because there are not a lot of files here
     /// and it's ok if the checksum is large.
-    pub(crate) sha512: SHA512String,
+    pub sha512: SHA512String,
 }
 
+/// The regular files in a directory tree, by their path relative to it
+/// (with `/` separators).
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) struct FileTree {
-    pub(crate) children: BTreeMap<String, FileMetadata>,
+#[non_exhaustive]
+pub struct FileTree {
+    #[serde(deserialize_with = "deserialize_children")]
+    pub children: BTreeMap<String, FileMetadata>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct FileTreeDiff {
-    pub(crate) additions: HashSet<String>,
-    pub(crate) removals: HashSet<String>,
-    pub(crate) changes: HashSet<String>,
+/// Deserialize the files of a tree, refusing paths outside of it: manifests
+/// are read from the state in `/boot`, which is on mutable media, and a
+/// path like `../..` would have updates write and remove files elsewhere.
+fn deserialize_children<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<BTreeMap<String, FileMetadata>, D::Error> {
+    let children = BTreeMap::<String, FileMetadata>::deserialize(d)?;
+    if let Some(path) = children.keys().find(|p| !pathnorm::is_tree_path(p)) {
+        return Err(serde::de::Error::custom(format!(
+            "Invalid path in file tree: {path:?}"
+        )));
+    }
+    Ok(children)
+}
+
+/// The paths of the files which differ between two trees, see
+/// [`FileTree::diff`].
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[non_exhaustive]
+pub struct FileTreeDiff {
+    /// Files only in the updated tree
+    pub additions: BTreeSet<String>,
+    /// Files only in the original tree
+    pub removals: BTreeSet<String>,
+    /// Files in both whose size or content differ
+    pub changes: BTreeSet<String>,
 }
 
 impl Display for FileTreeDiff {
@@ -71,7 +109,7 @@ impl Display for FileTreeDiff {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, have_filetree))]
 impl FileTreeDiff {
     pub(crate) fn count(&self) -> usize {
         self.additions.len() + self.removals.len() + self.changes.len()
@@ -79,16 +117,35 @@ impl FileTreeDiff {
 }
 
 impl FileMetadata {
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
     pub(crate) fn new_from_path<P: openat::AsPath>(
         dir: &openat::Dir,
         name: P,
     ) -> Result<FileMetadata> {
-        let mut r = dir.open_file(name)?;
+        Self::new_from_file(dir.open_file(name)?)
+    }
+
+    /// Compute the metadata of an opened file.
+    pub(crate) fn new_from_file(mut r: std::fs::File) -> Result<FileMetadata> {
+        use std::io::Read;
         let meta = r.metadata()?;
+        // Only a hint, so errors (e.g. on filesystems without support) don't matter
+        let _ = rustix::fs::fadvise(&r, 0, 0, rustix::fs::Advice::Sequential);
         let mut hasher =
             Hasher::new(MessageDigest::sha512()).expect("openssl sha512 hasher creation failed");
-        let _ = std::io::copy(&mut r, &mut hasher)?;
+        // Small files don't need a full-size buffer
+        let bufsize = usize::try_from(meta.len())
+            .unwrap_or(usize::MAX)
+            .clamp(1, HASH_MEMORY_BUDGET / HASH_JOBS);
+        let mut buf = vec![0u8; bufsize];
+        loop {
+            let n = match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            hasher.update(&buf[..n])?;
+        }
         let digest = SHA512String::from_hasher(&mut hasher);
         Ok(FileMetadata {
             size: meta.len(),
@@ -98,10 +155,8 @@ impl FileMetadata {
 }
 
 impl FileTree {
-    // Internal helper to generate a sub-tree
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-    fn unsorted_from_dir(dir: &openat::Dir) -> Result<HashMap<String, FileMetadata>> {
-        let mut ret = HashMap::new();
+    /// List the files of a sub-tree, relative to `dir`
+    pub(crate) fn list_files(dir: &openat::Dir, prefix: &str, ret: &mut Vec<String>) -> Result<()> {
         for entry in dir.list_dir(".")? {
             let entry = entry?;
             let Some(name) = entry.file_name().to_str() else {
@@ -112,17 +167,11 @@ impl FileTree {
             }
             match dir.get_file_type(&entry)? {
                 openat::SimpleType::File => {
-                    let meta = FileMetadata::new_from_path(dir, name)?;
-                    let _ = ret.insert(name.to_string(), meta);
+                    ret.push(format!("{prefix}{name}"));
                 }
                 openat::SimpleType::Dir => {
                     let child = dir.sub_dir(name)?;
-                    for (mut k, v) in FileTree::unsorted_from_dir(&child)?.drain() {
-                        k.reserve(name.len() + 1);
-                        k.insert(0, '/');
-                        k.insert_str(0, name);
-                        let _ = ret.insert(k, v);
-                    }
+                    FileTree::list_files(&child, &format!("{prefix}{name}/"), ret)?;
                 }
                 openat::SimpleType::Symlink => {
                     bail!("Unsupported symbolic link {:?}", entry.file_name())
@@ -132,46 +181,111 @@ impl FileTree {
                 }
             }
         }
-        Ok(ret)
+        Ok(())
     }
 
-    /// Create a FileTree from the target directory.
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-    pub(crate) fn new_from_dir(dir: &openat::Dir) -> Result<Self> {
+    /// Compute the metadata of `names` concurrently using `hash`; the
+    /// result is ordered by name regardless of which thread hashed a file.
+    pub(crate) fn hash_files<F>(names: &[String], hash: F) -> Result<BTreeMap<String, FileMetadata>>
+    where
+        F: Fn(&str) -> Result<FileMetadata> + Sync,
+    {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let _t = crate::timing::start(crate::timing::Phase::Hashing);
+        let jobs = std::thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(HASH_JOBS)
+            .min(names.len())
+            .max(1);
+        let next = AtomicUsize::new(0);
+        let outcomes = std::thread::scope(|s| {
+            let handles = (0..jobs)
+                .map(|_| {
+                    s.spawn(|| -> Result<Vec<(&str, FileMetadata)>> {
+                        let mut r = Vec::new();
+                        while let Some(name) = names.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            let meta = hash(name).with_context(|| format!("Hashing {name}"))?;
+                            r.push((name.as_str(), meta));
+                        }
+                        Ok(r)
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|h| {
+                    h.join()
+                        .unwrap_or_else(|_| Err(anyhow::anyhow!("Hashing panicked")))
+                })
+                .collect::<Vec<_>>()
+        });
         let mut children = BTreeMap::new();
-        for (k, v) in Self::unsorted_from_dir(dir)?.drain() {
-            children.insert(k, v);
+        for outcome in outcomes {
+            for (name, meta) in outcome? {
+                children.insert(name.to_string(), meta);
+            }
         }
+        Ok(children)
+    }
 
+    /// Create a FileTree from the target directory.
+    pub(crate) fn new_from_dir(dir: &openat::Dir) -> Result<Self> {
+        let mut names = Vec::new();
+        Self::list_files(dir, "", &mut names)?;
+        let children = Self::hash_files(&names, |n| FileMetadata::new_from_path(dir, n))?;
+        Ok(Self { children })
+    }
+
+    /// Create a FileTree from the directory `path`, hashing every file in
+    /// it.  Symbolic links and special files are rejected, as are names
+    /// which aren't UTF-8 or start with the prefix of our temporary files
+    /// (`.btmp.`); file modes and timestamps are ignored.
+    pub fn new_from_path(path: impl AsRef<std::path::Path>) -> crate::api::Result<Self> {
+        let path = path.as_ref();
+        let dir = openat::Dir::open(path).with_context(|| format!("Opening {path:?}"))?;
+        Ok(Self::new_from_dir(&dir)?)
+    }
+
+    /// Create a FileTree from the files of this tree which exist in `dir`,
+    /// e.g. to compare installed content to its payload.
+    #[cfg(have_filetree)]
+    pub(crate) fn subset_in(&self, dir: &openat::Dir) -> Result<Self> {
+        let mut names = Vec::new();
+        for path in self.children.keys() {
+            if let Some(meta) = dir.metadata_optional(path)? {
+                if matches!(meta.simple_type(), openat::SimpleType::File) {
+                    names.push(path.clone());
+                }
+            }
+        }
+        let children = Self::hash_files(&names, |n| FileMetadata::new_from_path(dir, n))?;
         Ok(Self { children })
     }
 
     /// Determine the changes *from* self to the updated tree
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-    pub(crate) fn diff(&self, updated: &Self) -> Result<FileTreeDiff> {
-        self.diff_impl(updated, true)
+    pub fn diff(&self, updated: &Self) -> crate::api::Result<FileTreeDiff> {
+        Ok(self.diff_impl(updated, true)?)
     }
 
     /// Determine any changes only using the files tracked in self as
     /// a reference.  In other words, this will ignore any unknown
     /// files and not count them as additions.
-    #[cfg(test)]
+    #[cfg(all(test, have_filetree))]
     pub(crate) fn changes(&self, current: &Self) -> Result<FileTreeDiff> {
         self.diff_impl(current, false)
     }
 
     /// The inverse of `changes` - determine if there are any files
     /// changed or added in `current` compared to self.
-    #[cfg(test)]
+    #[cfg(all(test, have_filetree))]
     pub(crate) fn updates(&self, current: &Self) -> Result<FileTreeDiff> {
         current.diff_impl(self, false)
     }
 
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
     fn diff_impl(&self, updated: &Self, check_additions: bool) -> Result<FileTreeDiff> {
-        let mut additions = HashSet::new();
-        let mut removals = HashSet::new();
-        let mut changes = HashSet::new();
+        let mut additions = BTreeSet::new();
+        let mut removals = BTreeSet::new();
+        let mut changes = BTreeSet::new();
 
         for (k, v1) in self.children.iter() {
             if let Some(v2) = updated.children.get(k) {
@@ -199,22 +313,30 @@ impl FileTree {
 
     /// Create a diff from a target directory.  This will ignore
     /// any files or directories that are not part of the original tree.
-    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[cfg(have_filetree)]
     pub(crate) fn relative_diff_to(&self, dir: &openat::Dir) -> Result<FileTreeDiff> {
-        let mut removals = HashSet::new();
-        let mut changes = HashSet::new();
+        self.relative_diff_with(dir, |files| {
+            Self::hash_files(files, |n| FileMetadata::new_from_path(dir, n))
+        })
+    }
 
-        for (path, info) in self.children.iter() {
+    /// Like [`Self::relative_diff_to`], computing the metadata of the files
+    /// in `dir` with `hash`.
+    #[cfg(have_filetree)]
+    pub(crate) fn relative_diff_with<F>(&self, dir: &openat::Dir, hash: F) -> Result<FileTreeDiff>
+    where
+        F: FnOnce(&[String]) -> Result<BTreeMap<String, FileMetadata>>,
+    {
+        let mut removals = BTreeSet::new();
+        let mut changes = BTreeSet::new();
+        let mut files = Vec::new();
+
+        for path in self.children.keys() {
             assert!(!path.starts_with('/'));
 
             if let Some(meta) = dir.metadata_optional(path)? {
                 match meta.simple_type() {
-                    openat::SimpleType::File => {
-                        let target_info = FileMetadata::new_from_path(dir, path)?;
-                        if info != &target_info {
-                            changes.insert(path.clone());
-                        }
-                    }
+                    openat::SimpleType::File => files.push(path.clone()),
                     _ => {
                         // If a file became a directory
                         changes.insert(path.clone());
@@ -224,8 +346,14 @@ impl FileTree {
                 removals.insert(path.clone());
             }
         }
+        let hashed = hash(&files)?;
+        for (path, target_info) in hashed {
+            if self.children.get(&path) != Some(&target_info) {
+                changes.insert(path);
+            }
+        }
         Ok(FileTreeDiff {
-            additions: HashSet::new(),
+            additions: BTreeSet::new(),
             removals,
             changes,
         })
@@ -233,8 +361,8 @@ impl FileTree {
 }
 
 // Recursively remove all files/dirs in the directory that start with our TMP_PREFIX
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-fn cleanup_tmp(dir: &openat::Dir) -> Result<()> {
+#[cfg(have_filetree)]
+pub(crate) fn cleanup_tmp(dir: &openat::Dir) -> Result<()> {
     for entry in dir.list_dir(".")? {
         let entry = entry?;
         let Some(name) = entry.file_name().to_str() else {
@@ -264,32 +392,39 @@ fn cleanup_tmp(dir: &openat::Dir) -> Result<()> {
 }
 
 #[derive(Default, Clone)]
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-pub(crate) struct ApplyUpdateOptions {
+#[cfg(have_filetree)]
+pub(crate) struct ApplyUpdateOptions<'a> {
     pub(crate) skip_removals: bool,
     pub(crate) skip_sync: bool,
-}
-
-// syncfs() is a Linux-specific system call, which doesn't seem
-// to be bound in nix today.  I found https://github.com/XuShaohua/nc
-// but that's a nontrivial dependency with not a lot of code review.
-// Let's just fork off a helper process for now.
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
-pub(crate) fn syncfs(d: &openat::Dir) -> Result<()> {
-    use rustix::fs::{Mode, OFlags};
-    let d = unsafe { BorrowedFd::borrow_raw(d.as_raw_fd()) };
-    let oflags = OFlags::RDONLY | OFlags::CLOEXEC | OFlags::DIRECTORY;
-    let d = rustix::fs::openat(d, ".", oflags, Mode::empty())?;
-    rustix::fs::syncfs(d).map_err(Into::into)
+    /// The top-level directory to exchange after all of the others, e.g.
+    /// `BOOT` on the ESP, whose fallback loader loads from the vendor
+    /// directories and so must not be updated before them.
+    pub(crate) last: Option<&'a str>,
+    /// Verify the copied files against these digests as they're read from
+    /// the source, e.g. when the source tree was computed from cached
+    /// digests.
+    pub(crate) expected: Option<&'a FileTree>,
+    /// Read back the copied files once they're synced; see
+    /// [`crate::readback`]
+    pub(crate) verify_writes: bool,
+    /// Limit copying to this many bytes per second; see [`crate::throttle`]
+    pub(crate) write_rate_limit: Option<u64>,
 }
 
 /// Copy from src to dst at root dir
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(have_filetree)]
 fn copy_dir(root: &openat::Dir, src: &str, dst: &str) -> Result<()> {
+    let _t = crate::timing::start(crate::timing::Phase::Copy);
+    if !crate::util::have_program("cp") {
+        let root = root.recover_path()?;
+        crate::native::copy_tree(&root.join(src), &root.join(dst))?;
+        log::debug!("Copy {src} to {dst}");
+        return Ok(());
+    }
     let rootfd = unsafe { BorrowedFd::borrow_raw(root.as_raw_fd()) };
     let r = unsafe {
-        Command::new("cp")
-            .args(["-a"])
+        crate::util::command("cp")
+            .args(["-a", "--reflink=auto"])
             .arg(src)
             .arg(dst)
             .pre_exec(move || rustix::process::fchdir(rootfd).map_err(Into::into))
@@ -302,10 +437,179 @@ fn copy_dir(root: &openat::Dir, src: &str, dst: &str) -> Result<()> {
     Ok(())
 }
 
+/// What's passed from reading a file to writing it.
+#[cfg(have_filetree)]
+enum CopyChunk {
+    /// The next file, with its mode
+    Start(u32),
+    Data(Vec<u8>),
+    End(FileMetadata),
+}
+
+/// Read and hash the files in `copies` from `srcdir` on a separate
+/// thread, sending their content in chunks to `tx`.
+#[cfg(have_filetree)]
+fn read_ahead(
+    srcdir: &openat::Dir,
+    copies: &[(Utf8PathBuf, &Utf8Path)],
+    tx: std::sync::mpsc::SyncSender<CopyChunk>,
+) -> Result<()> {
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+    for (_, src) in copies {
+        let mut f = srcdir
+            .open_file(src.as_std_path())
+            .with_context(|| format!("opening {src}"))?;
+        let meta = f.metadata()?;
+        let _ = rustix::fs::fadvise(&f, 0, 0, rustix::fs::Advice::Sequential);
+        let mut hasher = Hasher::new(MessageDigest::sha512())?;
+        let mode = meta.permissions().mode();
+        // The receiver only goes away if writing failed, which it reports
+        if tx.send(CopyChunk::Start(mode)).is_err() {
+            return Ok(());
+        }
+        loop {
+            let mut buf = Vec::with_capacity(COPY_CHUNK_SIZE);
+            let n = (&mut f)
+                .take(COPY_CHUNK_SIZE as u64)
+                .read_to_end(&mut buf)
+                .with_context(|| format!("reading {src}"))?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf)?;
+            if tx.send(CopyChunk::Data(buf)).is_err() {
+                return Ok(());
+            }
+        }
+        let meta = FileMetadata {
+            size: meta.len(),
+            sha512: SHA512String::from_hasher(&mut hasher),
+        };
+        if tx.send(CopyChunk::End(meta)).is_err() {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Copy each source path in `copies` from `srcdir` to the corresponding
+/// destination in `destdir`, atomically replacing it.
+///
+/// If the copies are to be checked, i.e. `opts` provide the `expected`
+/// digests or verify the writes, or writes are throttled, reading and
+/// hashing run ahead of writing on a separate thread, so slow writes (e.g.
+/// to FAT on USB or SD media) overlap with reading the next files instead
+/// of alternating with it.  Each file must then match its expected digest
+/// before it's renamed into place, and the metadata of the
+/// files read is returned, in the order of `copies`.  Otherwise the kernel
+/// copies the files, reflinking them where possible.
+#[cfg(have_filetree)]
+fn copy_files(
+    srcdir: &openat::Dir,
+    destdir: &openat::Dir,
+    copies: &[(Utf8PathBuf, &Utf8Path)],
+    opts: &ApplyUpdateOptions,
+) -> Result<Option<Vec<FileMetadata>>> {
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    let _t = crate::timing::start(crate::timing::Phase::Copy);
+    // Files are reported at their final path, not where they're staged
+    let reported = if crate::events::observing() {
+        Some(destdir.recover_path()?)
+    } else {
+        None
+    };
+    let expected = opts.expected;
+    if expected.is_none() && !opts.verify_writes && opts.write_rate_limit.is_none() {
+        let mut bytes = 0;
+        for (dest, src) in copies {
+            crate::backend::cancel::check()?;
+            bytes +=
+                crate::util::copy_file_at(srcdir, src.as_std_path(), destdir, dest.as_std_path())
+                    .with_context(|| format!("copying {src} to {dest}"))?;
+            if let Some(base) = &reported {
+                crate::events::file_written(&base.join(src.as_str()));
+            }
+        }
+        crate::spans::record_bytes(bytes);
+        return Ok(None);
+    }
+    let (tx, rx) = std::sync::mpsc::sync_channel(COPY_READ_AHEAD);
+    std::thread::scope(|s| {
+        let reader = s.spawn(|| read_ahead(srcdir, copies, tx));
+        let write = || -> Result<Vec<FileMetadata>> {
+            let mut written = Vec::with_capacity(copies.len());
+            for (dest, src) in copies {
+                crate::backend::cancel::check()?;
+                let Ok(CopyChunk::Start(mode)) = rx.recv() else {
+                    // Reading failed; the reader returns why
+                    return Ok(written);
+                };
+                let mut w = destdir.new_file_writer(0o600)?;
+                let meta = loop {
+                    match rx.recv() {
+                        Ok(CopyChunk::Data(buf)) => {
+                            w.writer
+                                .write_all(&buf)
+                                .with_context(|| format!("writing {dest}"))?;
+                            if let Some(rate) = opts.write_rate_limit {
+                                crate::throttle::wrote(&mut w.writer, buf.len(), rate)?;
+                            }
+                        }
+                        Ok(CopyChunk::End(meta)) => break meta,
+                        Ok(CopyChunk::Start(_)) => bail!("Reading {src} ended early"),
+                        Err(_) => return Ok(written),
+                    }
+                };
+                if let Some(expected) = expected.and_then(|t| t.children.get(src.as_str())) {
+                    if expected != &meta {
+                        bail!("{src} does not match the expected digest");
+                    }
+                }
+                w.complete_with(dest.as_std_path(), |f| {
+                    f.set_permissions(std::fs::Permissions::from_mode(mode))
+                })
+                .with_context(|| format!("copying {src} to {dest}"))?;
+                if let Some(base) = &reported {
+                    crate::events::file_written(&base.join(src.as_str()));
+                }
+                written.push(meta);
+            }
+            crate::spans::record_bytes(written.iter().map(|m| m.size).sum());
+            Ok(written)
+        };
+        let written = write();
+        // Unblock the reader if writing stopped early
+        drop(rx);
+        let read = reader
+            .join()
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Reading panicked")));
+        read.and(written).map(Some)
+    })
+}
+
+/// If `opts` verify the writes, read back the copies `copies`, which must
+/// match `written` as returned by [`copy_files`]; see [`crate::readback`].
+/// Unless they were synced, what's read may come from the page cache.
+#[cfg(have_filetree)]
+fn verify_copies(
+    destdir: &openat::Dir,
+    copies: &[(Utf8PathBuf, &Utf8Path)],
+    written: Option<&[FileMetadata]>,
+    opts: &ApplyUpdateOptions,
+) -> Result<()> {
+    let Some(written) = written.filter(|_| opts.verify_writes) else {
+        return Ok(());
+    };
+    let paths = copies.iter().map(|(dest, _)| dest.as_str());
+    crate::readback::verify_files(destdir, paths.zip(written))
+}
+
 /// Get first sub dir and tmp sub dir for the path
 /// "fedora/foo/bar" -> ("fedora", ".btmp.fedora")
 /// "foo" -> ("foo", ".btmp.foo")
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(have_filetree)]
 fn get_first_dir(path: &Utf8Path) -> Result<(&Utf8Path, String)> {
     let first = path
         .iter()
@@ -316,8 +620,82 @@ fn get_first_dir(path: &Utf8Path) -> Result<(&Utf8Path, String)> {
     Ok((first.into(), tmp))
 }
 
+/// The files [`apply_diff`] of `diff` writes, in order, and those it
+/// removes without writing another file in their place, to a directory
+/// which is FAT if `on_fat`.
+#[cfg(have_filetree)]
+pub(crate) fn write_order(diff: &FileTreeDiff, on_fat: bool) -> (Vec<&Utf8Path>, Vec<&Utf8Path>) {
+    let mut writes = diff
+        .changes
+        .iter()
+        .chain(diff.additions.iter())
+        .map(Utf8Path::new)
+        .collect::<Vec<_>>();
+    writes.sort();
+    // On FAT, a file renamed to another case is the file written above
+    let written = writes
+        .iter()
+        .filter(|_| on_fat)
+        .map(|path| pathnorm::fat_key(path.as_str()))
+        .collect::<BTreeSet<_>>();
+    let removals = diff
+        .removals
+        .iter()
+        .filter(|path| !written.contains(&pathnorm::fat_key(path)))
+        .map(Utf8Path::new)
+        .collect();
+    (writes, removals)
+}
+
+/// Like [`write_order`], for planning an update of the directory
+/// `destpath` without applying it.
+#[cfg(have_efi)]
+pub(crate) fn plan_diff<'a>(
+    destpath: &std::path::Path,
+    diff: &'a FileTreeDiff,
+) -> Result<(Vec<&'a Utf8Path>, Vec<&'a Utf8Path>)> {
+    let on_fat = crate::util::is_fat(destpath)?;
+    if on_fat {
+        check_fat_names(diff)?;
+    }
+    Ok(write_order(diff, on_fat))
+}
+
+/// The order in which to exchange the staged top-level directories
+/// `updates`: sorted, except that `last` goes after all of the others.
+#[cfg(have_filetree)]
+fn exchange_order<'a>(
+    updates: &'a HashMap<&Utf8Path, String>,
+    last: Option<&str>,
+) -> Vec<(&'a Utf8Path, &'a str)> {
+    let mut r = updates
+        .iter()
+        .map(|(dst, tmp)| (*dst, tmp.as_str()))
+        .collect::<Vec<_>>();
+    r.sort_by_key(|(dst, _)| (Some(dst.as_str()) == last, *dst));
+    r
+}
+
+/// Fail if two of the files `diff` writes are the same file on FAT, where
+/// e.g. `BOOTX64.EFI` would overwrite `bootx64.efi`; see [`pathnorm`].
+#[cfg(have_filetree)]
+fn check_fat_names(diff: &FileTreeDiff) -> Result<()> {
+    let mut seen = HashMap::new();
+    let mut lookalikes = HashMap::new();
+    for path in diff.changes.iter().chain(diff.additions.iter()) {
+        let key = pathnorm::fat_key(path);
+        if let Some(other) = seen.insert(key.clone(), path) {
+            bail!("{other} and {path} are the same file on FAT");
+        }
+        if let Some(other) = lookalikes.insert(pathnorm::nfc(&key).into_owned(), path) {
+            log::warn!("{other} and {path} only differ in their Unicode normalization");
+        }
+    }
+    Ok(())
+}
+
 /// Given two directories, apply a diff generated from srcdir to destdir
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(have_filetree)]
 pub(crate) fn apply_diff(
     srcdir: &openat::Dir,
     destdir: &openat::Dir,
@@ -329,6 +707,14 @@ pub(crate) fn apply_diff(
     };
     let opts = opts.unwrap_or(&default_opts);
     cleanup_tmp(destdir).context("cleaning up temporary files")?;
+    // Whatever is staged is removed if we fail
+    let destpath = destdir.recover_path()?;
+    let on_fat = crate::util::is_fat(&destpath)?;
+    if on_fat {
+        check_fat_names(diff)?;
+    }
+    let staging =
+        crate::cleanup::Guard::new(crate::cleanup::Artifact::Staging { path: destpath });
 
     let mut updates = HashMap::new();
     // Handle removals in temp dir, or remove directly if file not in dir
@@ -353,8 +739,9 @@ pub(crate) fn apply_diff(
         }
     }
     // Write changed or new files to temp dir or temp file
-    for pathstr in diff.changes.iter().chain(diff.additions.iter()) {
-        let path = Utf8Path::new(pathstr);
+    let mut copies = Vec::new();
+    for path in write_order(diff, on_fat).0 {
+        crate::backend::cancel::check()?;
         let (first_dir, first_dir_tmp) = get_first_dir(path)?;
         let mut path_tmp = Utf8PathBuf::from(&first_dir_tmp);
         if first_dir != path {
@@ -373,13 +760,23 @@ pub(crate) fn apply_diff(
                 .with_context(|| format!("removing {path_tmp} before copying"))?;
         }
         updates.insert(first_dir, first_dir_tmp);
-        srcdir
-            .copy_file_at(path.as_std_path(), destdir, path_tmp.as_std_path())
-            .with_context(|| format!("copying {:?} to {:?}", path, path_tmp))?;
+        copies.push((path_tmp, path));
+    }
+    let written = copy_files(srcdir, destdir, &copies, opts)?;
+
+    // Ensure all of the staged content is written persistently to disk,
+    // with a single sync for all of it, before anything is exchanged.
+    if !opts.skip_sync {
+        crate::util::syncfs(destdir)?;
     }
+    verify_copies(destdir, &copies, written.as_deref(), opts)?;
+
+    // Past this point we're committing; stopping partway through the
+    // exchanges would leave a mix of old and new content.
+    crate::backend::cancel::check()?;
 
     // do local exchange or rename
-    for (dst, tmp) in updates.iter() {
+    for (dst, tmp) in exchange_order(&updates, opts.last) {
         let dst = dst.as_std_path();
         log::trace!("doing local exchange for {} and {:?}", tmp, dst);
         if destdir.exists(dst)? {
@@ -393,25 +790,24 @@ pub(crate) fn apply_diff(
         }
         crate::try_fail_point!("update::exchange");
     }
-    // Ensure all of the updates & changes are written persistently to disk
+    // The exchanges are all top-level entries, so syncing the top-level
+    // directory makes the update durable.
     if !opts.skip_sync {
-        syncfs(destdir)?;
+        crate::util::fsync_dir(destdir, "")?;
     }
 
-    // finally remove the temp dir
+    // finally remove the temp dir; if this is interrupted, what's left
+    // is removed by cleanup_tmp() on the next update.
     for (_, tmp) in updates.iter() {
         log::trace!("cleanup: {}", tmp);
         destdir.remove_all(tmp).context("clean up temp")?;
     }
-    // A second full filesystem sync to narrow any races rather than
-    // waiting for writeback to kick in.
-    if !opts.skip_sync {
-        syncfs(destdir)?;
-    }
+    staging.disarm();
     Ok(())
 }
 
 #[cfg(test)]
+#[cfg(have_filetree)]
 mod tests {
     use super::*;
     use std::fs;
@@ -479,6 +875,146 @@ mod tests {
         };
         test_one_apply(a, b, None).context("testing apply (with removals)")?;
         test_one_apply(a, b, Some(&skip_removals)).context("testing apply (skipping removals)")?;
+        let last = ApplyUpdateOptions {
+            last: Some("foo"),
+            ..Default::default()
+        };
+        test_one_apply(a, b, Some(&last)).context("testing apply (foo last)")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_large_file() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        // Larger than the buffer, and not a multiple of its size
+        let data = (0..(HASH_MEMORY_BUDGET / HASH_JOBS) * 3 + 17)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(td.path().join("big"), &data)?;
+        std::fs::write(td.path().join("empty"), "")?;
+        let dir = openat::Dir::open(td.path())?;
+        let meta = FileMetadata::new_from_path(&dir, "big")?;
+        assert_eq!(meta.size, data.len() as u64);
+        let expected = openssl::hash::hash(MessageDigest::sha512(), &data)?;
+        assert_eq!(meta.sha512.0, format!("sha512:{}", hex::encode(expected)));
+        let empty = FileMetadata::new_from_path(&dir, "empty")?;
+        assert_eq!(empty.size, 0);
+        let expected = openssl::hash::hash(MessageDigest::sha512(), b"")?;
+        assert_eq!(empty.sha512.0, format!("sha512:{}", hex::encode(expected)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_files() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let td = tempfile::tempdir()?;
+        let src = td.path().join("src");
+        let dest = td.path().join("dest");
+        fs::create_dir_all(&src)?;
+        fs::create_dir_all(&dest)?;
+        // More chunks than are read ahead, plus some
+        let data = (0..COPY_CHUNK_SIZE * (COPY_READ_AHEAD + 2) + 17)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        fs::write(src.join("big"), &data)?;
+        fs::write(src.join("small"), "small")?;
+        fs::set_permissions(src.join("small"), fs::Permissions::from_mode(0o644))?;
+        fs::write(dest.join("small"), "old")?;
+        let srcd = openat::Dir::open(&src)?;
+        let destd = openat::Dir::open(&dest)?;
+        let names = ["big", "small"];
+        let copies = names
+            .iter()
+            .map(|n| (Utf8PathBuf::from(*n), Utf8Path::new(n)))
+            .collect::<Vec<_>>();
+        let expected = FileTree::new_from_dir(&srcd)?;
+        let checked = ApplyUpdateOptions {
+            expected: Some(&expected),
+            ..Default::default()
+        };
+        let unchecked = ApplyUpdateOptions::default();
+        let written = copy_files(&srcd, &destd, &copies, &checked)?.unwrap();
+        assert_eq!(written.len(), 2);
+        assert_eq!(fs::read(dest.join("big"))?, data);
+        assert_eq!(fs::read(dest.join("small"))?, b"small");
+        let mode = fs::metadata(dest.join("small"))?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+
+        // Without digests to check, the files aren't read here
+        fs::remove_file(dest.join("big"))?;
+        fs::write(dest.join("small"), "old")?;
+        assert!(copy_files(&srcd, &destd, &copies, &unchecked)?.is_none());
+        assert_eq!(fs::read(dest.join("big"))?, data);
+        assert_eq!(fs::read(dest.join("small"))?, b"small");
+        let mode = fs::metadata(dest.join("small"))?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+
+        // A source not matching the expected digests isn't written
+        fs::write(src.join("small"), "tampered")?;
+        fs::write(dest.join("small"), "old")?;
+        assert!(copy_files(&srcd, &destd, &copies, &checked).is_err());
+        assert_eq!(fs::read(dest.join("small"))?, b"old");
+        // Nor is anything after a missing source
+        fs::remove_file(src.join("big"))?;
+        assert!(copy_files(&srcd, &destd, &copies, &unchecked).is_err());
+        assert_eq!(fs::read(dest.join("small"))?, b"old");
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_fallback_last() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        let pa = p.join("a");
+        let pb = p.join("b");
+        for d in [&pa, &pb] {
+            std::fs::create_dir_all(d.join("BOOT"))?;
+            std::fs::create_dir_all(d.join("fedora"))?;
+            std::fs::write(d.join("fedora/shimx64.efi"), "shim")?;
+            std::fs::write(d.join("fedora/mmx64.efi"), "mm")?;
+        }
+        std::fs::write(pa.join("fedora/grubx64.efi"), "grub")?;
+        std::fs::write(pb.join("fedora/grubx64.efi"), "grub2")?;
+        std::fs::write(pa.join("BOOT/BOOTX64.EFI"), "shim")?;
+        std::fs::write(pb.join("BOOT/BOOTX64.EFI"), "shim2")?;
+        std::fs::remove_file(pb.join("fedora/mmx64.efi"))?;
+        let a = openat::Dir::open(&pa)?;
+        let b = openat::Dir::open(&pb)?;
+        let diff = FileTree::new_from_dir(&a)?.diff(&FileTree::new_from_dir(&b)?)?;
+        assert_eq!(diff.count(), 3);
+        let ino = |f: &str| -> Result<u64> { Ok(std::fs::metadata(pa.join(f))?.ino()) };
+        let boot = ino("BOOT")?;
+        let fedora = ino("fedora")?;
+        let opts = ApplyUpdateOptions {
+            last: Some("BOOT"),
+            ..Default::default()
+        };
+        apply_diff(&b, &a, &diff, Some(&opts))?;
+        // Both directories were staged and exchanged as a whole
+        assert_ne!(ino("BOOT")?, boot);
+        assert_ne!(ino("fedora")?, fedora);
+        assert_eq!(std::fs::read(pa.join("fedora/grubx64.efi"))?, b"grub2");
+        assert!(!pa.join("fedora/mmx64.efi").exists());
+        let diff = FileTree::new_from_dir(&a)?.diff(&FileTree::new_from_dir(&b)?)?;
+        assert_eq!(diff.count(), 0);
+        for e in std::fs::read_dir(&pa)? {
+            assert!(!e?.file_name().to_string_lossy().starts_with(TMP_PREFIX));
+        }
+
+        // The fallback directory goes last, though it sorts first
+        let updates = ["BOOT", "EFI", "fedora"]
+            .into_iter()
+            .map(|d| (Utf8Path::new(d), format!("{TMP_PREFIX}{d}")))
+            .collect::<HashMap<_, _>>();
+        let order = |last| {
+            exchange_order(&updates, last)
+                .into_iter()
+                .map(|(dst, _)| dst.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(order(Some("BOOT")), ["EFI", "fedora", "BOOT"]);
+        assert_eq!(order(None), ["BOOT", "EFI", "fedora"]);
         Ok(())
     }
 
@@ -538,6 +1074,28 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_filetree_many() -> Result<()> {
+        let tmpd = tempfile::tempdir()?;
+        let p = tmpd.path();
+        for i in 0..50 {
+            let dir = p.join(format!("d{}", i % 3));
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(dir.join(format!("f{i}")), format!("contents {i}"))?;
+        }
+        let d = openat::Dir::open(p)?;
+        let t = FileTree::new_from_dir(&d)?;
+        assert_eq!(t.children.len(), 50);
+        for (k, v) in t.children.iter() {
+            assert_eq!(v, &FileMetadata::new_from_path(&d, k.as_str())?);
+        }
+        // Serialization doesn't depend on the hashing order
+        let a = serde_json::to_string(&t)?;
+        let b = serde_json::to_string(&FileTree::new_from_dir(&d)?)?;
+        assert_eq!(a, b);
+        Ok(())
+    }
+
     #[test]
     fn test_filetree2() -> Result<()> {
         let tmpd = tempfile::tempdir()?;
@@ -595,6 +1153,20 @@ mod tests {
         Ok(())
     }
     #[test]
+    fn test_check_fat_names() -> Result<()> {
+        let set = |p: &[&str]| p.iter().map(|p| p.to_string()).collect::<BTreeSet<_>>();
+        let mut diff = FileTreeDiff {
+            additions: set(&["EFI/BOOT/BOOTX64.EFI"]),
+            removals: set(&["EFI/BOOT/bootx64.efi"]),
+            changes: set(&["EFI/fedora/grubx64.efi"]),
+        };
+        check_fat_names(&diff)?;
+        diff.changes.insert("efi/boot/bootx64.efi.".into());
+        let e = check_fat_names(&diff).unwrap_err();
+        assert!(e.to_string().contains("same file on FAT"), "{e}");
+        Ok(())
+    }
+    #[test]
     fn test_cleanup_tmp() -> Result<()> {
         let tmpd = tempfile::tempdir()?;
         let p = tmpd.path();
@@ -713,4 +1285,58 @@ mod tests {
         }
         Ok(())
     }
+
+    /// Compare `value` as pretty-printed JSON with the golden file `name`
+    /// in the fixtures; with `BOOTUPD_UPDATE_GOLDEN` set, update it instead.
+    fn assert_golden(fixtures: &Path, name: &str, value: &impl Serialize) -> Result<()> {
+        let path = fixtures.join(name);
+        let actual = serde_json::to_string_pretty(value)? + "\n";
+        if std::env::var_os("BOOTUPD_UPDATE_GOLDEN").is_some() {
+            fs::write(&path, &actual)?;
+        }
+        let expected = fs::read_to_string(&path).with_context(|| format!("Reading {path:?}"))?;
+        assert_eq!(actual, expected, "{name} differs");
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_paths() -> Result<()> {
+        let meta = FileMetadata {
+            size: 0,
+            sha512: SHA512String::from_hasher(&mut Hasher::new(MessageDigest::sha512())?),
+        };
+        for path in ["fedora/shimx64.efi", "BOOTX64.EFI", ".fedora.efi"] {
+            let tree = serde_json::json!({"children": {path: meta}});
+            serde_json::from_value::<FileTree>(tree)?;
+        }
+        for path in [
+            "",
+            "/etc/passwd",
+            "../grub2/grub.cfg",
+            "fedora/../../x",
+            "fedora//x",
+            "./x",
+            "fedora/",
+            "a\0b",
+        ] {
+            let tree = serde_json::json!({"children": {path: meta}});
+            assert!(serde_json::from_value::<FileTree>(tree).is_err(), "{path:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_golden() -> Result<()> {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/filetree");
+        let old = FileTree::new_from_path(fixtures.join("old"))?;
+        let new = FileTree::new_from_path(fixtures.join("new"))?;
+        assert_golden(&fixtures, "old.json", &old)?;
+        assert_golden(&fixtures, "new.json", &new)?;
+        assert_golden(&fixtures, "diff.json", &old.diff(&new)?)?;
+        assert_golden(&fixtures, "diff-reverse.json", &new.diff(&old)?)?;
+        // The manifests round-trip
+        let parsed: FileTree = serde_json::from_str(&fs::read_to_string(fixtures.join("old.json"))?)?;
+        assert_eq!(parsed, old);
+        Ok(())
+    }
 }
@@ -0,0 +1,121 @@
+//! Information on the platform firmware of the running system, reported
+//! next to the component versions as bootloader problems frequently
+//! depend on the firmware revision.
+
+use std::path::Path;
+
+use crate::model::{BootMode, Firmware};
+
+/// The EFI global variable reporting whether Secure Boot is enabled
+const SECURE_BOOT_VAR: &str = "SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+/// Return `true` if the system is booted via EFI
+pub(crate) fn is_efi_booted() -> anyhow::Result<bool> {
+    Path::new("/sys/firmware/efi")
+        .try_exists()
+        .map_err(Into::into)
+}
+
+/// Read a DMI attribute, ignoring missing or empty ones.
+fn read_dmi(dmi: &Path, name: &str) -> Option<String> {
+    let v = std::fs::read_to_string(dmi.join(name)).ok()?;
+    let v = v.trim();
+    (!v.is_empty()).then(|| v.to_string())
+}
+
+/// Query the firmware using the sysfs mounted at `sysfs`.
+fn query_in(sysfs: &Path) -> Firmware {
+    let efi = sysfs.join("firmware/efi");
+    let boot_mode = if efi.exists() {
+        BootMode::Uefi
+    } else if cfg!(target_arch = "x86_64") {
+        BootMode::Bios
+    } else {
+        BootMode::Other
+    };
+    // Skip the first 4 bytes, those are the EFI variable attributes.
+    let secure_boot = (boot_mode == BootMode::Uefi).then(|| {
+        std::fs::read(efi.join("efivars").join(SECURE_BOOT_VAR))
+            .map(|v| v.get(4) == Some(&1))
+            .unwrap_or_default()
+    });
+    let dmi = sysfs.join("class/dmi/id");
+    Firmware {
+        boot_mode,
+        secure_boot,
+        vendor: read_dmi(&dmi, "bios_vendor"),
+        version: read_dmi(&dmi, "bios_version"),
+        date: read_dmi(&dmi, "bios_date"),
+    }
+}
+
+/// Query the firmware of the running system.
+pub(crate) fn query() -> Firmware {
+    query_in(Path::new("/sys"))
+}
+
+/// Describe `firmware` for humans, e.g. `UEFI (Secure Boot enabled), <vendor> <version>`.
+pub(crate) fn describe(firmware: &Firmware) -> String {
+    let mut r = match firmware.boot_mode {
+        BootMode::Uefi => "UEFI".to_string(),
+        BootMode::Bios => "BIOS".to_string(),
+        BootMode::Other => "other".to_string(),
+    };
+    match firmware.secure_boot {
+        Some(true) => r.push_str(" (Secure Boot enabled)"),
+        Some(false) => r.push_str(" (Secure Boot disabled)"),
+        None => {}
+    }
+    let details = [&firmware.vendor, &firmware.version, &firmware.date]
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !details.is_empty() {
+        r.push_str(", ");
+        r.push_str(&details.join(" "));
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::fs;
+
+    #[test]
+    fn test_query() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sysfs = td.path();
+        let dmi = sysfs.join("class/dmi/id");
+        fs::create_dir_all(&dmi)?;
+        fs::write(dmi.join("bios_vendor"), "LENOVO\n")?;
+        fs::write(dmi.join("bios_version"), "N2HET77W (1.60 )\n")?;
+        fs::write(dmi.join("bios_date"), "\n")?;
+        let efivars = sysfs.join("firmware/efi/efivars");
+        fs::create_dir_all(&efivars)?;
+        fs::write(efivars.join(SECURE_BOOT_VAR), [6, 0, 0, 0, 1])?;
+        let firmware = query_in(sysfs);
+        assert_eq!(
+            firmware,
+            Firmware {
+                boot_mode: BootMode::Uefi,
+                secure_boot: Some(true),
+                vendor: Some("LENOVO".into()),
+                version: Some("N2HET77W (1.60 )".into()),
+                date: None,
+            }
+        );
+        assert_eq!(
+            describe(&firmware),
+            "UEFI (Secure Boot enabled), LENOVO N2HET77W (1.60 )"
+        );
+
+        fs::remove_dir_all(sysfs.join("firmware"))?;
+        let firmware = query_in(sysfs);
+        assert_eq!(firmware.secure_boot, None);
+        assert_ne!(firmware.boot_mode, BootMode::Uefi);
+        Ok(())
+    }
+}
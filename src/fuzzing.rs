@@ -0,0 +1,67 @@
+//! Entry points for the fuzz targets in `fuzz/`, which feed arbitrary
+//! input to the parsers of files bootupd reads as root from mutable media
+//! or other processes.  Besides not panicking, whatever is accepted must
+//! be usable: parsed state is written back and read again, and manifests
+//! are diffed.  This is not part of the library API.
+
+use std::io::Cursor;
+
+use crate::filetree::FileTree;
+use crate::model::SavedState;
+
+/// The state file in `/boot`, in the current or a legacy format.
+pub fn state_file(data: &[u8]) {
+    let Ok(state) = SavedState::parse(Cursor::new(data)) else {
+        return;
+    };
+    let written = serde_json::to_vec(&state).expect("serializing state");
+    let reparsed = SavedState::parse(Cursor::new(written)).expect("parsing written state");
+    let _ = crate::model::StateSummary::from(reparsed);
+}
+
+/// The update metadata of a component in `/usr/lib/bootupd/updates`.
+pub fn update_metadata(data: &[u8]) {
+    if let Ok(meta) = crate::component::parse_update_metadata(data) {
+        let _ = meta.can_upgrade_to(&crate::component::unknown_version());
+    }
+}
+
+/// A file tree manifest, as recorded in the state for the installed files.
+pub fn filetree(data: &[u8]) {
+    let Ok(tree) = serde_json::from_slice::<FileTree>(data) else {
+        return;
+    };
+    let unchanged = tree.diff(&tree).expect("diffing a tree with itself");
+    assert!(unchanged.additions.is_empty() && unchanged.removals.is_empty());
+    assert!(unchanged.changes.is_empty());
+    let empty = FileTree {
+        children: Default::default(),
+    };
+    let removed = tree.diff(&empty).expect("diffing with an empty tree");
+    assert_eq!(removed.removals.len(), tree.children.len());
+}
+
+/// The JSON output of `lsblk`, listing the block devices.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+pub fn lsblk(data: &[u8]) {
+    let Ok(lsblk) = std::str::from_utf8(data) else {
+        return;
+    };
+    let Ok(topology) = crate::blockdev::Topology::parse(lsblk) else {
+        return;
+    };
+    for device in topology.devices() {
+        let _ = topology.parent_disk(&device.path);
+        let _ = topology.children(&device.path).count();
+        let _ = topology.filesystem_devices(device);
+        #[cfg(all(have_bios, target_arch = "x86_64"))]
+        let _ = topology.lacks_bios_boot_partition(&device.path);
+    }
+    let _ = topology.mounted_at("/boot/efi");
+    let disks = topology.devices().iter().map(|d| d.path.clone()).collect();
+    let _ = topology.prep_partitions(&disks);
+}
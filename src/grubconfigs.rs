@@ -5,8 +5,6 @@ use anyhow::{anyhow, Context, Result};
 use fn_error_context::context;
 use openat_ext::OpenatDirExt;
 
-/// The subdirectory of /boot we use
-const GRUB2DIR: &str = "grub";
 const CONFIGDIR: &str = "/usr/lib/bootupd/grub-static";
 const DROPINDIR: &str = "configs.d";
 
@@ -25,8 +23,11 @@ pub(crate) fn install(
         root_dev != boot_dev
     };
 
-    if !bootdir.exists(GRUB2DIR)? {
-        bootdir.create_dir(GRUB2DIR, 0o700)?;
+    // Following the convention of what's already in /boot
+    let grub_dir =
+        crate::distro::Profile::detect(Path::new("/"))?.boot_grub_dir(&bootdir.recover_path()?)?;
+    if !bootdir.exists(grub_dir)? {
+        bootdir.create_dir(grub_dir, 0o700)?;
     }
 
     let mut config = std::fs::read_to_string(Path::new(CONFIGDIR).join("grub-static-pre.cfg"))?;
@@ -49,7 +50,7 @@ pub(crate) fn install(
         }
         writeln!(config, "source $prefix/{name}")?;
         dropindir
-            .copy_file_at(name, bootdir, format!("{GRUB2DIR}/{name}"))
+            .copy_file_at(name, bootdir, format!("{grub_dir}/{name}"))
             .with_context(|| format!("Copying {name}"))?;
         println!("Installed {name}");
     }
@@ -60,7 +61,7 @@ pub(crate) fn install(
     }
 
     bootdir
-        .write_file_contents(format!("{GRUB2DIR}/grub.cfg"), 0o644, config.as_bytes())
+        .write_file_contents(format!("{grub_dir}/grub.cfg"), 0o644, config.as_bytes())
         .context("Copying grub-static.cfg")?;
     println!("Installed: grub.cfg");
 
@@ -71,7 +72,7 @@ pub(crate) fn install(
             .uuid
             .ok_or_else(|| anyhow::anyhow!("Failed to find UUID for boot"))?;
         let grub2_uuid_contents = format!("set BOOT_UUID=\"{bootfs_uuid}\"\n");
-        let uuid_path = format!("{GRUB2DIR}/bootuuid.cfg");
+        let uuid_path = format!("{grub_dir}/bootuuid.cfg");
         bootdir
             .write_file_contents(&uuid_path, 0o644, grub2_uuid_contents)
             .context("Writing bootuuid.cfg")?;
@@ -84,9 +85,16 @@ pub(crate) fn install(
         log::debug!("vendordir={:?}", &vendordir);
         let vendor = PathBuf::from(vendordir);
         let target = &vendor.join("grub.cfg");
-        let dest_efidir = target_root
-            .sub_dir_optional("boot/efi/EFI")
-            .context("Opening /boot/efi/EFI")?;
+        // With the ESP mounted at /boot, it's /boot/EFI
+        let mut dest_efidir = None;
+        for p in ["boot/efi/EFI", "boot/EFI"] {
+            dest_efidir = target_root
+                .sub_dir_optional(p)
+                .with_context(|| format!("Opening /{p}"))?;
+            if dest_efidir.is_some() {
+                break;
+            }
+        }
         if let Some(efidir) = dest_efidir {
             efidir
                 .copy_file(&Path::new(CONFIGDIR).join("grub-static-efi.cfg"), target)
@@ -100,9 +108,12 @@ pub(crate) fn install(
                     .copy_file_at(uuid_path, &efidir, target)
                     .context("Writing bootuuid.cfg to efi dir")?;
             }
+            crate::util::syncfs(&efidir)?;
         }
     }
 
+    // grub.cfg and bootuuid.cfg are written in place
+    crate::util::syncfs(bootdir)?;
     Ok(())
 }
 
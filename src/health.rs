@@ -0,0 +1,111 @@
+//! Boot health checks, for use with greenboot.
+//!
+//! `bootupd health-check` is run from greenboot's `check/required.d` after
+//! boot and fails if the boot chain doesn't match what bootupd installed.
+//! `bootupd health-check --rollback` is the `red.d` handler: on ostree
+//! systems it reapplies the bootloader payloads of the rollback deployment,
+//! so that the boot chain matches the OS content that will be booted next.
+//! See `contrib/greenboot`.
+
+use anyhow::{bail, Result};
+
+use crate::bootupd;
+use crate::component::ValidationResult;
+use crate::model::SavedState;
+
+/// Return the problems found with the boot chain in `state`.
+fn problems(state: &SavedState) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    for (name, update) in state.pending.iter().flatten() {
+        r.push(format!(
+            "{name}: Update to {} was interrupted",
+            update.version
+        ));
+    }
+    for name in state.installed.keys() {
+        match bootupd::validate(name, &Default::default())? {
+            ValidationResult::Valid | ValidationResult::Skip => {}
+            ValidationResult::Errors(errs) => {
+                r.extend(errs.into_iter().map(|e| format!("{name}: {e}")));
+            }
+        }
+    }
+    let installed = state
+        .installed
+        .iter()
+        .map(|(name, c)| (name.clone(), c.into()))
+        .collect();
+    for i in crate::consistency::check_installed(&installed, &Default::default())? {
+        r.push(format!("Inconsistent: {i}"));
+    }
+    Ok(r)
+}
+
+/// Check the boot chain of the running system, erroring out if unhealthy.
+pub(crate) fn check() -> Result<()> {
+    let Some(state) = SavedState::load_from_disk("/")? else {
+        println!("No components installed.");
+        return Ok(());
+    };
+    let problems = problems(&state)?;
+    for p in problems.iter() {
+        eprintln!("{p}");
+    }
+    if !problems.is_empty() {
+        bail!("Boot chain is unhealthy");
+    }
+    for name in state.installed.keys() {
+        println!("Healthy: {name}");
+    }
+    Ok(())
+}
+
+/// Roll the installed components back to the payloads of the ostree
+/// rollback deployment.
+pub(crate) fn rollback() -> Result<()> {
+    let Some(deployments) = crate::ostreeutil::deployment_state()? else {
+        bail!("Bootloader rollback is only supported on ostree systems");
+    };
+    let Some(root) = deployments.rollback_root else {
+        bail!("No rollback deployment");
+    };
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let names = state.installed.keys().cloned().collect::<Vec<_>>();
+    if names.is_empty() {
+        println!("No components installed.");
+        return Ok(());
+    }
+    println!("Rolling back to the bootloader of {}", root.display());
+    let source = openat::Dir::open(&root)?;
+    let results = bootupd::update_many_impl(
+        &names,
+        bootupd::DEFAULT_UPDATE_JOBS,
+        &source,
+        true,
+        &Default::default(),
+    )?;
+    if bootupd::print_update_results(results).is_empty() {
+        println!("Bootloader already matches the rollback deployment");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problems_interrupted() -> Result<()> {
+        let mut state = SavedState::default();
+        assert!(problems(&state)?.is_empty());
+        let meta = serde_json::from_str(
+            r#"{"timestamp": "2020-09-15T13:01:21Z", "version": "grub2-efi-x64-1:2.04-23.fc32.x86_64"}"#,
+        )?;
+        state.pending = Some([("EFI".to_string(), meta)].into());
+        assert_eq!(
+            problems(&state)?,
+            ["EFI: Update to grub2-efi-x64-1:2.04-23.fc32.x86_64 was interrupted"]
+        );
+        Ok(())
+    }
+}
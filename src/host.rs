@@ -0,0 +1,134 @@
+//! Support for operating on the host system from inside a container.
+//!
+//! The container must share the host's PID namespace (so that `/proc/1`
+//! is the host's init) and be privileged enough to enter its mount
+//! namespace; e.g. `podman run --privileged --pid=host -v /dev:/dev`.
+
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+
+/// The mount namespace of the host's init process.
+const HOST_MNTNS: &str = "/proc/1/ns/mnt";
+/// The mount namespace of this process.
+const SELF_MNTNS: &str = "/proc/self/ns/mnt";
+/// The required container invocation, for diagnostics.
+pub(crate) const CONTAINER_HINT: &str =
+    "run the container with --privileged --pid=host -v /dev:/dev";
+
+/// Returns `true` if the two namespace links refer to different namespaces.
+fn namespaces_differ(a: &Path, b: &Path) -> Result<bool> {
+    let a = std::fs::read_link(a).with_context(|| format!("Reading {a:?}"))?;
+    let b = std::fs::read_link(b).with_context(|| format!("Reading {b:?}"))?;
+    Ok(a != b)
+}
+
+/// Returns `true` if this process has a mount namespace of its own, as under
+/// `systemd-run` with `MountFlags=slave` or after [`enter`], so that changes
+/// to mounts don't affect the rest of the system.
+pub(crate) fn in_private_mount_namespace() -> bool {
+    namespaces_differ(Path::new(HOST_MNTNS), Path::new(SELF_MNTNS)).unwrap_or_default()
+}
+
+/// Check the paths in the (host) root required for bootloader operations.
+fn verify_host_root(root: &Path) -> Result<()> {
+    for p in ["boot", "dev", "sys/block", "usr"] {
+        let p = root.join(p);
+        if !p.try_exists()? {
+            bail!("Missing {p:?} in host root; {CONTAINER_HINT}");
+        }
+    }
+    Ok(())
+}
+
+/// Enter the mount namespace of the host, so that `/` is the host root
+/// with its devices and mounts (including the ESP) visible.
+#[context("Entering host mount namespace")]
+pub(crate) fn enter() -> Result<()> {
+    if !rustix::process::getuid().is_root() {
+        bail!("--host requires root privileges");
+    }
+    if !Path::new("/proc/1/root").try_exists()? {
+        bail!("Host root not visible via /proc/1/root; {CONTAINER_HINT}");
+    }
+    let differ = namespaces_differ(Path::new(HOST_MNTNS), Path::new(SELF_MNTNS))
+        .with_context(|| format!("Failed to inspect namespaces; {CONTAINER_HINT}"))?;
+    if differ {
+        let ns = File::open(HOST_MNTNS).with_context(|| format!("Opening {HOST_MNTNS}"))?;
+        // SAFETY: setns() is safe to call with a valid fd; we're still
+        // single threaded at this point, which is required for mount namespaces.
+        let r = unsafe { libc::setns(ns.as_raw_fd(), libc::CLONE_NEWNS) };
+        if r != 0 {
+            let e = std::io::Error::last_os_error();
+            bail!("setns: {e}; {CONTAINER_HINT}");
+        }
+        log::debug!("Entered host mount namespace");
+    } else {
+        log::debug!("Already in host mount namespace");
+    }
+    verify_host_root(Path::new("/"))?;
+    unshare_mount_namespace()
+}
+
+/// Move into a mount namespace of our own, unless already in one (see
+/// [`in_private_mount_namespace`]), so that like under systemd-run, changes
+/// to mounts (e.g. a writable /boot) are kept to ourselves, while those of
+/// the host are still seen.  This fails once other threads were started.
+pub(crate) fn unshare_mount_namespace() -> Result<()> {
+    if crate::simulate::active() || in_private_mount_namespace() {
+        return Ok(());
+    }
+    // SAFETY: the mount arguments are valid NUL-terminated strings.
+    let r = unsafe {
+        if libc::unshare(libc::CLONE_NEWNS) != 0 {
+            -1
+        } else {
+            libc::mount(
+                std::ptr::null(),
+                b"/\0".as_ptr().cast(),
+                std::ptr::null(),
+                libc::MS_REC | libc::MS_SLAVE,
+                std::ptr::null(),
+            )
+        }
+    };
+    if r != 0 {
+        let e = std::io::Error::last_os_error();
+        bail!("Creating a private mount namespace: {e}");
+    }
+    log::debug!("Entered a private mount namespace");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_namespaces_differ() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let td = td.path();
+        std::os::unix::fs::symlink("mnt:[4026531841]", td.join("a"))?;
+        std::os::unix::fs::symlink("mnt:[4026531841]", td.join("b"))?;
+        std::os::unix::fs::symlink("mnt:[4026532563]", td.join("c"))?;
+        assert!(!namespaces_differ(&td.join("a"), &td.join("b"))?);
+        assert!(namespaces_differ(&td.join("a"), &td.join("c"))?);
+        assert!(namespaces_differ(&td.join("a"), &td.join("nonexistent")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_host_root() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let td = td.path();
+        assert!(verify_host_root(td).is_err());
+        for d in ["boot", "dev", "sys/block", "usr"] {
+            std::fs::create_dir_all(td.join(d))?;
+        }
+        verify_host_root(td)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+//! Installing a bootloader to a boot device, behind a trait so that the
+//! BIOS component can use other strategies than running `grub-install`
+//! (e.g. embedding a prebuilt core image, or a vendor installer).
+//!
+//! The component decides where to install to, checks the target and copies
+//! the modules to `/boot`; a [`BootloaderInstaller`] only writes the
+//! bootloader of a [`Target`] and reads it back for verification.
+//! [`GrubInstall`] runs `grub-install` as before.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+
+use crate::distro::Profile;
+use crate::system::System;
+use crate::util;
+
+/// What to install a bootloader to.
+#[derive(Debug)]
+pub(crate) struct Target<'a> {
+    /// The GRUB platform, e.g. `i386-pc`
+    pub(crate) platform: &'a str,
+    /// The device to install to: the disk on x86_64, the PReP partition on
+    /// PowerPC
+    pub(crate) device: &'a Path,
+    /// The directory of the modules of `platform` to install
+    pub(crate) modules: &'a Path,
+    /// The `/boot` of the target root
+    pub(crate) boot_dir: &'a Path,
+    /// The GRUB directory in `boot_dir`, e.g. `/boot/grub2`
+    pub(crate) grub_dir: &'a Path,
+}
+
+/// Writes the bootloader of a target, e.g. GRUB's core image to the BIOS
+/// boot partition.
+pub(crate) trait BootloaderInstaller: Send + Sync {
+    /// The name of the strategy, for logs and spans, e.g. `grub-install`.
+    fn name(&self) -> &'static str;
+
+    /// Install the bootloader of `target` on `system`.
+    fn install(&self, system: &dyn System, target: &Target) -> Result<()>;
+
+    /// Read back what [`BootloaderInstaller::install`] wrote to the device,
+    /// failing if it differs; see [`crate::readback`].
+    fn verify(&self, _system: &dyn System, _target: &Target) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Installs GRUB with the `grub-install` of the root of the system.
+#[derive(Debug, Default)]
+pub(crate) struct GrubInstall;
+
+impl BootloaderInstaller for GrubInstall {
+    fn name(&self) -> &'static str {
+        "grub-install"
+    }
+
+    fn install(&self, system: &dyn System, target: &Target) -> Result<()> {
+        let root = system.root();
+        let grub_install = root.join(Profile::detect(root)?.grub_install);
+        if !grub_install.exists() {
+            bail!("Failed to find {:?}", grub_install);
+        }
+
+        let mut cmd = util::command(grub_install);
+        // Forcibly add mdraid1x and part_gpt
+        #[cfg(target_arch = "x86_64")]
+        cmd.args(["--target", target.platform])
+            .arg("--directory")
+            .arg(target.modules)
+            .arg("--boot-directory")
+            .arg(target.boot_dir)
+            .args(["--modules", "mdraid1x part_gpt"])
+            .arg(target.device);
+
+        #[cfg(target_arch = "powerpc64")]
+        cmd.args(&["--target", target.platform])
+            .arg("--directory")
+            .arg(target.modules)
+            .arg("--boot-directory")
+            .arg(target.boot_dir)
+            .arg("--no-nvram")
+            .arg(target.device);
+
+        // udev may briefly hold the device open, e.g. after partitioning
+        let cmdout = system.output(&mut cmd)?;
+        if !cmdout.status.success() {
+            return Err(util::tool_failed(&cmd, cmdout.status, &cmdout.stderr));
+        }
+        Ok(())
+    }
+
+    // Read back the core image grub-install embedded in the BIOS boot
+    // partition or after the MBR of the device.  Its first sector is
+    // patched with where the rest is, so only the rest is compared; so is
+    // the size of the Reed-Solomon codes in the second, which are appended
+    // past the end of the image.
+    #[cfg(target_arch = "x86_64")]
+    fn verify(&self, system: &dyn System, target: &Target) -> Result<()> {
+        use crate::blockdev::EmbeddingArea;
+        use std::path::PathBuf;
+
+        const SKIP: u64 = 512;
+        // GRUB_KERNEL_I386_PC_REED_SOLOMON_REDUNDANCY, in the second sector
+        const REDUNDANCY: std::ops::Range<usize> = 0x10..0x14;
+        let image = std::fs::read(target.grub_dir.join(target.platform).join("core.img"))?;
+        let expected = image.get(SKIP as usize..).unwrap_or_default();
+        let topology = system.topology()?;
+        let device = target.device;
+        let (dev, offset) = match topology.embedding_area(device)? {
+            Some(EmbeddingArea::BiosBootPartition(path, _)) => (PathBuf::from(path), SKIP),
+            // Right after the MBR
+            Some(EmbeddingArea::MbrGap(_)) => (device.to_owned(), 2 * SKIP),
+            None => {
+                log::debug!("No embedding area on {}", device.display());
+                return Ok(());
+            }
+        };
+        for range in [0..REDUNDANCY.start, REDUNDANCY.end..expected.len()] {
+            let Some(part) = expected.get(range.clone()) else {
+                continue;
+            };
+            let offset = offset + range.start as u64;
+            crate::readback::verify_at(&dev, offset, "the GRUB core image", part)?;
+        }
+        Ok(())
+    }
+
+    // Read back the core image grub-install wrote to the PReP partition,
+    // unmodified.
+    #[cfg(target_arch = "powerpc64")]
+    fn verify(&self, _system: &dyn System, target: &Target) -> Result<()> {
+        let image = target.grub_dir.join(target.platform).join("core.elf");
+        let expected = std::fs::read(&image)?;
+        crate::readback::verify_at(target.device, 0, "the GRUB core image", &expected)
+    }
+}
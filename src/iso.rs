@@ -0,0 +1,175 @@
+//! Boot images for hybrid ISOs (live and installer media), built from the
+//! same payloads bootupd installs to disks:
+//!
+//! - `eltorito.img`: a GRUB core image for BIOS El Torito booting, built
+//!   from the `i386-pc` modules of the OS
+//! - `efiboot.img`: a FAT image with the content of the EFI payload, for
+//!   use as the El Torito EFI boot image and the appended ESP
+//!
+//! Assembling the ISO itself (e.g. with `xorriso`) is up to the caller.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+
+use crate::component::*;
+#[cfg(target_arch = "x86_64")]
+use crate::distro::Profile;
+use crate::util::CommandRunExt;
+
+/// The BIOS boot image, relative to the output directory.
+#[cfg(target_arch = "x86_64")]
+pub(crate) const ELTORITO_IMG: &str = "eltorito.img";
+/// The EFI boot image, relative to the output directory.
+pub(crate) const EFIBOOT_IMG: &str = "efiboot.img";
+
+/// Candidate paths to grub-mkimage, relative to the root
+#[cfg(target_arch = "x86_64")]
+const GRUB_MKIMAGE: &[&str] = &["usr/bin/grub2-mkimage", "usr/bin/grub-mkimage"];
+
+/// Modules built into the BIOS image, so that it can find and read its
+/// configuration on the ISO
+#[cfg(target_arch = "x86_64")]
+const ELTORITO_MODULES: &[&str] = &[
+    "biosdisk",
+    "iso9660",
+    "part_gpt",
+    "part_msdos",
+    "normal",
+    "configfile",
+    "search",
+    "search_label",
+];
+
+/// FAT cluster size assumed when sizing the EFI image
+const CLUSTER_SIZE: u64 = 4096;
+/// Space for the FAT structures and directories, in bytes
+const FAT_OVERHEAD: u64 = 1024 * 1024;
+
+/// Return the size (in KiB) of a FAT image holding files of the given sizes.
+fn efiboot_size_kib(sizes: impl IntoIterator<Item = u64>) -> u64 {
+    let data: u64 = sizes
+        .into_iter()
+        .map(|s| s.div_ceil(CLUSTER_SIZE) * CLUSTER_SIZE)
+        .sum();
+    (data + FAT_OVERHEAD).div_ceil(1024)
+}
+
+/// Build the BIOS El Torito image from the GRUB modules in `src_root`.
+#[cfg(target_arch = "x86_64")]
+#[context("Building {ELTORITO_IMG}")]
+fn build_eltorito(src_root: &Path, profile: &Profile, dest: &Path) -> Result<()> {
+    let Some(mkimage) = GRUB_MKIMAGE
+        .iter()
+        .map(|p| src_root.join(p))
+        .find(|p| p.exists())
+    else {
+        bail!("Failed to find grub-mkimage in {GRUB_MKIMAGE:?}");
+    };
+    let modules = profile.grub_modules_dir(src_root, "i386-pc")?;
+    crate::util::command(mkimage)
+        .args(["--format", "i386-pc-eltorito", "--directory"])
+        .arg(&modules)
+        .arg("--prefix")
+        .arg(format!("/boot/{}", profile.grub_dir))
+        .arg("--output")
+        .arg(dest.join(ELTORITO_IMG))
+        .args(ELTORITO_MODULES)
+        .run()
+}
+
+/// Build the EFI boot image from the EFI payload in `src_root`.
+#[context("Building {EFIBOOT_IMG}")]
+fn build_efiboot(src_root: &Path, dest: &Path) -> Result<()> {
+    let efi = new_from_name("EFI")?;
+    let payload = src_root.join(component_updatedirname(efi.as_ref()));
+    if !payload.exists() {
+        bail!("No EFI payload found in {payload:?}");
+    }
+    let mut sizes = Vec::new();
+    for entry in walkdir::WalkDir::new(&payload) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            sizes.push(entry.metadata()?.len());
+        }
+    }
+    let img = dest.join(EFIBOOT_IMG);
+    if img.exists() {
+        std::fs::remove_file(&img)?;
+    }
+    crate::util::command("mkfs.vfat")
+        .args(["-C", "-n", "EFIBOOT"])
+        .arg(&img)
+        .arg(efiboot_size_kib(sizes).to_string())
+        .run()?;
+    // mtools writes to the image without needing a loop mount; the payload
+    // is the content of the ESP's EFI directory
+    crate::util::command("mmd")
+        .arg("-i")
+        .arg(&img)
+        .arg("::/EFI")
+        .run()?;
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(&payload)? {
+        entries.push(entry?.path());
+    }
+    if entries.is_empty() {
+        return Ok(());
+    }
+    crate::util::command("mcopy")
+        .args(["-s", "-p", "-i"])
+        .arg(&img)
+        .args(&entries)
+        .arg("::/EFI")
+        .run()
+        .context("Copying EFI payload")
+}
+
+/// Write the ISO boot images for the payloads in `src_root` to `dest`.
+pub(crate) fn build_images(src_root: &str, dest: &Path) -> Result<()> {
+    let src_root = Path::new(src_root);
+    std::fs::create_dir_all(dest).with_context(|| format!("Creating {dest:?}"))?;
+    #[cfg(target_arch = "x86_64")]
+    {
+        let profile = Profile::detect(src_root)?;
+        build_eltorito(src_root, profile, dest)?;
+        println!("Wrote {}", dest.join(ELTORITO_IMG).display());
+    }
+    build_efiboot(src_root, dest)?;
+    println!("Wrote {}", dest.join(EFIBOOT_IMG).display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_efiboot_size() {
+        assert_eq!(efiboot_size_kib([]), 1024);
+        // Every file takes at least a cluster
+        assert_eq!(efiboot_size_kib([1, 1]), 1024 + 8);
+        assert_eq!(efiboot_size_kib([4096 * 100 + 1]), 1024 + 404);
+    }
+
+    // Requires mtools and dosfstools
+    #[test]
+    #[ignore]
+    fn test_build_efiboot() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let efi = new_from_name("EFI")?;
+        let payload = td.path().join(component_updatedirname(efi.as_ref()));
+        std::fs::create_dir_all(payload.join("BOOT"))?;
+        std::fs::write(payload.join("BOOT/BOOTX64.EFI"), "shim")?;
+        build_efiboot(td.path(), td.path())?;
+        let out = crate::util::command("mtype")
+            .arg("-i")
+            .arg(td.path().join(EFIBOOT_IMG))
+            .arg("::/EFI/BOOT/BOOTX64.EFI")
+            .output()?;
+        assert!(out.status.success());
+        assert_eq!(out.stdout, b"shim");
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+/*!
+**Boot**loader **upd**ater.
+
+This is an early prototype hidden/not-yet-standardized mechanism
+which just updates EFI for now (x86_64/aarch64 only).
+
+But in the future will hopefully gain some independence from
+ostree and also support e.g. updating the MBR etc.
+
+The [`api`] module exposes the core operations, the components and the
+file tree manifests and diffs used for updates for use as a library,
+following semantic versioning.
+
+Refs:
+ * <https://github.com/coreos/fedora-coreos-tracker/issues/510>
+!*/
+
+#![deny(unused_must_use)]
+// The style lints are more annoying than useful
+#![allow(clippy::style)]
+
+pub mod api;
+mod backend;
+#[cfg(have_bios)]
+mod bios;
+#[cfg(any(have_blockdev, feature = "fuzzing"))]
+mod blockdev;
+mod bootupd;
+mod cleanup;
+mod cli;
+mod component;
+mod consistency;
+#[cfg(have_coreboot)]
+mod coreboot;
+mod coreos;
+#[cfg(any(have_coreboot, have_efi, have_rpi))]
+mod digestcache;
+mod distro;
+#[cfg(have_efi)]
+mod efi;
+#[cfg(have_efi)]
+mod entries;
+mod errors;
+#[cfg(have_efi)]
+mod espmanifest;
+mod events;
+#[cfg(have_extlinux)]
+mod extlinux;
+mod failpoints;
+#[cfg(have_fat)]
+mod fatesp;
+#[cfg(feature = "ffi")]
+mod ffi;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+mod filesystem;
+mod filetree;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+mod firmware;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+mod grubconfigs;
+mod health;
+mod host;
+#[cfg(have_bios)]
+mod installer;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod iso;
+mod logging;
+mod model;
+mod model_legacy;
+mod native;
+mod ostreeutil;
+mod output;
+#[cfg(have_component)]
+mod packagesystem;
+#[cfg(all(have_bios, target_arch = "x86_64"))]
+mod partition;
+mod pathnorm;
+mod payload;
+#[cfg(all(have_bios, target_arch = "powerpc64"))]
+mod petitboot;
+mod plan;
+mod preflight;
+#[cfg(have_efi)]
+mod pxe;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(have_readback)]
+mod readback;
+#[cfg(have_rpi)]
+mod rpi;
+#[cfg(have_efi)]
+mod secureboot;
+mod selinux;
+mod sha512string;
+mod simulate;
+mod spans;
+#[cfg(have_bios)]
+mod system;
+mod throttle;
+mod timing;
+#[cfg(have_uboot)]
+mod uboot;
+mod util;
+
+/// CLI logic, for both daemon and client; returns the process exit code.
+#[doc(hidden)]
+pub fn run_cli() -> i32 {
+    // Parse command-line options.
+    let args: Vec<_> = std::env::args().collect();
+    // In place of a tool not run with `--simulate`
+    if args.first().map(String::as_str) == Some(simulate::STUB) {
+        return simulate::run_stub(&args[1..]);
+    }
+    let cli_opts = cli::MultiCall::from_args(args);
+
+    // Setup logging.
+    let config = logging::Config::load();
+    let filters = config.filter.iter().chain(cli_opts.log_filter());
+    let log_backend = cli_opts.log_backend().or(config.backend).unwrap_or_default();
+    logging::init(
+        log_backend,
+        cli_opts.loglevel(),
+        &filters.map(String::as_str).collect::<Vec<_>>(),
+    );
+
+    log::trace!("executing cli");
+
+    // Dispatch CLI subcommand.
+    let error_format = cli_opts.error_format();
+    if cli_opts.timing() {
+        timing::enable();
+    }
+    #[cfg(feature = "spans")]
+    if let Some(sink) = cli_opts.trace_spans() {
+        spans::enable(sink);
+    }
+    let r = match cli_opts.simulate() {
+        Some(root) => simulate::enter(&root),
+        None => Ok(()),
+    }
+    .and_then(|()| cli_opts.run());
+    timing::emit();
+    let code = match r {
+        Ok(_) => libc::EXIT_SUCCESS,
+        Err(e) => {
+            match error_format {
+                cli::ErrorFormat::Human => {
+                    let report = errors::ErrorReport::new(&e);
+                    if let Some(stderr) = report.stderr.as_deref() {
+                        eprintln!("{stderr}");
+                    }
+                    // Use the alternative formatter to get everything on a single line... it reads better.
+                    eprintln!("error: {:#}", e);
+                    if let Some(hint) = report.hint {
+                        eprintln!("hint: {hint}");
+                    }
+                }
+                cli::ErrorFormat::Json => {
+                    let report = errors::ErrorReport::new(&e);
+                    // Fall back to the human format if serialization somehow fails
+                    match serde_json::to_string(&report) {
+                        Ok(s) => eprintln!("{s}"),
+                        Err(_) => eprintln!("error: {:#}", e),
+                    }
+                }
+            }
+            // Which stderr may not be kept
+            if log_backend != logging::Backend::Stderr {
+                log::error!("{:#}", e);
+            }
+            libc::EXIT_FAILURE
+        }
+    };
+    // Log files are synced
+    log::logger().flush();
+    code
+}
@@ -0,0 +1,398 @@
+//! Where log lines go: to stderr as always, to the journal with structured
+//! fields, or to a rotating file in `/var/log/bootupd`, for systems without
+//! journald.  The backend and the filter are given by `--log-backend` and
+//! `--log-filter`, or else by `/etc/bootupd/logging.conf`:
+//!
+//! ```text
+//! # stderr, journal or file
+//! backend = file
+//! filter = bootupd::efi=debug
+//! ```
+//!
+//! Filters have the syntax of `RUST_LOG`, with levels per module path
+//! (e.g. `bootupd::bios`), and apply after it and `-v`.  The journal and
+//! the file keep at least the info messages of bootupd, and a line for
+//! each component operated on and file written, so that changes to the
+//! boot path can be traced afterwards.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use log::LevelFilter;
+
+use crate::events::{Observer, Operation};
+use crate::model::ContentMetadata;
+
+/// The configuration of the backend, if any.
+pub(crate) const CONFIG_PATH: &str = "/etc/bootupd/logging.conf";
+
+/// The directory of the log files of the `file` backend.
+pub(crate) const LOG_DIR: &str = "/var/log/bootupd";
+
+/// The current log file in [`LOG_DIR`]; older ones have a `.N` suffix.
+const LOG_NAME: &str = "bootupd.log";
+
+/// The size past which the log file is rotated.
+const MAX_LOG_SIZE: u64 = 1024 * 1024;
+
+/// The number of rotated log files kept.
+const KEEP_LOGS: u32 = 4;
+
+/// The target of the lines recording changes, i.e. events.
+const CHANGES_TARGET: &str = "bootupd::changes";
+
+/// Where log lines go.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Backend {
+    /// Standard error, as formatted by `env_logger`
+    #[default]
+    Stderr,
+    /// The journal, with `BOOTUPD_MODULE` and `BOOTUPD_COMPONENT` fields
+    Journal,
+    /// Files rotated in /var/log/bootupd
+    File,
+}
+
+/// The settings of [`CONFIG_PATH`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Config {
+    pub(crate) backend: Option<Backend>,
+    pub(crate) filter: Option<String>,
+}
+
+impl Config {
+    /// Parse the `key = value` lines of `data`, warning about the lines
+    /// which aren't understood: logging must not fail the operation.
+    fn parse(data: &str) -> Self {
+        let mut config = Config::default();
+        for line in data.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = match line.split_once('=').map(|(k, v)| (k.trim(), v.trim())) {
+                Some(("backend", v)) => Backend::from_str(v, true)
+                    .map(|b| config.backend = Some(b))
+                    .map_err(|e| anyhow::anyhow!(e)),
+                Some(("filter", v)) => parse_filter(v).map(|f| config.filter = Some(f)),
+                _ => Err(anyhow::anyhow!("Unknown setting")),
+            };
+            if let Err(e) = parsed {
+                eprintln!("warning: Ignoring {line:?} in {CONFIG_PATH}: {e}");
+            }
+        }
+        config
+    }
+
+    /// Read [`CONFIG_PATH`], if it exists.
+    pub(crate) fn load() -> Self {
+        match std::fs::read_to_string(CONFIG_PATH) {
+            Ok(data) => Self::parse(&data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => {
+                eprintln!("warning: Reading {CONFIG_PATH}: {e}");
+                Self::default()
+            }
+        }
+    }
+}
+
+/// Check the filter `s`: comma-separated directives of a level, a module,
+/// or `module=level`, optionally followed by a `/` and a regular expression.
+pub(crate) fn parse_filter(s: &str) -> Result<String> {
+    let directives = s.split('/').next().unwrap_or_default();
+    for directive in directives.split(',').map(str::trim) {
+        if let Some((module, level)) = directive.split_once('=') {
+            if module.trim().is_empty() {
+                bail!("Missing module in {directive:?}");
+            }
+            if level.trim().parse::<LevelFilter>().is_err() {
+                bail!("Invalid level in {directive:?}");
+            }
+        }
+    }
+    Ok(s.to_string())
+}
+
+/// Install the logger of the process: log to `backend`, at `level` for
+/// bootupd, then following `RUST_LOG` and `filters` in order.
+pub(crate) fn init(backend: Backend, level: LevelFilter, filters: &[&str]) {
+    let level = match backend {
+        Backend::Stderr => level,
+        Backend::Journal | Backend::File => level.max(LevelFilter::Info),
+    };
+    let mut builder = env_logger::Builder::new();
+    builder
+        .format_timestamp(None)
+        .format_module_path(false)
+        .filter(Some(clap::crate_name!()), level)
+        .parse_default_env();
+    for filter in filters {
+        builder.parse_filters(filter);
+    }
+    let filter = builder.build();
+    let max_level = filter.filter();
+    let logger: Box<dyn log::Log> = match backend {
+        Backend::Stderr => Box::new(filter),
+        Backend::Journal => Box::new(Logger {
+            filter,
+            sink: Sink::Journal,
+        }),
+        Backend::File => Box::new(Logger {
+            filter,
+            sink: Sink::File(Mutex::new(RotatingFile::new(Path::new(LOG_DIR)))),
+        }),
+    };
+    if log::set_boxed_logger(logger).is_err() {
+        return;
+    }
+    log::set_max_level(max_level);
+    if backend != Backend::Stderr {
+        // For the rest of the process
+        std::mem::forget(crate::events::register(Arc::new(ChangeLog)));
+    }
+}
+
+/// A backend other than stderr.
+enum Sink {
+    Journal,
+    File(Mutex<RotatingFile>),
+}
+
+/// Sends the records [`env_logger`] lets through to a [`Sink`].
+struct Logger {
+    filter: env_logger::Logger,
+    sink: Sink,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        log::Log::enabled(&self.filter, metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let r = match &self.sink {
+            Sink::Journal => send_to_journal(record),
+            Sink::File(file) => {
+                let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                file.write(&format_line(chrono::Utc::now(), record))
+            }
+        };
+        // Rather than losing the line
+        if r.is_err() {
+            eprintln!("{}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        if let Sink::File(file) = &self.sink {
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = file.sync();
+        }
+    }
+}
+
+/// The line of `record` in a log file, logged at `now`.
+fn format_line(now: chrono::DateTime<chrono::Utc>, record: &log::Record) -> String {
+    let ts = now.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    let message = record.args().to_string().replace('\n', " ");
+    format!(
+        "{ts} {:<5} {}: {message}\n",
+        record.level(),
+        record.target()
+    )
+}
+
+fn send_to_journal(record: &log::Record) -> Result<()> {
+    use libsystemd::logging::Priority;
+
+    let priority = match record.level() {
+        log::Level::Error => Priority::Error,
+        log::Level::Warn => Priority::Warning,
+        log::Level::Info => Priority::Info,
+        log::Level::Debug | log::Level::Trace => Priority::Debug,
+    };
+    let line = record.line().map(|l| l.to_string());
+    let component = crate::events::current_component();
+    let fields = [
+        ("SYSLOG_IDENTIFIER", Some(clap::crate_name!())),
+        ("BOOTUPD_MODULE", Some(record.target())),
+        ("BOOTUPD_COMPONENT", component.as_deref()),
+        ("CODE_FILE", record.file()),
+        ("CODE_LINE", line.as_deref()),
+    ];
+    let fields = fields.into_iter().filter_map(|(k, v)| v.map(|v| (k, v)));
+    libsystemd::logging::journal_send(priority, &record.args().to_string(), fields)?;
+    Ok(())
+}
+
+/// The log file of a directory, renamed with a `.1` suffix (and the older
+/// ones to the next) when it grows past [`MAX_LOG_SIZE`].
+struct RotatingFile {
+    dir: PathBuf,
+    /// The open log file and its size
+    file: Option<(std::fs::File, u64)>,
+}
+
+impl RotatingFile {
+    fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_owned(),
+            file: None,
+        }
+    }
+
+    fn path(&self, generation: u32) -> PathBuf {
+        match generation {
+            0 => self.dir.join(LOG_NAME),
+            n => self.dir.join(format!("{LOG_NAME}.{n}")),
+        }
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.file = None;
+        for n in (0..KEEP_LOGS).rev() {
+            match std::fs::rename(self.path(n), self.path(n + 1)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, line: &str) -> Result<()> {
+        let len = line.len() as u64;
+        if matches!(self.file, Some((_, size)) if size > 0 && size + len > MAX_LOG_SIZE) {
+            self.rotate()?;
+        }
+        if self.file.is_none() {
+            std::fs::create_dir_all(&self.dir)?;
+            let f = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path(0))?;
+            let size = f.metadata()?.len();
+            self.file = Some((f, size));
+            if size > 0 && size + len > MAX_LOG_SIZE {
+                return self.write(line);
+            }
+        }
+        // Unwrap safety: opened above
+        let (f, size) = self.file.as_mut().unwrap();
+        f.write_all(line.as_bytes())?;
+        *size += len;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if let Some((f, _)) = self.file.as_ref() {
+            f.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// Logs the operations on components and the files they write.
+struct ChangeLog;
+
+impl Observer for ChangeLog {
+    fn on_component_start(&self, component: &str, operation: Operation) {
+        log::info!(target: CHANGES_TARGET, "{operation:?} of {component} started");
+    }
+
+    fn on_file_written(&self, component: &str, path: &Path) {
+        log::info!(target: CHANGES_TARGET, "{component}: wrote {}", path.display());
+    }
+
+    fn on_component_complete(
+        &self,
+        component: &str,
+        operation: Operation,
+        version: &ContentMetadata,
+    ) {
+        log::info!(
+            target: CHANGES_TARGET,
+            "{operation:?} of {component} to {} completed",
+            version.version
+        );
+        log::logger().flush();
+    }
+
+    fn on_error(&self, component: &str, operation: Operation, error: &anyhow::Error) {
+        log::error!(target: CHANGES_TARGET, "{operation:?} of {component} failed: {error:#}");
+        log::logger().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config =
+            Config::parse("# Persistent logs\nbackend = file\nfilter=bootupd::efi=debug\n");
+        assert_eq!(
+            config,
+            Config {
+                backend: Some(Backend::File),
+                filter: Some("bootupd::efi=debug".into()),
+            }
+        );
+        // Bad lines are ignored
+        let config = Config::parse("backend = syslog\nfilter = =debug\nlevel = 3\n");
+        assert_eq!(config, Config::default());
+        assert!(parse_filter("warn,bootupd=info/EFI").is_ok());
+        assert!(parse_filter("bootupd=loud").is_err());
+    }
+
+    #[test]
+    fn test_rotating_file() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let dir = td.path().join("bootupd");
+        let mut file = RotatingFile::new(&dir);
+        let line = "x".repeat(MAX_LOG_SIZE as usize / 2 - 1) + "\n";
+        for _ in 0..2 + KEEP_LOGS * 2 {
+            file.write(&line)?;
+        }
+        file.sync()?;
+        for n in 0..=KEEP_LOGS {
+            let size = std::fs::metadata(file.path(n))?.len();
+            assert_eq!(size, 2 * line.len() as u64, "{n}");
+        }
+        assert!(!file.path(KEEP_LOGS + 1).exists());
+        // An existing file is appended to, then rotated
+        let mut file = RotatingFile::new(&dir);
+        file.write("y\n")?;
+        assert_eq!(std::fs::read_to_string(file.path(0))?, "y\n");
+        assert_eq!(
+            std::fs::metadata(file.path(1))?.len(),
+            2 * line.len() as u64
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_line() {
+        let now = chrono::DateTime::parse_from_rfc3339("2024-01-02T03:04:05Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let line = format_line(
+            now,
+            &log::Record::builder()
+                .args(format_args!("Updated\nEFI"))
+                .level(log::Level::Info)
+                .target("bootupd::efi")
+                .build(),
+        );
+        assert_eq!(
+            line,
+            "2024-01-02T03:04:05.000Z INFO  bootupd::efi: Updated EFI\n"
+        );
+    }
+}
@@ -5,19 +5,22 @@
  */
 
 use chrono::prelude::*;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::path::PathBuf;
 
 /// The directory where updates are stored
 pub(crate) const BOOTUPD_UPDATES_DIR: &str = "usr/lib/bootupd/updates";
 
-#[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Debug, Hash, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) struct ContentMetadata {
+#[non_exhaustive]
+pub struct ContentMetadata {
     /// The timestamp, which is used to determine update availability
-    pub(crate) timestamp: DateTime<Utc>,
+    pub timestamp: DateTime<Utc>,
     /// Human readable version number, like ostree it is not ever parsed, just displayed
-    pub(crate) version: String,
+    pub version: String,
 }
 
 impl ContentMetadata {
@@ -39,6 +42,10 @@ pub(crate) struct InstalledContent {
     pub(crate) filetree: Option<crate::filetree::FileTree>,
     /// The version this was originally adopted from
     pub(crate) adopted_from: Option<ContentMetadata>,
+    /// The persistent names of the devices the component was written to on
+    /// the running system, which are used again if they still exist
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) devices: Vec<PathBuf>,
 }
 
 /// Will be serialized into /boot/bootupd-state.json
@@ -52,12 +59,63 @@ pub(crate) struct SavedState {
     pub(crate) pending: Option<BTreeMap<String, ContentMetadata>>,
     /// If static bootloader configs are enabled, this contains the version
     pub(crate) static_configs: Option<ContentMetadata>,
+    /// When first boot provisioning (`bootupd firstboot`) completed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) firstboot_completed: Option<DateTime<Utc>>,
+    /// The commit of a staged ostree deployment; updates are deferred until
+    /// it is booted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) staged_deployment: Option<String>,
+}
+
+/// An installed component, without its filetree.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct InstalledSummary {
+    pub(crate) meta: ContentMetadata,
+    pub(crate) adopted_from: Option<ContentMetadata>,
+    #[serde(default)]
+    pub(crate) devices: Vec<PathBuf>,
+}
+
+impl From<&InstalledContent> for InstalledSummary {
+    fn from(content: &InstalledContent) -> Self {
+        Self {
+            meta: content.meta.clone(),
+            adopted_from: content.adopted_from.clone(),
+            devices: content.devices.clone(),
+        }
+    }
+}
+
+/// The parts of [`SavedState`] needed for status.  Filetrees make up most
+/// of the state, and are skipped over when parsing this rather than built.
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct StateSummary {
+    pub(crate) installed: BTreeMap<String, InstalledSummary>,
+    pub(crate) pending: Option<BTreeMap<String, ContentMetadata>>,
+}
+
+impl From<SavedState> for StateSummary {
+    fn from(state: SavedState) -> Self {
+        let installed = state
+            .installed
+            .into_iter()
+            .map(|(k, v)| (k, InstalledSummary::from(&v)))
+            .collect();
+        Self {
+            installed,
+            pending: state.pending,
+        }
+    }
 }
 
 /// The status of an individual component.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) enum ComponentUpdatable {
+#[non_exhaustive]
+pub enum ComponentUpdatable {
     NoUpdateAvailable,
     AtLatestVersion,
     Upgradable,
@@ -82,43 +140,214 @@ impl ComponentUpdatable {
 }
 
 /// The status of an individual component.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) struct ComponentStatus {
+#[non_exhaustive]
+pub struct ComponentStatus {
     /// Currently installed version
-    pub(crate) installed: ContentMetadata,
+    pub installed: ContentMetadata,
     /// In progress update that was interrupted
-    pub(crate) interrupted: Option<ContentMetadata>,
+    pub interrupted: Option<ContentMetadata>,
     /// Update in the deployed filesystem tree
-    pub(crate) update: Option<ContentMetadata>,
+    pub update: Option<ContentMetadata>,
     /// Is true if the version in `update` is different from `installed`
-    pub(crate) updatable: ComponentUpdatable,
+    pub updatable: ComponentUpdatable,
     /// Originally adopted version
-    pub(crate) adopted_from: Option<ContentMetadata>,
+    pub adopted_from: Option<ContentMetadata>,
+    /// The devices the component was last written to, by persistent name
+    /// such as `/dev/disk/by-id/wwn-*`; components have their own, as e.g.
+    /// the ESP and /boot may be on different disks
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<PathBuf>,
 }
 
 /// Information on a component that can be adopted
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
-pub(crate) struct Adoptable {
+#[non_exhaustive]
+pub struct Adoptable {
     /// A synthetic version
-    pub(crate) version: ContentMetadata,
+    pub version: ContentMetadata,
     /// True if we are likely to be able to reliably update this system
-    pub(crate) confident: bool,
+    pub confident: bool,
+}
+
+/// Whether bootupd could take over the bootloader of the running system,
+/// component by component.  Probing writes nothing, though the ESP may be
+/// mounted to look at it.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct AdoptionReport {
+    /// The platform firmware
+    pub firmware: Firmware,
+    /// Maps a component name to what was found of it
+    pub components: BTreeMap<String, ComponentAdoption>,
+}
+
+/// What was found of a component when probing for adoption.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct ComponentAdoption {
+    pub state: AdoptionState,
+    /// The bootloader already there, if recognized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detected: Option<DetectedBootloader>,
+    /// The version adopting would update to, from the OS image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update: Option<ContentMetadata>,
+    /// What adopting takes, in order; for a component which isn't
+    /// adoptable, what would make it so, if anything
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub actions: Vec<AdoptionAction>,
+}
+
+impl ComponentAdoption {
+    pub(crate) fn new(state: AdoptionState) -> Self {
+        Self {
+            state,
+            detected: None,
+            update: None,
+            actions: Vec::new(),
+        }
+    }
+
+    /// Not adoptable, for `reason`.
+    pub(crate) fn not_adoptable(reason: impl Into<String>) -> Self {
+        Self::new(AdoptionState::NotAdoptable {
+            reason: reason.into(),
+        })
+    }
+}
+
+/// Whether a component can be adopted.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(tag = "state", rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum AdoptionState {
+    /// Installed via bootupd, or already adopted
+    Installed,
+    /// Installed otherwise, and adoptable
+    Adoptable(Adoptable),
+    /// Not adoptable, e.g. as there's no ESP
+    NotAdoptable { reason: String },
+    /// The OS image ships no update for the component, so there's nothing
+    /// to adopt it with
+    NoPayload,
+}
+
+/// A bootloader found on the system.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct DetectedBootloader {
+    /// e.g. `shim`, `GRUB`, or as the bootloader reports itself, e.g.
+    /// `systemd-boot 255.4`
+    pub name: String,
+    /// Where it was found, e.g. `/dev/vda` or `EFI/fedora/shimx64.efi` in
+    /// the ESP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+/// A change adopting a component makes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum AdoptionAction {
+    /// Adoption must be requested explicitly, as bootupd isn't confident it
+    /// can update the component reliably, e.g. with `bootupctl
+    /// adopt-and-update`; first boot provisioning leaves it
+    Confirm,
+    /// Replace the files of the update in the ESP's `EFI` directory at
+    /// `path`, leaving any others there
+    ReplaceFiles { path: PathBuf },
+    /// Create a BIOS boot partition on `disk` for GRUB to embed its core
+    /// image in, which `--create-bios-boot-partition` allows
+    CreateBiosBootPartition { disk: PathBuf },
+    /// Write the bootloader to `device`, with `installer`, e.g.
+    /// `grub-install`
+    WriteDevice { device: PathBuf, installer: String },
+}
+
+/// How the running system was booted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum BootMode {
+    Uefi,
+    /// Legacy BIOS boot on x86
+    Bios,
+    /// Platform firmware without UEFI, e.g. OPAL or U-Boot
+    Other,
+}
+
+/// The platform firmware of the running system.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct Firmware {
+    pub boot_mode: BootMode,
+    /// Whether Secure Boot is enabled; unset if not booted via UEFI
+    pub secure_boot: Option<bool>,
+    /// Vendor, version and release date as reported by SMBIOS
+    pub vendor: Option<String>,
+    pub version: Option<String>,
+    pub date: Option<String>,
+}
+
+/// The version of the [`Status`] document, which is only bumped for
+/// incompatible changes; see "Machine-readable output" in README-design.md.
+pub const STATUS_SCHEMA_VERSION: u32 = 1;
+
+/// Documents from before the version was recorded are version 1.
+fn unversioned() -> u32 {
+    1
 }
 
 /// Representation of bootupd's worldview at a point in time.
 /// This is intended to be a stable format that is output by `bootupctl status --json`
 /// and parsed by higher level management tools.  Transitively then
-/// everything referenced from here should also be stable.
-#[derive(Serialize, Deserialize, Default, Debug)]
+/// everything referenced from here should also be stable: fields may be
+/// added, so unknown fields are ignored, but changing or removing any
+/// bumps the `schema-version`.  `bootupctl schema status` prints the JSON
+/// Schema.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
-#[serde(deny_unknown_fields)]
-pub(crate) struct Status {
+#[non_exhaustive]
+pub struct Status {
+    /// The version of this document's format
+    #[serde(default = "unversioned")]
+    pub schema_version: u32,
     /// Maps a component name to status
-    pub(crate) components: BTreeMap<String, ComponentStatus>,
+    pub components: BTreeMap<String, ComponentStatus>,
     /// Components that appear to be installed, not via bootupd
-    pub(crate) adoptable: BTreeMap<String, Adoptable>,
+    pub adoptable: BTreeMap<String, Adoptable>,
+    /// The platform firmware, to correlate with bootloader problems
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<Firmware>,
+    /// Mismatches between the installed components, e.g. a fallback loader
+    /// which isn't a copy of shim
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inconsistencies: Vec<String>,
+}
+
+/// The JSON Schema of [`Status`].
+pub(crate) fn status_schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(Status)
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self {
+            schema_version: STATUS_SCHEMA_VERSION,
+            components: Default::default(),
+            adoptable: Default::default(),
+            firmware: None,
+            inconsistencies: Vec::new(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -152,6 +381,22 @@ mod test {
             efi.meta.version,
             "grub2-efi-x64-1:2.04-23.fc32.x86_64,shim-x64-15-8.x86_64"
         );
+        assert!(state.firstboot_completed.is_none());
+        // Unset optional fields are omitted, so older versions can still parse this
+        let v = serde_json::to_value(&state)?;
+        assert!(v.get("firstboot-completed").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize_state_summary() -> Result<()> {
+        let data = include_str!("../tests/fixtures/example-state-v0.json");
+        let summary: StateSummary = serde_json::from_reader(data.as_bytes())?;
+        let state: SavedState = serde_json::from_str(data)?;
+        let expected = StateSummary::from(state);
+        assert_eq!(summary.installed, expected.installed);
+        assert_eq!(summary.pending, expected.pending);
+        assert!(summary.installed.contains_key("EFI"));
         Ok(())
     }
 
@@ -165,6 +410,27 @@ mod test {
             efi.installed.version,
             "grub2-efi-x64-1:2.04-23.fc32.x86_64,shim-x64-15-8.x86_64"
         );
+        // From before the version was recorded
+        assert_eq!(status.schema_version, 1);
+        Ok(())
+    }
+
+    /// The committed schema is that of the status, and fields added later
+    /// don't break parsing
+    #[test]
+    fn test_status_schema() -> Result<()> {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("doc/status.schema.json");
+        let actual = serde_json::to_string_pretty(&status_schema())? + "\n";
+        if std::env::var_os("BOOTUPD_UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, &actual)?;
+        }
+        assert_eq!(actual, std::fs::read_to_string(&path)?, "{path:?} differs");
+
+        let mut v = serde_json::to_value(Status::default())?;
+        assert_eq!(v["schema-version"], STATUS_SCHEMA_VERSION);
+        v["added-later"] = serde_json::json!(true);
+        let status: Status = serde_json::from_value(v)?;
+        assert_eq!(status.schema_version, STATUS_SCHEMA_VERSION);
         Ok(())
     }
 }
@@ -59,6 +59,7 @@ impl InstalledContent01 {
             meta: self.meta.upconvert(),
             filetree: self.filetree,
             adopted_from: None,
+            devices: Vec::new(),
         }
     }
 }
@@ -0,0 +1,410 @@
+//! Pure-Rust stand-ins for the coreutils and util-linux tools bootupd
+//! otherwise runs, for systems without them: a statically linked binary
+//! (see `make static`) works on its own in e.g. a recovery initramfs.
+//!
+//! The tools are still used where installed (see
+//! [`crate::util::have_program`]), as they handle more cases, e.g. `mount`
+//! detecting filesystem types and `cp` reflinking, and are what
+//! `--simulate` stubs.  Tools doing the bootloader-specific work, such as
+//! `grub-install` and `efibootmgr`, have no stand-in.
+
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64",
+    have_filetree
+))]
+use std::fs;
+use std::path::Path;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+use std::path::PathBuf;
+
+use anyhow::Result;
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+use anyhow::{anyhow, Context};
+use fn_error_context::context;
+use rustix::fs::StatVfsMountFlags;
+use rustix::mount::MountFlags;
+
+/// The mount table of this process.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+
+/// Where udev links filesystems by their UUID.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+const BY_UUID_DIR: &str = "/dev/disk/by-uuid";
+
+/// A mount, as listed in `/proc/self/mountinfo`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Mount {
+    /// The `major:minor` number of the mounted device
+    pub(crate) devno: String,
+    pub(crate) target: PathBuf,
+    pub(crate) fstype: String,
+    pub(crate) source: String,
+    /// The options of the mount, then those of the filesystem, as `findmnt`
+    /// lists them, e.g. `rw,relatime,fmask=0077`
+    pub(crate) options: String,
+}
+
+/// Undo the octal escapes of e.g. spaces (`\040`) in mountinfo fields.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+fn unescape(field: &str) -> String {
+    let mut out = Vec::with_capacity(field.len());
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|o| std::str::from_utf8(o).ok())
+            .and_then(|o| u8::from_str_radix(o, 8).ok());
+        match code {
+            Some(c) => {
+                out.push(c);
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Parse the mount table `data`, in the format of `/proc/self/mountinfo`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+pub(crate) fn parse_mountinfo(data: &str) -> Result<Vec<Mount>> {
+    let parse = |line: &str| -> Option<Mount> {
+        // Optional fields precede the separator
+        let (mount, fs) = line.split_once(" - ")?;
+        let mount = mount.split(' ').collect::<Vec<_>>();
+        let mut fs = fs.split(' ');
+        let (fstype, source, fs_options) = (fs.next()?, fs.next()?, fs.next()?);
+        // Whether it's writable is up to the mount
+        let fs_options = fs_options.split(',').filter(|o| !matches!(*o, "rw" | "ro"));
+        let options = mount.get(5)?.split(',').chain(fs_options);
+        Some(Mount {
+            devno: mount.get(2)?.to_string(),
+            target: unescape(mount.get(4)?).into(),
+            fstype: fstype.to_string(),
+            source: unescape(source),
+            options: options.collect::<Vec<_>>().join(","),
+        })
+    };
+    data.lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| parse(l).ok_or_else(|| anyhow!("Invalid mountinfo line: {l}")))
+        .collect()
+}
+
+/// The mounts of this process.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+#[context("Reading {}", MOUNTINFO)]
+pub(crate) fn mounts() -> Result<Vec<Mount>> {
+    parse_mountinfo(&fs::read_to_string(MOUNTINFO)?)
+}
+
+/// The mount `path` is on, as `findmnt` finds it: the last one on the
+/// longest prefix of its canonical path.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+pub(crate) fn find_mount<'a>(mounts: &'a [Mount], path: &Path) -> Result<&'a Mount> {
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("Resolving {path:?}"))?;
+    mounts
+        .iter()
+        .filter(|m| path.starts_with(&m.target))
+        .max_by_key(|m| m.target.components().count())
+        .ok_or_else(|| anyhow!("No mount found for {path:?}"))
+}
+
+/// The UUID of the filesystem on `device`, from the links in
+/// `/dev/disk/by-uuid`.
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+pub(crate) fn filesystem_uuid(device: &str) -> Option<String> {
+    let device = Path::new(device).canonicalize().ok()?;
+    fs::read_dir(BY_UUID_DIR)
+        .ok()?
+        .flatten()
+        .find(|e| e.path().canonicalize().is_ok_and(|p| p == device))
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+}
+
+/// Mount the `fstype` filesystem of `source` on `target`, as
+/// `mount -t fstype source target` does.
+#[cfg(have_efi)]
+#[context("Mounting {source:?} on {target:?}")]
+pub(crate) fn mount(source: &Path, target: &Path, fstype: &str) -> Result<()> {
+    rustix::mount::mount(source, target, fstype, MountFlags::empty(), "")?;
+    Ok(())
+}
+
+/// Remount `target` with the `mount -o` style `options`: `remount` with
+/// `rw` or `ro`, and optionally `bind` to only change the mount rather than
+/// the filesystem.  The other flags of the mount are kept.
+#[context("Remounting {target:?} with {options}")]
+pub(crate) fn remount(target: &Path, options: &str) -> Result<()> {
+    let current = rustix::fs::statvfs(target)?.f_flag;
+    let mut flags = [
+        (StatVfsMountFlags::NOSUID, MountFlags::NOSUID),
+        (StatVfsMountFlags::NODEV, MountFlags::NODEV),
+        (StatVfsMountFlags::NOEXEC, MountFlags::NOEXEC),
+        (StatVfsMountFlags::NOATIME, MountFlags::NOATIME),
+        (StatVfsMountFlags::NODIRATIME, MountFlags::NODIRATIME),
+        (StatVfsMountFlags::SYNCHRONOUS, MountFlags::SYNCHRONOUS),
+    ]
+    .into_iter()
+    .filter(|(st, _)| current.contains(*st))
+    .fold(MountFlags::empty(), |acc, (_, flag)| acc | flag);
+    for option in options.split(',') {
+        match option {
+            "remount" | "rw" => {}
+            "ro" => flags |= MountFlags::RDONLY,
+            "bind" => flags |= MountFlags::BIND,
+            o => anyhow::bail!("Unsupported option {o}"),
+        }
+    }
+    rustix::mount::mount_remount(target, flags, "")?;
+    Ok(())
+}
+
+/// Unmount `target`, as `umount` does.
+#[context("Unmounting {target:?}")]
+pub(crate) fn unmount(target: &Path) -> Result<()> {
+    rustix::mount::unmount(target, rustix::mount::UnmountFlags::empty())?;
+    Ok(())
+}
+
+/// Copy the tree `src` to `dest`, which must not exist, with the modes,
+/// ownership and modification times of files, as `cp -a` does (less
+/// extended attributes).
+#[cfg(have_filetree)]
+#[context("Copying {src:?} to {dest:?}")]
+pub(crate) fn copy_tree(src: &Path, dest: &Path) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = fs::symlink_metadata(src)?;
+    let file_type = meta.file_type();
+    if file_type.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(src)?, dest)?;
+    } else if file_type.is_dir() {
+        fs::create_dir(dest)?;
+        for entry in fs::read_dir(src)? {
+            let name = entry?.file_name();
+            copy_tree(&src.join(&name), &dest.join(&name))?;
+        }
+    } else {
+        fs::copy(src, dest)?;
+        fs::File::options()
+            .write(true)
+            .open(dest)?
+            .set_modified(meta.modified()?)?;
+    }
+    std::os::unix::fs::lchown(dest, Some(meta.uid()), Some(meta.gid()))?;
+    if !file_type.is_symlink() {
+        fs::set_permissions(dest, meta.permissions())?;
+    }
+    Ok(())
+}
+
+/// Move the tree `src` to `dest`, copying it if it can't be renamed, e.g.
+/// to another filesystem or from a lower layer of an overlayfs, as `mv`
+/// does.
+#[cfg(have_efi)]
+pub(crate) fn move_tree(src: &Path, dest: &Path) -> Result<()> {
+    match fs::rename(src, dest) {
+        Ok(()) => Ok(()),
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+            copy_tree(src, dest)?;
+            fs::remove_dir_all(src).with_context(|| format!("Removing {src:?}"))
+        }
+        Err(e) => Err(e).with_context(|| format!("Moving {src:?} to {dest:?}")),
+    }
+}
+
+/// The `BLKPG` ioctl, of `<linux/fs.h>`.
+#[cfg(all(have_bios, target_arch = "x86_64"))]
+const BLKPG: libc::c_ulong = 0x1269;
+/// The `BLKPG` operation adding a partition.
+#[cfg(all(have_bios, target_arch = "x86_64"))]
+const BLKPG_ADD_PARTITION: libc::c_int = 1;
+
+/// `struct blkpg_ioctl_arg`
+#[cfg(all(have_bios, target_arch = "x86_64"))]
+#[repr(C)]
+struct BlkpgIoctlArg {
+    op: libc::c_int,
+    flags: libc::c_int,
+    datalen: libc::c_int,
+    data: *mut libc::c_void,
+}
+
+/// `struct blkpg_partition`
+#[cfg(all(have_bios, target_arch = "x86_64"))]
+#[repr(C)]
+struct BlkpgPartition {
+    start: libc::c_longlong,
+    length: libc::c_longlong,
+    pno: libc::c_int,
+    devname: [libc::c_char; 64],
+    volname: [libc::c_char; 64],
+}
+
+/// Tell the kernel about partition `number` of `disk`, of `length` bytes
+/// from byte `start`, as `partx --add --nr` does.
+#[cfg(all(have_bios, target_arch = "x86_64"))]
+#[context("Adding partition {number} of {disk:?}")]
+pub(crate) fn add_partition(disk: &Path, number: u32, start: u64, length: u64) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mut partition = BlkpgPartition {
+        start: start.try_into()?,
+        length: length.try_into()?,
+        pno: number.try_into()?,
+        devname: [0; 64],
+        volname: [0; 64],
+    };
+    let mut arg = BlkpgIoctlArg {
+        op: BLKPG_ADD_PARTITION,
+        flags: 0,
+        datalen: std::mem::size_of::<BlkpgPartition>() as libc::c_int,
+        data: std::ptr::addr_of_mut!(partition).cast(),
+    };
+    let f = fs::File::open(disk)?;
+    // SAFETY: the argument and the partition it points to are valid for
+    // the duration of the call, and laid out as the kernel expects.
+    let r = unsafe { libc::ioctl(f.as_raw_fd(), BLKPG as _, &mut arg) };
+    if r != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "powerpc64"
+))]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "powerpc64"
+    ))]
+    fn test_mountinfo() -> Result<()> {
+        let data = "\
+22 1 253:0 / / rw,relatime shared:1 - xfs /dev/mapper/root rw,attr2
+29 22 8:2 / /boot rw,nosuid master:7 - ext4 /dev/sda2 rw,seclabel
+30 29 8:1 / /boot/efi rw,relatime - vfat /dev/sda1 rw,fmask=0077
+31 22 0:45 / /var/my\\040mnt rw - tmpfs tmpfs rw
+";
+        let mounts = parse_mountinfo(data)?;
+        assert_eq!(mounts.len(), 4);
+        assert_eq!(
+            mounts[2],
+            Mount {
+                devno: "8:1".into(),
+                target: "/boot/efi".into(),
+                fstype: "vfat".into(),
+                source: "/dev/sda1".into(),
+                options: "rw,relatime,fmask=0077".into(),
+            }
+        );
+        assert_eq!(mounts[3].target, Path::new("/var/my mnt"));
+        assert!(parse_mountinfo("22 1 253:0 / / rw").is_err());
+
+        let td = tempfile::tempdir()?;
+        let root = td.path().canonicalize()?;
+        fs::create_dir_all(root.join("boot/efi/EFI"))?;
+        let mounts = parse_mountinfo(&format!(
+            "1 0 8:3 / / rw - xfs /dev/sda3 rw\n\
+             2 1 8:2 / {0}/boot rw - ext4 /dev/sda2 rw\n\
+             3 2 8:1 / {0}/boot/efi rw - vfat /dev/sda1 rw\n\
+             4 2 8:4 / {0}/boot rw - ext4 /dev/sda4 rw\n",
+            root.display()
+        ))?;
+        let m = |p: &str| find_mount(&mounts, &root.join(p)).map(|m| m.source.as_str());
+        assert_eq!(m("boot/efi/EFI")?, "/dev/sda1");
+        // The last mount on a path hides the others
+        assert_eq!(m("boot")?, "/dev/sda4");
+        assert_eq!(m("")?, "/dev/sda3");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(have_efi)]
+    fn test_copy_tree() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let td = tempfile::tempdir()?;
+        let src = td.path().join("src");
+        fs::create_dir_all(src.join("EFI/fedora"))?;
+        fs::write(src.join("EFI/fedora/grub.cfg"), "config")?;
+        fs::set_permissions(
+            src.join("EFI/fedora/grub.cfg"),
+            fs::Permissions::from_mode(0o600),
+        )?;
+        std::os::unix::fs::symlink("fedora", src.join("EFI/link"))?;
+        let dest = td.path().join("dest");
+        move_tree(&src, &dest)?;
+        assert!(!src.exists());
+        copy_tree(&dest, &src)?;
+        let copied = src.join("EFI/fedora/grub.cfg");
+        assert_eq!(fs::read_to_string(&copied)?, "config");
+        let (a, b) = (
+            fs::metadata(&copied)?,
+            fs::metadata(dest.join("EFI/fedora/grub.cfg"))?,
+        );
+        assert_eq!(a.permissions().mode() & 0o777, 0o600);
+        assert_eq!(a.modified()?, b.modified()?);
+        assert_eq!(fs::read_link(src.join("EFI/link"))?, Path::new("fedora"));
+        Ok(())
+    }
+}
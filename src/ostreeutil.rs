@@ -4,21 +4,26 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+#[cfg(have_component)]
 use log::debug;
-
-use crate::util::CommandRunExt;
+use serde::Deserialize;
 
 /// https://github.com/coreos/rpm-ostree/pull/969/commits/dc0e8db5bd92e1f478a0763d1a02b48e57022b59
-#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[cfg(have_efi)]
 pub(crate) const BOOT_PREFIX: &str = "usr/lib/ostree-boot";
-const LEGACY_RPMOSTREE_DBPATH: &str = "usr/share/rpm";
-const SYSIMAGE_RPM_DBPATH: &str = "usr/lib/sysimage/rpm";
+#[cfg(have_component)]
+pub(crate) const LEGACY_RPMOSTREE_DBPATH: &str = "usr/share/rpm";
+#[cfg(have_component)]
+pub(crate) const SYSIMAGE_RPM_DBPATH: &str = "usr/lib/sysimage/rpm";
+/// Present if the system is booted via ostree
+const OSTREE_BOOTED: &str = "/run/ostree-booted";
 
 /// Returns true if the target directory contains at least one file that does
 /// not start with `.`
+#[cfg(have_component)]
 fn is_nonempty_dir(path: impl AsRef<Path>) -> Result<bool> {
     let path = path.as_ref();
     let it = match std::fs::read_dir(path) {
@@ -37,8 +42,9 @@ fn is_nonempty_dir(path: impl AsRef<Path>) -> Result<bool> {
     Ok(false)
 }
 
+#[cfg(have_component)]
 pub(crate) fn rpm_cmd<P: AsRef<Path>>(sysroot: P) -> Result<std::process::Command> {
-    let mut c = std::process::Command::new("rpm");
+    let mut c = crate::util::command("rpm");
     let sysroot = sysroot.as_ref();
     // Take the first non-empty database path
     let mut arg = None;
@@ -47,10 +53,6 @@ pub(crate) fn rpm_cmd<P: AsRef<Path>>(sysroot: P) -> Result<std::process::Comman
         if !is_nonempty_dir(&dbpath)? {
             continue;
         }
-        std::process::Command::new("ls")
-            .arg("-al")
-            .arg(&dbpath)
-            .run()?;
         let mut s = std::ffi::OsString::new();
         s.push("--dbpath=");
         s.push(dbpath.as_os_str());
@@ -66,3 +68,187 @@ pub(crate) fn rpm_cmd<P: AsRef<Path>>(sysroot: P) -> Result<std::process::Comman
     }
     Ok(c)
 }
+
+/// A deployment, as output by `rpm-ostree status --json` (or
+/// `ostree admin status`).
+#[derive(Deserialize, Debug)]
+struct Deployment {
+    checksum: String,
+    #[serde(default)]
+    osname: String,
+    #[serde(default)]
+    serial: u32,
+    #[serde(default)]
+    booted: bool,
+    #[serde(default)]
+    staged: bool,
+}
+
+impl Deployment {
+    /// The root of the deployment's filesystem tree.
+    fn root(&self) -> PathBuf {
+        Path::new("/ostree/deploy")
+            .join(&self.osname)
+            .join("deploy")
+            .join(format!("{}.{}", self.checksum, self.serial))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct RpmOstreeStatus {
+    deployments: Vec<Deployment>,
+}
+
+/// The commits of the booted and staged ostree deployments.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct DeploymentState {
+    pub(crate) booted: Option<String>,
+    pub(crate) staged: Option<String>,
+    /// The root of the deployment booted by default after the booted one
+    /// is rolled back from
+    pub(crate) rollback_root: Option<PathBuf>,
+}
+
+fn parse_deployment_state(buf: &str) -> Result<DeploymentState> {
+    let status: RpmOstreeStatus = serde_json::from_str(buf)?;
+    Ok(DeploymentState::new(status.deployments))
+}
+
+/// Parse the output of `ostree admin status`, for systems without
+/// rpm-ostree: a line per deployment, like `* fedora-coreos b.0`, marked
+/// with `*` if booted and followed by `(staged)` if staged.  The indented
+/// lines in between describe the deployment above.
+fn parse_ostree_admin_status(buf: &str) -> Result<DeploymentState> {
+    let mut deployments = Vec::new();
+    for line in buf.lines() {
+        let (booted, rest) = match (line.strip_prefix("* "), line.strip_prefix("  ")) {
+            (Some(rest), _) => (true, rest),
+            (None, Some(rest)) if !rest.starts_with(' ') => (false, rest),
+            _ => continue,
+        };
+        let mut words = rest.split_whitespace();
+        let (Some(osname), Some(id)) = (words.next(), words.next()) else {
+            continue;
+        };
+        let Some((checksum, serial)) = id.rsplit_once('.') else {
+            anyhow::bail!("Invalid deployment {id:?}");
+        };
+        deployments.push(Deployment {
+            checksum: checksum.to_string(),
+            osname: osname.to_string(),
+            serial: serial
+                .parse()
+                .with_context(|| format!("Invalid deployment {id:?}"))?,
+            booted,
+            staged: words.any(|w| w == "(staged)"),
+        });
+    }
+    Ok(DeploymentState::new(deployments))
+}
+
+impl DeploymentState {
+    /// The state of `deployments`, listed in boot order.
+    fn new(deployments: Vec<Deployment>) -> Self {
+        let mut r = DeploymentState::default();
+        for d in deployments {
+            if d.booted {
+                r.booted = Some(d.checksum);
+            } else if d.staged {
+                r.staged = Some(d.checksum);
+            } else if r.booted.is_some() && r.rollback_root.is_none() {
+                // Deployments are listed in boot order
+                r.rollback_root = Some(d.root());
+            }
+        }
+        r
+    }
+}
+
+/// Fail with guidance if `path` is on the read-only `/usr` of a booted
+/// ostree deployment, where e.g. update metadata can't be written: it's
+/// part of the OS image, and generated when that is built.
+pub(crate) fn ensure_mutable_usr(path: &Path) -> Result<()> {
+    if !Path::new(OSTREE_BOOTED).exists() {
+        return Ok(());
+    }
+    // The path itself may not exist yet
+    let Some(existing) = path.ancestors().find(|p| p.exists()) else {
+        return Ok(());
+    };
+    if crate::util::is_readonly(existing)? {
+        return Err(crate::errors::Error::new(
+            crate::errors::ErrorKind::ImmutableUsr,
+            format!("{} is read-only in this ostree deployment", path.display()),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Query the deployments, if the system is booted via ostree; from
+/// `ostree admin status` on systems without rpm-ostree.
+pub(crate) fn deployment_state() -> Result<Option<DeploymentState>> {
+    if !Path::new(OSTREE_BOOTED).exists() {
+        return Ok(None);
+    }
+    if !crate::util::have_program("rpm-ostree") {
+        let buf =
+            crate::util::cmd_output(crate::util::command("ostree").args(["admin", "status"]))?;
+        return parse_ostree_admin_status(&buf)
+            .context("Parsing ostree admin status")
+            .map(Some);
+    }
+    let buf =
+        crate::util::cmd_output(crate::util::command("rpm-ostree").args(["status", "--json"]))?;
+    parse_deployment_state(&buf)
+        .context("Parsing rpm-ostree status")
+        .map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_deployment_state() -> Result<()> {
+        let data = r#"{
+            "deployments": [
+                { "id": "fedora-coreos-a.0", "checksum": "a", "staged": true, "booted": false },
+                { "id": "fedora-coreos-b.0", "checksum": "b", "booted": true },
+                { "id": "fedora-coreos-c.0", "osname": "fedora-coreos", "checksum": "c", "serial": 1, "booted": false }
+            ]
+        }"#;
+        let state = parse_deployment_state(data)?;
+        assert_eq!(state.booted.as_deref(), Some("b"));
+        assert_eq!(state.staged.as_deref(), Some("a"));
+        assert_eq!(
+            state.rollback_root.as_deref(),
+            Some(Path::new("/ostree/deploy/fedora-coreos/deploy/c.1"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_ostree_admin_status() -> Result<()> {
+        let data = "  fedora-coreos a.0 (staged)
+* fedora-coreos b.0
+    Version: 41.20250101.3.0
+    origin refspec: fedora:fedora/x86_64/coreos/stable
+  fedora-coreos c.1 (rollback)
+    Version: 41.20241215.3.0
+";
+        let state = parse_ostree_admin_status(data)?;
+        assert_eq!(state.booted.as_deref(), Some("b"));
+        assert_eq!(state.staged.as_deref(), Some("a"));
+        assert_eq!(
+            state.rollback_root.as_deref(),
+            Some(Path::new("/ostree/deploy/fedora-coreos/deploy/c.1"))
+        );
+        assert_eq!(
+            parse_ostree_admin_status("* fedora-coreos b.0\n")?.staged,
+            None
+        );
+        assert!(parse_ostree_admin_status("* fedora-coreos b\n").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,132 @@
+//! Helpers for human-oriented terminal output.
+
+use std::io::IsTerminal;
+
+/// A semantic style for a table cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Style {
+    Plain,
+    Ok,
+    Pending,
+    Warning,
+    Info,
+}
+
+impl Style {
+    /// The ANSI SGR sequence for this style.
+    fn ansi(&self) -> Option<&'static str> {
+        match self {
+            Style::Plain => None,
+            Style::Ok => Some("\x1b[32m"),
+            Style::Pending => Some("\x1b[33m"),
+            Style::Warning => Some("\x1b[31m"),
+            Style::Info => Some("\x1b[36m"),
+        }
+    }
+}
+
+/// Return `true` if colored output should be used; this honors an explicit
+/// `--no-color` request as well as <https://no-color.org/>.
+pub(crate) fn use_color(no_color: bool) -> bool {
+    if no_color {
+        return false;
+    }
+    if std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+        return false;
+    }
+    std::io::stdout().is_terminal()
+}
+
+/// Return `true` if the table format should be used by default; scripts
+/// parsing the output will not have a terminal on stdout.
+pub(crate) fn use_table() -> bool {
+    std::io::stdout().is_terminal()
+}
+
+/// A simple table with left-aligned columns.
+#[derive(Debug, Default)]
+pub(crate) struct Table {
+    header: Vec<String>,
+    rows: Vec<Vec<(String, Style)>>,
+}
+
+impl Table {
+    pub(crate) fn new<I: IntoIterator<Item = S>, S: Into<String>>(header: I) -> Self {
+        Self {
+            header: header.into_iter().map(Into::into).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push_row(&mut self, row: Vec<(String, Style)>) {
+        assert_eq!(row.len(), self.header.len());
+        self.rows.push(row);
+    }
+
+    /// Render the table; the last column is not padded.
+    pub(crate) fn render(&self, color: bool) -> String {
+        let mut widths: Vec<usize> = self.header.iter().map(|h| h.chars().count()).collect();
+        for row in self.rows.iter() {
+            for (i, (cell, _)) in row.iter().enumerate() {
+                widths[i] = widths[i].max(cell.chars().count());
+            }
+        }
+        let mut out = String::new();
+        let header = self
+            .header
+            .iter()
+            .map(|h| (h.clone(), Style::Plain))
+            .collect::<Vec<_>>();
+        for row in std::iter::once(&header).chain(self.rows.iter()) {
+            let last = row.len() - 1;
+            for (i, (cell, style)) in row.iter().enumerate() {
+                let pad = if i == last {
+                    0
+                } else {
+                    widths[i] - cell.chars().count() + 2
+                };
+                match style.ansi().filter(|_| color) {
+                    Some(code) => {
+                        out.push_str(code);
+                        out.push_str(cell);
+                        out.push_str("\x1b[0m");
+                    }
+                    None => out.push_str(cell),
+                }
+                out.extend(std::iter::repeat(' ').take(pad));
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_render() {
+        let mut t = Table::new(["COMPONENT", "STATE"]);
+        t.push_row(vec![
+            ("EFI".to_string(), Style::Plain),
+            ("up-to-date".to_string(), Style::Ok),
+        ]);
+        t.push_row(vec![
+            ("BIOS".to_string(), Style::Plain),
+            ("pending".to_string(), Style::Pending),
+        ]);
+        assert_eq!(
+            t.render(false),
+            "COMPONENT  STATE\nEFI        up-to-date\nBIOS       pending\n"
+        );
+        let colored = t.render(true);
+        assert!(colored.contains("\x1b[32mup-to-date\x1b[0m"));
+        assert!(colored.contains("\x1b[33mpending\x1b[0m"));
+    }
+
+    #[test]
+    fn test_use_color() {
+        assert!(!use_color(true));
+    }
+}
@@ -1,77 +1,363 @@
 use std::collections::{BTreeMap, BTreeSet};
-use std::io::Write;
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Context, Result};
 use chrono::prelude::*;
+use openssl::hash::{Hasher, MessageDigest};
 
+use crate::distro::{PackageSystem, Profile};
 use crate::model::*;
 use crate::ostreeutil;
+use crate::sha512string::SHA512String;
 
-/// Parse the output of `rpm -q`
+/// The package databases, relative to the root.
+fn database_paths(package_system: PackageSystem) -> &'static [&'static str] {
+    match package_system {
+        PackageSystem::Rpm => &[
+            ostreeutil::SYSIMAGE_RPM_DBPATH,
+            ostreeutil::LEGACY_RPMOSTREE_DBPATH,
+            "var/lib/rpm",
+        ],
+        PackageSystem::Dpkg => &["var/lib/dpkg/status", "var/lib/dpkg/info"],
+        PackageSystem::Pacman => &["var/lib/pacman/local"],
+    }
+}
+
+/// The `rpm --queryformat` parsed by [`rpm_parse_metadata`]: a line per
+/// queried file.
+const RPM_QUERY_FORMAT: &str = "%{nevra}\t%{buildtime}\n";
+
+/// Parse the output of `rpm -q` with [`RPM_QUERY_FORMAT`].  Files not owned
+/// by any package are skipped, as long as some are.
 fn rpm_parse_metadata(stdout: &[u8]) -> Result<ContentMetadata> {
-    let pkgs = std::str::from_utf8(stdout)?
-        .split_whitespace()
-        .map(|s| -> Result<_> {
-            let parts: Vec<_> = s.splitn(2, ',').collect();
-            let name = parts[0];
-            if let Some(ts) = parts.get(1) {
-                let nt = DateTime::parse_from_str(ts, "%s")
-                    .context("Failed to parse rpm buildtime")?
-                    .with_timezone(&chrono::Utc);
-                Ok((name, nt))
-            } else {
-                bail!("Failed to parse: {}", s);
+    let mut pkgs = BTreeMap::new();
+    for line in std::str::from_utf8(stdout)?.lines() {
+        let Some((name, ts)) = line.split_once('\t') else {
+            if line.starts_with("file ") && line.ends_with(" is not owned by any package") {
+                log::debug!("{line}");
+                continue;
             }
-        })
-        .collect::<Result<BTreeMap<&str, DateTime<Utc>>>>()?;
-    if pkgs.is_empty() {
+            bail!("Failed to parse: {line}");
+        };
+        let nt = DateTime::parse_from_str(ts, "%s")
+            .context("Failed to parse rpm buildtime")?
+            .with_timezone(&chrono::Utc);
+        pkgs.insert(name, nt);
+    }
+    let Some(timestamp) = pkgs.values().max().copied() else {
         bail!("Failed to find any RPM packages matching files in source efidir");
+    };
+    let version = pkgs.keys().copied().collect::<Vec<_>>().join(",");
+    Ok(ContentMetadata { timestamp, version })
+}
+
+/// Query the package database of the OS in `sysroot_path` and list the
+/// packages owning `paths` along with their build (or install) times.
+pub(crate) fn query_files<T>(
+    sysroot_path: &str,
+    paths: impl IntoIterator<Item = T>,
+) -> Result<ContentMetadata>
+where
+    T: AsRef<Path>,
+{
+    let mut meta = match Profile::detect(Path::new(sysroot_path))?.package_system {
+        PackageSystem::Rpm => rpm_query_files(sysroot_path, paths),
+        PackageSystem::Dpkg => dpkg_query_files(sysroot_path, paths),
+        PackageSystem::Pacman => pacman_query_files(sysroot_path, paths),
+    }?;
+    // Install times, unlike build times, differ across builds of an image
+    meta.timestamp = crate::util::clamp_to_source_date_epoch(meta.timestamp)?;
+    Ok(meta)
+}
+
+/// Return a digest of what the result of [`query_files`] depends on: the
+/// queried paths, and the state of the package database (the size and
+/// modification time of its files).
+pub(crate) fn query_inputs_digest(sysroot_path: &str, paths: &[PathBuf]) -> Result<SHA512String> {
+    let root = Path::new(sysroot_path);
+    let package_system = Profile::detect(root)?.package_system;
+    let mut hasher = Hasher::new(MessageDigest::sha512())?;
+    for p in paths {
+        hasher.update(p.as_os_str().as_bytes())?;
+        hasher.update(b"\0")?;
     }
-    let timestamps: BTreeSet<&DateTime<Utc>> = pkgs.values().collect();
-    // Unwrap safety: We validated pkgs has at least one value above
-    let largest_timestamp = timestamps.iter().last().unwrap();
-    let version = pkgs.keys().fold("".to_string(), |mut s, n| {
-        if !s.is_empty() {
-            s.push(',');
+    for db in database_paths(package_system) {
+        let path = root.join(db);
+        let Ok(meta) = std::fs::metadata(&path) else {
+            continue;
+        };
+        let mut entries = vec![(db.to_string(), meta)];
+        if entries[0].1.is_dir() {
+            for entry in std::fs::read_dir(&path)? {
+                let entry = entry?;
+                let name = format!("{db}/{}", entry.file_name().to_string_lossy());
+                entries.push((name, entry.metadata()?));
+            }
         }
-        s.push_str(n);
-        s
-    });
-    Ok(ContentMetadata {
-        timestamp: **largest_timestamp,
-        version,
-    })
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, meta) in entries {
+            let mtime = meta.modified()?.duration_since(std::time::UNIX_EPOCH)?;
+            let line = format!("{name} {} {}\n", meta.len(), mtime.as_nanos());
+            hasher.update(line.as_bytes())?;
+        }
+    }
+    Ok(SHA512String::from_hasher(&mut hasher))
 }
 
-/// Query the rpm database and list the package and build times.
-pub(crate) fn query_files<T>(
+/// Query the rpm database and list the package and build times, with a
+/// single `rpm` invocation for all of `paths`.
+fn rpm_query_files<T>(
     sysroot_path: &str,
     paths: impl IntoIterator<Item = T>,
 ) -> Result<ContentMetadata>
 where
     T: AsRef<Path>,
 {
+    let paths = paths
+        .into_iter()
+        .map(|p| p.as_ref().to_owned())
+        .collect::<BTreeSet<_>>();
     let mut c = ostreeutil::rpm_cmd(sysroot_path)?;
-    c.args(["-q", "--queryformat", "%{nevra},%{buildtime} ", "-f"]);
+    c.args(["-q", "--queryformat", RPM_QUERY_FORMAT, "-f"]);
+    c.args(&paths);
+
+    let rpmout = c.output()?;
+    // rpm also fails if only some of the files aren't owned by a package
+    match rpm_parse_metadata(&rpmout.stdout) {
+        Ok(meta) => Ok(meta),
+        Err(e) if !rpmout.status.success() => {
+            log::debug!("Querying rpm: {e}");
+            Ok(ContentMetadata {
+                timestamp: crate::util::build_time()?,
+                version: "unknown".to_string(),
+            })
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Parse the output of `dpkg-query --search`, returning the package names.
+fn dpkg_parse_search(stdout: &str) -> BTreeSet<&str> {
+    stdout
+        .lines()
+        .filter(|l| !l.starts_with("diversion by"))
+        .filter_map(|l| l.split_once(": "))
+        .flat_map(|(pkgs, _path)| pkgs.split(", "))
+        .collect()
+}
+
+/// Query the dpkg database; as dpkg doesn't record build times, we use
+/// the time the package was installed, which [`query_files`] clamps to
+/// `SOURCE_DATE_EPOCH`.
+fn dpkg_query_files<T>(
+    sysroot_path: &str,
+    paths: impl IntoIterator<Item = T>,
+) -> Result<ContentMetadata>
+where
+    T: AsRef<Path>,
+{
+    let root = format!("--root={sysroot_path}");
+    let mut c = crate::util::command("dpkg-query");
+    c.arg(&root).arg("--search");
     for arg in paths {
         c.arg(arg.as_ref());
     }
+    let out = c.output()?;
+    if !out.status.success() {
+        return Ok(ContentMetadata {
+            timestamp: crate::util::build_time()?,
+            version: "unknown".to_string(),
+        });
+    }
+    let stdout = String::from_utf8(out.stdout)?;
+    let pkgs = dpkg_parse_search(&stdout);
+    if pkgs.is_empty() {
+        bail!("Failed to find any packages matching files in source efidir");
+    }
+    let out = crate::util::cmd_output(
+        crate::util::command("dpkg-query")
+            .arg(&root)
+            .args([
+                "--show",
+                "--showformat",
+                "${binary:Package} ${Package}-${Version}.${Architecture}\\n",
+            ])
+            .args(&pkgs),
+    )?;
+    let infodir = Path::new(sysroot_path).join("var/lib/dpkg/info");
+    let mut timestamp = None;
+    let mut versions = Vec::new();
+    for line in out.lines() {
+        let Some((pkg, version)) = line.split_once(' ') else {
+            bail!("Failed to parse: {line}");
+        };
+        let list = infodir.join(format!("{pkg}.list"));
+        let installed: DateTime<Utc> = std::fs::metadata(&list)
+            .and_then(|m| m.modified())
+            .with_context(|| format!("Querying {list:?}"))?
+            .into();
+        timestamp = timestamp.max(Some(installed));
+        versions.push(version);
+    }
+    let Some(timestamp) = timestamp else {
+        bail!("Failed to query packages: {pkgs:?}");
+    };
+    Ok(ContentMetadata {
+        timestamp,
+        version: versions.join(","),
+    })
+}
 
-    let rpmout = c.output()?;
-    if !rpmout.status.success() {
-        return Ok(ContentMetadata { timestamp: chrono::Utc::now(), version: "unknown".to_string() });
+/// A package in the local pacman database.
+#[derive(Debug, PartialEq, Eq)]
+struct PacmanPackage {
+    nevra: String,
+    buildtime: DateTime<Utc>,
+}
+
+/// Parse the `desc` file of a package in the local pacman database.
+fn pacman_parse_desc(desc: &str) -> Result<PacmanPackage> {
+    let mut fields = BTreeMap::new();
+    let mut lines = desc.lines();
+    while let Some(line) = lines.next() {
+        if let Some(key) = line.strip_prefix('%').and_then(|l| l.strip_suffix('%')) {
+            if let Some(value) = lines.next() {
+                fields.insert(key, value);
+            }
+        }
+    }
+    let field = |k: &str| {
+        fields
+            .get(k)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("Missing %{k}% in package description"))
+    };
+    let buildtime = DateTime::parse_from_str(field("BUILDDATE")?, "%s")
+        .context("Failed to parse pacman builddate")?
+        .with_timezone(&chrono::Utc);
+    Ok(PacmanPackage {
+        nevra: format!(
+            "{}-{}.{}",
+            field("NAME")?,
+            field("VERSION")?,
+            field("ARCH")?
+        ),
+        buildtime,
+    })
+}
+
+/// Query the local pacman database, which is a directory per package
+/// containing its description and file list.
+fn pacman_query_files<T>(
+    sysroot_path: &str,
+    paths: impl IntoIterator<Item = T>,
+) -> Result<ContentMetadata>
+where
+    T: AsRef<Path>,
+{
+    let paths = paths
+        .into_iter()
+        .map(|p| {
+            let p = p.as_ref().to_string_lossy();
+            p.trim_matches('/').to_string()
+        })
+        .collect::<BTreeSet<_>>();
+    let localdir = Path::new(sysroot_path).join("var/lib/pacman/local");
+    let mut pkgs = Vec::new();
+    for entry in std::fs::read_dir(&localdir).with_context(|| format!("Reading {localdir:?}"))? {
+        let pkgdir = entry?.path();
+        let files = pkgdir.join("files");
+        if !files.exists() {
+            continue;
+        }
+        let files = std::fs::read_to_string(&files)?;
+        if !files
+            .lines()
+            .any(|l| paths.contains(l.trim_end_matches('/')))
+        {
+            continue;
+        }
+        let desc = std::fs::read_to_string(pkgdir.join("desc"))?;
+        pkgs.push(pacman_parse_desc(&desc).with_context(|| format!("Parsing {pkgdir:?}"))?);
     }
+    pkgs.sort_by(|a, b| a.nevra.cmp(&b.nevra));
+    let Some(timestamp) = pkgs.iter().map(|p| p.buildtime).max() else {
+        bail!("Failed to find any packages matching files in source efidir");
+    };
+    let version = pkgs
+        .iter()
+        .map(|p| p.nevra.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+    Ok(ContentMetadata { timestamp, version })
+}
 
-    rpm_parse_metadata(&rpmout.stdout)
+#[test]
+fn test_pacman_query_files() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let pkgdir = td.path().join("var/lib/pacman/local/grub-2:2.12-3");
+    std::fs::create_dir_all(&pkgdir)?;
+    std::fs::write(
+        pkgdir.join("desc"),
+        "%NAME%\ngrub\n\n%VERSION%\n2:2.12-3\n\n%ARCH%\nx86_64\n\n%BUILDDATE%\n1722270574\n",
+    )?;
+    std::fs::write(
+        pkgdir.join("files"),
+        "%FILES%\nusr/\nusr/lib/grub/\nusr/lib/grub/x86_64-efi/\n",
+    )?;
+    let sysroot = td.path().to_str().unwrap();
+    let meta = pacman_query_files(sysroot, ["/usr/lib/grub/x86_64-efi"])?;
+    assert_eq!(meta.version, "grub-2:2.12-3.x86_64");
+    assert_eq!(meta.timestamp.timestamp(), 1722270574);
+    assert!(pacman_query_files(sysroot, ["/usr/lib/systemd/boot"]).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_parse_dpkg_search() {
+    let testdata = "diversion by dash from: /bin/sh\nshim-signed: /usr/lib/shim/shimx64.efi.signed\ngrub-efi-amd64-signed, grub-efi-amd64-bin: /usr/lib/grub\n";
+    let parsed = dpkg_parse_search(testdata);
+    assert_eq!(
+        parsed.into_iter().collect::<Vec<_>>(),
+        ["grub-efi-amd64-bin", "grub-efi-amd64-signed", "shim-signed"]
+    );
 }
 
 #[test]
 fn test_parse_rpmout() {
-    let testdata = "grub2-efi-x64-1:2.06-95.fc38.x86_64,1681321788 grub2-efi-x64-1:2.06-95.fc38.x86_64,1681321788 shim-x64-15.6-2.x86_64,1657222566 shim-x64-15.6-2.x86_64,1657222566 shim-x64-15.6-2.x86_64,1657222566";
+    let testdata = "grub2-efi-x64-1:2.06-95.fc38.x86_64\t1681321788\ngrub2-efi-x64-1:2.06-95.fc38.x86_64\t1681321788\nshim-x64-15.6-2.x86_64\t1657222566\nshim-x64-15.6-2.x86_64\t1657222566\nshim-x64-15.6-2.x86_64\t1657222566\n";
     let parsed = rpm_parse_metadata(testdata.as_bytes()).unwrap();
     assert_eq!(
         parsed.version,
         "grub2-efi-x64-1:2.06-95.fc38.x86_64,shim-x64-15.6-2.x86_64"
     );
+    assert_eq!(parsed.timestamp.timestamp(), 1681321788);
+}
+
+#[test]
+fn test_parse_rpmout_unowned() {
+    let testdata = "file /boot/efi/EFI/fedora/grubenv is not owned by any package\nshim-x64-15.6-2.x86_64\t1657222566\n";
+    let parsed = rpm_parse_metadata(testdata.as_bytes()).unwrap();
+    assert_eq!(parsed.version, "shim-x64-15.6-2.x86_64");
+    let unowned = "file /boot/efi/EFI/fedora/grubenv is not owned by any package\n";
+    assert!(rpm_parse_metadata(unowned.as_bytes()).is_err());
+    assert!(rpm_parse_metadata(b"garbage\n").is_err());
+}
+
+#[test]
+fn test_query_inputs_digest() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let root = td.path().to_str().unwrap();
+    let dbdir = td.path().join(ostreeutil::SYSIMAGE_RPM_DBPATH);
+    std::fs::create_dir_all(&dbdir)?;
+    std::fs::write(dbdir.join("rpmdb.sqlite"), "db")?;
+    let paths = vec![PathBuf::from("/usr/sbin/grub2-install")];
+    let digest = query_inputs_digest(root, &paths)?;
+    assert_eq!(query_inputs_digest(root, &paths)?, digest);
+    assert_ne!(query_inputs_digest(root, &[])?, digest);
+    // Any change to the database invalidates
+    std::fs::write(dbdir.join("rpmdb.sqlite"), "updated db")?;
+    assert_ne!(query_inputs_digest(root, &paths)?, digest);
+    Ok(())
 }
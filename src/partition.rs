@@ -0,0 +1,744 @@
+//! Reading the GPT and MBR partition tables of disks natively, and adding a
+//! partition to the free space of a GPT disk.
+//!
+//! This is for adopting machines converted from other layouts, e.g. a GPT
+//! disk which was only ever booted via UEFI has no BIOS boot partition for
+//! GRUB to embed its core image in, but usually has about 1 MiB free before
+//! its first partition.  Partitions are only ever added on explicit request
+//! (see [`crate::component::Options::create_bios_boot_partition`]);
+//! existing ones are never moved or resized.  Both the primary and backup
+//! tables are updated, and a disk whose tables don't check out is refused.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+
+use crate::system::System;
+use crate::util;
+
+/// The signature of a GPT header.
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+/// The size of the GPT header fields we know of.
+const GPT_HEADER_SIZE: usize = 92;
+/// The smallest size of a GPT partition entry.
+const GPT_ENTRY_SIZE: usize = 128;
+/// The most space a GPT partition entry array may take, way more than the
+/// 16 KiB of the usual 128 entries, so that a corrupt header can't make us
+/// allocate a lot.
+const GPT_MAX_ENTRIES_SIZE: usize = 1024 * 1024;
+/// The MBR partition type of the protective partition of a GPT disk.
+const MBR_PROTECTIVE: u8 = 0xee;
+/// New partitions start at multiples of this when there's room, as
+/// partitioning tools do.
+const ALIGNMENT: u64 = 1024 * 1024;
+/// New partitions start at least at multiples of this, for disks with 4 KiB
+/// physical sectors.
+const MIN_ALIGNMENT: u64 = 4096;
+
+/// A GUID, in the mixed-endian layout of GPT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) struct Guid([u8; 16]);
+
+impl Guid {
+    /// Parse a GUID in its usual textual form.
+    pub(crate) fn parse(s: &str) -> Result<Self> {
+        let hex: String = s.chars().filter(|&c| c != '-').collect();
+        if hex.len() != 32 || s.len() != 36 {
+            bail!("Invalid GUID {s:?}");
+        }
+        let mut bytes = [0u8; 16];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                .with_context(|| format!("Invalid GUID {s:?}"))?;
+        }
+        // The first three fields are little-endian
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        Ok(Self(bytes))
+    }
+
+    /// A random (version 4) GUID.
+    fn random() -> Result<Self> {
+        let mut bytes = [0u8; 16];
+        openssl::rand::rand_bytes(&mut bytes)?;
+        bytes[7] = (bytes[7] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Ok(Self(bytes))
+    }
+
+    fn is_nil(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl std::fmt::Display for Guid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6]
+        )?;
+        write!(f, "{:02x}{:02x}-", b[8], b[9])?;
+        b[10..].iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+/// The kinds of partitions we create.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PartitionType {
+    /// A BIOS boot partition, which GRUB embeds its core image in
+    BiosBoot,
+}
+
+impl PartitionType {
+    /// The partition type GUID.
+    pub(crate) fn guid(self) -> Guid {
+        let guid = match self {
+            PartitionType::BiosBoot => "21686148-6449-6e6f-744e-656564454649",
+        };
+        // Unwrap safety: constant
+        Guid::parse(guid).unwrap()
+    }
+
+    /// The label given to new partitions, as on Fedora CoreOS.
+    fn label(self) -> &'static str {
+        match self {
+            PartitionType::BiosBoot => "BIOS-BOOT",
+        }
+    }
+}
+
+/// A partition of an MBR.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MbrPartition {
+    /// The partition number, from 1 to 4
+    pub(crate) number: u32,
+    /// The partition type, e.g. `0xee` for the protective partition of GPT
+    pub(crate) kind: u8,
+    /// The first sector
+    pub(crate) start: u64,
+    /// The size in sectors
+    pub(crate) sectors: u64,
+}
+
+/// The primary partitions of an MBR.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Mbr {
+    pub(crate) partitions: Vec<MbrPartition>,
+}
+
+impl Mbr {
+    /// Parse the MBR in `sector`, the first of a disk.
+    pub(crate) fn parse(sector: &[u8]) -> Result<Self> {
+        if sector.len() < 512 || sector[510..512] != [0x55, 0xaa] {
+            bail!("No MBR signature");
+        }
+        let partitions = sector[446..510]
+            .chunks_exact(16)
+            .zip(1..)
+            .map(|(e, number)| MbrPartition {
+                number,
+                kind: e[4],
+                start: le32(&e[8..12]).into(),
+                sectors: le32(&e[12..16]).into(),
+            })
+            .filter(|p| p.kind != 0 && p.sectors != 0)
+            .collect();
+        Ok(Self { partitions })
+    }
+
+    /// Whether this is the protective MBR of a GPT disk.
+    pub(crate) fn is_protective(&self) -> bool {
+        self.partitions.iter().any(|p| p.kind == MBR_PROTECTIVE)
+    }
+}
+
+/// A partition of a GPT.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct GptPartition {
+    /// The partition number, i.e. its index in the entry array plus one
+    pub(crate) number: u32,
+    pub(crate) type_guid: Guid,
+    pub(crate) guid: Guid,
+    pub(crate) first_lba: u64,
+    pub(crate) last_lba: u64,
+    pub(crate) name: String,
+}
+
+impl GptPartition {
+    /// The size in sectors, failing for an entry ending before it starts.
+    pub(crate) fn sectors(&self) -> Result<u64> {
+        match self.last_lba.checked_sub(self.first_lba) {
+            Some(n) => Ok(n + 1),
+            None => bail!(
+                "Invalid GPT partition {}, ending before it starts",
+                self.number
+            ),
+        }
+    }
+}
+
+/// A GPT header, parsed and as read.
+#[derive(Debug, Clone)]
+struct GptHeader {
+    raw: Vec<u8>,
+    my_lba: u64,
+    alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    entries_lba: u64,
+    num_entries: u32,
+    entry_size: u32,
+    entries_crc: u32,
+}
+
+impl GptHeader {
+    fn parse(sector: &[u8]) -> Result<Self> {
+        if sector.get(..8) != Some(GPT_SIGNATURE) {
+            bail!("No GPT signature");
+        }
+        let size = le32(&sector[12..16]) as usize;
+        if !(GPT_HEADER_SIZE..=sector.len()).contains(&size) {
+            bail!("Invalid GPT header size {size}");
+        }
+        let mut raw = sector[..size].to_vec();
+        let crc = le32(&raw[16..20]);
+        raw[16..20].fill(0);
+        if crc32(&raw) != crc {
+            bail!("Invalid GPT header checksum");
+        }
+        raw[16..20].copy_from_slice(&crc.to_le_bytes());
+        let header = Self {
+            my_lba: le64(&raw[24..32]),
+            alternate_lba: le64(&raw[32..40]),
+            first_usable_lba: le64(&raw[40..48]),
+            last_usable_lba: le64(&raw[48..56]),
+            entries_lba: le64(&raw[72..80]),
+            num_entries: le32(&raw[80..84]),
+            entry_size: le32(&raw[84..88]),
+            entries_crc: le32(&raw[88..92]),
+            raw,
+        };
+        let entry_size = header.entry_size as usize;
+        if entry_size < GPT_ENTRY_SIZE || entry_size % 8 != 0 {
+            bail!("Invalid GPT partition entry size {entry_size}");
+        }
+        if header.entries_size() > GPT_MAX_ENTRIES_SIZE {
+            bail!("Too many GPT partition entries: {}", header.num_entries);
+        }
+        if header.first_usable_lba > header.last_usable_lba {
+            bail!("Invalid GPT usable area");
+        }
+        Ok(header)
+    }
+
+    fn entries_size(&self) -> usize {
+        self.num_entries as usize * self.entry_size as usize
+    }
+
+    /// The header for an entry array checksummed `entries_crc`.
+    fn with_entries_crc(&self, entries_crc: u32) -> Vec<u8> {
+        let mut raw = self.raw.clone();
+        raw[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        raw[16..20].fill(0);
+        let crc = crc32(&raw);
+        raw[16..20].copy_from_slice(&crc.to_le_bytes());
+        raw
+    }
+}
+
+/// A GPT, with the primary and backup headers it was read from.
+#[derive(Debug, Clone)]
+pub(crate) struct Gpt {
+    sector_size: u64,
+    primary: GptHeader,
+    backup: GptHeader,
+    entries: Vec<u8>,
+}
+
+impl Gpt {
+    /// Read and check the GPT of `disk`, whose logical sectors are
+    /// `sector_size` bytes.
+    fn read(disk: &mut (impl Read + Seek), sector_size: u64) -> Result<Self> {
+        let primary = GptHeader::parse(&read_at(disk, sector_size, sector_size as usize)?)
+            .context("Reading the primary GPT header")?;
+        if primary.my_lba != 1 {
+            bail!("Invalid location of the primary GPT header");
+        }
+        let entries = read_at(
+            disk,
+            primary.entries_lba * sector_size,
+            primary.entries_size(),
+        )?;
+        if crc32(&entries) != primary.entries_crc {
+            bail!("Invalid GPT partition entries checksum");
+        }
+        let backup = read_at(
+            disk,
+            primary.alternate_lba * sector_size,
+            sector_size as usize,
+        )
+        .map_err(anyhow::Error::from)
+        .and_then(|s| GptHeader::parse(&s))
+        .context("Reading the backup GPT header")?;
+        if backup.my_lba != primary.alternate_lba || backup.entries_size() != primary.entries_size()
+        {
+            bail!("The backup GPT header doesn't match the primary one");
+        }
+        let backup_entries = read_at(disk, backup.entries_lba * sector_size, entries.len())?;
+        if backup_entries != entries {
+            bail!("The backup GPT partition entries don't match the primary ones");
+        }
+        let gpt = Self {
+            sector_size,
+            primary,
+            backup,
+            entries,
+        };
+        for p in gpt.partitions() {
+            p.sectors()?;
+        }
+        Ok(gpt)
+    }
+
+    fn entry(&self, index: usize) -> &[u8] {
+        let size = self.primary.entry_size as usize;
+        &self.entries[index * size..][..size]
+    }
+
+    /// The partitions, by number.
+    pub(crate) fn partitions(&self) -> Vec<GptPartition> {
+        (0..self.primary.num_entries as usize)
+            .filter_map(|i| {
+                let e = self.entry(i);
+                let type_guid = Guid(e[0..16].try_into().unwrap());
+                if type_guid.is_nil() {
+                    return None;
+                }
+                let name: Vec<u16> = e[56..128]
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .take_while(|&c| c != 0)
+                    .collect();
+                Some(GptPartition {
+                    number: i as u32 + 1,
+                    type_guid,
+                    guid: Guid(e[16..32].try_into().unwrap()),
+                    first_lba: le64(&e[32..40]),
+                    last_lba: le64(&e[40..48]),
+                    name: String::from_utf16_lossy(&name),
+                })
+            })
+            .collect()
+    }
+
+    /// The free extents of the usable area, as inclusive ranges of sectors.
+    /// The headers and entry arrays are usually outside of it, but needn't
+    /// be, and are left alone like the partitions.
+    fn free_extents(&self) -> Vec<(u64, u64)> {
+        let mut used: Vec<_> = self
+            .partitions()
+            .iter()
+            .map(|p| (p.first_lba, p.last_lba))
+            .collect();
+        let entries_sectors = (self.entries.len() as u64).div_ceil(self.sector_size);
+        for header in [&self.primary, &self.backup] {
+            used.push((header.my_lba, header.my_lba));
+            let last = header
+                .entries_lba
+                .saturating_add(entries_sectors.max(1) - 1);
+            used.push((header.entries_lba, last));
+        }
+        used.sort_unstable();
+        let mut free = Vec::new();
+        let mut next = self.primary.first_usable_lba;
+        for (first, last) in used {
+            if first > next {
+                free.push((next, (first - 1).min(self.primary.last_usable_lba)));
+            }
+            next = next.max(last.saturating_add(1));
+        }
+        if next <= self.primary.last_usable_lba {
+            free.push((next, self.primary.last_usable_lba));
+        }
+        free.retain(|(first, last)| first <= last);
+        free
+    }
+
+    /// Add a partition of `kind` in the first free extent with room for at
+    /// least `min` bytes, of up to `max` bytes.
+    fn add(&mut self, kind: PartitionType, min: u64, max: u64) -> Result<GptPartition> {
+        let sector = self.sector_size;
+        let Some(index) = (0..self.primary.num_entries as usize)
+            .find(|&i| Guid(self.entry(i)[0..16].try_into().unwrap()).is_nil())
+        else {
+            bail!("No free GPT partition entry");
+        };
+        let min_sectors = min.div_ceil(sector);
+        let max_sectors = max.div_ceil(sector).max(min_sectors);
+        let fit = |(first, last): (u64, u64), alignment: u64| {
+            let alignment = (alignment / sector).max(1);
+            let start = first.div_ceil(alignment) * alignment;
+            (start <= last && last - start + 1 >= min_sectors)
+                .then(|| (start, last.min(start + max_sectors - 1)))
+        };
+        let Some((first_lba, last_lba)) = self
+            .free_extents()
+            .into_iter()
+            .find_map(|e| fit(e, ALIGNMENT).or_else(|| fit(e, MIN_ALIGNMENT)))
+        else {
+            bail!("No free space of at least {} KiB", min / 1024);
+        };
+        let partition = GptPartition {
+            number: index as u32 + 1,
+            type_guid: kind.guid(),
+            guid: Guid::random()?,
+            first_lba,
+            last_lba,
+            name: kind.label().to_owned(),
+        };
+        let size = self.primary.entry_size as usize;
+        let e = &mut self.entries[index * size..][..size];
+        e.fill(0);
+        e[0..16].copy_from_slice(&partition.type_guid.0);
+        e[16..32].copy_from_slice(&partition.guid.0);
+        e[32..40].copy_from_slice(&first_lba.to_le_bytes());
+        e[40..48].copy_from_slice(&last_lba.to_le_bytes());
+        for (c, u) in e[56..128]
+            .chunks_exact_mut(2)
+            .zip(partition.name.encode_utf16())
+        {
+            c.copy_from_slice(&u.to_le_bytes());
+        }
+        Ok(partition)
+    }
+
+    /// Write the backup and then the primary table to `disk`, syncing each.
+    fn write(&self, disk: &mut File) -> Result<()> {
+        let crc = crc32(&self.entries);
+        for header in [&self.backup, &self.primary] {
+            disk.seek(SeekFrom::Start(header.entries_lba * self.sector_size))?;
+            disk.write_all(&self.entries)?;
+            disk.seek(SeekFrom::Start(header.my_lba * self.sector_size))?;
+            disk.write_all(&header.with_entries_crc(crc))?;
+            disk.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// The partition table of a disk.
+#[derive(Debug, Clone)]
+pub(crate) enum PartitionTable {
+    Gpt(Gpt),
+    Mbr(Mbr),
+}
+
+/// Read `len` bytes at `offset` of `disk`.
+fn read_at(disk: &mut (impl Read + Seek), offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// The logical sector size of `disk`; disk images have 512 byte sectors.
+fn sector_size(disk: &File) -> Result<u64> {
+    if !disk.metadata()?.file_type().is_block_device() {
+        return Ok(512);
+    }
+    Ok(rustix::fs::ioctl_blksszget(disk)?.into())
+}
+
+/// Read the partition table of `disk`.
+fn read_table(disk: &mut File) -> Result<PartitionTable> {
+    let sector_size = sector_size(disk)?;
+    let mbr = Mbr::parse(&read_at(disk, 0, 512)?)?;
+    if !mbr.is_protective() {
+        return Ok(PartitionTable::Mbr(mbr));
+    }
+    Ok(PartitionTable::Gpt(Gpt::read(disk, sector_size)?))
+}
+
+/// Read the partition table of the disk at `path`.
+#[context("Reading the partition table of {}", path.display())]
+pub(crate) fn read(path: &Path) -> Result<PartitionTable> {
+    read_table(&mut File::open(path)?)
+}
+
+/// Add a partition of `kind` of between `min` and `max` bytes to the free
+/// space of the GPT disk at `disk`, and tell the kernel about it.
+#[context("Creating a partition on {}", disk.display())]
+pub(crate) fn create(
+    system: &dyn System,
+    disk: &Path,
+    kind: PartitionType,
+    min: u64,
+    max: u64,
+) -> Result<GptPartition> {
+    let mut file = File::options().read(true).write(true).open(disk)?;
+    let PartitionTable::Gpt(mut gpt) = read_table(&mut file)? else {
+        bail!("Not a GPT disk");
+    };
+    let partition = gpt.add(kind, min, max)?;
+    gpt.write(&mut file)?;
+    drop(file);
+    log::info!(
+        "Created partition {} of {} KiB on {}",
+        partition.number,
+        partition.sectors()? * gpt.sector_size / 1024,
+        disk.display()
+    );
+    if file_is_block_device(disk) && !util::have_program("partx") {
+        let start = partition.first_lba * gpt.sector_size;
+        let length = partition.sectors()? * gpt.sector_size;
+        if let Err(e) = crate::native::add_partition(disk, partition.number, start, length) {
+            log::warn!("{e:#}");
+        }
+    } else if file_is_block_device(disk) {
+        // Unlike re-reading the whole table, this works with partitions in use
+        let mut cmd = util::command("partx");
+        cmd.arg("--add")
+            .arg("--nr")
+            .arg(partition.number.to_string())
+            .arg(disk);
+        let out = system.output(&mut cmd)?;
+        if !out.status.success() {
+            log::warn!("{:#}", util::tool_failed(&cmd, out.status, &out.stderr));
+        }
+    }
+    Ok(partition)
+}
+
+fn file_is_block_device(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|m| m.file_type().is_block_device())
+}
+
+fn le32(b: &[u8]) -> u32 {
+    // Unwrap safety: callers pass 4 bytes
+    u32::from_le_bytes(b.try_into().unwrap())
+}
+
+fn le64(b: &[u8]) -> u64 {
+    // Unwrap safety: callers pass 8 bytes
+    u64::from_le_bytes(b.try_into().unwrap())
+}
+
+/// The CRC-32 (as of zlib) of `data`, which GPT checksums with.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| {
+        (0..8).fold(crc ^ u32::from(b), |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR: u64 = 512;
+    const NO_DEVICES: &str = r#"{"blockdevices": []}"#;
+
+    /// Write a GPT disk image of `sectors` with partitions of `(first,
+    /// last)` sectors, as sfdisk would.
+    fn gpt_image(path: &Path, sectors: u64, partitions: &[(u64, u64)]) -> Result<()> {
+        gpt_image_with(path, sectors, partitions, 2)
+    }
+
+    /// Like [`gpt_image`], with the primary entry array at `entries_lba`.
+    fn gpt_image_with(
+        path: &Path,
+        sectors: u64,
+        partitions: &[(u64, u64)],
+        entries_lba: u64,
+    ) -> Result<()> {
+        let mut f = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        f.set_len(sectors * SECTOR)?;
+        let mut mbr = vec![0u8; 512];
+        mbr[446 + 4] = MBR_PROTECTIVE;
+        mbr[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+        mbr[446 + 12..446 + 16].copy_from_slice(&((sectors - 1) as u32).to_le_bytes());
+        mbr[510..].copy_from_slice(&[0x55, 0xaa]);
+        f.write_all(&mbr)?;
+
+        let mut entries = vec![0u8; 128 * 128];
+        let linux = Guid::parse("0fc63daf-8483-4772-8e79-3d69d8477de4")?;
+        for (e, (first, last)) in entries.chunks_exact_mut(128).zip(partitions) {
+            e[0..16].copy_from_slice(&linux.0);
+            e[16..32].copy_from_slice(&Guid::random()?.0);
+            e[32..40].copy_from_slice(&first.to_le_bytes());
+            e[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+        let header = |my: u64, alternate: u64, entries_lba: u64| {
+            let mut h = vec![0u8; GPT_HEADER_SIZE];
+            h[0..8].copy_from_slice(GPT_SIGNATURE);
+            h[8..12].copy_from_slice(&0x10000u32.to_le_bytes());
+            h[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+            h[24..32].copy_from_slice(&my.to_le_bytes());
+            h[32..40].copy_from_slice(&alternate.to_le_bytes());
+            h[40..48].copy_from_slice(&34u64.to_le_bytes());
+            h[48..56].copy_from_slice(&(sectors - 34).to_le_bytes());
+            h[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+            h[80..84].copy_from_slice(&128u32.to_le_bytes());
+            h[84..88].copy_from_slice(&128u32.to_le_bytes());
+            h[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+            let crc = crc32(&h);
+            h[16..20].copy_from_slice(&crc.to_le_bytes());
+            h
+        };
+        let last = sectors - 1;
+        for (my, alternate, entries_lba) in [(1, last, entries_lba), (last, 1, last - 32)] {
+            f.seek(SeekFrom::Start(entries_lba * SECTOR))?;
+            f.write_all(&entries)?;
+            f.seek(SeekFrom::Start(my * SECTOR))?;
+            f.write_all(&header(my, alternate, entries_lba))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_guid() -> Result<()> {
+        let s = "21686148-6449-6e6f-744e-656564454649";
+        let guid = Guid::parse(s)?;
+        // As stored on disk: "Hah!IdontNeedEFI"
+        assert_eq!(&guid.0, b"Hah!IdontNeedEFI");
+        assert_eq!(guid.to_string(), s);
+        assert_eq!(PartitionType::BiosBoot.guid(), guid);
+        assert!(Guid::parse("21686148-6449-6e6f-744e").is_err());
+        assert!(Guid::random()?.to_string().as_bytes()[14] == b'4');
+        Ok(())
+    }
+
+    #[test]
+    fn test_mbr() -> Result<()> {
+        let mut sector = vec![0u8; 512];
+        assert!(Mbr::parse(&sector).is_err());
+        sector[510..].copy_from_slice(&[0x55, 0xaa]);
+        let e = &mut sector[446 + 16..446 + 32];
+        e[4] = 0x83;
+        e[8..12].copy_from_slice(&2048u32.to_le_bytes());
+        e[12..16].copy_from_slice(&4096u32.to_le_bytes());
+        let mbr = Mbr::parse(&sector)?;
+        assert!(!mbr.is_protective());
+        assert_eq!(mbr.partitions[0].number, 2);
+        assert_eq!(mbr.partitions[0].start, 2048);
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_bios_boot() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let disk = td.path().join("disk.img");
+        // An EFI-only layout: ESP, then root
+        gpt_image(&disk, 16384, &[(2048, 4095), (4096, 16349)])?;
+        let system = crate::system::Mock::new(td.path(), NO_DEVICES)?;
+
+        let created = create(
+            &system,
+            &disk,
+            PartitionType::BiosBoot,
+            64 * 1024,
+            1024 * 1024,
+        )?;
+        // Only the gap before the first partition is free, too small to align
+        assert_eq!(created.number, 3);
+        assert_eq!((created.first_lba, created.last_lba), (40, 2047));
+        let PartitionTable::Gpt(gpt) = read(&disk)? else {
+            panic!("Not GPT");
+        };
+        let partitions = gpt.partitions();
+        assert_eq!(partitions.len(), 3);
+        assert_eq!(partitions[2], created);
+        assert_eq!(created.name, "BIOS-BOOT");
+        assert_eq!(created.type_guid, PartitionType::BiosBoot.guid());
+
+        // No room left
+        let e = create(
+            &system,
+            &disk,
+            PartitionType::BiosBoot,
+            64 * 1024,
+            1024 * 1024,
+        )
+        .unwrap_err();
+        assert!(format!("{e:#}").contains("No free space"), "{e:#}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_create_aligned() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let disk = td.path().join("disk.img");
+        gpt_image(&disk, 16384, &[(2048, 4095)])?;
+        let system = crate::system::Mock::new(td.path(), NO_DEVICES)?;
+        let created = create(
+            &system,
+            &disk,
+            PartitionType::BiosBoot,
+            1024 * 1024,
+            2 * 1024 * 1024,
+        )?;
+        assert_eq!((created.first_lba, created.last_lba), (4096, 8191));
+
+        // Nor over an entry array in the usable area
+        gpt_image_with(&disk, 16384, &[(2048, 4095)], 4096)?;
+        let created = create(
+            &system,
+            &disk,
+            PartitionType::BiosBoot,
+            1024 * 1024,
+            2 * 1024 * 1024,
+        )?;
+        assert_eq!((created.first_lba, created.last_lba), (6144, 10239));
+        Ok(())
+    }
+
+    #[test]
+    fn test_refuse_corrupt() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let disk = td.path().join("disk.img");
+        gpt_image(&disk, 16384, &[(2048, 4095)])?;
+        // Damage the backup entries
+        let mut f = File::options().write(true).open(&disk)?;
+        f.seek(SeekFrom::Start((16384 - 33) * SECTOR))?;
+        f.write_all(b"garbage")?;
+        drop(f);
+        let system = crate::system::Mock::new(td.path(), NO_DEVICES)?;
+        let e = create(
+            &system,
+            &disk,
+            PartitionType::BiosBoot,
+            64 * 1024,
+            1024 * 1024,
+        )
+        .unwrap_err();
+        assert!(format!("{e:#}").contains("backup"), "{e:#}");
+
+        // An entry ending before it starts
+        gpt_image(&disk, 16384, &[(4095, 2048)])?;
+        let e = read(&disk).unwrap_err();
+        assert!(
+            format!("{e:#}").contains("ending before it starts"),
+            "{e:#}"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! The rules by which the paths of file trees are validated, rendered and
+//! compared, as pure functions, so that manifests and the filesystems they
+//! describe agree on which names are the same file.
+//!
+//! Tree paths are relative with `/` separators (see [`is_tree_path`]); the
+//! firmware is given them with `\` ([`to_efi_path`]).  On FAT, names match
+//! regardless of ASCII case and trailing dots ([`fat_key`]), as with
+//! Linux's vfat.  Neither FAT nor we normalize Unicode: the NFC and NFD
+//! spellings of a name are different files, which [`nfc`] tells apart from
+//! really different names.
+
+#[cfg(have_filetree)]
+use std::borrow::Cow;
+
+#[cfg(have_filetree)]
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+/// Whether `path` is below the directory of a tree, with a file name and
+/// no `.`, `..` or empty components.
+pub(crate) fn is_tree_path(path: &str) -> bool {
+    !path.contains('\0') && path.split('/').all(|c| !matches!(c, "" | "." | ".."))
+}
+
+/// The tree path `path` as the firmware takes it, absolute with `\`
+/// separators, e.g. `\EFI\fedora\shimx64.efi` for `EFI/fedora/shimx64.efi`.
+#[cfg(have_efi)]
+pub(crate) fn to_efi_path(path: &str) -> String {
+    path.split('/')
+        .filter(|c| !c.is_empty())
+        .flat_map(|c| ["\\", c])
+        .collect()
+}
+
+/// The name `name` in Unicode normalization form C, e.g. with `e` and a
+/// combining acute accent composed to `é`.
+#[cfg(have_filetree)]
+pub(crate) fn nfc(name: &str) -> Cow<'_, str> {
+    match is_nfc_quick(name.chars()) {
+        IsNormalized::Yes => Cow::Borrowed(name),
+        _ => Cow::Owned(name.nfc().collect()),
+    }
+}
+
+/// The name under which FAT finds the file `name`: uppercased, as long
+/// names only match regardless of ASCII case, and without trailing dots,
+/// which are dropped from names.
+#[cfg(have_filetree)]
+pub(crate) fn fat_name(name: &str) -> String {
+    let trimmed = name.trim_end_matches('.');
+    // `.` and `..` are kept, as they aren't names of files
+    let name = if trimmed.is_empty() { name } else { trimmed };
+    name.to_ascii_uppercase()
+}
+
+/// The key under which two paths of a tree are the same file on FAT; see
+/// [`fat_name`].
+#[cfg(have_filetree)]
+pub(crate) fn fat_key(path: &str) -> String {
+    path.split('/').map(fat_name).collect::<Vec<_>>().join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// A file name, with a few trailing dots and mixed case to fold.
+    const NAME: &str = "[a-zA-Z0-9_~é-][a-zA-Zé0-9._ -]{0,12}";
+
+    fn tree_path() -> impl Strategy<Value = String> {
+        prop::collection::vec(NAME, 1..5).prop_map(|c| c.join("/"))
+    }
+
+    #[test]
+    fn test_examples() {
+        assert!(is_tree_path("EFI/BOOT/BOOTX64.EFI"));
+        for p in [
+            "",
+            "/EFI",
+            "EFI/",
+            "EFI//BOOT",
+            "EFI/./BOOT",
+            "../EFI",
+            "a\0b",
+        ] {
+            assert!(!is_tree_path(p), "{p:?}");
+        }
+        #[cfg(have_efi)]
+        assert_eq!(
+            to_efi_path("EFI/fedora/shimx64.efi"),
+            "\\EFI\\fedora\\shimx64.efi"
+        );
+        #[cfg(have_filetree)]
+        {
+            assert_eq!(fat_key("EFI/boot/bootx64.efi."), "EFI/BOOT/BOOTX64.EFI");
+            assert_eq!(fat_key("EFI/../.."), "EFI/../..");
+            assert_eq!(nfc("e\u{301}"), "\u{e9}");
+            assert!(matches!(nfc("shimx64.efi"), Cow::Borrowed(_)));
+            // Only ASCII is case insensitive
+            assert_ne!(fat_name("é"), fat_name("É"));
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn prop_tree_paths(path in tree_path()) {
+            prop_assert!(is_tree_path(&path));
+            let (below, absolute) = (format!("{path}/.."), format!("/{path}"));
+            prop_assert!(!is_tree_path(&below));
+            prop_assert!(!is_tree_path(&absolute));
+        }
+
+        #[test]
+        #[cfg(have_efi)]
+        fn prop_efi_path(path in tree_path()) {
+            let efi = to_efi_path(&path);
+            prop_assert!(efi.starts_with('\\') && !efi.contains('/'));
+            prop_assert_eq!(efi[1..].replace('\\', "/"), path);
+        }
+
+        #[test]
+        #[cfg(have_filetree)]
+        fn prop_fat_key(path in tree_path(), dots in "\\.{0,3}") {
+            let key = fat_key(&path);
+            prop_assert_eq!(fat_key(&key), key.clone());
+            prop_assert_eq!(fat_key(&path.to_ascii_lowercase()), key.clone());
+            prop_assert_eq!(fat_key(&path.to_ascii_uppercase()), key.clone());
+            prop_assert_eq!(fat_key(&format!("{path}{dots}")), key.clone());
+            prop_assert_eq!(key.split('/').count(), path.split('/').count());
+        }
+
+        #[test]
+        #[cfg(have_filetree)]
+        fn prop_nfc(name in NAME) {
+            let nfd: String = name.nfd().collect();
+            prop_assert_eq!(nfc(&nfd), nfc(&name));
+            let composed = nfc(&name);
+            prop_assert_eq!(nfc(&composed), composed.clone());
+            // FAT doesn't normalize, so the spellings stay distinct
+            if nfd != name {
+                prop_assert_ne!(fat_name(&nfd), fat_name(&name));
+            }
+        }
+    }
+}
@@ -0,0 +1,760 @@
+//! Self-contained update payload bundles.
+//!
+//! A bundle is a tarball containing the update payloads from
+//! `/usr/lib/bootupd/updates` along with a manifest describing them,
+//! optionally accompanied by a detached signature of the manifest.  This
+//! allows generating bootloader updates on a build machine and shipping them
+//! to hosts independently of an OS update.
+//!
+//! To save bandwidth, large files (e.g. UKIs) can be shipped as binary
+//! deltas against the previous version, as made by `zstd --patch-from`.
+//! The full files are reconstructed from the installed ones on the host,
+//! and verified against the manifest before anything is updated.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::sign::{Signer, Verifier};
+use serde::{Deserialize, Serialize};
+
+use crate::model::{ContentMetadata, BOOTUPD_UPDATES_DIR};
+use crate::pathnorm;
+use crate::util::CommandRunExt;
+
+/// The name of the manifest in the bundle.
+pub(crate) const MANIFEST_NAME: &str = "bootupd-payload.json";
+/// The name of the detached manifest signature in the bundle.
+pub(crate) const SIGNATURE_NAME: &str = "bootupd-payload.json.sig";
+/// The directory holding the payloads in the bundle.
+pub(crate) const UPDATES_NAME: &str = "updates";
+/// The directory holding the deltas in the bundle, by component and path.
+pub(crate) const DELTAS_NAME: &str = "deltas";
+/// Files smaller than this are always shipped in full.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DELTA_MIN_SIZE: u64 = 1024 * 1024;
+/// The smallest and largest windows of zstd, as powers of two.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const ZSTD_WINDOWLOG_MIN: u32 = 10;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const ZSTD_WINDOWLOG_MAX: u32 = 31;
+/// The current manifest format.
+const MANIFEST_VERSION: u32 = 1;
+
+/// A single component in a payload bundle.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PayloadComponent {
+    /// The update metadata, as in `/usr/lib/bootupd/updates/<component>.json`
+    pub(crate) meta: ContentMetadata,
+    /// Digests of the payload files, for components that have them
+    pub(crate) filetree: Option<crate::filetree::FileTree>,
+    /// The payload files shipped as deltas, by path, with the installed
+    /// file they apply to
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        deserialize_with = "deserialize_deltas"
+    )]
+    pub(crate) deltas: BTreeMap<String, crate::filetree::FileMetadata>,
+}
+
+/// Describes the content of a payload bundle.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PayloadManifest {
+    pub(crate) version: u32,
+    #[serde(deserialize_with = "deserialize_components")]
+    pub(crate) components: BTreeMap<String, PayloadComponent>,
+}
+
+/// Deserialize the deltas of a component, refusing paths outside of its
+/// payload: they are joined to the installed and extracted trees.
+fn deserialize_deltas<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<BTreeMap<String, crate::filetree::FileMetadata>, D::Error> {
+    let deltas = BTreeMap::<String, crate::filetree::FileMetadata>::deserialize(d)?;
+    if let Some(path) = deltas.keys().find(|p| !pathnorm::is_tree_path(p)) {
+        return Err(serde::de::Error::custom(format!(
+            "Invalid delta path: {path:?}"
+        )));
+    }
+    Ok(deltas)
+}
+
+/// Deserialize the components of a manifest, refusing names which aren't a
+/// single path component, as they name the directories of the payloads.
+fn deserialize_components<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<BTreeMap<String, PayloadComponent>, D::Error> {
+    let components = BTreeMap::<String, PayloadComponent>::deserialize(d)?;
+    if let Some(name) = components
+        .keys()
+        .find(|n| n.contains('/') || !pathnorm::is_tree_path(n))
+    {
+        return Err(serde::de::Error::custom(format!(
+            "Invalid component name: {name:?}"
+        )));
+    }
+    Ok(components)
+}
+
+/// Sign the serialized manifest with a PEM private key.
+pub(crate) fn sign_manifest(manifest: &[u8], key: &PKey<Private>) -> Result<Vec<u8>> {
+    let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+    signer.update(manifest)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+/// Verify a detached manifest signature against a PEM public key.
+pub(crate) fn verify_manifest(manifest: &[u8], sig: &[u8], key: &PKey<Public>) -> Result<()> {
+    let mut verifier = Verifier::new(MessageDigest::sha256(), key)?;
+    verifier.update(manifest)?;
+    if !verifier.verify(sig)? {
+        anyhow::bail!("Invalid signature for {MANIFEST_NAME}");
+    }
+    Ok(())
+}
+
+/// The window for a delta from `base_size` bytes to `target_size` bytes,
+/// as a power of two, so that all of the base can be referred to, as
+/// `zstd --patch-from` chooses it.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn delta_window_log(base_size: u64, target_size: u64) -> u32 {
+    let size = base_size.saturating_add(target_size).max(1);
+    (u64::BITS - (size - 1).leading_zeros()).clamp(ZSTD_WINDOWLOG_MIN, ZSTD_WINDOWLOG_MAX)
+}
+
+/// Write a delta from `base` to `target` as `patch`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[context("Creating delta for {target:?}")]
+fn make_delta(base: &Path, target: &Path, patch: &Path) -> Result<()> {
+    let base = std::fs::read(base).with_context(|| format!("Reading {base:?}"))?;
+    let mut target = std::fs::File::open(target)?;
+    let size = target.metadata()?.len();
+    let out = std::fs::File::create(patch).with_context(|| format!("Creating {patch:?}"))?;
+    let mut encoder = zstd::stream::write::Encoder::with_ref_prefix(out, 19, &base)?;
+    encoder.window_log(delta_window_log(base.len() as u64, size))?;
+    encoder.long_distance_matching(true)?;
+    encoder.set_pledged_src_size(Some(size))?;
+    std::io::copy(&mut target, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    Ok(())
+}
+
+/// Reconstruct `target` by applying `patch` to `base`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[context("Applying delta to {base:?}")]
+fn apply_delta(base: &Path, patch: &Path, target: &Path) -> Result<()> {
+    let base = std::fs::read(base)?;
+    let patch = std::fs::File::open(patch).with_context(|| format!("Opening {patch:?}"))?;
+    let mut decoder =
+        zstd::stream::read::Decoder::with_ref_prefix(std::io::BufReader::new(patch), &base)?;
+    decoder.window_log_max(ZSTD_WINDOWLOG_MAX)?;
+    let mut out = std::fs::File::create(target).with_context(|| format!("Creating {target:?}"))?;
+    std::io::copy(&mut decoder, &mut out)?;
+    out.sync_all()?;
+    Ok(())
+}
+
+/// Write deltas to `deltadir` for the large files of the payload in
+/// `updatedir` which changed from `basedir`, returning the files for
+/// which a delta is smaller than the file itself.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn write_deltas(
+    tree: &crate::filetree::FileTree,
+    updatedir: &Path,
+    basedir: &Path,
+    deltadir: &Path,
+) -> Result<BTreeMap<String, crate::filetree::FileMetadata>> {
+    let mut r = BTreeMap::new();
+    let base = openat::Dir::open(basedir).with_context(|| format!("Opening {basedir:?}"))?;
+    for (path, meta) in tree.children.iter() {
+        if meta.size < DELTA_MIN_SIZE || !base.exists(path.as_str())? {
+            continue;
+        }
+        let basemeta = crate::filetree::FileMetadata::new_from_path(&base, path.as_str())?;
+        if &basemeta == meta {
+            continue;
+        }
+        let patch = deltadir.join(path);
+        std::fs::create_dir_all(patch.parent().unwrap())?;
+        make_delta(&basedir.join(path), &updatedir.join(path), &patch)?;
+        if std::fs::metadata(&patch)?.len() >= meta.size {
+            std::fs::remove_file(&patch)?;
+            continue;
+        }
+        r.insert(path.clone(), basemeta);
+    }
+    Ok(r)
+}
+
+/// Write deltas to `staging` for the components of `manifest` whose
+/// payload in `sysroot_path` changed from that in the root `base`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn add_deltas(
+    mut manifest: PayloadManifest,
+    sysroot_path: &str,
+    base: &Path,
+    staging: &Path,
+) -> Result<PayloadManifest> {
+    for (name, c) in manifest.components.iter_mut() {
+        let Some(tree) = c.filetree.as_ref() else {
+            continue;
+        };
+        let basedir = base.join(BOOTUPD_UPDATES_DIR).join(name);
+        if !basedir.exists() {
+            log::debug!("No previous payload for {name}");
+            continue;
+        }
+        let updatedir = Path::new(sysroot_path).join(BOOTUPD_UPDATES_DIR).join(name);
+        let deltadir = staging.join(DELTAS_NAME).join(name);
+        c.deltas = write_deltas(tree, &updatedir, &basedir, &deltadir)?;
+    }
+    Ok(manifest)
+}
+
+/// Gather the manifest for the updates available in `sysroot`.
+fn manifest_for(sysroot: &openat::Dir) -> Result<PayloadManifest> {
+    let mut components = BTreeMap::new();
+    for (name, component) in crate::bootupd::get_components() {
+        let Some(meta) = component.query_update(sysroot)? else {
+            log::debug!("No update payload for {name}");
+            continue;
+        };
+        let payloaddir = Path::new(BOOTUPD_UPDATES_DIR).join(name);
+        let filetree = match sysroot.sub_dir_optional(&payloaddir)? {
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            Some(d) => Some(crate::filetree::FileTree::new_from_dir(&d)?),
+            _ => None,
+        };
+        let deltas = BTreeMap::new();
+        components.insert(
+            name.to_string(),
+            PayloadComponent {
+                meta,
+                filetree,
+                deltas,
+            },
+        );
+    }
+    if components.is_empty() {
+        anyhow::bail!("No update payloads found in {BOOTUPD_UPDATES_DIR}");
+    }
+    Ok(PayloadManifest {
+        version: MANIFEST_VERSION,
+        components,
+    })
+}
+
+/// Write a payload bundle for the updates in `sysroot_path` to `dest`.  If
+/// `delta_from` is provided, large files which changed from the payloads
+/// in that root are shipped as deltas.
+#[context("Exporting payload bundle")]
+pub(crate) fn export(
+    sysroot_path: &str,
+    dest: &Path,
+    sign_key: Option<&Path>,
+    delta_from: Option<&Path>,
+) -> Result<()> {
+    let sysroot = openat::Dir::open(sysroot_path)?;
+    let manifest = manifest_for(&sysroot)?;
+
+    let staging = tempfile::tempdir()?;
+    let mut toplevel = vec![MANIFEST_NAME];
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    if delta_from.is_some() {
+        anyhow::bail!("Deltas are only supported for EFI payloads");
+    }
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    let manifest = match delta_from {
+        Some(base) => add_deltas(manifest, sysroot_path, base, staging.path())?,
+        None => manifest,
+    };
+    if manifest.components.values().any(|c| !c.deltas.is_empty()) {
+        toplevel.push(DELTAS_NAME);
+    }
+    let manifest_data = serde_json::to_vec_pretty(&manifest)?;
+    std::fs::write(staging.path().join(MANIFEST_NAME), &manifest_data)?;
+    if let Some(keypath) = sign_key {
+        let pem = std::fs::read(keypath).with_context(|| format!("Reading {keypath:?}"))?;
+        let key = PKey::private_key_from_pem(&pem).context("Parsing signing key")?;
+        let sig = sign_manifest(&manifest_data, &key)?;
+        std::fs::write(staging.path().join(SIGNATURE_NAME), sig)?;
+        toplevel.push(SIGNATURE_NAME);
+    }
+
+    // The payload lives in the parent of the updates directory; we only
+    // include the components listed in the manifest.
+    let updates_parent = Path::new(sysroot_path).join(BOOTUPD_UPDATES_DIR);
+    let updates_parent = updates_parent.parent().unwrap();
+    let mut entries = Vec::new();
+    let mut excludes = Vec::new();
+    for (name, c) in manifest.components.iter() {
+        for path in c.deltas.keys() {
+            excludes.push(format!("--exclude={UPDATES_NAME}/{name}/{path}"));
+        }
+        let data = crate::component::shipped_update_data_name(&sysroot, name)?
+            .ok_or_else(|| crate::errors::Error::payload_missing(name))?;
+        entries.push(format!("{UPDATES_NAME}/{}", data.display()));
+        if c.filetree.is_some() {
+            entries.push(format!("{UPDATES_NAME}/{name}"));
+        }
+    }
+    crate::util::command("tar")
+        .args([
+            "--create",
+            "--sort=name",
+            "--owner=0",
+            "--group=0",
+            "--numeric-owner",
+            "--anchored",
+            "--no-wildcards",
+        ])
+        .args(&excludes)
+        .arg("--file")
+        .arg(dest)
+        .arg("-C")
+        .arg(staging.path())
+        .args(&toplevel)
+        .arg("-C")
+        .arg(updates_parent)
+        .args(&entries)
+        .run()?;
+    for (name, c) in manifest.components.iter() {
+        println!("Exported {name}: {}", c.meta.version);
+        for path in c.deltas.keys() {
+            println!("  As delta: {path}");
+        }
+    }
+    Ok(())
+}
+
+/// Extract the `members` of the tarball at `path` into `dest`; all of them
+/// must be present.
+fn extract(path: &Path, dest: &Path, members: &[String]) -> Result<()> {
+    if members.is_empty() {
+        return Ok(());
+    }
+    let mut list = tempfile::NamedTempFile::new()?;
+    for m in members {
+        list.write_all(m.as_bytes())?;
+        list.write_all(b"\0")?;
+    }
+    list.flush()?;
+    crate::util::command("tar")
+        .args([
+            "--extract",
+            "--no-same-owner",
+            "--no-same-permissions",
+            "--anchored",
+            "--no-wildcards",
+            "--null",
+        ])
+        .arg("--file")
+        .arg(path)
+        .arg("-C")
+        .arg(dest)
+        .arg("--files-from")
+        .arg(list.path())
+        .run()
+}
+
+/// An extracted and verified payload bundle.
+pub(crate) struct Bundle {
+    /// A synthetic root holding the payloads at the usual location
+    root: tempfile::TempDir,
+    pub(crate) manifest: PayloadManifest,
+}
+
+impl Bundle {
+    /// Extract the bundle at `path`, and check that its content matches
+    /// its manifest.  If `verify_key` is provided, the manifest must be
+    /// signed by the corresponding private key; otherwise the bundle is
+    /// only accepted with `allow_unsigned`.  Nothing but the manifest and
+    /// its signature is extracted before they are checked, and then only
+    /// the files the manifest lists.
+    #[context("Opening payload bundle {path:?}")]
+    pub(crate) fn open(
+        path: &Path,
+        verify_key: Option<&Path>,
+        allow_unsigned: bool,
+    ) -> Result<Self> {
+        let root = tempfile::tempdir()?;
+        // Lay out the content so the root can be used in place of the sysroot
+        let destdir = Path::new(root.path()).join(BOOTUPD_UPDATES_DIR);
+        let destdir = destdir.parent().unwrap();
+        std::fs::create_dir_all(destdir)?;
+        let members = crate::util::cmd_output(
+            crate::util::command("tar")
+                .arg("--list")
+                .arg("--file")
+                .arg(path),
+        )?;
+        let members = members.lines().collect::<BTreeSet<_>>();
+        let signed = members.contains(SIGNATURE_NAME);
+        let mut toplevel = vec![MANIFEST_NAME.to_string()];
+        if signed {
+            toplevel.push(SIGNATURE_NAME.to_string());
+        }
+        extract(path, destdir, &toplevel)?;
+
+        let manifest_data = std::fs::read(destdir.join(MANIFEST_NAME))
+            .with_context(|| format!("Reading {MANIFEST_NAME}"))?;
+        let sigpath = destdir.join(SIGNATURE_NAME);
+        match (verify_key, signed) {
+            (Some(keypath), true) => {
+                let pem = std::fs::read(keypath).with_context(|| format!("Reading {keypath:?}"))?;
+                let key = PKey::public_key_from_pem(&pem).context("Parsing verification key")?;
+                verify_manifest(&manifest_data, &std::fs::read(&sigpath)?, &key)?;
+                log::debug!("Verified signature of {MANIFEST_NAME}");
+            }
+            (Some(_), false) => anyhow::bail!("Bundle is not signed"),
+            (None, _) if allow_unsigned => {
+                log::warn!("Not verifying bundle signature; no key provided")
+            }
+            (None, _) => anyhow::bail!(
+                "No key provided to verify the bundle with; an unverified bundle requires --allow-unsigned"
+            ),
+        }
+        let manifest: PayloadManifest =
+            serde_json::from_slice(&manifest_data).context("Parsing manifest")?;
+        if manifest.version != MANIFEST_VERSION {
+            anyhow::bail!("Unsupported bundle version {}", manifest.version);
+        }
+
+        let mut content = Vec::new();
+        for (name, c) in manifest.components.iter() {
+            let base = format!("{UPDATES_NAME}/{name}.json");
+            content.extend(
+                [format!("{base}.zst"), base]
+                    .into_iter()
+                    .filter(|m| members.contains(m.as_str())),
+            );
+            for path in c.filetree.iter().flat_map(|t| t.children.keys()) {
+                if !c.deltas.contains_key(path) {
+                    content.push(format!("{UPDATES_NAME}/{name}/{path}"));
+                }
+            }
+            for path in c.deltas.keys() {
+                content.push(format!("{DELTAS_NAME}/{name}/{path}"));
+            }
+        }
+        extract(path, destdir, &content)?;
+        let bundle = Self { root, manifest };
+        bundle.verify_content()?;
+        Ok(bundle)
+    }
+
+    /// Reconstruct the files of the components `names` shipped as deltas,
+    /// using `base` to find the installed files of a component, and verify
+    /// the complete payloads.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[context("Applying deltas")]
+    pub(crate) fn apply_deltas(
+        &self,
+        names: &[String],
+        base: impl Fn(&str) -> Result<openat::Dir>,
+    ) -> Result<()> {
+        let root = self.open_root()?;
+        let updates = self.root.path().join(BOOTUPD_UPDATES_DIR);
+        let deltas = updates.parent().unwrap().join(DELTAS_NAME);
+        for (name, c) in self.manifest.components.iter() {
+            if c.deltas.is_empty() || !names.contains(name) {
+                continue;
+            }
+            let installed = base(name)?;
+            let installed_path = installed.recover_path()?;
+            let target = updates.join(name);
+            for (path, basemeta) in c.deltas.iter() {
+                let found = crate::filetree::FileMetadata::new_from_path(&installed, path.as_str())
+                    .with_context(|| format!("Reading installed {path}"))?;
+                if &found != basemeta {
+                    anyhow::bail!(
+                        "Installed {path} for {name} is not the base of its delta; a full bundle is required"
+                    );
+                }
+                let target = target.join(path);
+                std::fs::create_dir_all(target.parent().unwrap())?;
+                apply_delta(
+                    &installed_path.join(path),
+                    &deltas.join(name).join(path),
+                    &target,
+                )?;
+                log::debug!("Reconstructed {path} for {name}");
+            }
+            self.verify_component(&root, name, c, false)?;
+        }
+        Ok(())
+    }
+
+    /// Check the extracted payload of a component against the manifest;
+    /// with `partial`, files shipped as deltas are ignored.
+    fn verify_component(
+        &self,
+        root: &openat::Dir,
+        name: &str,
+        c: &PayloadComponent,
+        partial: bool,
+    ) -> Result<()> {
+        let payloaddir = Path::new(BOOTUPD_UPDATES_DIR).join(name);
+        let dir = root.sub_dir_optional(&payloaddir)?;
+        match (c.filetree.as_ref(), dir) {
+            (Some(expected), Some(d)) => {
+                let mut expected = expected.clone();
+                if partial {
+                    expected.children.retain(|k, _| !c.deltas.contains_key(k));
+                }
+                let found = crate::filetree::FileTree::new_from_dir(&d)?;
+                if found != expected {
+                    anyhow::bail!("Payload for {name} does not match manifest");
+                }
+            }
+            (None, None) => {}
+            _ => anyhow::bail!("Payload for {name} does not match manifest"),
+        }
+        Ok(())
+    }
+
+    /// Check the extracted payloads against the manifest.
+    fn verify_content(&self) -> Result<()> {
+        let root = self.open_root()?;
+        for (name, c) in self.manifest.components.iter() {
+            let component = crate::component::new_from_name(name)?;
+            let meta = component
+                .query_update(&root)?
+                .ok_or_else(|| anyhow::anyhow!("Bundle is missing metadata for {name}"))?;
+            if meta != c.meta {
+                anyhow::bail!("Metadata for {name} does not match manifest");
+            }
+            self.verify_component(&root, name, c, true)?;
+        }
+        Ok(())
+    }
+
+    /// Open the root to use as the source of updates.
+    pub(crate) fn open_root(&self) -> Result<openat::Dir> {
+        Ok(openat::Dir::open(self.root.path())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+
+    #[test]
+    fn test_sign_manifest() -> Result<()> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        let manifest = PayloadManifest {
+            version: MANIFEST_VERSION,
+            components: BTreeMap::new(),
+        };
+        let data = serde_json::to_vec(&manifest)?;
+        let sig = sign_manifest(&data, &key)?;
+        let public = PKey::public_key_from_pem(&key.public_key_to_pem()?)?;
+        verify_manifest(&data, &sig, &public)?;
+        assert!(verify_manifest(b"{}", &sig, &public).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest_paths() {
+        let parse = |components: &str| {
+            serde_json::from_str::<PayloadManifest>(&format!(
+                r#"{{"version": 1, "components": {components}}}"#
+            ))
+        };
+        let component = |deltas: &str| {
+            format!(
+                r#"{{"meta": {{"timestamp": "2024-01-01T00:00:00Z", "version": "1"}},
+                    "filetree": null, "deltas": {deltas}}}"#
+            )
+        };
+        let delta = r#"{"size": 1, "sha512": "sha512:00"}"#;
+        let ok = component(&format!(r#"{{"fedora/uki.efi": {delta}}}"#));
+        parse(&format!(r#"{{"EFI": {ok}}}"#)).unwrap();
+        for name in ["..", "../EFI", "/EFI", "EFI/fedora", ""] {
+            assert!(parse(&format!(r#"{{"{name}": {ok}}}"#)).is_err(), "{name}");
+        }
+        for path in ["../../etc/shadow", "/etc/shadow", "fedora/../../x"] {
+            let c = component(&format!(r#"{{"{path}": {delta}}}"#));
+            assert!(parse(&format!(r#"{{"EFI": {c}}}"#)).is_err(), "{path}");
+        }
+    }
+
+    #[test]
+    #[cfg(have_efi)]
+    fn test_open_bundle() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let src = td.path().join("src");
+        let efidir = src.join(UPDATES_NAME).join("EFI/fedora");
+        std::fs::create_dir_all(&efidir)?;
+        std::fs::write(efidir.join("shimx64.efi"), "shim")?;
+        let meta = ContentMetadata {
+            timestamp: chrono::Utc::now(),
+            version: "shim-x64-15.8-3.x86_64".into(),
+        };
+        std::fs::write(
+            src.join(UPDATES_NAME).join("EFI.json"),
+            serde_json::to_vec(&meta)?,
+        )?;
+        let filetree = crate::filetree::FileTree::new_from_dir(&openat::Dir::open(
+            &src.join(UPDATES_NAME).join("EFI"),
+        )?)?;
+        let mut components = BTreeMap::new();
+        components.insert(
+            "EFI".to_string(),
+            PayloadComponent {
+                meta,
+                filetree: Some(filetree),
+                deltas: BTreeMap::new(),
+            },
+        );
+        let manifest = PayloadManifest {
+            version: MANIFEST_VERSION,
+            components,
+        };
+        std::fs::write(src.join(MANIFEST_NAME), serde_json::to_vec(&manifest)?)?;
+        let bundle = td.path().join("bundle.tar");
+        let mktar = || {
+            crate::util::command("tar")
+                .arg("-cf")
+                .arg(&bundle)
+                .arg("-C")
+                .arg(&src)
+                .args([MANIFEST_NAME, UPDATES_NAME])
+                .run()
+        };
+        mktar()?;
+        let b = Bundle::open(&bundle, None, true)?;
+        assert!(b.manifest.components.contains_key("EFI"));
+        assert!(b
+            .open_root()?
+            .exists(&Path::new(BOOTUPD_UPDATES_DIR).join("EFI/fedora/shimx64.efi"))?);
+        // An unsigned bundle is only accepted on request
+        assert!(Bundle::open(&bundle, None, false).is_err());
+        // An unsigned bundle is rejected if a key is required
+        let keypath = td.path().join("key.pem");
+        std::fs::write(&keypath, "")?;
+        assert!(Bundle::open(&bundle, Some(&keypath), false).is_err());
+
+        // Files the manifest doesn't list aren't extracted
+        std::fs::write(src.join("extra"), "extra")?;
+        crate::util::command("tar")
+            .arg("-rf")
+            .arg(&bundle)
+            .arg("-C")
+            .arg(&src)
+            .arg("extra")
+            .run()?;
+        let b = Bundle::open(&bundle, None, true)?;
+        assert!(!b.root.path().join("usr/lib/bootupd/extra").exists());
+        assert!(!b.root.path().join("extra").exists());
+
+        // Tampered content is rejected
+        std::fs::write(efidir.join("shimx64.efi"), "evil")?;
+        mktar()?;
+        assert!(Bundle::open(&bundle, None, true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(have_efi)]
+    fn test_bundle_deltas() -> Result<()> {
+        use crate::filetree::{FileMetadata, FileTree};
+        let td = tempfile::tempdir()?;
+        let installed = td.path().join("esp");
+        std::fs::create_dir_all(installed.join("fedora"))?;
+        let old = (0..200_000u32)
+            .flat_map(|i| i.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut new = old.clone();
+        new[1000..1010].copy_from_slice(b"0123456789");
+        std::fs::write(installed.join("fedora/uki.efi"), &old)?;
+
+        let src = td.path().join("src");
+        let efidir = src.join(UPDATES_NAME).join("EFI/fedora");
+        std::fs::create_dir_all(&efidir)?;
+        std::fs::write(efidir.join("uki.efi"), &new)?;
+        std::fs::write(efidir.join("shimx64.efi"), "shim")?;
+        let meta = ContentMetadata {
+            timestamp: chrono::Utc::now(),
+            version: "uki-2".into(),
+        };
+        std::fs::write(
+            src.join(UPDATES_NAME).join("EFI.json"),
+            serde_json::to_vec(&meta)?,
+        )?;
+        let filetree =
+            FileTree::new_from_dir(&openat::Dir::open(&src.join(UPDATES_NAME).join("EFI"))?)?;
+        let deltadir = src.join(DELTAS_NAME).join("EFI");
+        let deltas = write_deltas(
+            &filetree,
+            &src.join(UPDATES_NAME).join("EFI"),
+            &installed,
+            &deltadir,
+        )?;
+        assert!(deltas.is_empty(), "small files are shipped in full");
+        std::fs::create_dir_all(deltadir.join("fedora"))?;
+        make_delta(
+            &installed.join("fedora/uki.efi"),
+            &efidir.join("uki.efi"),
+            &deltadir.join("fedora/uki.efi"),
+        )?;
+        std::fs::remove_file(efidir.join("uki.efi"))?;
+        let mut deltas = BTreeMap::new();
+        let installedd = openat::Dir::open(&installed)?;
+        deltas.insert(
+            "fedora/uki.efi".to_string(),
+            FileMetadata::new_from_path(&installedd, "fedora/uki.efi")?,
+        );
+        let mut components = BTreeMap::new();
+        components.insert(
+            "EFI".to_string(),
+            PayloadComponent {
+                meta,
+                filetree: Some(filetree),
+                deltas,
+            },
+        );
+        let manifest = PayloadManifest {
+            version: MANIFEST_VERSION,
+            components,
+        };
+        std::fs::write(src.join(MANIFEST_NAME), serde_json::to_vec(&manifest)?)?;
+        let bundle = td.path().join("bundle.tar");
+        crate::util::command("tar")
+            .arg("-cf")
+            .arg(&bundle)
+            .arg("-C")
+            .arg(&src)
+            .args([MANIFEST_NAME, UPDATES_NAME, DELTAS_NAME])
+            .run()?;
+
+        let names = ["EFI".to_string()];
+        let b = Bundle::open(&bundle, None, true)?;
+        b.apply_deltas(&names, |_| Ok(openat::Dir::open(&installed)?))?;
+        let reconstructed = b
+            .root
+            .path()
+            .join(BOOTUPD_UPDATES_DIR)
+            .join("EFI/fedora/uki.efi");
+        assert_eq!(std::fs::read(reconstructed)?, new);
+
+        // The delta only applies to the version it was made from
+        std::fs::write(installed.join("fedora/uki.efi"), &new)?;
+        let b = Bundle::open(&bundle, None, true)?;
+        assert!(b
+            .apply_deltas(&names, |_| Ok(openat::Dir::open(&installed)?))
+            .is_err());
+        Ok(())
+    }
+}
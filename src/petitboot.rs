@@ -0,0 +1,104 @@
+//! OPAL/PowerNV machines boot via petitboot, which is part of the firmware
+//! and scans the boot partitions for entries itself; there's no PReP
+//! partition and nothing to run grub-install for.  What we manage instead
+//! is that the entries petitboot picks up reference kernels and initrds
+//! which actually exist where it looks for them.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+/// The device tree compatible strings of the running system
+const DT_COMPATIBLE: &str = "/proc/device-tree/compatible";
+
+/// The GRUB configs parsed by petitboot, relative to the boot partition
+const GRUB_CONFIGS: &[&str] = &["grub2/grub.cfg", "grub/grub.cfg", "boot/grub2/grub.cfg"];
+
+/// Return `true` if the machine is an OPAL/PowerNV system.
+pub(crate) fn is_powernv() -> bool {
+    std::fs::read(DT_COMPATIBLE)
+        .map(|c| is_powernv_compatible(&c))
+        .unwrap_or_default()
+}
+
+fn is_powernv_compatible(compatible: &[u8]) -> bool {
+    compatible.split(|&b| b == 0).any(|c| c == b"ibm,powernv")
+}
+
+/// Return the kernel and initrd paths referenced by a BLS entry.
+fn bls_boot_files(entry: &str) -> Vec<&str> {
+    entry
+        .lines()
+        .filter_map(|l| l.trim().split_once(char::is_whitespace))
+        .filter(|(k, _)| *k == "linux" || *k == "initrd")
+        .flat_map(|(_, v)| v.split_whitespace())
+        .collect()
+}
+
+/// List the problems that would keep petitboot from booting the OS in `root`.
+pub(crate) fn problems(root: &Path) -> Result<Vec<String>> {
+    let partroot = crate::util::boot_partition_root(root)?;
+    let mut r = Vec::new();
+    let mut entries = 0;
+    let entriesdir = root.join("boot/loader/entries");
+    if entriesdir.exists() {
+        for entry in std::fs::read_dir(&entriesdir)? {
+            let path = entry?.path();
+            if path.extension() != Some("conf".as_ref()) {
+                continue;
+            }
+            entries += 1;
+            let content = std::fs::read_to_string(&path)?;
+            for f in bls_boot_files(&content) {
+                if !partroot.join(f.trim_start_matches('/')).exists() {
+                    let name = path.file_name().unwrap().to_string_lossy();
+                    r.push(format!("{name}: {f} not found on the boot partition"));
+                }
+            }
+        }
+    }
+    let has_grub_config = GRUB_CONFIGS.iter().any(|c| partroot.join(c).exists());
+    if entries == 0 && !has_grub_config {
+        r.push("No boot entries or GRUB config for petitboot to scan".to_string());
+    }
+    Ok(r)
+}
+
+/// Error out if petitboot wouldn't be able to boot the OS in `root`.
+pub(crate) fn check(root: &Path) -> Result<()> {
+    let problems = problems(root)?;
+    if !problems.is_empty() {
+        anyhow::bail!("Unbootable by petitboot: {}", problems.join("; "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_powernv() {
+        assert!(is_powernv_compatible(b"ibm,powernv\0ibm,p9\0"));
+        assert!(!is_powernv_compatible(b"IBM,9009-42A\0"));
+    }
+
+    #[test]
+    fn test_problems() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = td.path();
+        assert_eq!(problems(root)?.len(), 1);
+        let entries = root.join("boot/loader/entries");
+        std::fs::create_dir_all(&entries)?;
+        std::fs::write(
+            entries.join("fedora-6.11.conf"),
+            "title Fedora\nlinux /boot/vmlinuz-6.11\ninitrd /boot/initramfs-6.11.img\n",
+        )?;
+        assert_eq!(problems(root)?.len(), 2);
+        std::fs::write(root.join("boot/vmlinuz-6.11"), "")?;
+        std::fs::write(root.join("boot/initramfs-6.11.img"), "")?;
+        assert!(problems(root)?.is_empty());
+        check(root)?;
+        Ok(())
+    }
+}
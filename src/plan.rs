@@ -0,0 +1,137 @@
+//! The plan printed by `bootupctl update --plan --json`: the actions an
+//! update would take, component by component, as a versioned document for
+//! orchestrators to review and approve before running the update.
+//!
+//! Paths are those of the booted system, e.g. below the mounted ESP.  The
+//! state file is also rewritten before and after each wave of components,
+//! which isn't listed.  Updates never change the firmware's boot entries
+//! (NVRAM), so there's no action for them: they're only created by
+//! installing with `--update-firmware` and on first boot.
+
+use std::path::PathBuf;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::model::ContentMetadata;
+
+/// The version of the [`UpdatePlan`] document, bumped under the same rules
+/// as that of the status; see "Machine-readable output" in README-design.md.
+pub(crate) const PLAN_SCHEMA_VERSION: u32 = 1;
+
+/// What `bootupctl update` would do, given the same components.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct UpdatePlan {
+    /// The version of this document's format
+    pub(crate) schema_version: u32,
+    /// The staged ostree deployment the update is deferred to, in which
+    /// case nothing is updated until it's booted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) deferred_until: Option<String>,
+    /// The components to update, in order
+    pub(crate) components: Vec<ComponentPlan>,
+    /// The components left as they are: already current, or adoptable but
+    /// needing an explicit `adopt-and-update`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) skipped: Vec<String>,
+}
+
+impl Default for UpdatePlan {
+    fn default() -> Self {
+        Self {
+            schema_version: PLAN_SCHEMA_VERSION,
+            deferred_until: None,
+            components: Vec::new(),
+            skipped: Vec::new(),
+        }
+    }
+}
+
+/// The update of one component.
+#[derive(Serialize, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ComponentPlan {
+    /// The name of the component, e.g. `EFI`
+    pub(crate) component: String,
+    /// The components of a wave may be updated concurrently, after those
+    /// of the previous waves
+    pub(crate) wave: usize,
+    /// Whether the component is adopted, as it wasn't installed by bootupd
+    pub(crate) adopt: bool,
+    /// The installed version; unknown when adopting
+    pub(crate) from: Option<ContentMetadata>,
+    /// The version of the update
+    pub(crate) to: ContentMetadata,
+    /// The actions, in order, unless the component can't tell them in
+    /// advance
+    pub(crate) actions: Option<Vec<Action>>,
+}
+
+/// A change an update makes to the system.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub(crate) enum Action {
+    /// Write the file `path`, replacing it atomically if it exists; `size`
+    /// is unknown for content only generated when writing, e.g. signatures
+    WriteFile { path: PathBuf, size: Option<u64> },
+    /// Delete the file `path`
+    DeleteFile { path: PathBuf },
+    /// Write a bootloader to `device`, with `installer`, e.g. `grub-install`
+    WriteDevice { device: PathBuf, installer: String },
+}
+
+/// The JSON Schema of [`UpdatePlan`].
+pub(crate) fn plan_schema() -> schemars::schema::RootSchema {
+    schemars::schema_for!(UpdatePlan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    /// The committed schema is that of the plan, and actions are tagged by
+    /// name
+    #[test]
+    fn test_plan_schema() -> Result<()> {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("doc/plan.schema.json");
+        let actual = serde_json::to_string_pretty(&plan_schema())? + "\n";
+        if std::env::var_os("BOOTUPD_UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, &actual)?;
+        }
+        assert_eq!(actual, std::fs::read_to_string(&path)?, "{path:?} differs");
+
+        let plan = UpdatePlan {
+            components: vec![ComponentPlan {
+                component: "EFI".into(),
+                wave: 0,
+                adopt: false,
+                from: None,
+                to: ContentMetadata {
+                    timestamp: chrono::Utc::now(),
+                    version: "shim-x64-15.8-3.x86_64".into(),
+                },
+                actions: Some(vec![
+                    Action::WriteFile {
+                        path: "/boot/efi/EFI/fedora/shimx64.efi".into(),
+                        size: Some(4),
+                    },
+                    Action::DeleteFile {
+                        path: "/boot/efi/EFI/fedora/old.efi".into(),
+                    },
+                ]),
+            }],
+            ..Default::default()
+        };
+        let v = serde_json::to_value(&plan)?;
+        assert_eq!(v["schema-version"], PLAN_SCHEMA_VERSION);
+        let actions = &v["components"][0]["actions"];
+        assert_eq!(actions[0]["action"], "write-file");
+        assert_eq!(actions[1]["action"], "delete-file");
+        assert!(v.get("deferred-until").is_none());
+        let parsed: UpdatePlan = serde_json::from_value(v)?;
+        assert_eq!(parsed.components[0].actions, plan.components[0].actions);
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+//! Early checks of the privileges and mounts an operation needs.
+//!
+//! Run in a container lacking e.g. `CAP_SYS_ADMIN`, the host's device nodes
+//! or efivarfs, operations fail deep inside with errors like `EPERM` from
+//! mount(2) or a missing `/dev/disk/by-partlabel` link.  [`check`] names
+//! what's missing up front instead, along with how to run the container.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::errors::{Error, ErrorKind};
+
+/// The capability needed to mount filesystems.
+const CAP_SYS_ADMIN: u32 = 21;
+/// Where the EFI variables are mounted.
+const EFIVARS: &str = "/sys/firmware/efi/efivars";
+/// The filesystem type of [`EFIVARS`].
+const EFIVARFS_MAGIC: u64 = 0xde5e81e4;
+
+/// What an operation needs from its environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Requirement {
+    /// Mounting filesystems, e.g. the ESP
+    Mount,
+    /// The device nodes of the disks
+    Devices,
+    /// Writable EFI variables, for firmware boot entries; only checked on
+    /// systems booted via EFI
+    Efivars,
+}
+
+/// Parse the effective capabilities from `/proc/self/status`.
+fn effective_caps(status: &str) -> Option<u64> {
+    let caps = status.lines().find_map(|l| l.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(caps.trim(), 16).ok()
+}
+
+/// The disks listed in `sys_block` without a device node in `dev`.
+fn missing_device_nodes(sys_block: &Path, dev: &Path) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    let Ok(entries) = std::fs::read_dir(sys_block) else {
+        return Ok(r);
+    };
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        // Not backed by a disk
+        if ["loop", "ram", "zram"].iter().any(|p| name.starts_with(p)) {
+            continue;
+        }
+        // Hidden disks, e.g. the paths of multipathed NVMe namespaces, never
+        // get a device node
+        let hidden = std::fs::read_to_string(sys_block.join(&name).join("hidden"));
+        if hidden.is_ok_and(|h| h.trim() == "1") {
+            continue;
+        }
+        if !dev.join(&name).try_exists()? {
+            r.push(name);
+        }
+    }
+    r.sort();
+    Ok(r)
+}
+
+fn efivars_writable() -> Result<bool> {
+    let Ok(st) = rustix::fs::statfs(EFIVARS) else {
+        return Ok(false);
+    };
+    #[allow(clippy::unnecessary_cast)]
+    let is_efivarfs = st.f_type as u64 == EFIVARFS_MAGIC;
+    Ok(is_efivarfs && !crate::util::is_readonly(Path::new(EFIVARS))?)
+}
+
+/// Fail if any of `requirements` isn't met, describing what's missing.
+pub(crate) fn check(requirements: &[Requirement]) -> Result<()> {
+    // Nothing is mounted or written to devices when simulating
+    if crate::simulate::active() {
+        return Ok(());
+    }
+    let mut missing = Vec::new();
+    for r in requirements {
+        match r {
+            Requirement::Mount => {
+                let status = std::fs::read_to_string("/proc/self/status")?;
+                let caps = effective_caps(&status).unwrap_or_default();
+                if caps & (1 << CAP_SYS_ADMIN) == 0 {
+                    missing.push("the CAP_SYS_ADMIN capability to mount filesystems".to_string());
+                }
+            }
+            Requirement::Devices => {
+                let nodes = missing_device_nodes(Path::new("/sys/block"), Path::new("/dev"))?;
+                if !nodes.is_empty() {
+                    missing.push(format!("the device nodes of {}", nodes.join(", ")));
+                }
+            }
+            Requirement::Efivars => {
+                if crate::firmware::is_efi_booted()? && !efivars_writable()? {
+                    missing.push(format!("efivarfs mounted read-write at {EFIVARS}"));
+                }
+            }
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let mut msg = format!("Missing {}", missing.join(", and "));
+    if crate::util::running_in_container() {
+        msg.push_str(&format!("; {}", crate::host::CONTAINER_HINT));
+    }
+    Err(Error::new(ErrorKind::PermissionDenied, msg).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_effective_caps() {
+        let status = "Name:\tbootupd\nCapInh:\t0000000000000000\nCapEff:\t000001ffffffffff\n";
+        assert_eq!(effective_caps(status), Some(0x1ffffffffff));
+        assert_eq!(effective_caps("Name:\tbootupd\n"), None);
+    }
+
+    #[test]
+    fn test_missing_device_nodes() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let sys_block = td.path().join("sys/block");
+        let dev = td.path().join("dev");
+        assert!(missing_device_nodes(&sys_block, &dev)?.is_empty());
+        for d in ["vda", "vdb", "loop0", "nvme0c0n1", "nvme0n1"] {
+            std::fs::create_dir_all(sys_block.join(d))?;
+        }
+        std::fs::write(sys_block.join("nvme0c0n1/hidden"), "1\n")?;
+        std::fs::write(sys_block.join("nvme0n1/hidden"), "0\n")?;
+        std::fs::create_dir_all(&dev)?;
+        std::fs::write(dev.join("vda"), "")?;
+        assert_eq!(missing_device_nodes(&sys_block, &dev)?, ["nvme0n1", "vdb"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+//! Export of the installed EFI boot chain for network booting.
+//!
+//! The loader binaries are copied out of the ESP into a flat directory
+//! (as served by TFTP or HTTP boot servers), together with a manifest
+//! naming the installed version and the digest of each file.  Every
+//! exported binary is verified against the digests recorded at install
+//! time, so a provisioning server is guaranteed to serve exactly what the
+//! fleet has on disk.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+use serde::{Deserialize, Serialize};
+
+use crate::component::Component;
+use crate::efi::Efi;
+use crate::filetree::{FileMetadata, FileTree};
+use crate::model::*;
+
+/// The name of the manifest in the export directory.
+pub(crate) const MANIFEST_NAME: &str = "bootupd-pxe.json";
+/// The current manifest format.
+const MANIFEST_VERSION: u32 = 1;
+
+/// A single exported file.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PxeFile {
+    /// Path of the file below `EFI` on the ESP
+    pub(crate) source: String,
+    /// Whether the file is tracked in the bootupd state; the GRUB config
+    /// may have been written by other tools
+    pub(crate) tracked: bool,
+    #[serde(flatten)]
+    pub(crate) meta: FileMetadata,
+}
+
+/// Describes the content of an export directory.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PxeManifest {
+    pub(crate) version: u32,
+    /// The installed EFI component
+    pub(crate) installed: ContentMetadata,
+    /// The exported files, by name in the export directory
+    pub(crate) files: BTreeMap<String, PxeFile>,
+}
+
+/// The loader binaries in the `vendor` directory of the installed `tree`.
+fn vendor_binaries<'a>(tree: &'a FileTree, vendor: &str) -> Vec<&'a str> {
+    tree.children
+        .keys()
+        .filter(|k| {
+            k.strip_prefix(vendor)
+                .and_then(|n| n.strip_prefix('/'))
+                .is_some_and(|n| !n.contains('/') && n.to_ascii_lowercase().ends_with(".efi"))
+        })
+        .map(|k| k.as_str())
+        .collect()
+}
+
+/// Copy `source` from `esp` to `dest`, returning the metadata of the copy.
+fn copy_out(
+    esp: &openat::Dir,
+    source: &str,
+    dest: &openat::Dir,
+    name: &str,
+) -> Result<FileMetadata> {
+    let mut src = esp
+        .open_file(source)
+        .with_context(|| format!("Opening {source}"))?;
+    dest.write_file_with_sync(name, 0o644, |w| -> Result<()> {
+        std::io::copy(&mut src, w)?;
+        Ok(())
+    })?;
+    FileMetadata::new_from_path(dest, name)
+}
+
+/// Export the installed EFI loaders and GRUB config to `dest`.
+#[context("Exporting PXE artifacts")]
+pub(crate) fn export(dest: &Path) -> Result<()> {
+    let state = SavedState::load_from_disk("/")?.unwrap_or_default();
+    let efi = Efi::default();
+    let Some(installed) = state.installed.get(efi.name()) else {
+        bail!("Component {} is not installed", efi.name());
+    };
+    let tree = installed
+        .filetree
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("No filetree for installed EFI found!"))?;
+    let sysroot = openat::Dir::open("/")?;
+    let Some(vendor) = efi.get_efi_vendor(&sysroot)? else {
+        bail!("Failed to find the EFI vendor directory");
+    };
+    let esp = efi.open_esp()?;
+
+    std::fs::create_dir_all(dest).with_context(|| format!("Creating {dest:?}"))?;
+    let destd = openat::Dir::open(dest).with_context(|| format!("Opening {dest:?}"))?;
+    let mut files = BTreeMap::new();
+    for source in vendor_binaries(tree, &vendor) {
+        let name = source.rsplit('/').next().unwrap();
+        let meta = copy_out(&esp, source, &destd, name)?;
+        if Some(&meta) != tree.children.get(source) {
+            bail!(
+                "{source} on the ESP doesn't match the installed state; see `bootupctl validate`"
+            );
+        }
+        let source = source.to_string();
+        files.insert(
+            name.to_string(),
+            PxeFile {
+                source,
+                tracked: true,
+                meta,
+            },
+        );
+    }
+    if files.is_empty() {
+        bail!("No loaders installed in {vendor}");
+    }
+    let grubcfg = format!("{vendor}/grub.cfg");
+    if esp.exists(grubcfg.as_str())? {
+        let meta = copy_out(&esp, &grubcfg, &destd, "grub.cfg")?;
+        let tracked = match tree.children.get(&grubcfg) {
+            Some(m) if m != &meta => {
+                bail!("{grubcfg} on the ESP doesn't match the installed state")
+            }
+            Some(_) => true,
+            None => false,
+        };
+        let source = grubcfg;
+        files.insert(
+            "grub.cfg".to_string(),
+            PxeFile {
+                source,
+                tracked,
+                meta,
+            },
+        );
+    }
+
+    let manifest = PxeManifest {
+        version: MANIFEST_VERSION,
+        installed: installed.meta.clone(),
+        files,
+    };
+    destd.write_file_with_sync(MANIFEST_NAME, 0o644, |w| -> Result<()> {
+        serde_json::to_writer_pretty(&mut *w, &manifest)?;
+        Ok(())
+    })?;
+    for name in manifest.files.keys() {
+        println!("Exported: {name}");
+    }
+    println!("Wrote {}", dest.join(MANIFEST_NAME).display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vendor_binaries() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        for f in [
+            "fedora/shimx64.efi",
+            "fedora/grubx64.efi",
+            "fedora/grub.cfg",
+            "fedora/fw/fwupd.efi",
+            "BOOT/BOOTX64.EFI",
+        ] {
+            let p = td.path().join(f);
+            std::fs::create_dir_all(p.parent().unwrap())?;
+            std::fs::write(p, f)?;
+        }
+        let tree = FileTree::new_from_dir(&openat::Dir::open(td.path())?)?;
+        assert_eq!(
+            vendor_binaries(&tree, "fedora"),
+            ["fedora/grubx64.efi", "fedora/shimx64.efi"]
+        );
+        assert_eq!(vendor_binaries(&tree, "BOOT"), ["BOOT/BOOTX64.EFI"]);
+        assert!(vendor_binaries(&tree, "fed").is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_out() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        std::fs::create_dir_all(td.path().join("esp/fedora"))?;
+        std::fs::create_dir_all(td.path().join("out"))?;
+        std::fs::write(td.path().join("esp/fedora/shimx64.efi"), "shim")?;
+        let esp = openat::Dir::open(&td.path().join("esp"))?;
+        let out = openat::Dir::open(&td.path().join("out"))?;
+        let meta = copy_out(&esp, "fedora/shimx64.efi", &out, "shimx64.efi")?;
+        assert_eq!(
+            meta,
+            FileMetadata::new_from_path(&esp, "fedora/shimx64.efi")?
+        );
+        assert_eq!(std::fs::read(td.path().join("out/shimx64.efi"))?, b"shim");
+        Ok(())
+    }
+}
@@ -0,0 +1,322 @@
+//! The `bootupd` Python module, wrapping the [`crate::api`] operations for
+//! tools like Anaconda and osbuild; see `python/bootupd.pyi` for the types.
+//!
+//! Results are returned as read-only objects mirroring the JSON output of
+//! `bootupctl`, with timestamps as `datetime` and enumerations as their
+//! names in the JSON (e.g. `"upgradable"`).  Failures raise
+//! `bootupd.Error`, whose `error_class` is the class in the JSON error
+//! report (e.g. `"esp-full"`).  The GIL is released while operating.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use serde::Serialize;
+
+use crate::api;
+
+create_exception!(bootupd, Error, PyException, "A failed bootupd operation.");
+
+/// Convert `e` to a [`Error`], with its class.
+fn to_py_err(e: api::Error) -> PyErr {
+    let report = e.report();
+    let err = Error::new_err(report.message);
+    Python::with_gil(|py| {
+        let _ = err
+            .value(py)
+            .setattr("error_class", serialized_name(&report.class));
+        let _ = err.value(py).setattr("hint", report.hint);
+    });
+    err
+}
+
+/// The name of the unit variant `v` in JSON.
+fn serialized_name(v: &impl Serialize) -> String {
+    match serde_json::to_value(v) {
+        Ok(serde_json::Value::String(s)) => s,
+        _ => String::new(),
+    }
+}
+
+#[pyclass(frozen, get_all, module = "bootupd")]
+#[derive(Clone)]
+struct ContentMetadata {
+    timestamp: DateTime<Utc>,
+    version: String,
+}
+
+impl From<api::ContentMetadata> for ContentMetadata {
+    fn from(m: api::ContentMetadata) -> Self {
+        Self {
+            timestamp: m.timestamp,
+            version: m.version,
+        }
+    }
+}
+
+#[pymethods]
+impl ContentMetadata {
+    fn __repr__(&self) -> String {
+        format!("ContentMetadata(version={:?})", self.version)
+    }
+}
+
+#[pyclass(frozen, get_all, module = "bootupd")]
+struct ComponentStatus {
+    installed: ContentMetadata,
+    interrupted: Option<ContentMetadata>,
+    update: Option<ContentMetadata>,
+    updatable: String,
+    adopted_from: Option<ContentMetadata>,
+    devices: Vec<String>,
+}
+
+impl From<api::ComponentStatus> for ComponentStatus {
+    fn from(s: api::ComponentStatus) -> Self {
+        Self {
+            installed: s.installed.into(),
+            interrupted: s.interrupted.map(Into::into),
+            update: s.update.map(Into::into),
+            updatable: serialized_name(&s.updatable),
+            adopted_from: s.adopted_from.map(Into::into),
+            devices: s
+                .devices
+                .iter()
+                .map(|d| d.to_string_lossy().into_owned())
+                .collect(),
+        }
+    }
+}
+
+#[pyclass(frozen, get_all, module = "bootupd")]
+struct Adoptable {
+    version: ContentMetadata,
+    confident: bool,
+}
+
+#[pyclass(frozen, get_all, module = "bootupd")]
+struct Firmware {
+    boot_mode: String,
+    secure_boot: Option<bool>,
+    vendor: Option<String>,
+    version: Option<String>,
+    date: Option<String>,
+}
+
+#[pyclass(frozen, get_all, module = "bootupd")]
+struct Status {
+    components: BTreeMap<String, Py<ComponentStatus>>,
+    adoptable: BTreeMap<String, Py<Adoptable>>,
+    firmware: Option<Py<Firmware>>,
+}
+
+impl Status {
+    fn new(py: Python<'_>, s: api::Status) -> PyResult<Self> {
+        let components = s
+            .components
+            .into_iter()
+            .map(|(k, v)| Ok((k, Py::new(py, ComponentStatus::from(v))?)))
+            .collect::<PyResult<_>>()?;
+        let adoptable = s
+            .adoptable
+            .into_iter()
+            .map(|(k, v)| {
+                let a = Adoptable {
+                    version: v.version.into(),
+                    confident: v.confident,
+                };
+                Ok((k, Py::new(py, a)?))
+            })
+            .collect::<PyResult<_>>()?;
+        let firmware = s
+            .firmware
+            .map(|f| {
+                let f = Firmware {
+                    boot_mode: serialized_name(&f.boot_mode),
+                    secure_boot: f.secure_boot,
+                    vendor: f.vendor,
+                    version: f.version,
+                    date: f.date,
+                };
+                Py::new(py, f)
+            })
+            .transpose()?;
+        Ok(Self {
+            components,
+            adoptable,
+            firmware,
+        })
+    }
+}
+
+/// The result of updating a component; `None` for `previous` and `new`
+/// if it was already at the latest version.
+#[pyclass(frozen, get_all, module = "bootupd")]
+struct UpdateResult {
+    previous: Option<ContentMetadata>,
+    interrupted: Option<ContentMetadata>,
+    new: Option<ContentMetadata>,
+}
+
+impl From<api::ComponentUpdateResult> for UpdateResult {
+    fn from(r: api::ComponentUpdateResult) -> Self {
+        match r {
+            api::ComponentUpdateResult::Updated {
+                previous,
+                interrupted,
+                new,
+            } => Self {
+                previous: Some(previous.into()),
+                interrupted: interrupted.map(Into::into),
+                new: Some(new.into()),
+            },
+            _ => Self {
+                previous: None,
+                interrupted: None,
+                new: None,
+            },
+        }
+    }
+}
+
+/// The result of validating a component: `state` is `"valid"`, `"skip"`
+/// if it doesn't support validation, or `"errors"`, listed in `errors`.
+#[pyclass(frozen, get_all, module = "bootupd")]
+struct Validation {
+    state: String,
+    errors: Vec<String>,
+}
+
+impl From<api::ValidationResult> for Validation {
+    fn from(r: api::ValidationResult) -> Self {
+        match r {
+            api::ValidationResult::Errors(errors) => Self {
+                state: "errors".into(),
+                errors,
+            },
+            r => Self {
+                state: serialized_name(&r),
+                errors: Vec::new(),
+            },
+        }
+    }
+}
+
+/// Return the status of the installed and adoptable components.
+#[pyfunction]
+fn status(py: Python<'_>) -> PyResult<Status> {
+    let s = py.allow_threads(api::status).map_err(to_py_err)?;
+    Status::new(py, s)
+}
+
+/// Update the installed components (or those in `components`) which have
+/// an available update.
+#[pyfunction]
+#[pyo3(signature = (components=None, jobs=None))]
+fn update(
+    py: Python<'_>,
+    components: Option<Vec<String>>,
+    jobs: Option<usize>,
+) -> PyResult<BTreeMap<String, UpdateResult>> {
+    let mut opts = api::UpdateOptions::default();
+    opts.components = components;
+    if let Some(jobs) = jobs {
+        opts.jobs = jobs;
+    }
+    let r = py.allow_threads(|| api::update(&opts)).map_err(to_py_err)?;
+    Ok(r.into_iter().map(|(k, v)| (k, v.into())).collect())
+}
+
+/// Adopt `component`, which wasn't installed via bootupd, and update it;
+/// with `create_bios_boot_partition`, one is created for BIOS if needed.
+#[pyfunction]
+#[pyo3(signature = (component, create_bios_boot_partition=false))]
+fn adopt_and_update(
+    py: Python<'_>,
+    component: &str,
+    create_bios_boot_partition: bool,
+) -> PyResult<ContentMetadata> {
+    let mut opts = api::AdoptOptions::default();
+    opts.create_bios_boot_partition = create_bios_boot_partition;
+    let r = py
+        .allow_threads(|| api::adopt_and_update(component, &opts))
+        .map_err(to_py_err)?;
+    Ok(r.into())
+}
+
+/// Validate the installed files of the installed components, or of those
+/// in `components`.
+#[pyfunction]
+#[pyo3(signature = (components=None))]
+fn validate(
+    py: Python<'_>,
+    components: Option<Vec<String>>,
+) -> PyResult<BTreeMap<String, Validation>> {
+    let r = py
+        .allow_threads(|| api::validate(components.as_deref()))
+        .map_err(to_py_err)?;
+    Ok(r.into_iter().map(|(k, v)| (k, v.into())).collect())
+}
+
+#[pymodule]
+fn bootupd(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("Error", m.py().get_type::<Error>())?;
+    m.add_class::<ContentMetadata>()?;
+    m.add_class::<ComponentStatus>()?;
+    m.add_class::<Adoptable>()?;
+    m.add_class::<Firmware>()?;
+    m.add_class::<Status>()?;
+    m.add_class::<UpdateResult>()?;
+    m.add_class::<Validation>()?;
+    m.add_function(wrap_pyfunction!(status, m)?)?;
+    m.add_function(wrap_pyfunction!(update, m)?)?;
+    m.add_function(wrap_pyfunction!(adopt_and_update, m)?)?;
+    m.add_function(wrap_pyfunction!(validate, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions() -> Result<(), Box<dyn std::error::Error>> {
+        let meta = api::ContentMetadata {
+            timestamp: chrono::DateTime::from_timestamp(1681321788, 0).unwrap(),
+            version: "grub2-2.06-95.fc38".into(),
+        };
+        let status: api::Status = serde_json::from_value(serde_json::json!({
+            "components": {"BIOS": {
+                "installed": meta, "interrupted": null, "update": meta,
+                "updatable": "at-latest-version", "adopted-from": null,
+            }},
+            "adoptable": {},
+        }))?;
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| -> PyResult<()> {
+            let status = Py::new(py, Status::new(py, status)?)?;
+            let bios = status
+                .getattr(py, "components")?
+                .call_method1(py, "get", ("BIOS",))?;
+            let updatable: String = bios.getattr(py, "updatable")?.extract(py)?;
+            assert_eq!(updatable, "at-latest-version");
+            let version: String = bios
+                .getattr(py, "installed")?
+                .getattr(py, "version")?
+                .extract(py)?;
+            assert_eq!(version, meta.version);
+            assert!(status.getattr(py, "firmware")?.is_none(py));
+
+            let err =
+                to_py_err(anyhow::Error::from(crate::errors::Error::payload_missing("EFI")).into());
+            let class: String = err.value(py).getattr("error_class")?.extract()?;
+            assert_eq!(class, "payload-missing");
+            Ok(())
+        })?;
+        let v = Validation::from(api::ValidationResult::Skip);
+        assert_eq!((v.state.as_str(), v.errors.len()), ("skip", 0));
+        Ok(())
+    }
+}
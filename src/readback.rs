@@ -0,0 +1,159 @@
+//! Optional verification of what was written, by reading it back.
+//!
+//! Flaky SD cards and eMMC can acknowledge writes, and even a sync, without
+//! having stored the data.  With verification enabled (`--verify-writes`),
+//! the files written are read back once they are synced and compared
+//! against the digests of their source, before the update is committed or
+//! reported as successful; so are the images written to raw devices, such
+//! as the GRUB core image embedded for BIOS boot.  The cached pages are
+//! dropped before reading, so that the data comes from the device rather
+//! than from memory.
+
+use std::fs::File;
+use std::io::Read;
+#[cfg(any(have_bios, have_uboot))]
+use std::io::{Seek, SeekFrom};
+#[cfg(any(have_bios, all(have_efi, target_arch = "x86_64"), have_uboot))]
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+use openssl::hash::{Hasher, MessageDigest};
+
+use crate::errors::{Error, ErrorKind};
+#[cfg(have_filecopy)]
+use crate::filetree::FileMetadata;
+use crate::sha512string::SHA512String;
+
+/// Drop the cached pages of `f`, so the next reads hit the device; only the
+/// pages which were synced can be dropped.
+fn drop_cache(f: &File) {
+    // Only a hint, so errors (e.g. on filesystems without support) don't matter
+    let _ = rustix::fs::fadvise(f, 0, 0, rustix::fs::Advice::DontNeed);
+}
+
+/// Hash up to `len` bytes read from `r`, returning how many there were.
+fn digest(r: impl Read, len: u64) -> Result<(u64, SHA512String)> {
+    let mut hasher = Hasher::new(MessageDigest::sha512())?;
+    let n = std::io::copy(&mut r.take(len), &mut hasher)?;
+    Ok((n, SHA512String::from_hasher(&mut hasher)))
+}
+
+/// Read back the file `path` of `dir` and fail unless it matches `expected`.
+#[cfg(have_filecopy)]
+pub(crate) fn verify_file(dir: &openat::Dir, path: &str, expected: &FileMetadata) -> Result<()> {
+    let f = dir
+        .open_file(path)
+        .with_context(|| format!("Opening {path} to verify it"))?;
+    drop_cache(&f);
+    let (size, sha512) = digest(&f, u64::MAX).with_context(|| format!("Reading back {path}"))?;
+    if size != expected.size || sha512 != expected.sha512 {
+        return Err(Error::new(
+            ErrorKind::ReadbackMismatch,
+            format!("{path} doesn't match what was written when read back"),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Read back the files `files` of `dir`, by path and expected metadata.
+#[cfg(have_filetree)]
+#[context("Verifying written files")]
+pub(crate) fn verify_files<'a>(
+    dir: &openat::Dir,
+    files: impl IntoIterator<Item = (&'a str, &'a FileMetadata)>,
+) -> Result<()> {
+    for (path, expected) in files {
+        verify_file(dir, path, expected)?;
+    }
+    Ok(())
+}
+
+/// Read back the files copied from `src` to `dest` recursively, and fail
+/// unless they match their source.
+#[cfg(any(have_bios, all(have_efi, target_arch = "x86_64")))]
+#[context("Verifying the copy of {src:?} in {dest:?}")]
+pub(crate) fn verify_copy(src: &Path, dest: &Path) -> Result<()> {
+    let destdir = openat::Dir::open(dest)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            verify_copy(&entry.path(), &dest.join(entry.file_name()))?;
+        } else if file_type.is_file() {
+            let (size, sha512) = digest(File::open(entry.path())?, u64::MAX)?;
+            let name = entry.file_name();
+            let name = name
+                .to_str()
+                .ok_or_else(|| anyhow::anyhow!("Invalid filename {name:?}"))?;
+            verify_file(&destdir, name, &FileMetadata { size, sha512 })?;
+        }
+    }
+    Ok(())
+}
+
+/// Read back `expected.len()` bytes at `offset` in `dev` (e.g. a disk or a
+/// partition), where `name` was written, and fail unless they match.
+#[cfg(any(have_bios, have_uboot))]
+#[context("Verifying {name} on {dev:?}")]
+pub(crate) fn verify_at(dev: &Path, offset: u64, name: &str, expected: &[u8]) -> Result<()> {
+    let mut f = File::open(dev).with_context(|| format!("Opening {dev:?}"))?;
+    drop_cache(&f);
+    f.seek(SeekFrom::Start(offset))?;
+    let len = expected.len() as u64;
+    let (n, sha512) = digest(&f, len)?;
+    let (_, want) = digest(expected, len)?;
+    if n != len || sha512 != want {
+        return Err(Error::new(
+            ErrorKind::ReadbackMismatch,
+            format!(
+                "{name} on {} doesn't match what was written when read back",
+                dev.display()
+            ),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(any(have_bios, all(have_efi, target_arch = "x86_64"), have_uboot))]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(any(have_bios, all(have_efi, target_arch = "x86_64")))]
+    fn test_verify_copy() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let src = td.path().join("src");
+        let dest = td.path().join("dest");
+        for d in [&src, &dest] {
+            std::fs::create_dir_all(d.join("i386-pc"))?;
+            std::fs::write(d.join("i386-pc/normal.mod"), "normal")?;
+        }
+        verify_copy(&src, &dest)?;
+        std::fs::write(dest.join("i386-pc/normal.mod"), "norma")?;
+        let e = verify_copy(&src, &dest).unwrap_err();
+        let e = e.downcast_ref::<Error>().unwrap();
+        assert_eq!(e.kind, ErrorKind::ReadbackMismatch);
+        assert_eq!(
+            e.to_string(),
+            "normal.mod doesn't match what was written when read back"
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(any(have_bios, have_uboot))]
+    fn test_verify_at() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let disk = td.path().join("disk");
+        std::fs::write(&disk, b"\0\0core.img\0")?;
+        verify_at(&disk, 2, "core.img", b"core.img")?;
+        assert!(verify_at(&disk, 1, "core.img", b"core.img").is_err());
+        // Reading past the end is a mismatch too
+        assert!(verify_at(&disk, 4, "core.img", b"core.img").is_err());
+        Ok(())
+    }
+}
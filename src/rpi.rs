@@ -0,0 +1,300 @@
+//! The Raspberry Pi firmware partition: the VideoCore firmware, device
+//! trees and overlays, and the kernel or U-Boot image it loads.  User
+//! configuration such as `config.txt` is never touched.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use crate::component::*;
+use crate::filetree::{self, FileTree};
+use crate::model::*;
+use crate::util;
+
+/// Where ostree systems keep the content of /boot/efi, which is shared
+/// between the ESP and the Pi firmware.
+const OSTREE_FIRMWARE_DIR: &str = "usr/lib/ostree-boot/efi";
+
+/// Directories containing the firmware shipped by the OS, relative to the root.
+const FIRMWARE_DIRS: &[&str] = &[
+    // Debian and Ubuntu
+    "usr/lib/raspi-firmware",
+    OSTREE_FIRMWARE_DIR,
+];
+
+/// Where the firmware partition is mounted, relative to the root.
+const FIRMWARE_MOUNTS: &[&str] = &["boot/firmware", "boot/efi", "boot"];
+
+/// Files read by the boot ROM of every Pi generation
+const BOOT_FILES: &[&str] = &["bootcode.bin", "start.elf", "start4.elf"];
+
+/// Whether the OS in `root` ships Raspberry Pi firmware.
+pub(crate) fn is_shipped(root: &Path) -> bool {
+    FIRMWARE_DIRS
+        .iter()
+        .any(|d| BOOT_FILES.iter().any(|f| root.join(d).join(f).exists()))
+}
+
+/// Whether `path` in the firmware partition is managed by us.
+fn is_firmware_file(path: &str) -> bool {
+    if let Some(overlay) = path.strip_prefix("overlays/") {
+        return !overlay.contains('/');
+    }
+    if path.contains('/') {
+        return false;
+    }
+    let matches = |prefix: &str, suffix: &str| path.starts_with(prefix) && path.ends_with(suffix);
+    matches("start", ".elf")
+        || matches("fixup", ".dat")
+        || matches("bcm27", ".dtb")
+        || matches("armstub", ".bin")
+        || matches("kernel", ".img")
+        || matches("u-boot", ".bin")
+        || path == "bootcode.bin"
+}
+
+/// Find the mounted firmware partition below `root`.
+fn find_firmware_mount(root: &Path) -> Result<Option<PathBuf>> {
+    for &mnt in FIRMWARE_MOUNTS {
+        let mnt = root.join(mnt);
+        if !mnt.exists() {
+            continue;
+        }
+        if !util::is_fat(&mnt)? {
+            continue;
+        }
+        if BOOT_FILES.iter().any(|f| mnt.join(f).exists()) {
+            return Ok(Some(mnt));
+        }
+    }
+    Ok(None)
+}
+
+#[derive(Default)]
+pub(crate) struct Rpi {
+    opts: crate::component::Options,
+}
+
+impl Rpi {
+    /// The Raspberry Pi component, writing as `opts` tell.
+    pub(crate) fn new(opts: crate::component::Options) -> Self {
+        Self { opts }
+    }
+
+    /// Open the firmware partition below `root`, which must be mounted,
+    /// writable until the returned guard is dropped.
+    fn open_firmware(&self, root: &Path) -> Result<(util::WritableMount, openat::Dir)> {
+        let Some(mnt) = find_firmware_mount(root)? else {
+            bail!("Failed to find the Raspberry Pi firmware partition");
+        };
+        let writable = util::WritableMount::new(&mnt)?;
+        let dir = openat::Dir::open(&mnt).with_context(|| format!("opening {mnt:?}"))?;
+        Ok((writable, dir))
+    }
+
+    /// Apply `diff` from the payload in `sysroot` to `dest`, staging the
+    /// new files and then exchanging them into place.
+    fn apply(
+        &self,
+        sysroot: &openat::Dir,
+        dest: &openat::Dir,
+        diff: &filetree::FileTreeDiff,
+    ) -> Result<()> {
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        log::trace!("applying diff: {}", &diff);
+        filetree::apply_diff(&updated, dest, diff, Some(&self.opts.apply_options()))
+            .context("applying filesystem changes")
+    }
+
+    fn payload_tree(&self, sysroot: &openat::Dir) -> Result<FileTree> {
+        let updated = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        crate::digestcache::payload_tree(&updated).context("reading update dir")
+    }
+}
+
+impl Component for Rpi {
+    fn name(&self) -> &'static str {
+        "RPI"
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        if find_firmware_mount(Path::new("/"))?.is_none() {
+            log::trace!("No Raspberry Pi firmware partition detected");
+            return Ok(None);
+        }
+        crate::component::query_adopt_state()
+    }
+
+    fn adopt_update(
+        &self,
+        sysroot: &openat::Dir,
+        update: &ContentMetadata,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = self.query_adopt()? else {
+            anyhow::bail!("Failed to find adoptable system")
+        };
+        let (_writable, dest) = self.open_firmware(Path::new("/"))?;
+        let updatef = self.payload_tree(sysroot)?;
+        // For adoption, we should only touch files that we know about.
+        let diff = updatef.relative_diff_to(&dest)?;
+        self.apply(sysroot, &dest, &diff)?;
+        Ok(InstalledContent {
+            meta: update.clone(),
+            filetree: Some(updatef),
+            adopted_from: Some(meta.version),
+            devices: Vec::new(),
+        })
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        _device: Option<&Path>,
+        _update_firmware: bool,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = get_component_update(src_root, self)? else {
+            return Err(crate::errors::Error::payload_missing(self.name()).into());
+        };
+        let (_writable, dest) = self.open_firmware(Path::new(dest_root))?;
+        let ft = self.payload_tree(src_root)?;
+        let empty = FileTree {
+            children: Default::default(),
+        };
+        let diff = empty.diff(&ft)?;
+        self.apply(src_root, &dest, &diff)?;
+        Ok(InstalledContent {
+            meta,
+            filetree: Some(ft),
+            adopted_from: None,
+            devices: Vec::new(),
+        })
+    }
+
+    fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
+        let root = Path::new(sysroot_path);
+        let Some(srcdir) = FIRMWARE_DIRS.iter().find(|d| root.join(d).exists()) else {
+            bail!("Failed to find Raspberry Pi firmware in {FIRMWARE_DIRS:?}");
+        };
+        let src = openat::Dir::open(&root.join(srcdir))?;
+        // The files are packaged in /boot/efi, before being moved by ostree
+        let pkgdir = if *srcdir == OSTREE_FIRMWARE_DIR {
+            "boot/efi"
+        } else {
+            srcdir
+        };
+        let dest = component_updatedir(sysroot_path, self);
+        let mut files = Vec::new();
+        for name in util::filenames(&src)? {
+            let name = name.trim_start_matches('/');
+            if !is_firmware_file(name) {
+                continue;
+            }
+            let target = dest.join(name);
+            std::fs::create_dir_all(target.parent().unwrap())?;
+            std::fs::copy(root.join(srcdir).join(name), &target)
+                .with_context(|| format!("Copying {name}"))?;
+            files.push(Path::new("/").join(pkgdir).join(name));
+        }
+        if files.is_empty() {
+            bail!("No firmware files found in {srcdir}");
+        }
+        files.sort();
+        let meta = write_packaged_update_metadata(sysroot_path, self, files)?;
+        Ok(meta)
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed RPI found!"))?;
+        let updatemeta = self.query_update(sysroot)?.expect("update available");
+        let updatef = self.payload_tree(sysroot)?;
+        let diff = currentf.diff(&updatef)?;
+        let (_writable, dest) = self.open_firmware(Path::new("/"))?;
+        self.apply(sysroot, &dest, &diff)?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(updatef),
+            adopted_from: None,
+            devices: Vec::new(),
+        })
+    }
+
+    fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        let Some(mnt) = find_firmware_mount(Path::new("/"))? else {
+            return Ok(ValidationResult::Skip);
+        };
+        let currentf = current
+            .filetree
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No filetree for installed RPI found!"))?;
+        let dest = openat::Dir::open(&mnt)?;
+        let diff = currentf.relative_diff_to(&dest)?;
+        let mut errs = Vec::new();
+        for f in diff.changes.iter() {
+            errs.push(format!("Changed: {}", f));
+        }
+        for f in diff.removals.iter() {
+            errs.push(format!("Removed: {}", f));
+        }
+        if !errs.is_empty() {
+            Ok(ValidationResult::Errors(errs))
+        } else {
+            Ok(ValidationResult::Valid)
+        }
+    }
+
+    fn fingerprint_installed(&self, sysroot: &openat::Dir) -> Result<Option<InstalledContent>> {
+        let Some(mnt) = find_firmware_mount(Path::new("/"))? else {
+            return Ok(None);
+        };
+        let Some(updatemeta) = self.query_update(sysroot)? else {
+            return Err(crate::errors::Error::payload_missing(self.name()).into());
+        };
+        let updatef = self.payload_tree(sysroot)?;
+        let dest = openat::Dir::open(&mnt)?;
+        fingerprint_tree(&updatef, updatemeta, &dest)
+    }
+
+    fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_firmware_file() {
+        for f in [
+            "start4.elf",
+            "fixup4cd.dat",
+            "bootcode.bin",
+            "bcm2711-rpi-4-b.dtb",
+            "overlays/vc4-kms-v3d.dtbo",
+            "overlays/README",
+            "u-boot-rpi4.bin",
+            "kernel8.img",
+        ] {
+            assert!(is_firmware_file(f), "{f}");
+        }
+        for f in ["config.txt", "cmdline.txt", "EFI/fedora/grubaa64.efi"] {
+            assert!(!is_firmware_file(f), "{f}");
+        }
+    }
+}
@@ -0,0 +1,445 @@
+//! Checking the first stage loader against the Secure Boot `db`.
+//!
+//! With Secure Boot enabled, the firmware only runs a first stage loader
+//! (usually shim) whose signature chains to a certificate in its `db`
+//! variable, or whose hash is listed there.  Updating to a loader signed
+//! otherwise, e.g. by another vendor or not at all, leaves the machine
+//! unbootable until Secure Boot is disabled, so such updates are refused
+//! unless forced.  Later stages are verified by shim against its built-in
+//! vendor certificate and the MOK list rather than by the firmware; as the
+//! vendor certificate isn't enrolled anywhere, they aren't checked before
+//! updating, but [`vendor_trusts`] tells whether the GRUB installed next to
+//! shim is signed by it, for [`crate::consistency`].
+//!
+//! Only the certificates are checked: that the image matches its
+//! signature is up to the payload digests.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyFlags;
+use openssl::x509::{X509StoreContext, X509};
+
+/// The variable listing the signatures the firmware allows.
+const DB_VAR: &str = "db-d719b2cb-3d3a-4596-a3bc-dad00e67656f";
+/// `EFI_CERT_X509_GUID`, in its on-disk byte order.
+const CERT_X509_GUID: [u8; 16] = [
+    0xa1, 0x59, 0xc0, 0xa5, 0xe4, 0x94, 0xa7, 0x4a, 0x87, 0xb5, 0xab, 0x15, 0x5c, 0x2b, 0xf0, 0x72,
+];
+/// The size of an `EFI_SIGNATURE_LIST` header.
+const SIGNATURE_LIST_HEADER: usize = 28;
+/// The `WIN_CERTIFICATE` type of an Authenticode signature.
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 2;
+
+/// The contents of a signature database such as `db`.
+#[derive(Debug, Default)]
+struct SignatureDb {
+    certs: Vec<X509>,
+    /// The number of other signatures, i.e. the hashes of allowed images
+    hashes: usize,
+}
+
+/// Parse the `EFI_SIGNATURE_LIST`s in `buf`.
+fn parse_signature_lists(mut buf: &[u8]) -> Result<SignatureDb> {
+    let u32_at = |b: &[u8], o: usize| -> usize {
+        u32::from_le_bytes(b[o..o + 4].try_into().unwrap()) as usize
+    };
+    let mut r = SignatureDb::default();
+    while !buf.is_empty() {
+        if buf.len() < SIGNATURE_LIST_HEADER {
+            anyhow::bail!("Truncated signature list");
+        }
+        let list_size = u32_at(buf, 16);
+        let header_size = u32_at(buf, 20);
+        let signature_size = u32_at(buf, 24);
+        let signatures = buf
+            .get(SIGNATURE_LIST_HEADER + header_size..list_size)
+            .filter(|_| signature_size > 16)
+            .ok_or_else(|| anyhow::anyhow!("Invalid signature list"))?;
+        for signature in signatures.chunks_exact(signature_size) {
+            // After the GUID of the owner
+            let data = &signature[16..];
+            if buf[..16] == CERT_X509_GUID {
+                r.certs
+                    .push(X509::from_der(data).context("Parsing certificate")?);
+            } else {
+                r.hashes += 1;
+            }
+        }
+        buf = &buf[list_size..];
+    }
+    Ok(r)
+}
+
+// The offsets of PE images are untrusted, and may overflow on 32-bit
+// targets
+fn get(buf: &[u8], o: usize, n: usize) -> Option<&[u8]> {
+    buf.get(o..o.checked_add(n)?)
+}
+
+fn u16_at(buf: &[u8], o: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(get(buf, o, 2)?.try_into().ok()?))
+}
+
+fn u32_at(buf: &[u8], o: usize) -> Option<usize> {
+    let v = u32::from_le_bytes(get(buf, o, 4)?.try_into().ok()?);
+    usize::try_from(v).ok()
+}
+
+/// The offset of the COFF header of the PE image `buf`, after its
+/// signature; `None` if it isn't a PE image.
+fn coff_header(buf: &[u8]) -> Option<usize> {
+    let pe = u32_at(buf, 0x3c)?;
+    if get(buf, pe, 4)? != b"PE\0\0" {
+        return None;
+    }
+    pe.checked_add(4)
+}
+
+/// The file offset and size of the certificate table of the PE image
+/// `buf`, which has a size of zero in unsigned images; `None` if it isn't a
+/// PE image.
+fn certificate_table(buf: &[u8]) -> Option<(usize, usize)> {
+    // The optional header follows the 20 byte COFF header
+    let opt = coff_header(buf)?.checked_add(20)?;
+    let directories = match u16_at(buf, opt)? {
+        0x10b => opt + 96,
+        0x20b => opt + 112,
+        _ => return None,
+    };
+    // The certificate table is the fifth data directory
+    const SECURITY_DIRECTORY: usize = 4;
+    if u32_at(buf, directories - 4)? <= SECURITY_DIRECTORY {
+        return Some((0, 0));
+    }
+    let entry = directories + 8 * SECURITY_DIRECTORY;
+    Some((u32_at(buf, entry)?, u32_at(buf, entry + 4)?))
+}
+
+/// The contents of the section `name` of the PE image `buf`, if any.
+/// Names longer than 8 bytes are found whether they were truncated, or
+/// stored in the COFF string table as binutils does.
+fn pe_section<'a>(buf: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let coff = coff_header(buf)?;
+    let sections = usize::from(u16_at(buf, coff + 2)?);
+    let symbols = u32_at(buf, coff + 8)?;
+    let strings = symbols.checked_add(u32_at(buf, coff + 12)?.checked_mul(18)?)?;
+    let table = coff.checked_add(20 + usize::from(u16_at(buf, coff + 16)?))?;
+    let truncated = &name.as_bytes()[..name.len().min(8)];
+    for i in 0..sections {
+        let header = get(buf, table.checked_add(40 * i)?, 40)?;
+        let raw = header[..8].split(|&b| b == 0).next().unwrap_or_default();
+        let found = match raw.strip_prefix(b"/") {
+            Some(offset) => {
+                let offset = std::str::from_utf8(offset).ok()?.parse::<usize>().ok()?;
+                let s = buf.get(strings.checked_add(offset)?..)?;
+                s.split(|&b| b == 0).next() == Some(name.as_bytes())
+            }
+            None => raw == truncated,
+        };
+        if found {
+            return get(buf, u32_at(header, 20)?, u32_at(header, 16)?);
+        }
+    }
+    None
+}
+
+/// The certificates built into `shim` to verify the next stage with; `None`
+/// if there are none, as when it only trusts the MOK list.
+fn vendor_certs(shim: &[u8]) -> Result<Option<SignatureDb>> {
+    let Some(section) = pe_section(shim, ".vendor_cert") else {
+        return Ok(None);
+    };
+    // The sizes then offsets of the allowed and of the revoked signatures
+    let Some(allowed) = u32_at(section, 0)
+        .zip(u32_at(section, 8))
+        .and_then(|(size, offset)| get(section, offset, size))
+    else {
+        anyhow::bail!("Invalid vendor certificate table");
+    };
+    if allowed.is_empty() {
+        return Ok(None);
+    }
+    match X509::from_der(allowed) {
+        Ok(cert) => Ok(Some(SignatureDb {
+            certs: vec![cert],
+            hashes: 0,
+        })),
+        // Built with a vendor db rather than a single certificate
+        Err(_) => Ok(Some(
+            parse_signature_lists(allowed).context("Parsing the vendor db")?,
+        )),
+    }
+}
+
+/// The Authenticode signatures in the certificate table of `image`.
+fn pe_signatures(image: &[u8]) -> Vec<&[u8]> {
+    let mut r = Vec::new();
+    let Some(mut table) = certificate_table(image)
+        .and_then(|(offset, size)| image.get(offset..offset.checked_add(size)?))
+    else {
+        return r;
+    };
+    // WIN_CERTIFICATEs, aligned to 8 bytes
+    while table.len() >= 8 {
+        let len = u32::from_le_bytes(table[..4].try_into().unwrap()) as usize;
+        let kind = u16::from_le_bytes(table[6..8].try_into().unwrap());
+        let Some(cert) = table.get(8..len) else {
+            break;
+        };
+        if kind == WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+            r.push(cert);
+        }
+        table = table.get(len.next_multiple_of(8)..).unwrap_or_default();
+    }
+    r
+}
+
+/// Whether a signature of `image` chains to a certificate of `db`.
+fn signed_by(image: &[u8], db: &SignatureDb) -> Result<bool> {
+    let mut store = X509StoreBuilder::new()?;
+    for cert in &db.certs {
+        store.add_cert(cert.clone())?;
+    }
+    // The db may hold intermediate CAs, e.g. the Microsoft UEFI CA, and the
+    // firmware ignores expiry
+    store.set_flags(X509VerifyFlags::PARTIAL_CHAIN | X509VerifyFlags::NO_CHECK_TIME)?;
+    let store = store.build();
+    for signature in pe_signatures(image) {
+        let pkcs7 = Pkcs7::from_der(signature).context("Parsing signature")?;
+        let mut chain = Stack::new()?;
+        if let Some(certs) = pkcs7.signed().and_then(|s| s.certificates()) {
+            for cert in certs {
+                chain.push(cert.to_owned())?;
+            }
+        }
+        for signer in &pkcs7.signers(&chain, Pkcs7Flags::empty())? {
+            let mut ctx = X509StoreContext::new()?;
+            if ctx.init(&store, signer, &chain, |c| c.verify_cert())? {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Whether `shim` verifies `image` by its built-in vendor certificates;
+/// `None` if it can't be told, as `shim` has none, or they list hashes.
+pub(crate) fn vendor_trusts(shim: &[u8], image: &[u8]) -> Result<Option<bool>> {
+    let Some(db) = vendor_certs(shim)? else {
+        return Ok(None);
+    };
+    if signed_by(image, &db)? {
+        return Ok(Some(true));
+    }
+    // We don't compute Authenticode hashes
+    Ok((db.hashes == 0).then_some(false))
+}
+
+/// Check the first stage loader `image`, to be installed as `path`, against
+/// the signature database `db`.
+fn check_loader_in(path: &str, image: &[u8], db: &SignatureDb) -> Result<()> {
+    if signed_by(image, db)? {
+        return Ok(());
+    }
+    // We don't compute Authenticode hashes
+    if db.hashes > 0 {
+        log::debug!("{path} isn't signed by a certificate in db, which also lists hashes");
+        return Ok(());
+    }
+    Err(crate::errors::Error::new(
+        crate::errors::ErrorKind::SecureBootMismatch,
+        format!(
+            "Secure Boot is enabled, but {path} isn't signed by a certificate in the \
+             firmware db (use --force to update anyway)"
+        ),
+    )
+    .into())
+}
+
+/// Refuse to install the first stage loader `image` as `path` on the ESP
+/// if Secure Boot is enabled and the firmware wouldn't run it, unless
+/// `force`d.
+pub(crate) fn check_loader(path: &str, image: &[u8], force: bool) -> Result<()> {
+    if force || crate::firmware::query().secure_boot != Some(true) {
+        return Ok(());
+    }
+    let var = Path::new("/sys/firmware/efi/efivars").join(DB_VAR);
+    let db = match std::fs::read(&var) {
+        Ok(v) => v,
+        Err(e) => {
+            log::debug!("Not checking {path}: reading {var:?}: {e}");
+            return Ok(());
+        }
+    };
+    // Skip the 4 bytes of EFI variable attributes
+    let db = parse_signature_lists(db.get(4..).unwrap_or_default())
+        .with_context(|| format!("Parsing {var:?}"))?;
+    check_loader_in(path, image, &db)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::x509::X509NameBuilder;
+
+    /// The headers of a PE32+ image with 16 data directories.
+    fn pe_image() -> Vec<u8> {
+        let mut image = vec![0u8; 0x200];
+        image[0x3c..0x40].copy_from_slice(&0x80u32.to_le_bytes());
+        image[0x80..0x84].copy_from_slice(b"PE\0\0");
+        image[0x98..0x9a].copy_from_slice(&0x20bu16.to_le_bytes());
+        image[0x104..0x108].copy_from_slice(&16u32.to_le_bytes());
+        image
+    }
+
+    fn self_signed(cn: &str) -> Result<(X509, PKey<Private>)> {
+        let key = PKey::from_rsa(Rsa::generate(2048)?)?;
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_text("CN", cn)?;
+        let name = name.build();
+        let mut cert = X509::builder()?;
+        cert.set_version(2)?;
+        cert.set_subject_name(&name)?;
+        cert.set_issuer_name(&name)?;
+        cert.set_pubkey(&key)?;
+        cert.set_not_before(&*Asn1Time::days_from_now(0)?)?;
+        cert.set_not_after(&*Asn1Time::days_from_now(1)?)?;
+        cert.sign(&key, MessageDigest::sha256())?;
+        Ok((cert.build(), key))
+    }
+
+    fn signature_list(guid: [u8; 16], data: &[u8]) -> Vec<u8> {
+        let signature_size = 16 + data.len();
+        let mut r = guid.to_vec();
+        r.extend(((SIGNATURE_LIST_HEADER + signature_size) as u32).to_le_bytes());
+        r.extend(0u32.to_le_bytes());
+        r.extend((signature_size as u32).to_le_bytes());
+        r.extend([0u8; 16]);
+        r.extend(data);
+        r
+    }
+
+    #[test]
+    fn test_certificate_table() {
+        let mut image = pe_image();
+        assert_eq!(certificate_table(&image), Some((0, 0)));
+        image[0x12c..0x130].copy_from_slice(&0x1000u32.to_le_bytes());
+        assert_eq!(certificate_table(&image), Some((0, 0x1000)));
+        assert_eq!(certificate_table(b"#!/bin/sh"), None);
+        assert_eq!(certificate_table(&image[..0x100]), None);
+    }
+
+    /// A PE image signed by `cert`, with the certificate table appended.
+    fn signed_image(cert: &X509, key: &PKey<Private>, content: &[u8]) -> Result<Vec<u8>> {
+        let certs = Stack::new()?;
+        let pkcs7 = Pkcs7::sign(cert, key, &certs, content, Pkcs7Flags::BINARY)?.to_der()?;
+        let mut image = pe_image();
+        let offset = image.len();
+        let len = 8 + pkcs7.len();
+        image.extend((len as u32).to_le_bytes());
+        image.extend(0x200u16.to_le_bytes());
+        image.extend(WIN_CERT_TYPE_PKCS_SIGNED_DATA.to_le_bytes());
+        image.extend(&pkcs7);
+        image[0x128..0x12c].copy_from_slice(&(offset as u32).to_le_bytes());
+        image[0x12c..0x130].copy_from_slice(&(len as u32).to_le_bytes());
+        Ok(image)
+    }
+
+    #[test]
+    fn test_check_loader() -> Result<()> {
+        let (vendor, key) = self_signed("Vendor Secure Boot CA")?;
+        let (other, _) = self_signed("Other Secure Boot CA")?;
+        let image = signed_image(&vendor, &key, b"shim")?;
+        // The signature follows the 8 byte WIN_CERTIFICATE header
+        assert_eq!(pe_signatures(&image), [&image[0x208..]]);
+        let mut bogus = image.clone();
+        bogus[0x128..0x130].fill(0xff);
+        assert!(pe_signatures(&bogus).is_empty());
+
+        let mut buf = signature_list(CERT_X509_GUID, &other.to_der()?);
+        let db = parse_signature_lists(&buf)?;
+        assert_eq!(db.certs.len(), 1);
+        let e = check_loader_in("EFI/fedora/shimx64.efi", &image, &db).unwrap_err();
+        assert_eq!(
+            crate::errors::classify(&e),
+            crate::errors::ErrorKind::SecureBootMismatch
+        );
+        // Nor is an unsigned image allowed
+        assert!(check_loader_in("EFI/fedora/shimx64.efi", &pe_image(), &db).is_err());
+
+        buf.extend(signature_list(CERT_X509_GUID, &vendor.to_der()?));
+        let db = parse_signature_lists(&buf)?;
+        assert_eq!(db.certs.len(), 2);
+        check_loader_in("EFI/fedora/shimx64.efi", &image, &db)?;
+
+        // It may be allowed by its hash instead
+        let db = parse_signature_lists(&signature_list([0u8; 16], &[0u8; 32]))?;
+        assert_eq!(db.hashes, 1);
+        check_loader_in("EFI/fedora/shimx64.efi", &pe_image(), &db)?;
+        assert!(parse_signature_lists(&buf[..20]).is_err());
+        Ok(())
+    }
+
+    /// `image` with a section `name` appended, holding `data`.
+    fn with_section(mut image: Vec<u8>, name: &[u8], data: &[u8]) -> Vec<u8> {
+        // One section, after the 240 byte optional header of PE32+
+        image[0x86..0x88].copy_from_slice(&1u16.to_le_bytes());
+        image[0x94..0x96].copy_from_slice(&0xf0u16.to_le_bytes());
+        let header = 0x188;
+        image[header..header + name.len()].copy_from_slice(name);
+        let offset = image.len();
+        image[header + 16..header + 20].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        image[header + 20..header + 24].copy_from_slice(&(offset as u32).to_le_bytes());
+        image.extend(data);
+        image
+    }
+
+    #[test]
+    fn test_vendor_trusts() -> Result<()> {
+        let (vendor, key) = self_signed("Vendor Secure Boot CA")?;
+        let (other, other_key) = self_signed("Other Secure Boot CA")?;
+        let grub = signed_image(&vendor, &key, b"grub")?;
+        let foreign = signed_image(&other, &other_key, b"grub")?;
+        let cert = vendor.to_der()?;
+        let mut table = Vec::new();
+        for v in [cert.len(), 0, 16, 16 + cert.len()] {
+            table.extend((v as u32).to_le_bytes());
+        }
+        table.extend(&cert);
+
+        let shim = with_section(pe_image(), b".vendor_", &table);
+        assert_eq!(pe_section(&shim, ".vendor_cert"), Some(table.as_slice()));
+        assert_eq!(vendor_trusts(&shim, &grub)?, Some(true));
+        assert_eq!(vendor_trusts(&shim, &foreign)?, Some(false));
+        assert_eq!(vendor_trusts(&shim, &pe_image())?, Some(false));
+        // Or with the long name in the string table, here right after it
+        let mut shim = with_section(pe_image(), b"/4", &table);
+        let strings = shim.len();
+        shim[0x8c..0x90].copy_from_slice(&(strings as u32).to_le_bytes());
+        shim.extend(b"\0\0\0\0.vendor_cert\0");
+        assert_eq!(vendor_trusts(&shim, &grub)?, Some(true));
+
+        // Without vendor certificates, it can't be told
+        assert_eq!(vendor_trusts(&pe_image(), &grub)?, None);
+        let db = signature_list(CERT_X509_GUID, &other.to_der()?);
+        let mut table = Vec::new();
+        for v in [db.len(), 0, 16, 16 + db.len()] {
+            table.extend((v as u32).to_le_bytes());
+        }
+        table.extend(&db);
+        let shim = with_section(pe_image(), b".vendor_", &table);
+        assert_eq!(vendor_trusts(&shim, &foreign)?, Some(true));
+        assert_eq!(vendor_trusts(&shim, &grub)?, Some(false));
+        let shim = with_section(pe_image(), b".vendor_", &[0xff; 16]);
+        assert!(vendor_trusts(&shim, &grub).is_err());
+        Ok(())
+    }
+}
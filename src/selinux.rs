@@ -0,0 +1,149 @@
+//! SELinux labels of the files we write to `/boot`.
+//!
+//! Files we create, or which grub-install creates for us, get the default
+//! label of their parent directory, rather than the one the policy assigns
+//! to their path.  When SELinux is enabled, the files we own in `/boot` are
+//! relabeled after being written, following the file contexts of the policy
+//! of the OS being installed; `validate` reports labels which don't match,
+//! and `validate --fix` restores them.  The ESP is FAT, which has no labels.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use fn_error_context::context;
+
+use crate::model::SavedState;
+
+/// Where the kernel exposes SELinux, if enabled.
+const SELINUXFS: &str = "/sys/fs/selinux";
+
+/// What we own below `/boot`: the GRUB directories of the various
+/// distributions, the extlinux configuration and our state.
+const BOOT_PATHS: &[&str] = &[
+    "grub",
+    "grub2",
+    "extlinux",
+    SavedState::STATEFILE_NAME,
+    SavedState::STATEFILE_BACKUP_NAME,
+];
+
+fn enabled() -> bool {
+    Path::new(SELINUXFS).join("enforce").exists()
+}
+
+/// Return the file contexts of the policy configured in `policy_root`,
+/// if any.
+fn file_contexts(policy_root: &Path) -> Result<Option<PathBuf>> {
+    let config = policy_root.join("etc/selinux/config");
+    let config = match std::fs::read_to_string(&config) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Reading {config:?}")),
+    };
+    let Some(policy) = config
+        .lines()
+        .filter_map(|l| l.trim().strip_prefix("SELINUXTYPE="))
+        .next_back()
+    else {
+        return Ok(None);
+    };
+    let path = policy_root
+        .join("etc/selinux")
+        .join(policy.trim().trim_matches('"'))
+        .join("contexts/files/file_contexts");
+    Ok(path.exists().then_some(path))
+}
+
+/// Run setfiles on what we own in `root`/boot, according to the policy of
+/// `policy_root`, with the additional `args`.  Returns its output, or
+/// `None` if there's nothing to label.
+fn setfiles(policy_root: &Path, root: &Path, args: &[&str]) -> Result<Option<String>> {
+    if !enabled() {
+        return Ok(None);
+    }
+    let Some(contexts) = file_contexts(policy_root)? else {
+        log::debug!("No SELinux policy in {policy_root:?}");
+        return Ok(None);
+    };
+    let boot = root.join("boot");
+    let paths = BOOT_PATHS
+        .iter()
+        .map(|p| boot.join(p))
+        .filter(|p| p.exists())
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        return Ok(None);
+    }
+    let mut cmd = crate::util::command("setfiles");
+    // Labels are matched on the paths below the root
+    cmd.args(args)
+        .arg("-F")
+        .arg("-r")
+        .arg(root)
+        .arg(contexts)
+        .args(paths);
+    let out = cmd.output().context("Running setfiles")?;
+    if !out.status.success() {
+        return Err(crate::util::tool_failed(&cmd, out.status, &out.stderr));
+    }
+    Ok(Some(String::from_utf8_lossy(&out.stdout).into_owned()))
+}
+
+/// Label what we own in `root`/boot according to the policy of
+/// `policy_root`, if SELinux is enabled.
+#[context("Relabeling {:?}", root.join("boot"))]
+pub(crate) fn relabel(policy_root: &Path, root: &Path) -> Result<()> {
+    if setfiles(policy_root, root, &[])?.is_some() {
+        crate::util::syncfs(&openat::Dir::open(&root.join("boot"))?)?;
+    }
+    Ok(())
+}
+
+/// Return the files we own in `/boot` of the booted system whose labels
+/// don't match the policy.
+pub(crate) fn mislabeled() -> Result<Vec<String>> {
+    let root = Path::new("/");
+    let out = setfiles(root, root, &["-n", "-v"])?.unwrap_or_default();
+    Ok(parse_mislabeled(&out))
+}
+
+/// Parse the output of `setfiles -n -v`, e.g.
+/// `Would relabel /boot/grub2/grub.cfg from system_u:object_r:unlabeled_t:s0 to ...`.
+fn parse_mislabeled(out: &str) -> Vec<String> {
+    out.lines()
+        .filter_map(|l| {
+            l.strip_prefix("Would relabel ")
+                .or_else(|| l.strip_prefix("Relabeled "))
+        })
+        .map(|l| l.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_contexts() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        assert_eq!(file_contexts(td.path())?, None);
+        std::fs::create_dir_all(td.path().join("etc/selinux/targeted/contexts/files"))?;
+        std::fs::write(
+            td.path().join("etc/selinux/config"),
+            "# comment\nSELINUX=enforcing\nSELINUXTYPE=targeted\n",
+        )?;
+        assert_eq!(file_contexts(td.path())?, None);
+        let contexts = td
+            .path()
+            .join("etc/selinux/targeted/contexts/files/file_contexts");
+        std::fs::write(&contexts, "")?;
+        assert_eq!(file_contexts(td.path())?, Some(contexts));
+
+        let out = "Would relabel /boot/grub2/grub.cfg from a_t to boot_t\nother\n";
+        assert_eq!(
+            parse_mislabeled(out),
+            ["/boot/grub2/grub.cfg from a_t to boot_t"]
+        );
+        Ok(())
+    }
+}
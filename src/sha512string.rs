@@ -8,8 +8,10 @@ use openssl::hash::Hasher;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 
+/// A SHA-512 digest, displayed and serialized as `sha512:` and its hex
+/// encoding.
 #[derive(Serialize, Deserialize, Clone, Debug, Hash, Ord, PartialOrd, PartialEq, Eq)]
-pub(crate) struct SHA512String(pub(crate) String);
+pub struct SHA512String(pub(crate) String);
 
 impl fmt::Display for SHA512String {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -0,0 +1,259 @@
+//! Simulating operations against a prepared fake root, without privileges
+//! or touching the devices of the host.
+//!
+//! With `--simulate DIR`, the process enters a user and mount namespace of
+//! its own, chroots into `DIR` and runs as usual: installs and updates
+//! plan and write into the fake root, whose state file and "ESP" can be
+//! inspected afterwards.  The `/usr` of the host is layered below that of
+//! the fake root, for the tools run, and `/proc` is the host's.
+//!
+//! The tools inspecting or changing devices, mounts and firmware (see
+//! [`STUBBED`]) aren't run; their command lines are appended to
+//! `/.bootupd-simulate/commands.log` and their output is the content of
+//! `/.bootupd-simulate/<tool>` (e.g. the lsblk JSON of the simulated
+//! topology), if any.  The directories listed in `/.bootupd-simulate/fat`
+//! (e.g. `boot/efi`) are taken as mounted FAT filesystems; the system is
+//! taken as booted via EFI if `/sys/firmware/efi` exists, as usual.
+
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::OnceLock;
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+
+/// The directory of the canned answers and records, in the fake root.
+const STATE_DIR: &str = "/.bootupd-simulate";
+/// The `argv[0]` with which we run ourselves in place of stubbed tools.
+pub(crate) const STUB: &str = "bootupd-simulated-tool";
+/// The tools which aren't run when simulating.
+const STUBBED: &[&str] = &[
+    "efibootmgr",
+    "findmnt",
+    "flashrom",
+    "grub-install",
+    "grub2-install",
+    "lsblk",
+    "mount",
+    "partx",
+    "rpm-ostree",
+    "umount",
+];
+/// The symlinks of a merged `/usr`, through which the tools of the host
+/// (and their dynamic linker) are found.
+const USR_LINKS: &[(&str, &str)] = &[
+    ("bin", "usr/bin"),
+    ("lib", "usr/lib"),
+    ("lib64", "usr/lib64"),
+    ("sbin", "usr/sbin"),
+];
+
+/// The FAT mounts of the fake root; set once simulating.
+static FAT_MOUNTS: OnceLock<Vec<PathBuf>> = OnceLock::new();
+
+/// Whether operations are simulated.
+pub(crate) fn active() -> bool {
+    FAT_MOUNTS.get().is_some()
+}
+
+/// Whether `path` is on one of the FAT filesystems of the fake root.
+#[cfg(have_filecopy)]
+pub(crate) fn is_fat(path: &Path) -> bool {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+    FAT_MOUNTS
+        .get()
+        .is_some_and(|m| m.iter().any(|m| path.starts_with(m)))
+}
+
+/// Whether `path` is one of the FAT mounts of the fake root.
+pub(crate) fn is_fat_mount(path: &Path) -> bool {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+    FAT_MOUNTS.get().is_some_and(|m| m.contains(&path))
+}
+
+/// The command running `program`, or a stub of it when simulating.
+pub(crate) fn command(program: &std::ffi::OsStr) -> Option<Command> {
+    if !active() {
+        return None;
+    }
+    let name = Path::new(program).file_name()?.to_str()?;
+    if !STUBBED.contains(&name) {
+        return None;
+    }
+    let mut cmd = Command::new("/proc/self/exe");
+    cmd.arg0(STUB).arg(name);
+    Some(cmd)
+}
+
+/// Run as the stub of the tool `args[0]`, with the arguments `args[1..]`;
+/// returns the exit code.
+pub(crate) fn run_stub(args: &[String]) -> i32 {
+    let state = Path::new(STATE_DIR);
+    let r = (|| -> std::io::Result<()> {
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(state.join("commands.log"))?;
+        writeln!(log, "{}", args.join(" "))?;
+        if let Some(output) = args.first().map(|t| state.join(t)) {
+            if output.exists() {
+                std::io::stdout().write_all(&std::fs::read(output)?)?;
+            }
+        }
+        Ok(())
+    })();
+    match r {
+        Ok(()) => libc::EXIT_SUCCESS,
+        Err(e) => {
+            eprintln!("{STUB}: {e}");
+            libc::EXIT_FAILURE
+        }
+    }
+}
+
+fn cstr(p: &Path) -> Result<CString> {
+    CString::new(p.as_os_str().as_bytes()).with_context(|| format!("Invalid path {p:?}"))
+}
+
+/// Call mount(2).
+fn mount(
+    source: Option<&Path>,
+    target: &Path,
+    fstype: Option<&str>,
+    flags: libc::c_ulong,
+    data: Option<&str>,
+) -> Result<()> {
+    let source = source.map(cstr).transpose()?;
+    let target = cstr(target)?;
+    let fstype = fstype.map(CString::new).transpose()?;
+    let data = data.map(CString::new).transpose()?;
+    let ptr = |s: &Option<CString>| s.as_ref().map_or(std::ptr::null(), |s| s.as_ptr());
+    // SAFETY: the arguments are valid NUL-terminated strings, or null.
+    let r = unsafe {
+        libc::mount(
+            ptr(&source),
+            target.as_ptr(),
+            ptr(&fstype),
+            flags,
+            ptr(&data).cast(),
+        )
+    };
+    if r != 0 {
+        let e = std::io::Error::last_os_error();
+        bail!("Mounting {target:?}: {e}");
+    }
+    Ok(())
+}
+
+/// Read the FAT mounts listed in the fake root at `root`.
+fn read_fat_mounts(root: &Path) -> Result<Vec<PathBuf>> {
+    let list = root.join(STATE_DIR.trim_start_matches('/')).join("fat");
+    let list = match std::fs::read_to_string(&list) {
+        Ok(l) => l,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Reading {list:?}")),
+    };
+    Ok(list
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| Path::new("/").join(l.trim_start_matches('/')))
+        .collect())
+}
+
+/// Simulate the operations of this process against the fake root `root`.
+/// Must be called while still single threaded, which entering a user
+/// namespace requires.
+#[context("Entering simulation against {root:?}")]
+pub(crate) fn enter(root: &Path) -> Result<()> {
+    let root = root
+        .canonicalize()
+        .with_context(|| format!("Opening {root:?}"))?;
+    std::fs::create_dir_all(root.join(STATE_DIR.trim_start_matches('/')))?;
+    for d in ["dev", "proc", "run", "tmp", "usr"] {
+        std::fs::create_dir_all(root.join(d))?;
+    }
+    for (link, target) in USR_LINKS {
+        let link = root.join(link);
+        if link.symlink_metadata().is_err() {
+            std::os::unix::fs::symlink(target, link)?;
+        }
+    }
+    let null = root.join("dev/null");
+    if !null.exists() {
+        std::fs::write(&null, "")?;
+    }
+    let fat_mounts = read_fat_mounts(&root)?;
+
+    let uid = rustix::process::getuid().as_raw();
+    let gid = rustix::process::getgid().as_raw();
+    // SAFETY: unshare() has no memory safety requirements.
+    if unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS) } != 0 {
+        let e = std::io::Error::last_os_error();
+        bail!("Creating a user namespace (are unprivileged user namespaces disabled?): {e}");
+    }
+    // Map ourselves to root, as which we're allowed to mount and chroot
+    std::fs::write("/proc/self/setgroups", "deny")?;
+    std::fs::write("/proc/self/uid_map", format!("0 {uid} 1"))?;
+    std::fs::write("/proc/self/gid_map", format!("0 {gid} 1"))?;
+    mount(
+        None,
+        Path::new("/"),
+        None,
+        libc::MS_REC | libc::MS_PRIVATE,
+        None,
+    )?;
+    let usr = root.join("usr");
+    let lowerdir = format!("lowerdir={}:/usr", usr.display());
+    mount(
+        Some(Path::new("overlay")),
+        &usr,
+        Some("overlay"),
+        libc::MS_RDONLY,
+        Some(&lowerdir),
+    )?;
+    mount(
+        Some(Path::new("/proc")),
+        &root.join("proc"),
+        None,
+        libc::MS_BIND | libc::MS_REC,
+        None,
+    )?;
+    mount(
+        Some(Path::new("/dev/null")),
+        &null,
+        None,
+        libc::MS_BIND,
+        None,
+    )?;
+    std::os::unix::fs::chroot(&root).context("chroot")?;
+    std::env::set_current_dir("/")?;
+    log::info!("Simulating against {root:?}");
+    // Unwrap safety: only entered once, from the CLI
+    FAT_MOUNTS.set(fat_mounts).unwrap();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_fat_mounts() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        assert!(read_fat_mounts(td.path())?.is_empty());
+        let state = td.path().join(STATE_DIR.trim_start_matches('/'));
+        std::fs::create_dir_all(&state)?;
+        std::fs::write(state.join("fat"), "# The ESP\nboot/efi\n\n/efi\n")?;
+        assert_eq!(
+            read_fat_mounts(td.path())?,
+            [Path::new("/boot/efi"), Path::new("/efi")]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,530 @@
+//! Hierarchical spans of the operations of an invocation.
+//!
+//! Log lines of components updated concurrently interleave, which makes it
+//! hard to follow a single update across modules.  With the `tracing` cargo
+//! feature, which is meant for library consumers, each operation is a
+//! [`tracing`] span named `bootupd` with the `operation` and, where known,
+//! the `component`, `device` and `bytes` copied, so that they flow to the
+//! consumer's subscriber.  Without it, spans do nothing, and byte counts
+//! are logged instead.
+//!
+//! With the `spans` cargo feature, `--trace-spans journal` or
+//! `--trace-spans PATH` installs a subscriber whose [`Recorder`] layer
+//! records each span when it closes: its path from the outermost span, such
+//! as `update{component=EFI}/copy`, its duration and its outcome.  Spans go
+//! to the journal as structured `BOOTUPD_SPAN*` fields, or are appended as
+//! compact lines to `PATH`.
+//!
+//! Spans nest per thread; threads working on behalf of a span continue it
+//! by attaching the [`Context`] of their parent.
+
+use anyhow::Result;
+
+#[cfg(feature = "spans")]
+use std::io::Write;
+#[cfg(feature = "spans")]
+use std::path::PathBuf;
+#[cfg(feature = "spans")]
+use std::sync::Mutex;
+#[cfg(feature = "spans")]
+use std::time::Instant;
+
+/// Where spans are recorded.
+#[cfg(feature = "spans")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Sink {
+    /// Structured journal entries
+    Journal,
+    /// Lines appended to a file
+    File(PathBuf),
+}
+
+#[cfg(feature = "spans")]
+impl std::str::FromStr for Sink {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "journal" {
+            return Ok(Self::Journal);
+        }
+        let path = PathBuf::from(s);
+        // We may re-exec via systemd-run, which doesn't preserve our working directory
+        if !path.is_absolute() {
+            anyhow::bail!("Expected `journal` or an absolute path: {s}");
+        }
+        Ok(Self::File(path))
+    }
+}
+
+/// Record spans to `sink` for the rest of the process.
+#[cfg(feature = "spans")]
+pub(crate) fn enable(sink: Sink) {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let subscriber = tracing_subscriber::registry().with(Recorder::new(sink));
+    if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
+        log::debug!("Not recording spans: {e}");
+    }
+}
+
+/// An operation in progress, which ends when dropped; see [`enter`].
+#[must_use]
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    tracing: tracing::span::EnteredSpan,
+}
+
+/// The tracing span of the span `name`; of `fields`, those declared here
+/// are recorded.
+#[cfg(feature = "tracing")]
+fn tracing_span(name: &str, fields: &[(&str, &str)]) -> tracing::Span {
+    use tracing::field::Empty;
+    let span = tracing::info_span!(
+        target: "bootupd",
+        "bootupd",
+        operation = name,
+        component = Empty,
+        device = Empty,
+        bytes = Empty
+    );
+    for (k, v) in fields {
+        span.record(*k, *v);
+    }
+    span
+}
+
+/// Enter the span `name`, nested in the current span of this thread.
+#[cfg(feature = "tracing")]
+pub(crate) fn enter(name: &str, fields: &[(&str, &str)]) -> Span {
+    Span {
+        tracing: tracing_span(name, fields).entered(),
+    }
+}
+
+/// Enter the span `name`, nested in the current span of this thread.
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn enter(_name: &str, _fields: &[(&str, &str)]) -> Span {
+    Span {}
+}
+
+/// Record that the operation of the current span copied `bytes`.
+#[cfg(have_filetree)]
+pub(crate) fn record_bytes(bytes: u64) {
+    #[cfg(feature = "tracing")]
+    tracing::Span::current().record("bytes", bytes);
+    #[cfg(not(feature = "tracing"))]
+    log::debug!("Copied {bytes} bytes");
+}
+
+/// Run `f` in the span `name`, recording whether it failed.
+pub(crate) fn in_span<T>(
+    name: &str,
+    fields: &[(&str, &str)],
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let mut span = enter(name, fields);
+    let r = f();
+    if let Err(e) = r.as_ref() {
+        span.fail(e);
+    }
+    r
+}
+
+impl Span {
+    /// Record that the operation failed with `e`.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn fail(&mut self, e: &anyhow::Error) {
+        tracing::debug!(target: "bootupd", parent: &*self.tracing, error = %format!("{e:#}"), "failed");
+    }
+
+    /// Record that the operation failed with `e`.
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn fail(&mut self, _e: &anyhow::Error) {}
+}
+
+/// The spans a thread is in, to be continued by another thread.
+pub(crate) struct Context {
+    #[cfg(feature = "tracing")]
+    tracing: tracing::Span,
+}
+
+/// Return the spans this thread is in.
+pub(crate) fn current() -> Context {
+    Context {
+        #[cfg(feature = "tracing")]
+        tracing: tracing::Span::current(),
+    }
+}
+
+/// Restores the previous spans of the thread when dropped; see
+/// [`Context::attach`].
+#[must_use]
+pub(crate) struct Attached {
+    #[cfg(feature = "tracing")]
+    _tracing: tracing::span::EnteredSpan,
+}
+
+impl Context {
+    /// Nest the spans of this thread in `self`.
+    pub(crate) fn attach(self) -> Attached {
+        Attached {
+            #[cfg(feature = "tracing")]
+            _tracing: self.tracing.entered(),
+        }
+    }
+}
+
+/// A layer recording the spans named `bootupd` to a [`Sink`] as they
+/// close.
+#[cfg(feature = "spans")]
+pub(crate) struct Recorder {
+    sink: Sink,
+    /// The file of [`Sink::File`], once opened
+    file: Mutex<Option<std::fs::File>>,
+}
+
+/// What is known of a span being recorded, kept in its extensions.
+#[cfg(feature = "spans")]
+struct Recording {
+    segment: SegmentVisitor,
+    start: Instant,
+    error: Option<String>,
+}
+
+/// Collects the segment of a span in paths from its fields, as they are
+/// recorded.
+#[cfg(feature = "spans")]
+#[derive(Default)]
+struct SegmentVisitor {
+    operation: String,
+    fields: Vec<String>,
+}
+
+#[cfg(feature = "spans")]
+impl tracing::field::Visit for SegmentVisitor {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        match field.name() {
+            "operation" => self.operation = value.to_string(),
+            // Not known when the span is entered, so not part of its path
+            "bytes" => {}
+            k => self.fields.push(format!("{k}={value}")),
+        }
+    }
+
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{value:?}"));
+    }
+}
+
+#[cfg(feature = "spans")]
+impl SegmentVisitor {
+    /// The segment, e.g. `update{component=EFI}`.
+    fn segment(&self) -> String {
+        if self.fields.is_empty() {
+            return self.operation.clone();
+        }
+        format!("{}{{{}}}", self.operation, self.fields.join(","))
+    }
+}
+
+/// Collects the `error` field of a failure event.
+#[cfg(feature = "spans")]
+#[derive(Default)]
+struct ErrorVisitor(Option<String>);
+
+#[cfg(feature = "spans")]
+impl tracing::field::Visit for ErrorVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "error" {
+            self.0 = Some(format!("{value:?}"));
+        }
+    }
+}
+
+#[cfg(feature = "spans")]
+impl Recorder {
+    pub(crate) fn new(sink: Sink) -> Self {
+        Self {
+            sink,
+            file: Mutex::new(None),
+        }
+    }
+
+    fn record(&self, path: &str, duration_us: u64, error: Option<&str>) -> Result<()> {
+        match &self.sink {
+            Sink::Journal => {
+                if !libsystemd::logging::connected_to_journal() {
+                    return Ok(());
+                }
+                let duration = duration_us.to_string();
+                let mut fields = vec![
+                    ("BOOTUPD_SPAN", path),
+                    ("BOOTUPD_SPAN_DURATION_US", duration.as_str()),
+                    (
+                        "BOOTUPD_SPAN_RESULT",
+                        if error.is_some() { "error" } else { "ok" },
+                    ),
+                ];
+                let (priority, msg) = match error {
+                    None => (
+                        libsystemd::logging::Priority::Info,
+                        format!("bootupd: {path} completed in {duration_us}us"),
+                    ),
+                    Some(e) => {
+                        fields.push(("BOOTUPD_SPAN_ERROR", e));
+                        (
+                            libsystemd::logging::Priority::Warning,
+                            format!("bootupd: {path} failed after {duration_us}us: {e}"),
+                        )
+                    }
+                };
+                libsystemd::logging::journal_send(priority, &msg, fields.into_iter())?;
+            }
+            Sink::File(p) => {
+                let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+                if file.is_none() {
+                    *file = Some(
+                        std::fs::OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(p)?,
+                    );
+                }
+                // Unwrap safety: opened above
+                let f = file.as_mut().unwrap();
+                f.write_all(compact_line(path, duration_us, error).as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "spans")]
+impl<S> tracing_subscriber::Layer<S> for Recorder
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        if attrs.metadata().name() != "bootupd" {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor = SegmentVisitor::default();
+        attrs.record(&mut visitor);
+        span.extensions_mut().insert(Recording {
+            segment: visitor,
+            start: Instant::now(),
+            error: None,
+        });
+    }
+
+    fn on_record(
+        &self,
+        id: &tracing::span::Id,
+        values: &tracing::span::Record<'_>,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut ext = span.extensions_mut();
+        if let Some(r) = ext.get_mut::<Recording>() {
+            values.record(&mut r.segment);
+        }
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let mut visitor = ErrorVisitor::default();
+        event.record(&mut visitor);
+        let Some(error) = visitor.0 else {
+            return;
+        };
+        let mut ext = span.extensions_mut();
+        if let Some(r) = ext.get_mut::<Recording>() {
+            r.error = Some(error);
+        }
+    }
+
+    fn on_close(&self, id: tracing::span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let path = span
+            .scope()
+            .from_root()
+            .filter_map(|s| {
+                let ext = s.extensions();
+                ext.get::<Recording>().map(|r| r.segment.segment())
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        let ext = span.extensions();
+        let Some(r) = ext.get::<Recording>() else {
+            return;
+        };
+        let error = if std::thread::panicking() {
+            Some("panicked")
+        } else {
+            r.error.as_deref()
+        };
+        let duration_us: u64 = r.start.elapsed().as_micros().try_into().unwrap_or(u64::MAX);
+        if let Err(e) = self.record(&path, duration_us, error) {
+            log::debug!("Failed to record span {path}: {e}");
+        }
+    }
+}
+
+/// Format a span for [`Sink::File`].
+#[cfg(feature = "spans")]
+fn compact_line(path: &str, duration_us: u64, error: Option<&str>) -> String {
+    let ts = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.6fZ");
+    match error {
+        None => format!("{ts} {path} {duration_us}us ok\n"),
+        Some(e) => format!(
+            "{ts} {path} {duration_us}us error: {}\n",
+            e.replace('\n', " ")
+        ),
+    }
+}
+
+#[cfg(all(test, feature = "spans"))]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_spans() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let log = td.path().join("spans.log");
+        assert!("relative/path".parse::<Sink>().is_err());
+        let sink = format!("{}", log.display()).parse()?;
+        let dispatch =
+            tracing::Dispatch::new(tracing_subscriber::registry().with(Recorder::new(sink)));
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _update = enter("update", &[]);
+            let context = current();
+            std::thread::scope(|s| {
+                s.spawn(|| {
+                    tracing::dispatcher::with_default(&dispatch, || {
+                        {
+                            let _attached = context.attach();
+                            let _ =
+                                in_span("install", &[("component", "EFI")], || -> Result<()> {
+                                    let _copy = enter("copy", &[]);
+                                    Ok(())
+                                });
+                            in_span("install", &[("component", "BIOS")], || -> Result<()> {
+                                anyhow::bail!("no\ndevice")
+                            })
+                            .unwrap_err();
+                        }
+                        // The thread's own spans are unaffected by attaching
+                        assert!(tracing::Span::current().is_none());
+                    });
+                });
+            });
+        });
+        let content = std::fs::read_to_string(&log)?;
+        let spans = content
+            .lines()
+            .map(|l| {
+                let mut parts = l.splitn(4, ' ');
+                let _ts = parts.next();
+                let path = parts.next().unwrap().to_string();
+                let _duration = parts.next();
+                (path, parts.next().unwrap().to_string())
+            })
+            .collect::<Vec<_>>();
+        let result = |path: &str, result: &str| (path.to_string(), result.to_string());
+        assert_eq!(
+            spans,
+            [
+                result("update/install{component=EFI}/copy", "ok"),
+                result("update/install{component=EFI}", "ok"),
+                result("update/install{component=BIOS}", "error: no device"),
+                result("update", "ok"),
+            ]
+        );
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tracing_tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Mutex;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata};
+
+    /// Records the fields of the spans created, as `name=value`.
+    #[derive(Default)]
+    struct Recorder {
+        next_id: AtomicU64,
+        fields: Mutex<Vec<String>>,
+    }
+
+    struct Fields<'a>(&'a mut Vec<String>);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.push(format!("{}={value:?}", field.name()));
+        }
+
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.push(format!("{}={value}", field.name()));
+        }
+    }
+
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            span.record(&mut Fields(&mut self.fields.lock().unwrap()));
+            Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+
+        fn record(&self, _: &Id, values: &Record<'_>) {
+            values.record(&mut Fields(&mut self.fields.lock().unwrap()));
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn test_tracing() {
+        let recorder = std::sync::Arc::new(Recorder::default());
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let _update = enter("update", &[("component", "EFI"), ("unknown", "x")]);
+            let _install = enter("grub-install", &[("device", "/dev/vda")]);
+        });
+        assert_eq!(
+            *recorder.fields.lock().unwrap(),
+            [
+                "operation=update",
+                "component=EFI",
+                "operation=grub-install",
+                "device=/dev/vda"
+            ]
+        );
+    }
+}
@@ -0,0 +1,152 @@
+//! The system the BIOS component operates on, behind a trait so that its
+//! logic (which devices to install to, whether to adopt, what to run) can
+//! be unit-tested without root or real disks.
+//!
+//! [`crate::bios::Bios`] holds a [`System`], which is the running [`Host`]
+//! outside of tests; so do the partitioning helpers it calls.  Files are
+//! accessed below [`System::root`], block devices are queried through the
+//! [`Topology`] it returns and external tools are run via
+//! [`System::output`].  In tests, a [`Mock`] provides a temporary
+//! directory as the root, a topology parsed from lsblk output and canned
+//! command results, recording the commands run.
+//!
+//! The other components still access the running system directly.
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+use anyhow::Result;
+
+use crate::blockdev::Topology;
+
+/// Access to the system for components.
+pub(crate) trait System: Send + Sync {
+    /// The root filesystem of the system, e.g. to detect its distribution
+    /// and look up the tools and modules it ships.
+    fn root(&self) -> &Path;
+
+    /// The block devices of the system.
+    fn topology(&self) -> Result<&Topology>;
+
+    /// Whether the system was booted via EFI.
+    #[cfg(target_arch = "x86_64")]
+    fn is_efi_booted(&self) -> Result<bool>;
+
+    /// Run `cmd` to completion, capturing its output; failures which are
+    /// likely transient, such as a device briefly held by udev, are retried.
+    fn output(&self, cmd: &mut Command) -> Result<Output>;
+}
+
+/// The running system.
+#[derive(Default)]
+pub(crate) struct Host;
+
+impl System for Host {
+    fn root(&self) -> &Path {
+        Path::new("/")
+    }
+
+    fn topology(&self) -> Result<&Topology> {
+        Topology::get()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn is_efi_booted(&self) -> Result<bool> {
+        crate::firmware::is_efi_booted()
+    }
+
+    fn output(&self, cmd: &mut Command) -> Result<Output> {
+        crate::util::output_retrying(cmd)
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+pub(crate) use mock::Mock;
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod mock {
+    use std::collections::BTreeMap;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::PathBuf;
+    use std::process::ExitStatus;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// A system with a fixed root, topology and firmware, which records the
+    /// commands run instead of running them.
+    pub(crate) struct Mock {
+        root: PathBuf,
+        topology: Topology,
+        efi_booted: bool,
+        /// The exit codes and output of commands, by program file name;
+        /// others succeed without output.
+        outputs: BTreeMap<String, (i32, String)>,
+        commands: Mutex<Vec<String>>,
+    }
+
+    impl Mock {
+        /// A BIOS booted system rooted at `root` with the block devices
+        /// listed in the lsblk JSON output `lsblk`.
+        pub(crate) fn new(root: &Path, lsblk: &str) -> Result<Self> {
+            Ok(Self {
+                root: root.to_owned(),
+                topology: Topology::parse(lsblk)?,
+                efi_booted: false,
+                outputs: BTreeMap::new(),
+                commands: Mutex::new(Vec::new()),
+            })
+        }
+
+        pub(crate) fn efi_booted(mut self, efi_booted: bool) -> Self {
+            self.efi_booted = efi_booted;
+            self
+        }
+
+        /// Make `program` exit with `code`, printing `stdout`.
+        pub(crate) fn with_output(mut self, program: &str, code: i32, stdout: &str) -> Self {
+            self.outputs
+                .insert(program.to_string(), (code, stdout.to_string()));
+            self
+        }
+
+        /// The commands run so far, each as its program file name and
+        /// arguments separated with spaces.
+        pub(crate) fn commands(&self) -> Vec<String> {
+            self.commands.lock().unwrap().clone()
+        }
+    }
+
+    impl System for Mock {
+        fn root(&self) -> &Path {
+            &self.root
+        }
+
+        fn topology(&self) -> Result<&Topology> {
+            Ok(&self.topology)
+        }
+
+        fn is_efi_booted(&self) -> Result<bool> {
+            Ok(self.efi_booted)
+        }
+
+        fn output(&self, cmd: &mut Command) -> Result<Output> {
+            let program = Path::new(cmd.get_program())
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            let line = std::iter::once(program.clone())
+                .chain(cmd.get_args().map(|a| a.to_string_lossy().into_owned()))
+                .collect::<Vec<_>>()
+                .join(" ");
+            self.commands.lock().unwrap().push(line);
+            let (code, stdout) = self.outputs.get(&program).cloned().unwrap_or_default();
+            Ok(Output {
+                status: ExitStatus::from_raw(code << 8),
+                stdout: stdout.into_bytes(),
+                stderr: Vec::new(),
+            })
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Optional pacing of the writes of an update.
+//!
+//! On a busy machine, writing a bootloader update as fast as the disk
+//! allows can starve latency-sensitive workloads sharing it.  With a rate
+//! limit set (`bootupctl update --write-rate-limit`), each copied file is
+//! flushed as it's written and copying pauses whenever it gets ahead of the
+//! limit, so the disk sees a steady trickle rather than a burst at sync
+//! time.
+
+#[cfg(have_filetree)]
+use std::sync::Mutex;
+#[cfg(have_filetree)]
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+/// Parse a rate such as `512K` or `10M`, in bytes per second; suffixes are
+/// powers of 1024 as for systemd's `IOWriteBandwidthMax=`.
+pub(crate) fn parse_rate(s: &str) -> Result<u64> {
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'K')) => (&s[..i], 10),
+        Some((i, 'M')) => (&s[..i], 20),
+        Some((i, 'G')) => (&s[..i], 30),
+        _ => (s, 0),
+    };
+    let n: u64 = digits
+        .parse()
+        .with_context(|| format!("Invalid rate {s:?}"))?;
+    let r = n
+        .checked_mul(1 << shift)
+        .ok_or_else(|| anyhow::anyhow!("Rate {s:?} is too large"))?;
+    if r == 0 {
+        anyhow::bail!("Rate must be positive");
+    }
+    Ok(r)
+}
+
+/// Return when writing `n` more bytes at `now` is due to be done, if the
+/// previous writes were due at `previous`.  Idle time isn't credited, so
+/// writing can't burst after a pause.
+#[cfg(have_filetree)]
+fn schedule(previous: Option<Instant>, now: Instant, n: u64, rate: u64) -> Instant {
+    let start = previous.map_or(now, |p| p.max(now));
+    start + Duration::from_secs_f64(n as f64 / rate as f64)
+}
+
+/// Account for `n` bytes just written to `w`, flushing them and sleeping
+/// as needed to stay within `rate` bytes per second.  The pace is shared by
+/// all threads, i.e. components updated concurrently.
+#[cfg(have_filetree)]
+pub(crate) fn wrote(w: &mut std::io::BufWriter<std::fs::File>, n: usize, rate: u64) -> Result<()> {
+    static NEXT: Mutex<Option<Instant>> = Mutex::new(None);
+    // Otherwise the writes would only reach the disk at sync time
+    std::io::Write::flush(w)?;
+    rustix::fs::fdatasync(w.get_ref())?;
+    let now = Instant::now();
+    let due = {
+        let mut next = NEXT.lock().unwrap_or_else(|e| e.into_inner());
+        let due = schedule(*next, now, n as u64, rate);
+        *next = Some(due);
+        due
+    };
+    std::thread::sleep(due.saturating_duration_since(now));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() -> Result<()> {
+        assert_eq!(parse_rate("4096")?, 4096);
+        assert_eq!(parse_rate("512K")?, 512 * 1024);
+        assert_eq!(parse_rate("10M")?, 10 * 1024 * 1024);
+        assert_eq!(parse_rate("1G")?, 1024 * 1024 * 1024);
+        for invalid in ["", "0", "M", "10MB", "-1K", "99999999999G"] {
+            assert!(parse_rate(invalid).is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(have_filetree)]
+    fn test_schedule() {
+        let rate = 1024 * 1024;
+        let now = Instant::now();
+        let second = Duration::from_secs(1);
+        let t = schedule(None, now, rate, rate);
+        assert_eq!(t, now + second);
+        // Back to back writes queue up
+        assert_eq!(schedule(Some(t), now, rate / 2, rate), t + second / 2);
+        // But a pause doesn't allow a burst
+        let later = now + 10 * second;
+        assert_eq!(schedule(Some(t), later, rate, rate), later + second);
+    }
+}
@@ -0,0 +1,169 @@
+//! Optional instrumentation of where the time of an invocation goes.
+//!
+//! With `--timing`, the durations of the phases below are recorded as
+//! they run, and a JSON summary is printed to stderr on exit.  Phases of
+//! components updated concurrently are summed, so a phase can add up to
+//! more than the total.  When disabled, recording is a single atomic load.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// The instrumented phases.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Phase {
+    /// Reading the block device topology
+    #[cfg(have_blockdev)]
+    DeviceResolution,
+    /// Computing file digests
+    Hashing,
+    /// Copying payload files into place
+    #[cfg(have_filecopy)]
+    Copy,
+    /// Waiting for writes to reach the disk
+    Sync,
+    /// Reading and writing EFI boot entries
+    #[cfg(have_efi)]
+    Nvram,
+}
+
+impl Phase {
+    /// The name of the phase, as serialized.
+    fn name(self) -> &'static str {
+        match self {
+            #[cfg(have_blockdev)]
+            Phase::DeviceResolution => "device-resolution",
+            Phase::Hashing => "hashing",
+            #[cfg(have_filecopy)]
+            Phase::Copy => "copy",
+            Phase::Sync => "sync",
+            #[cfg(have_efi)]
+            Phase::Nvram => "nvram",
+        }
+    }
+}
+
+/// The time spent in a phase.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct PhaseTiming {
+    /// How often the phase was entered
+    pub(crate) count: u64,
+    pub(crate) total_us: u64,
+}
+
+/// The summary printed with `--timing`.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct TimingReport {
+    /// Time since timing was enabled
+    pub(crate) total_us: u64,
+    pub(crate) phases: BTreeMap<Phase, PhaseTiming>,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static PHASES: Mutex<BTreeMap<Phase, PhaseTiming>> = Mutex::new(BTreeMap::new());
+
+fn micros(d: Duration) -> u64 {
+    d.as_micros().try_into().unwrap_or(u64::MAX)
+}
+
+/// Start recording for the rest of the process.
+pub(crate) fn enable() {
+    STARTED.get_or_init(Instant::now);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Records the time until it's dropped; see [`start`].  The phase is
+/// also recorded as a span, see [`crate::spans`].
+#[must_use]
+pub(crate) struct Timer {
+    phase: Phase,
+    start: Option<Instant>,
+    _span: crate::spans::Span,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        let Some(start) = self.start else {
+            return;
+        };
+        let elapsed = micros(start.elapsed());
+        let mut phases = PHASES.lock().unwrap_or_else(|e| e.into_inner());
+        let t = phases.entry(self.phase).or_default();
+        t.count += 1;
+        t.total_us = t.total_us.saturating_add(elapsed);
+    }
+}
+
+/// Attribute the time until the returned [`Timer`] is dropped to `phase`.
+pub(crate) fn start(phase: Phase) -> Timer {
+    let start = ENABLED.load(Ordering::Relaxed).then(Instant::now);
+    let _span = crate::spans::enter(phase.name(), &[]);
+    Timer {
+        phase,
+        start,
+        _span,
+    }
+}
+
+/// Return the recorded timings, if enabled.
+pub(crate) fn report() -> Option<TimingReport> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let total_us = STARTED
+        .get()
+        .map(|s| micros(s.elapsed()))
+        .unwrap_or_default();
+    let phases = PHASES.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    Some(TimingReport { total_us, phases })
+}
+
+/// Print the recorded timings to stderr, if enabled; also to be called
+/// before exiting early.
+pub(crate) fn emit() {
+    if let Some(report) = report() {
+        match serde_json::to_string(&report) {
+            Ok(s) => eprintln!("{s}"),
+            Err(e) => log::warn!("Failed to serialize timing report: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timing() -> anyhow::Result<()> {
+        enable();
+        for _ in 0..2 {
+            let _t = start(Phase::Sync);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let r = report().unwrap();
+        let sync = r.phases[&Phase::Sync];
+        assert!(sync.count >= 2);
+        assert!(sync.total_us >= 2000);
+        assert!(r.total_us >= sync.total_us);
+        let v = serde_json::to_value(&r)?;
+        assert!(v["phases"]["sync"]["total-us"].is_u64());
+        for phase in [Phase::Hashing, Phase::Sync] {
+            assert_eq!(serde_json::to_value(phase)?, phase.name());
+        }
+        #[cfg(have_filecopy)]
+        assert_eq!(serde_json::to_value(Phase::Copy)?, "copy");
+        #[cfg(have_blockdev)]
+        assert_eq!(
+            serde_json::to_value(Phase::DeviceResolution)?,
+            "device-resolution"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,573 @@
+//! U-Boot firmware on single-board computers, written to raw offsets of
+//! the boot disk or to a dedicated firmware partition.
+//!
+//! Each supported board is described by a JSON profile in [`BOARDS_DIR`],
+//! shipped in /usr along with the U-Boot builds.
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+use openat_ext::OpenatDirExt;
+use openssl::hash::{Hasher, MessageDigest};
+use serde::Deserialize;
+
+use crate::component::*;
+use crate::filetree::FileTree;
+use crate::model::*;
+use crate::sha512string::SHA512String;
+
+/// Board profiles, relative to the root.
+pub(crate) const BOARDS_DIR: &str = "usr/lib/bootupd/uboot";
+
+/// The device tree compatible strings of the running system
+const DT_COMPATIBLE: &str = "/proc/device-tree/compatible";
+
+/// Where a U-Boot image is written.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Target {
+    /// Byte offset from the start of the boot disk
+    Offset(u64),
+    /// Start of the partition with this GPT partition label
+    Partition(String),
+}
+
+/// A U-Boot image to write.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+struct Image {
+    /// File name in the board's source directory
+    file: String,
+    #[serde(flatten)]
+    target: Target,
+}
+
+/// A board profile, e.g. `/usr/lib/bootupd/uboot/rock64-rk3328.json`.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+struct Board {
+    /// Device tree compatible strings identifying the board
+    compatible: Vec<String>,
+    /// Directory containing the U-Boot build, relative to the root
+    source: String,
+    /// Images in the order they're written
+    images: Vec<Image>,
+}
+
+/// Load all board profiles shipped in `root`, keyed by name.
+fn load_boards(root: &openat::Dir) -> Result<BTreeMap<String, Board>> {
+    let mut r = BTreeMap::new();
+    let Some(dir) = root.sub_dir_optional(BOARDS_DIR)? else {
+        return Ok(r);
+    };
+    for entry in dir.list_dir(".")? {
+        let entry = entry?;
+        let Some(name) = entry
+            .file_name()
+            .to_str()
+            .and_then(|n| n.strip_suffix(".json"))
+        else {
+            continue;
+        };
+        let f = std::io::BufReader::new(dir.open_file(entry.file_name())?);
+        let board: Board =
+            serde_json::from_reader(f).with_context(|| format!("Parsing board profile {name}"))?;
+        r.insert(name.to_string(), board);
+    }
+    Ok(r)
+}
+
+/// Find the profile for the board whose NUL separated device tree
+/// `compatible` strings are given.
+fn match_board<'a>(
+    boards: &'a BTreeMap<String, Board>,
+    compatible: &[u8],
+) -> Option<(&'a str, &'a Board)> {
+    // The strings are most specific first
+    let compatible = compatible
+        .split(|&b| b == 0)
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| std::str::from_utf8(s).ok());
+    for c in compatible {
+        if let Some((name, board)) = boards
+            .iter()
+            .find(|(_, b)| b.compatible.iter().any(|bc| bc == c))
+        {
+            return Some((name.as_str(), board));
+        }
+    }
+    None
+}
+
+/// Determine the board we're running on.
+fn detect_board(boards: &BTreeMap<String, Board>) -> Result<Option<(&str, &Board)>> {
+    let compatible = match std::fs::read(DT_COMPATIBLE) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context(DT_COMPATIBLE),
+    };
+    Ok(match_board(boards, &compatible))
+}
+
+/// Determine the board a target root is installed for: the one given
+/// explicitly, or else the only one it has a profile for.  The board the
+/// installer runs on says nothing about the target.
+fn target_board<'a>(
+    boards: &'a BTreeMap<String, Board>,
+    dest_boards: &BTreeMap<String, Board>,
+    explicit: Option<&str>,
+) -> Result<&'a str> {
+    let name = match explicit {
+        Some(name) => name,
+        None if dest_boards.len() == 1 => dest_boards.keys().next().unwrap(),
+        None if dest_boards.is_empty() => {
+            bail!("No board profile in the target root; a board must be given")
+        }
+        None => bail!(
+            "The target root has profiles for several boards ({}); a board must be given",
+            dest_boards.keys().cloned().collect::<Vec<_>>().join(", ")
+        ),
+    };
+    boards
+        .get_key_value(name)
+        .map(|(k, _)| k.as_str())
+        .ok_or_else(|| anyhow::anyhow!("Unknown board {name}"))
+}
+
+/// Write `data` (`name` is used for messages) at `offset` of `dev`, and if
+/// `verify`, read it back; see [`crate::readback`].
+fn write_image(dev: &Path, offset: u64, name: &str, data: &[u8], verify: bool) -> Result<()> {
+    let f = OpenOptions::new()
+        .write(true)
+        .open(dev)
+        .with_context(|| format!("Opening {dev:?}"))?;
+    f.write_all_at(data, offset)
+        .with_context(|| format!("Writing {name} to {dev:?} at offset {offset}"))?;
+    f.sync_all()?;
+    if verify {
+        crate::readback::verify_at(dev, offset, name, data)?;
+    }
+    log::debug!("Wrote {name} to {dev:?} at offset {offset}");
+    Ok(())
+}
+
+/// Compute the checksum of `len` bytes at `offset` of `dev`.
+fn checksum_at(dev: &Path, offset: u64, len: u64) -> Result<SHA512String> {
+    let mut f = std::fs::File::open(dev).with_context(|| format!("Opening {dev:?}"))?;
+    std::io::Seek::seek(&mut f, std::io::SeekFrom::Start(offset))?;
+    let mut hasher = Hasher::new(MessageDigest::sha512())?;
+    let n = std::io::copy(&mut f.take(len), &mut hasher)?;
+    if n != len {
+        bail!("Short read from {dev:?}");
+    }
+    Ok(SHA512String::from_hasher(&mut hasher))
+}
+
+/// Return the offset (in bytes) of the first partition on `disk`.
+fn first_partition_start(disk: &Path) -> Result<Option<u64>> {
+    let Some(name) = disk.file_name() else {
+        bail!("Invalid disk {disk:?}");
+    };
+    let sysdir = Path::new("/sys/class/block").join(name);
+    let mut r = None;
+    for entry in std::fs::read_dir(&sysdir).with_context(|| format!("Reading {sysdir:?}"))? {
+        let start = entry?.path().join("start");
+        if !start.exists() {
+            continue;
+        }
+        // Always in units of 512 byte sectors
+        let sectors: u64 = std::fs::read_to_string(&start)?.trim().parse()?;
+        let start = sectors * 512;
+        r = Some(r.map_or(start, |r: u64| r.min(start)));
+    }
+    Ok(r)
+}
+
+/// Find the partition labeled `label` on `disk`.
+fn partition_by_label(disk: &Path, label: &str) -> Result<PathBuf> {
+    let disk = disk
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("Invalid disk {disk:?}"))?;
+    crate::blockdev::Topology::get()?
+        .partition_by_label(disk, label)
+        .map(Into::into)
+}
+
+#[derive(Default)]
+pub(crate) struct Uboot {
+    opts: crate::component::Options,
+}
+
+impl Uboot {
+    /// The U-Boot component, writing as `opts` tell; see
+    /// [`crate::component::Options::uboot_board`].
+    pub(crate) fn new(opts: crate::component::Options) -> Self {
+        Self { opts }
+    }
+
+    /// The disk U-Boot was recorded as written to in `current` if it still
+    /// exists, or else the disk containing /boot.
+    fn target_disk(&self, current: &InstalledContent) -> Result<PathBuf> {
+        match crate::blockdev::resolve_ids(&current.devices) {
+            Some(disks) if disks.len() == 1 => Ok(disks[0].clone()),
+            _ => self.boot_disk(),
+        }
+    }
+
+    /// The persistent name to record `disk` by in state.
+    fn disk_ids(disk: &Path) -> Vec<PathBuf> {
+        crate::blockdev::stable_ids(&[disk])
+    }
+
+    /// The disk containing /boot, where U-Boot is read from by the SoC ROM.
+    fn boot_disk(&self) -> Result<PathBuf> {
+        let boot = openat::Dir::open("/boot")?;
+        let fsinfo = crate::filesystem::inspect_filesystem(&boot, ".")?;
+        crate::blockdev::Topology::get()?
+            .parent_disk(&fsinfo.source)
+            .map(Into::into)
+    }
+
+    /// Write the images of `board` from the payload in `srcdir` to `disk`.
+    #[context("Writing U-Boot for {name}")]
+    fn write_board(
+        &self,
+        srcdir: &openat::Dir,
+        name: &str,
+        board: &Board,
+        disk: &Path,
+    ) -> Result<()> {
+        let reserved = first_partition_start(disk)?;
+        for image in board.images.iter() {
+            let path = format!("{name}/{}", image.file);
+            let mut data = Vec::new();
+            srcdir
+                .open_file(path.as_str())
+                .with_context(|| format!("Opening {path}"))?
+                .read_to_end(&mut data)?;
+            let (dev, offset) = match &image.target {
+                Target::Offset(offset) => {
+                    let end = offset + data.len() as u64;
+                    if reserved.is_some_and(|r| end > r) {
+                        bail!(
+                            "{} would overwrite the first partition of {disk:?}",
+                            image.file
+                        );
+                    }
+                    (disk.to_owned(), *offset)
+                }
+                Target::Partition(label) => (partition_by_label(disk, label)?, 0),
+            };
+            write_image(&dev, offset, &image.file, &data, self.opts.verify_writes)?;
+        }
+        Ok(())
+    }
+
+    /// Write the payload for the detected board to `disk`.
+    fn install_to(
+        &self,
+        root: &openat::Dir,
+        boards: &BTreeMap<String, Board>,
+        name: &str,
+        disk: &Path,
+    ) -> Result<FileTree> {
+        let board = boards
+            .get(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown board {name}"))?;
+        let srcdir = root
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        self.write_board(&srcdir, name, board, disk)?;
+        Self::board_tree(&srcdir, name)
+    }
+
+    /// The files of the payload in `srcdir` for the board `name`.
+    fn board_tree(srcdir: &openat::Dir, name: &str) -> Result<FileTree> {
+        let mut ft = FileTree::new_from_dir(srcdir)?;
+        let prefix = format!("{name}/");
+        ft.children.retain(|k, _| k.starts_with(&prefix));
+        Ok(ft)
+    }
+
+    /// The images of `board` (named `name`) on `disk` which don't match
+    /// those recorded in `ft`.
+    fn check_images(name: &str, board: &Board, ft: &FileTree, disk: &Path) -> Result<Vec<String>> {
+        let mut errs = Vec::new();
+        for image in board.images.iter() {
+            let key = format!("{name}/{}", image.file);
+            let Some(expected) = ft.children.get(&key) else {
+                errs.push(format!("Not installed: {key}"));
+                continue;
+            };
+            let (dev, offset) = match &image.target {
+                Target::Offset(offset) => (disk.to_owned(), *offset),
+                Target::Partition(label) => (partition_by_label(disk, label)?, 0),
+            };
+            if checksum_at(&dev, offset, expected.size)? != expected.sha512 {
+                errs.push(format!("Changed: {}", image.file));
+            }
+        }
+        Ok(errs)
+    }
+
+    /// The board recorded in the installed file tree.
+    fn installed_board(current: &InstalledContent) -> Result<&str> {
+        current
+            .filetree
+            .as_ref()
+            .and_then(|ft| ft.children.keys().next())
+            .and_then(|k| k.split_once('/'))
+            .map(|(board, _)| board)
+            .ok_or_else(|| anyhow::anyhow!("No board recorded for installed U-Boot"))
+    }
+}
+
+impl Component for Uboot {
+    fn name(&self) -> &'static str {
+        "UBOOT"
+    }
+
+    fn query_adopt(&self) -> Result<Option<Adoptable>> {
+        let root = openat::Dir::open("/")?;
+        let boards = load_boards(&root)?;
+        if detect_board(&boards)?.is_none() {
+            log::trace!("No supported board detected");
+            return Ok(None);
+        }
+        // Overwriting firmware we didn't install is never automatic
+        Ok(crate::component::query_adopt_state()?.map(|a| Adoptable {
+            confident: false,
+            ..a
+        }))
+    }
+
+    fn adopt_update(
+        &self,
+        sysroot: &openat::Dir,
+        update: &ContentMetadata,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = self.query_adopt()? else {
+            anyhow::bail!("Failed to find adoptable system")
+        };
+        let boards = load_boards(sysroot)?;
+        let Some((name, _)) = detect_board(&boards)? else {
+            anyhow::bail!("No supported board detected");
+        };
+        let disk = self.boot_disk()?;
+        let ft = self.install_to(sysroot, &boards, name, &disk)?;
+        Ok(InstalledContent {
+            meta: update.clone(),
+            filetree: Some(ft),
+            adopted_from: Some(meta.version),
+            devices: Self::disk_ids(&disk),
+        })
+    }
+
+    fn install(
+        &self,
+        src_root: &openat::Dir,
+        dest_root: &str,
+        device: Option<&Path>,
+        _update_firmware: bool,
+    ) -> Result<InstalledContent> {
+        let Some(meta) = get_component_update(src_root, self)? else {
+            return Err(crate::errors::Error::payload_missing(self.name()).into());
+        };
+        let Some(device) = device else {
+            anyhow::bail!("A target device is required to install U-Boot");
+        };
+        let boards = load_boards(src_root)?;
+        let dest_boards = load_boards(&openat::Dir::open(dest_root)?)?;
+        let explicit = self.opts.uboot_board.as_deref();
+        let name = target_board(&boards, &dest_boards, explicit)?;
+        let ft = self.install_to(src_root, &boards, name, device)?;
+        Ok(InstalledContent {
+            meta,
+            filetree: Some(ft),
+            adopted_from: None,
+            devices: Vec::new(),
+        })
+    }
+
+    fn generate_update_metadata(&self, sysroot_path: &str) -> Result<ContentMetadata> {
+        let sysroot = openat::Dir::open(sysroot_path)?;
+        let boards = load_boards(&sysroot)?;
+        if boards.is_empty() {
+            bail!("No board profiles found in {BOARDS_DIR}");
+        }
+        let dest = component_updatedir(sysroot_path, self);
+        let mut files = Vec::new();
+        for (name, board) in boards.iter() {
+            let destdir = dest.join(name);
+            std::fs::create_dir_all(&destdir)?;
+            for image in board.images.iter() {
+                let src = Path::new("/").join(&board.source).join(&image.file);
+                let rootsrc = Path::new(sysroot_path).join(src.strip_prefix("/")?);
+                std::fs::copy(&rootsrc, destdir.join(&image.file))
+                    .with_context(|| format!("Copying {rootsrc:?}"))?;
+                files.push(src);
+            }
+        }
+        let meta = write_packaged_update_metadata(sysroot_path, self, files)?;
+        Ok(meta)
+    }
+
+    fn query_update(&self, sysroot: &openat::Dir) -> Result<Option<ContentMetadata>> {
+        get_component_update(sysroot, self)
+    }
+
+    fn run_update(
+        &self,
+        sysroot: &openat::Dir,
+        current: &InstalledContent,
+    ) -> Result<InstalledContent> {
+        let updatemeta = self.query_update(sysroot)?.expect("update available");
+        let name = Self::installed_board(current)?;
+        let boards = load_boards(&openat::Dir::open("/")?)?;
+        let disk = self.target_disk(current)?;
+        let ft = self.install_to(sysroot, &boards, name, &disk)?;
+        Ok(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(ft),
+            adopted_from: None,
+            devices: Self::disk_ids(&disk),
+        })
+    }
+
+    fn validate(&self, current: &InstalledContent) -> Result<ValidationResult> {
+        let name = Self::installed_board(current)?;
+        let boards = load_boards(&openat::Dir::open("/")?)?;
+        let Some(board) = boards.get(name) else {
+            return Ok(ValidationResult::Errors(vec![format!(
+                "No profile for board {name}"
+            )]));
+        };
+        let ft = current.filetree.as_ref().unwrap();
+        let disk = self.target_disk(current)?;
+        let errs = Self::check_images(name, board, ft, &disk)?;
+        if errs.is_empty() {
+            Ok(ValidationResult::Valid)
+        } else {
+            Ok(ValidationResult::Errors(errs))
+        }
+    }
+
+    fn fingerprint_installed(&self, sysroot: &openat::Dir) -> Result<Option<InstalledContent>> {
+        let boards = load_boards(sysroot)?;
+        let Some((name, board)) = detect_board(&boards)? else {
+            return Ok(None);
+        };
+        let Some(updatemeta) = self.query_update(sysroot)? else {
+            return Err(crate::errors::Error::payload_missing(self.name()).into());
+        };
+        let srcdir = sysroot
+            .sub_dir(&component_updatedirname(self))
+            .context("opening update dir")?;
+        let ft = Self::board_tree(&srcdir, name)?;
+        let disk = self.boot_disk()?;
+        // Firmware which doesn't match the payload may not be ours at all
+        let errs = Self::check_images(name, board, &ft, &disk)?;
+        if !errs.is_empty() {
+            log::debug!("U-Boot on {disk:?} doesn't match the payload: {errs:?}");
+            return Ok(None);
+        }
+        Ok(Some(InstalledContent {
+            meta: updatemeta,
+            filetree: Some(ft),
+            adopted_from: None,
+            devices: Self::disk_ids(&disk),
+        }))
+    }
+
+    fn get_efi_vendor(&self, _: &openat::Dir) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROCK64: &str = r#"{
+        "compatible": ["pine64,rock64", "rockchip,rk3328"],
+        "source": "usr/share/uboot/rock64-rk3328",
+        "images": [
+            { "file": "idbloader.img", "offset": 32768 },
+            { "file": "u-boot.itb", "partition": "uboot" }
+        ]
+    }"#;
+
+    #[test]
+    fn test_boards() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let boardsdir = td.path().join(BOARDS_DIR);
+        std::fs::create_dir_all(&boardsdir)?;
+        std::fs::write(boardsdir.join("rock64-rk3328.json"), ROCK64)?;
+        let boards = load_boards(&openat::Dir::open(td.path())?)?;
+        let board = &boards["rock64-rk3328"];
+        assert_eq!(
+            board.images[0],
+            Image {
+                file: "idbloader.img".into(),
+                target: Target::Offset(32768)
+            }
+        );
+        assert_eq!(board.images[1].target, Target::Partition("uboot".into()));
+
+        let (name, _) = match_board(&boards, b"pine64,rock64\0rockchip,rk3328\0").unwrap();
+        assert_eq!(name, "rock64-rk3328");
+        assert!(match_board(&boards, b"raspberrypi,4-model-b\0brcm,bcm2711\0").is_none());
+
+        let none = BTreeMap::new();
+        assert_eq!(target_board(&boards, &boards, None)?, "rock64-rk3328");
+        assert_eq!(
+            target_board(&boards, &none, Some("rock64-rk3328"))?,
+            "rock64-rk3328"
+        );
+        assert!(target_board(&boards, &none, None).is_err());
+        assert!(target_board(&boards, &none, Some("rockpro64-rk3399")).is_err());
+
+        let lsblk = crate::blockdev::Topology::parse(
+            r#"{"blockdevices": [
+                {"path": "/dev/mmcblk1", "pttype": "gpt", "parttypename": null},
+                {"path": "/dev/mmcblk1p1", "pkname": "/dev/mmcblk1", "pttype": "gpt",
+                 "parttypename": null, "partlabel": "uboot"},
+                {"path": "/dev/mmcblk1p2", "pkname": "/dev/mmcblk1", "pttype": "gpt",
+                 "parttypename": null, "partlabel": "EFI System Partition"}
+            ]}"#,
+        )?;
+        assert_eq!(
+            lsblk.partition_by_label("/dev/mmcblk1", "uboot")?,
+            "/dev/mmcblk1p1"
+        );
+        assert_eq!(
+            lsblk.partition_by_label("/dev/mmcblk1", "EFI System Partition")?,
+            "/dev/mmcblk1p2"
+        );
+        assert!(lsblk.partition_by_label("/dev/mmcblk1", "boot").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_image() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let disk = td.path().join("disk");
+        std::fs::write(&disk, vec![0u8; 4096])?;
+        write_image(&disk, 1024, "idbloader.img", b"u-boot", true)?;
+        let data = std::fs::read(&disk)?;
+        assert_eq!(data.len(), 4096);
+        assert_eq!(&data[1024..1030], b"u-boot");
+
+        let mut hasher = Hasher::new(MessageDigest::sha512())?;
+        hasher.update(b"u-boot")?;
+        let expected = SHA512String::from_hasher(&mut hasher);
+        assert_eq!(checksum_at(&disk, 1024, 6)?, expected);
+        Ok(())
+    }
+}
@@ -1,9 +1,16 @@
+#[cfg(any(have_efi, have_rpi))]
 use std::collections::HashSet;
-use std::path::Path;
-use std::process::Command;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::time::{Duration, SystemTime};
 
 use anyhow::{bail, Context, Result};
+use fn_error_context::context;
+#[cfg(have_filetree)]
 use openat_ext::OpenatDirExt;
+use rustix::fd::BorrowedFd;
 
 pub(crate) trait CommandRunExt {
     fn run(&mut self) -> Result<()>;
@@ -19,6 +26,49 @@ impl CommandRunExt for Command {
     }
 }
 
+/// The variables passed on to the commands we run; see [`command`].
+const COMMAND_ENV: &[&str] = &["PATH", "TMPDIR", "SOURCE_DATE_EPOCH"];
+/// The search path for commands if we have none.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Create a command running `program` in the C locale, with only the
+/// variables in [`COMMAND_ENV`] passed on.  Tools like lsblk, findmnt and
+/// efibootmgr translate their output (e.g. partition type names, or error
+/// messages we match as in [`is_transient`]) according to the locale, and
+/// may change behavior depending on other variables inherited from the
+/// user; output we parse must not depend on either.
+pub(crate) fn command(program: impl AsRef<std::ffi::OsStr>) -> Command {
+    let program = program.as_ref();
+    let mut cmd = crate::simulate::command(program).unwrap_or_else(|| Command::new(program));
+    cmd.env_clear();
+    for name in COMMAND_ENV {
+        if let Some(v) = std::env::var_os(name) {
+            cmd.env(name, v);
+        }
+    }
+    if std::env::var_os("PATH").is_none() {
+        cmd.env("PATH", DEFAULT_PATH);
+    }
+    cmd.env("LC_ALL", "C");
+    cmd
+}
+
+/// Whether `program` can be run with [`command`]: it's in the search path,
+/// or stubbed when simulating.  Minimal systems such as recovery initramfs
+/// images may lack even coreutils, in which case [`crate::native`] stands
+/// in for them.
+pub(crate) fn have_program(program: &str) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    if crate::simulate::command(program.as_ref()).is_some() {
+        return true;
+    }
+    let path = std::env::var_os("PATH").unwrap_or_else(|| DEFAULT_PATH.into());
+    std::env::split_paths(&path).any(|dir| {
+        std::fs::metadata(dir.join(program))
+            .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    })
+}
+
 /// Parse an environment variable as UTF-8
 #[allow(dead_code)]
 pub(crate) fn getenv_utf8(n: &str) -> Result<Option<String>> {
@@ -33,6 +83,7 @@ pub(crate) fn getenv_utf8(n: &str) -> Result<Option<String>> {
     }
 }
 
+#[cfg(any(have_efi, have_rpi))]
 pub(crate) fn filenames(dir: &openat::Dir) -> Result<HashSet<String>> {
     let mut ret = HashSet::new();
     for entry in dir.list_dir(".")? {
@@ -64,23 +115,191 @@ pub(crate) fn filenames(dir: &openat::Dir) -> Result<HashSet<String>> {
     Ok(ret)
 }
 
-pub(crate) fn ensure_writable_mount<P: AsRef<Path>>(p: P) -> Result<()> {
-    let p = p.as_ref();
+/// Open the directory `path` below `d` for reading; `openat::Dir` only
+/// holds an `O_PATH` descriptor, which can't be synced.
+fn open_dir_rdonly(d: &openat::Dir, path: &Path) -> Result<rustix::fd::OwnedFd> {
+    use rustix::fs::{Mode, OFlags};
+    let path = if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    };
+    let d = unsafe { BorrowedFd::borrow_raw(d.as_raw_fd()) };
+    let oflags = OFlags::RDONLY | OFlags::CLOEXEC | OFlags::DIRECTORY;
+    Ok(rustix::fs::openat(d, path, oflags, Mode::empty())?)
+}
+
+/// Flush the filesystem containing the directory `d` to disk, i.e. the
+/// data and metadata of everything written to it, including by external
+/// tools such as grub-install.
+pub(crate) fn syncfs(d: &openat::Dir) -> Result<()> {
+    let _t = crate::timing::start(crate::timing::Phase::Sync);
+    let d = open_dir_rdonly(d, Path::new("."))?;
+    rustix::fs::syncfs(d).context("syncfs")
+}
+
+/// fsync() the directory `path` below `d`, making the entries renamed into
+/// (or removed from) it durable.
+pub(crate) fn fsync_dir(d: &openat::Dir, path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let _t = crate::timing::start(crate::timing::Phase::Sync);
+    let d = open_dir_rdonly(d, path)?;
+    rustix::fs::fsync(d).with_context(|| format!("fsync {path:?}"))
+}
+
+/// Take an exclusive advisory lock on the directory `d`, serializing the
+/// writers of the files in it until the returned file is dropped.  Files
+/// are replaced by renaming over them, so locking the files themselves
+/// wouldn't exclude anything, and lock files would end up in e.g. the
+/// `/usr` of images.
+pub(crate) fn lock_dir(d: &openat::Dir) -> Result<File> {
+    use fs2::FileExt;
+    let f = File::from(open_dir_rdonly(d, Path::new("."))?);
+    f.lock_exclusive().context("Locking directory")?;
+    Ok(f)
+}
+
+/// Return `true` if `path` is the root of a mounted filesystem.
+pub(crate) fn is_mountpoint(path: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let Some(parent) = path.parent() else {
+        return Ok(true);
+    };
+    if crate::simulate::is_fat_mount(path) {
+        return Ok(true);
+    }
+    let dev = path.metadata()?.dev();
+    Ok(dev != parent.metadata()?.dev())
+}
+
+/// Return `true` if `path` is on a FAT filesystem, e.g. the ESP.
+#[cfg(have_filecopy)]
+pub(crate) fn is_fat(path: &Path) -> Result<bool> {
+    if crate::simulate::active() {
+        return Ok(crate::simulate::is_fat(path));
+    }
+    let st = rustix::fs::statfs(path).with_context(|| format!("statfs failed for {path:?}"))?;
+    Ok(st.f_type == libc::MSDOS_SUPER_MAGIC)
+}
+
+/// A read-only mount made writable for a transaction, restored when
+/// dropped; see [`WritableMount::new`].
+#[must_use]
+pub(crate) struct WritableMount {
+    path: PathBuf,
+    /// Whether the mount was made writable for the whole system, and has to
+    /// be remounted read-only again
+    restore: bool,
+}
+
+impl WritableMount {
+    /// Make the mount at `p` writable if it's read-only, as `/boot` may be
+    /// on ostree systems, rather than failing deep inside e.g. grub-install
+    /// with `EROFS`.  Only our view of the mount is changed, in a mount
+    /// namespace of our own (see [`crate::host::unshare_mount_namespace`]);
+    /// if that's not enough because the filesystem itself is read-only, it's
+    /// remounted read-write and made read-only again afterwards.
+    #[context("Making {:?} writable", p.as_ref())]
+    pub(crate) fn new(p: impl AsRef<Path>) -> Result<Self> {
+        let path = p.as_ref().to_owned();
+        if !is_readonly(&path)? {
+            return Ok(Self {
+                path,
+                restore: false,
+            });
+        }
+        crate::host::unshare_mount_namespace()?;
+        remount(&path, "remount,bind,rw")?;
+        let restore = is_readonly(&path)?;
+        if restore {
+            remount(&path, "remount,rw")?;
+        }
+        log::info!("Remounted {path:?} writable");
+        Ok(Self { path, restore })
+    }
+}
+
+impl Drop for WritableMount {
+    fn drop(&mut self) {
+        if !self.restore {
+            return;
+        }
+        match remount(&self.path, "remount,ro") {
+            Ok(()) => log::info!("Remounted {:?} read-only", self.path),
+            Err(e) => log::warn!("Failed to restore read-only {:?}: {e:#}", self.path),
+        }
+    }
+}
+
+/// Return `true` if the filesystem containing `p` is mounted read-only.
+pub(crate) fn is_readonly(p: &Path) -> Result<bool> {
     let stat = rustix::fs::statvfs(p)?;
-    if !stat.f_flag.contains(rustix::fs::StatVfsMountFlags::RDONLY) {
-        return Ok(());
+    Ok(stat.f_flag.contains(rustix::fs::StatVfsMountFlags::RDONLY))
+}
+
+/// The error for `cmd` exiting with `status`, having written `stderr`.
+pub(crate) fn tool_failed(cmd: &Command, status: ExitStatus, stderr: &[u8]) -> anyhow::Error {
+    crate::errors::Error::tool_failed(
+        format!("{cmd:?}"),
+        status,
+        String::from_utf8_lossy(stderr).trim_end().to_string(),
+    )
+    .into()
+}
+
+fn remount(p: &Path, options: &str) -> Result<()> {
+    if !have_program("mount") {
+        return crate::native::remount(p, options);
     }
-    let status = std::process::Command::new("mount")
-        .args(["-o", "remount,rw"])
-        .arg(p)
-        .status()?;
+    let status = command("mount").args(["-o", options]).arg(p).status()?;
     if !status.success() {
-        anyhow::bail!("Failed to remount {:?} writable", p);
+        bail!("mount -o {options} {p:?} failed: {status}");
     }
     Ok(())
 }
 
-/// Runs the provided Command object, captures its stdout, and swallows its stderr except on
+/// How often a command failing transiently is run before giving up.
+#[cfg(any(have_bios, have_efi))]
+const TRANSIENT_ATTEMPTS: u32 = 4;
+/// The delay before the first retry, doubled for each further one.
+#[cfg(any(have_bios, have_efi))]
+const TRANSIENT_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Return `true` if the error output of a failed command suggests that the
+/// failure is transient, e.g. the device was briefly held open by udev.
+pub(crate) fn is_transient(stderr: &str) -> bool {
+    let stderr = stderr.to_ascii_lowercase();
+    ["device or resource busy", "is busy", "mount point busy"]
+        .iter()
+        .any(|m| stderr.contains(m))
+}
+
+/// Run `cmd` for its output like [`Command::output`], running it again
+/// with a backoff while it fails transiently (see [`is_transient`]).  The
+/// output of the last attempt is returned, whether it succeeded or not.
+#[cfg(any(have_bios, have_efi))]
+pub(crate) fn output_retrying(cmd: &mut Command) -> Result<std::process::Output> {
+    let mut delay = TRANSIENT_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let out = cmd
+            .output()
+            .with_context(|| format!("running {:#?}", cmd))?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        if out.status.success() || attempt == TRANSIENT_ATTEMPTS || !is_transient(&stderr) {
+            return Ok(out);
+        }
+        log::warn!(
+            "{cmd:?} failed transiently, retrying in {delay:?}: {}",
+            stderr.trim_end()
+        );
+        std::thread::sleep(delay);
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// Runs the provided Command object, captures its stdout, and keeps its stderr in the error on
 /// failure. Returns a Result<String> describing whether the command failed, and if not, its
 /// standard output. Output is assumed to be UTF-8. Errors are adequately prefixed with the full
 /// command.
@@ -90,13 +309,130 @@ pub(crate) fn cmd_output(cmd: &mut Command) -> Result<String> {
         .output()
         .with_context(|| format!("running {:#?}", cmd))?;
     if !result.status.success() {
-        eprintln!("{}", String::from_utf8_lossy(&result.stderr));
-        bail!("{:#?} failed with {}", cmd, result.status);
+        return Err(tool_failed(cmd, result.status, &result.stderr));
     }
     String::from_utf8(result.stdout)
         .with_context(|| format!("decoding as UTF-8 output of `{:#?}`", cmd))
 }
 
+/// Copy the content of `src` to the empty file `dest`.  Extents are shared
+/// with a reflink where the filesystem supports it (e.g. /boot on XFS or
+/// btrfs); otherwise the kernel copies them via `copy_file_range`, falling
+/// back to a plain read/write loop.
+#[cfg(have_filecopy)]
+pub(crate) fn copy_file_contents(src: &File, dest: &File) -> Result<()> {
+    match rustix::fs::ioctl_ficlone(dest, src) {
+        Ok(()) => return Ok(()),
+        Err(e) => log::trace!("Not reflinking: {e}"),
+    }
+    std::io::copy(&mut &*src, &mut &*dest)?;
+    Ok(())
+}
+
+/// Like [`std::fs::copy`], but reflinking where possible.
+#[cfg(any(have_bios, have_efi))]
+pub(crate) fn copy_file(src: &Path, dest: &Path) -> Result<()> {
+    let _t = crate::timing::start(crate::timing::Phase::Copy);
+    let srcf = File::open(src).with_context(|| format!("Opening {src:?}"))?;
+    let perms = srcf.metadata()?.permissions();
+    let destf = File::create(dest).with_context(|| format!("Creating {dest:?}"))?;
+    copy_file_contents(&srcf, &destf)?;
+    destf.set_permissions(perms)?;
+    Ok(())
+}
+
+/// Like [`OpenatDirExt::copy_file_at`], atomically replacing `dest`, but
+/// reflinking where possible.  Returns the size of the file.
+#[cfg(have_filetree)]
+pub(crate) fn copy_file_at(
+    srcdir: &openat::Dir,
+    src: &Path,
+    destdir: &openat::Dir,
+    dest: &Path,
+) -> Result<u64> {
+    use std::os::unix::fs::PermissionsExt;
+    let srcf = srcdir.open_file(src)?;
+    let meta = srcf.metadata()?;
+    let w = destdir.new_file_writer(0o600)?;
+    copy_file_contents(&srcf, w.writer.get_ref())?;
+    w.complete_with(dest, |f| {
+        f.set_permissions(std::fs::Permissions::from_mode(meta.permissions().mode()))
+    })?;
+    Ok(meta.len())
+}
+
+/// Parse `SOURCE_DATE_EPOCH`, as set by reproducible build pipelines.
+pub(crate) fn source_date_epoch() -> Result<Option<SystemTime>> {
+    let Some(v) = getenv_utf8("SOURCE_DATE_EPOCH")? else {
+        return Ok(None);
+    };
+    let secs: u64 = v
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid SOURCE_DATE_EPOCH: {v}"))?;
+    Ok(Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)))
+}
+
+/// The time to record for what's generated now: `SOURCE_DATE_EPOCH` if
+/// set, so that rebuilding the same inputs records the same.
+#[cfg(have_component)]
+pub(crate) fn build_time() -> Result<chrono::DateTime<chrono::Utc>> {
+    Ok(source_date_epoch()?
+        .map(Into::into)
+        .unwrap_or_else(chrono::Utc::now))
+}
+
+/// Clamp `t` to `SOURCE_DATE_EPOCH` if set, as e.g. files installed
+/// during the build are newer.
+pub(crate) fn clamp_to_source_date_epoch(
+    t: chrono::DateTime<chrono::Utc>,
+) -> Result<chrono::DateTime<chrono::Utc>> {
+    Ok(clamp_time(t, source_date_epoch()?))
+}
+
+fn clamp_time(
+    t: chrono::DateTime<chrono::Utc>,
+    epoch: Option<SystemTime>,
+) -> chrono::DateTime<chrono::Utc> {
+    match epoch {
+        Some(epoch) => t.min(epoch.into()),
+        None => t,
+    }
+}
+
+/// Set the timestamps of `path` and everything below it to `t`.
+pub(crate) fn set_times_recursive(path: &Path, t: SystemTime) -> Result<()> {
+    let times = std::fs::FileTimes::new().set_accessed(t).set_modified(t);
+    for entry in walkdir::WalkDir::new(path).sort_by_file_name() {
+        let entry = entry?;
+        if entry.file_type().is_symlink() {
+            continue;
+        }
+        std::fs::File::open(entry.path())
+            .and_then(|f| f.set_times(times))
+            .with_context(|| format!("Setting timestamps of {:?}", entry.path()))?;
+    }
+    Ok(())
+}
+
+/// The directory firmware boot menus (petitboot, U-Boot) treat as the root
+/// of the boot partition: `/boot` if it's a separate filesystem, otherwise `/`.
+#[cfg(any(all(have_bios, target_arch = "powerpc64"), have_extlinux))]
+pub(crate) fn boot_partition_root(root: &Path) -> Result<std::path::PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+    let boot = root.join("boot");
+    let dev = |p: &Path| {
+        p.metadata()
+            .map(|m| m.dev())
+            .with_context(|| format!("Querying {p:?}"))
+    };
+    if boot.exists() && dev(&boot)? != dev(root)? {
+        Ok(boot)
+    } else {
+        Ok(root.to_owned())
+    }
+}
+
 /// Copy from https://github.com/containers/bootc/blob/main/ostree-ext/src/container_utils.rs#L20
 /// Attempts to detect if the current process is running inside a container.
 /// This looks for the `container` environment variable or the presence
@@ -115,3 +451,74 @@ pub fn running_in_container() -> bool {
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_dir() -> Result<()> {
+        use fs2::FileExt;
+        let td = tempfile::tempdir()?;
+        let d = openat::Dir::open(td.path())?;
+        let lock = lock_dir(&d)?;
+        let other = File::open(td.path())?;
+        assert!(other.try_lock_exclusive().is_err());
+        drop(lock);
+        other.try_lock_exclusive()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_clamp_time() {
+        let t = chrono::DateTime::from_timestamp(1681321788, 0).unwrap();
+        assert_eq!(clamp_time(t, None), t);
+        let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1657222566);
+        assert_eq!(clamp_time(t, Some(epoch)).timestamp(), 1657222566);
+        let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+        assert_eq!(clamp_time(t, Some(epoch)), t);
+    }
+
+    #[test]
+    #[cfg(any(have_bios, have_efi))]
+    fn test_output_retrying() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let marker = td.path().join("attempted");
+        // Busy on the first attempt only
+        let script = format!(
+            "if [ -e {0:?} ]; then echo ok; else touch {0:?}; echo 'Device or resource busy' >&2; exit 1; fi",
+            marker
+        );
+        let out = output_retrying(Command::new("sh").args(["-c", &script]))?;
+        assert!(out.status.success());
+        assert_eq!(out.stdout, b"ok\n");
+        // Permanent failures aren't retried
+        let out = output_retrying(Command::new("sh").args([
+            "-c",
+            &format!("echo x >> {marker:?}; echo 'No such device' >&2; exit 1"),
+        ]))?;
+        assert!(!out.status.success());
+        assert_eq!(std::fs::read_to_string(&marker)?, "x\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_command() -> Result<()> {
+        let out = cmd_output(command("env").env("LANG", "de_DE.UTF-8"))?;
+        let vars = out
+            .lines()
+            .filter_map(|l| l.split_once('='))
+            .collect::<Vec<_>>();
+        assert!(vars.contains(&("LC_ALL", "C")));
+        assert!(vars.iter().any(|(k, _)| *k == "PATH"));
+        // Explicitly set variables are still passed, but LC_ALL overrides them
+        assert!(vars.contains(&("LANG", "de_DE.UTF-8")));
+        for (k, _) in vars {
+            assert!(
+                COMMAND_ENV.contains(&k) || k == "LC_ALL" || k == "LANG",
+                "{k}"
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+//! Disk images for the integration tests, attached as loop devices.
+//!
+//! An [`Image`] is a sparse file with a GPT partition table holding a BIOS
+//! boot partition, an ESP and a root filesystem, which is mounted with the
+//! ESP at `boot/efi`.  Update payloads are written to the root filesystem
+//! with [`Image::write_payload`], from which `bootupd backend install`
+//! installs them.  Operations on the booted system (`bootupctl update`,
+//! `validate`, ...) run chrooted into the image, in a mount namespace of
+//! their own where `/usr` is the image's layered over the host's (for the
+//! shared libraries, `grub-install`, ...).
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use tempfile::TempDir;
+
+/// The size of the images, allocated on demand.
+const IMAGE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// The partition layout, as an `sfdisk` script.
+const LAYOUT: &str = "label: gpt
+size=1MiB, type=21686148-6449-6E6F-744E-656564454649, name=BIOS-BOOT
+size=127MiB, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, name=EFI-SYSTEM
+type=0FC63DAF-8483-4772-8E79-3D69D8477DE4, name=root
+";
+
+/// The directory of the update payloads in a root.
+const UPDATES_DIR: &str = "usr/lib/bootupd/updates";
+
+/// The bootupd binary under test.
+const BOOTUPD: &str = env!("CARGO_BIN_EXE_bootupd");
+
+/// How long to wait for the partitions of a loop device to appear.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Run `cmd`, failing with its stderr unless it succeeds, and return its
+/// stdout.
+pub fn run(cmd: &mut Command) -> Result<String> {
+    let out = cmd.output().with_context(|| format!("Running {cmd:?}"))?;
+    if !out.status.success() {
+        bail!(
+            "{cmd:?} failed ({}): {}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim_end()
+        );
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Whether the host has the GRUB tools and modules for installing BIOS.
+pub fn has_bios_grub() -> bool {
+    Path::new("/usr/sbin/grub-install").exists()
+        && ["i386-pc", "x86_64-efi"]
+            .iter()
+            .all(|p| Path::new("/usr/lib/grub").join(p).exists())
+}
+
+/// A disk image attached to a loop device, with its filesystems mounted.
+pub struct Image {
+    dir: TempDir,
+    loopdev: String,
+    root: PathBuf,
+}
+
+impl Image {
+    /// Create, partition and format a new image, and mount it.
+    pub fn new() -> Result<Self> {
+        if !rustix::process::getuid().is_root() {
+            bail!("The loop device tests must run as root");
+        }
+        let dir = tempfile::tempdir_in("/var/tmp")?;
+        let path = dir.path().join("disk.img");
+        fs::File::create(&path)?.set_len(IMAGE_SIZE)?;
+        let layout = dir.path().join("layout");
+        fs::write(&layout, LAYOUT)?;
+        run(Command::new("sfdisk")
+            .arg("--quiet")
+            .arg(&path)
+            .stdin(fs::File::open(&layout)?))?;
+        let loopdev = run(Command::new("losetup")
+            .args(["--find", "--show", "--partscan"])
+            .arg(&path))?
+        .trim()
+        .to_string();
+        let root = dir.path().join("root");
+        let mut image = Self {
+            dir,
+            loopdev,
+            root: PathBuf::new(),
+        };
+        image.wait_for_partitions()?;
+        run(Command::new("mkfs.fat")
+            .args(["-F", "32", "-n", "EFI-SYSTEM"])
+            .arg(image.partition(2)))?;
+        run(Command::new("mkfs.ext4")
+            .args(["-q", "-L", "root"])
+            .arg(image.partition(3)))?;
+        fs::create_dir(&root)?;
+        run(Command::new("mount").arg(image.partition(3)).arg(&root))?;
+        image.root = root;
+        image.populate_root()?;
+        let esp = image.root.join("boot/efi");
+        fs::create_dir_all(&esp)?;
+        run(Command::new("mount").arg(image.partition(2)).arg(&esp))?;
+        Ok(image)
+    }
+
+    /// The loop device of the whole disk.
+    pub fn device(&self) -> &str {
+        &self.loopdev
+    }
+
+    /// The partition `n` of the disk, counting from 1.
+    pub fn partition(&self, n: u32) -> String {
+        format!("{}p{n}", self.loopdev)
+    }
+
+    /// The mounted root filesystem.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The mounted ESP.
+    pub fn esp(&self) -> PathBuf {
+        self.root.join("boot/efi")
+    }
+
+    /// Unmount the ESP, e.g. to update it through its device.
+    #[cfg(have_fat)]
+    pub fn unmount_esp(&self) -> Result<()> {
+        run(Command::new("umount").arg(self.esp()))?;
+        Ok(())
+    }
+
+    /// Mount the ESP again after [`Image::unmount_esp`].
+    #[cfg(have_fat)]
+    pub fn mount_esp(&self) -> Result<()> {
+        run(Command::new("mount").arg(self.partition(2)).arg(self.esp()))?;
+        Ok(())
+    }
+
+    fn wait_for_partitions(&self) -> Result<()> {
+        let start = Instant::now();
+        while !(1..=3).all(|n| Path::new(&self.partition(n)).exists()) {
+            if start.elapsed() > SETTLE_TIMEOUT {
+                bail!("Partitions of {} didn't appear", self.loopdev);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+
+    /// Lay out an empty root, with the mount points and the symlinks of a
+    /// merged `/usr` to chroot into.
+    fn populate_root(&self) -> Result<()> {
+        for d in [
+            "boot", "dev", "etc", "proc", "run", "sys", "tmp", "usr/bin", "usr/lib",
+        ] {
+            fs::create_dir_all(self.root.join(d))?;
+        }
+        for (link, target) in [
+            ("bin", "usr/bin"),
+            ("lib", "usr/lib"),
+            ("lib64", "usr/lib64"),
+            ("sbin", "usr/sbin"),
+        ] {
+            std::os::unix::fs::symlink(target, self.root.join(link))?;
+        }
+        fs::write(self.root.join("etc/os-release"), "ID=fedora\n")?;
+        // Copied to /boot with the EFI component
+        #[cfg(target_arch = "x86_64")]
+        {
+            let modules = self.root.join("usr/lib/grub/x86_64-efi");
+            fs::create_dir_all(&modules)?;
+            fs::write(modules.join("normal.mod"), "normal")?;
+        }
+        Ok(())
+    }
+
+    /// Replace the update payload of `component` in the image with `files`
+    /// (path and content), as `version`; higher versions are newer.
+    pub fn write_payload(
+        &self,
+        component: &str,
+        version: u32,
+        files: &[(&str, &str)],
+    ) -> Result<()> {
+        let updates = self.root.join(UPDATES_DIR);
+        let payload = updates.join(component);
+        fs::create_dir_all(&updates)?;
+        if payload.exists() {
+            fs::remove_dir_all(&payload)?;
+        }
+        for (path, content) in files {
+            let path = payload.join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, content)?;
+        }
+        let timestamp = chrono::DateTime::from_timestamp(1_700_000_000 + i64::from(version), 0)
+            .context("Invalid version")?;
+        let meta = serde_json::json!({
+            "timestamp": timestamp,
+            "version": format!("{component}-{version}"),
+        });
+        fs::write(updates.join(format!("{component}.json")), meta.to_string())?;
+        Ok(())
+    }
+
+    /// Run `bootupd backend install` from the payloads in the image to the
+    /// image, with `args`.
+    pub fn install(&self, args: &[&str]) -> Result<String> {
+        run(Command::new(BOOTUPD)
+            .args(["backend", "install", "--force", "--src-root"])
+            .arg(&self.root)
+            .args(args)
+            .arg(&self.root))
+    }
+
+    /// Run `bootupctl` with `args` as if the image was booted.
+    pub fn bootupctl(&self, args: &[&str]) -> Result<String> {
+        let layer = self.dir.path().join("layer");
+        fs::create_dir_all(layer.join("bin"))?;
+        fs::copy(BOOTUPD, layer.join("bin/bootupctl"))?;
+        let root = self.root.display();
+        let script = format!(
+            "set -e
+mount -t overlay overlay -o ro,lowerdir={}:{root}/usr:/usr {root}/usr
+for d in dev proc sys; do mount --rbind /$d {root}/$d; done
+exec chroot {root} \"$@\"",
+            layer.display()
+        );
+        // Not via systemd-run, which isn't reachable from the chroot
+        run(Command::new("unshare")
+            .args([
+                "--mount",
+                "--propagation",
+                "private",
+                "sh",
+                "-c",
+                &script,
+                "sh",
+            ])
+            .args(["env", "INVOCATION_ID=bootupd-loopdev", "/usr/bin/bootupctl"])
+            .args(args))
+    }
+
+    /// Boot the image under qemu with BIOS firmware, until `marker` is
+    /// printed on the serial console or `timeout` passes; returns whether it
+    /// was printed.
+    pub fn boot_bios(&self, marker: &str, timeout: Duration) -> Result<bool> {
+        run(&mut Command::new("sync"))?;
+        let serial = self.dir.path().join("serial.log");
+        let mut qemu = Command::new("qemu-system-x86_64")
+            .args(["-m", "512", "-display", "none", "-no-reboot"])
+            .arg("-drive")
+            .arg(format!(
+                "file={},format=raw,snapshot=on",
+                self.dir.path().join("disk.img").display()
+            ))
+            .arg("-serial")
+            .arg(format!("file:{}", serial.display()))
+            .spawn()
+            .context("Running qemu")?;
+        let start = Instant::now();
+        let found = loop {
+            let out = fs::read_to_string(&serial).unwrap_or_default();
+            if out.contains(marker) {
+                break true;
+            }
+            if start.elapsed() > timeout || qemu.try_wait()?.is_some() {
+                break false;
+            }
+            std::thread::sleep(Duration::from_millis(500));
+        };
+        let _ = qemu.kill();
+        qemu.wait()?;
+        Ok(found)
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        if !self.root.as_os_str().is_empty() {
+            let _ = run(Command::new("umount").arg("--recursive").arg(&self.root));
+        }
+        let _ = run(Command::new("losetup").arg("--detach").arg(&self.loopdev));
+    }
+}
@@ -0,0 +1,162 @@
+//! Integration tests installing, updating and validating the bootloaders
+//! of disk images attached as loop devices, exercising the actual write
+//! paths.  They need root and `sfdisk`, `mkfs.fat`, `mkfs.ext4` and
+//! `unshare`, so they are only built with the `integration-tests` feature:
+//!
+//! ```text
+//! sudo cargo test --features integration-tests --test loopdev
+//! ```
+//!
+//! Installing BIOS needs the GRUB tools and modules of the host and is
+//! skipped without them; with `BOOTUPD_TEST_QEMU=1`, the result is booted
+//! under qemu too.
+
+mod harness;
+
+use std::fs;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use harness::Image;
+
+#[cfg(target_arch = "x86_64")]
+const SHIM: &str = "shimx64.efi";
+#[cfg(target_arch = "x86_64")]
+const FALLBACK: &str = "BOOTX64.EFI";
+#[cfg(target_arch = "aarch64")]
+const SHIM: &str = "shimaa64.efi";
+#[cfg(target_arch = "aarch64")]
+const FALLBACK: &str = "BOOTAA64.EFI";
+
+/// How long booting the image under qemu may take.
+const BOOT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Write an EFI payload to `image` with a shim of `content` and the files
+/// `extra`.
+fn write_efi_payload(image: &Image, version: u32, content: &str, extra: &[&str]) -> Result<()> {
+    let shim = format!("fedora/{SHIM}");
+    let fallback = format!("BOOT/{FALLBACK}");
+    let mut files = vec![(shim.as_str(), content), (fallback.as_str(), content)];
+    files.extend(extra.iter().map(|f| (*f, "extra")));
+    image.write_payload("EFI", version, &files)
+}
+
+#[test]
+fn test_install_efi() -> Result<()> {
+    let image = Image::new()?;
+    write_efi_payload(&image, 1, "shim 1", &[])?;
+    image.install(&["--component", "EFI"])?;
+    let esp = image.esp();
+    assert_eq!(
+        fs::read_to_string(esp.join(format!("EFI/fedora/{SHIM}")))?,
+        "shim 1"
+    );
+    assert_eq!(
+        fs::read_to_string(esp.join(format!("EFI/BOOT/{FALLBACK}")))?,
+        "shim 1"
+    );
+    assert!(image.root().join("boot/bootupd-state.json").exists());
+
+    let status: serde_json::Value = serde_json::from_str(&image.bootupctl(&["status", "--json"])?)?;
+    assert_eq!(status["components"]["EFI"]["installed"]["version"], "EFI-1");
+    assert_eq!(
+        status["components"]["EFI"]["updatable"],
+        "at-latest-version"
+    );
+    // A second installation would overwrite what's recorded
+    assert!(image.install(&["--component", "EFI"]).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_update_efi() -> Result<()> {
+    let image = Image::new()?;
+    write_efi_payload(&image, 1, "shim 1", &["fedora/mmx64.efi"])?;
+    image.install(&["--component", "EFI"])?;
+    write_efi_payload(&image, 2, "shim 2", &["fedora/fbx64.efi"])?;
+    let status: serde_json::Value = serde_json::from_str(&image.bootupctl(&["status", "--json"])?)?;
+    assert_eq!(status["components"]["EFI"]["updatable"], "upgradable");
+
+    image.bootupctl(&["update"])?;
+    let efi = image.esp().join("EFI");
+    assert_eq!(
+        fs::read_to_string(efi.join(format!("fedora/{SHIM}")))?,
+        "shim 2"
+    );
+    assert_eq!(
+        fs::read_to_string(efi.join(format!("BOOT/{FALLBACK}")))?,
+        "shim 2"
+    );
+    assert!(efi.join("fedora/fbx64.efi").exists());
+    assert!(!efi.join("fedora/mmx64.efi").exists());
+    let status: serde_json::Value = serde_json::from_str(&image.bootupctl(&["status", "--json"])?)?;
+    assert_eq!(status["components"]["EFI"]["installed"]["version"], "EFI-2");
+    image.bootupctl(&["validate"])?;
+    Ok(())
+}
+
+#[test]
+#[cfg(have_fat)]
+fn test_update_efi_direct() -> Result<()> {
+    let image = Image::new()?;
+    write_efi_payload(&image, 1, "shim 1", &["fedora/mmx64.efi"])?;
+    image.install(&["--component", "EFI"])?;
+    write_efi_payload(&image, 2, "shim 2", &[])?;
+    image.unmount_esp()?;
+    image.bootupctl(&["update", "--direct-esp"])?;
+    image.mount_esp()?;
+    let efi = image.esp().join("EFI");
+    assert_eq!(
+        fs::read_to_string(efi.join(format!("fedora/{SHIM}")))?,
+        "shim 2"
+    );
+    assert!(!efi.join("fedora/mmx64.efi").exists());
+    let status: serde_json::Value = serde_json::from_str(&image.bootupctl(&["status", "--json"])?)?;
+    assert_eq!(status["components"]["EFI"]["installed"]["version"], "EFI-2");
+    image.bootupctl(&["validate"])?;
+    Ok(())
+}
+
+#[test]
+fn test_validate_efi() -> Result<()> {
+    let image = Image::new()?;
+    write_efi_payload(&image, 1, "shim 1", &[])?;
+    image.install(&["--component", "EFI"])?;
+    image.bootupctl(&["validate"])?;
+    fs::write(image.esp().join(format!("EFI/fedora/{SHIM}")), "corrupted")?;
+    let e = image.bootupctl(&["validate"]).unwrap_err();
+    assert!(format!("{e:#}").contains("validation errors"), "{e:#}");
+    Ok(())
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn test_install_bios() -> Result<()> {
+    if !harness::has_bios_grub() {
+        eprintln!("Skipping: no GRUB tools and modules for BIOS on the host");
+        return Ok(());
+    }
+    let image = Image::new()?;
+    image.write_payload("BIOS", 1, &[])?;
+    image.install(&["--component", "BIOS", "--device", image.device()])?;
+    let grub_dir = ["boot/grub2", "boot/grub"]
+        .iter()
+        .map(|d| image.root().join(d))
+        .find(|d| d.join("i386-pc").exists())
+        .expect("GRUB modules installed");
+    // The core image is embedded in the BIOS boot partition
+    let core = fs::read(image.partition(1))?;
+    assert!(core.iter().take(512).any(|&b| b != 0));
+
+    if std::env::var_os("BOOTUPD_TEST_QEMU").is_none() {
+        return Ok(());
+    }
+    let marker = "bootupd-loopdev-booted";
+    fs::write(
+        grub_dir.join("grub.cfg"),
+        format!("serial\nterminal_output serial\necho {marker}\nhalt\n"),
+    )?;
+    assert!(image.boot_bios(marker, BOOT_TIMEOUT)?, "GRUB didn't boot");
+    Ok(())
+}
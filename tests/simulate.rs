@@ -0,0 +1,127 @@
+//! End-to-end tests of installing and updating in a fake root with
+//! `--simulate`, which need neither root nor disks.  They are skipped where
+//! unprivileged user namespaces are unavailable.
+
+#![cfg(all(feature = "efi", target_arch = "x86_64"))]
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use anyhow::{bail, Result};
+
+/// The bootupd binary under test.
+const BOOTUPD: &str = env!("CARGO_BIN_EXE_bootupd");
+
+/// What entering the simulation fails with where unprivileged user
+/// namespaces, or mounting in them, aren't permitted.
+const UNSUPPORTED: &[&str] = &["Creating a user namespace", "Operation not permitted"];
+
+/// Write an EFI payload with a shim of `content` as `version` to `root`.
+fn write_efi_payload(root: &Path, version: u32, content: &str) -> Result<()> {
+    let updates = root.join("usr/lib/bootupd/updates");
+    for f in ["EFI/fedora/shimx64.efi", "EFI/BOOT/BOOTX64.EFI"] {
+        let f = updates.join(f);
+        fs::create_dir_all(f.parent().unwrap())?;
+        fs::write(f, content)?;
+    }
+    let meta = serde_json::json!({
+        "timestamp": format!("2024-01-0{version}T00:00:00Z"),
+        "version": format!("EFI-{version}"),
+    });
+    fs::write(updates.join("EFI.json"), meta.to_string())?;
+    Ok(())
+}
+
+/// Prepare a fake root of a Fedora system booted via EFI, with its ESP at
+/// `/boot/efi`.
+fn fake_root(root: &Path) -> Result<()> {
+    for d in [
+        "boot/efi",
+        "etc",
+        "sys/firmware/efi",
+        "usr/lib/grub/x86_64-efi",
+    ] {
+        fs::create_dir_all(root.join(d))?;
+    }
+    fs::write(root.join("etc/os-release"), "ID=fedora\n")?;
+    fs::write(root.join("usr/lib/grub/x86_64-efi/normal.mod"), "normal")?;
+    fs::create_dir_all(root.join(".bootupd-simulate"))?;
+    fs::write(root.join(".bootupd-simulate/fat"), "boot/efi\n")?;
+    Ok(())
+}
+
+/// Run the binary as `name` with `args`, simulating against `root`; `None`
+/// if simulating isn't possible here.
+fn run(root: &Path, name: &str, args: &[&str]) -> Result<Option<Output>> {
+    let bin = root.parent().unwrap().join(name);
+    if !bin.exists() {
+        std::os::unix::fs::symlink(BOOTUPD, &bin)?;
+    }
+    let out = Command::new(&bin)
+        .arg("--simulate")
+        .arg(root)
+        .args(args)
+        .output()?;
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    if stderr.contains("Entering simulation") && UNSUPPORTED.iter().any(|s| stderr.contains(s)) {
+        eprintln!("Skipping: can't simulate here: {stderr}");
+        return Ok(None);
+    }
+    if !out.status.success() {
+        bail!("{name} {args:?} failed: {stderr}");
+    }
+    Ok(Some(out))
+}
+
+#[test]
+fn test_simulate_install_and_update() -> Result<()> {
+    let td = tempfile::tempdir()?;
+    let root = td.path().join("root");
+    fake_root(&root)?;
+    write_efi_payload(&root, 1, "shim 1")?;
+    let install = ["install", "--src-root", "/", "--component", "EFI", "/"];
+    if run(&root, "bootupd", &install)?.is_none() {
+        return Ok(());
+    }
+    let esp = root.join("boot/efi/EFI");
+    assert_eq!(
+        fs::read_to_string(esp.join("fedora/shimx64.efi"))?,
+        "shim 1"
+    );
+    assert!(root.join("boot/bootupd-state.json").exists());
+
+    write_efi_payload(&root, 2, "shim 2")?;
+    // Planning changes nothing
+    let out = run(&root, "bootupctl", &["update", "--plan", "--json"])?.unwrap();
+    let plan: serde_json::Value = serde_json::from_slice(&out.stdout)?;
+    assert_eq!(plan["schema-version"], 1);
+    let efi = &plan["components"][0];
+    assert_eq!(efi["component"], "EFI");
+    assert_eq!(efi["to"]["version"], "EFI-2");
+    let shim = serde_json::json!({
+        "action": "write-file",
+        "path": "/boot/efi/EFI/fedora/shimx64.efi",
+        "size": 6,
+    });
+    assert!(efi["actions"].as_array().unwrap().contains(&shim), "{plan}");
+    assert_eq!(
+        fs::read_to_string(esp.join("fedora/shimx64.efi"))?,
+        "shim 1"
+    );
+
+    let out = run(&root, "bootupctl", &["update"])?.unwrap();
+    assert!(String::from_utf8_lossy(&out.stdout).contains("Updated EFI: EFI-2"));
+    assert_eq!(
+        fs::read_to_string(esp.join("fedora/shimx64.efi"))?,
+        "shim 2"
+    );
+    assert_eq!(fs::read_to_string(esp.join("BOOT/BOOTX64.EFI"))?, "shim 2");
+    // The ESP was inspected with the stub of findmnt
+    let commands = fs::read_to_string(root.join(".bootupd-simulate/commands.log"))?;
+    assert!(
+        commands.lines().any(|l| l.starts_with("findmnt ")),
+        "{commands}"
+    );
+    Ok(())
+}